@@ -1,8 +1,6 @@
-#![feature(test)]
 #[macro_use]
 extern crate criterion;
 
-extern crate test;
 extern crate rand;
 extern crate snow;
 extern crate x25519_dalek;
@@ -14,11 +12,57 @@ use snow::params::*;
 const MSG_SIZE: usize = 4096;
 
 pub fn copy_memory(data: &[u8], out: &mut [u8]) -> usize {
-    for count in 0..data.len() {
-        out[count] = data[count];
+    out[..data.len()].copy_from_slice(data);
+    data.len()
+}
+
+/// Handshake patterns and suites covered by the "handshake" group, beyond the original ones.
+const HANDSHAKES: &[&str] = &[
+    "Noise_NN_25519_ChaChaPoly_BLAKE2s",
+    "Noise_XX_25519_ChaChaPoly_BLAKE2s",
+    "Noise_IK_25519_ChaChaPoly_BLAKE2s",
+    "Noise_XX_25519_AESGCM_SHA256",
+    "Noise_IK_25519_AESGCM_SHA256",
+    #[cfg(feature = "448")] "Noise_XX_448_ChaChaPoly_BLAKE2b",
+];
+
+/// Templates for both sides of a handshake with `params`, with whatever keys it needs.
+fn templates(params: &NoiseParams) -> (BuilderTemplate, BuilderTemplate) {
+    let pattern = params.handshake.pattern;
+    let keys_i = Builder::new(params.clone()).generate_keypair().unwrap();
+    let keys_r = Builder::new(params.clone()).generate_keypair().unwrap();
+    let mut b_i = Builder::new(params.clone());
+    let mut b_r = Builder::new(params.clone());
+    if pattern.needs_local_static_key(true) {
+        b_i = b_i.local_private_key(&keys_i.private);
+    }
+    if pattern.needs_local_static_key(false) {
+        b_r = b_r.local_private_key(&keys_r.private);
+    }
+    if pattern.need_known_remote_pubkey(true) {
+        b_i = b_i.remote_public_key(&keys_r.public);
     }
+    if pattern.need_known_remote_pubkey(false) {
+        b_r = b_r.remote_public_key(&keys_i.public);
+    }
+    (b_i.into_template().unwrap(), b_r.into_template().unwrap())
+}
 
-    data.len()
+/// Run a whole handshake between `h_i` and `h_r`.
+fn handshake(h_i: &mut Session, h_r: &mut Session, buffer_msg: &mut [u8], buffer_out: &mut [u8]) {
+    while !(h_i.is_handshake_finished() && h_r.is_handshake_finished()) {
+        let (sender, receiver) = if h_i.is_my_turn() { (&mut *h_i, &mut *h_r) } else { (&mut *h_r, &mut *h_i) };
+        let len = sender.write_message(&[], buffer_msg).unwrap();
+        receiver.read_message(&buffer_msg[..len], buffer_out).unwrap();
+    }
+}
+
+/// A pair of transport mode sessions for `pattern`.
+fn transport_pair(pattern: &str) -> (Session, Session) {
+    let mut h_i = Builder::new(pattern.parse().unwrap()).build_initiator().unwrap();
+    let mut h_r = Builder::new(pattern.parse().unwrap()).build_responder().unwrap();
+    handshake(&mut h_i, &mut h_r, &mut [0u8; 1024], &mut [0u8; 1024]);
+    (h_i.into_transport_mode().unwrap(), h_r.into_transport_mode().unwrap())
 }
 
 
@@ -81,7 +125,7 @@ fn benchmarks(c: &mut Criterion) {
     }).throughput(Throughput::Elements(1)));
 
     c.bench("transport", Benchmark::new("AESGCM_SHA256 throughput", |b| {
-        static PATTERN: &'static str = "Noise_NN_25519_AESGCM_SHA256";
+        static PATTERN: &str = "Noise_NN_25519_AESGCM_SHA256";
 
         let mut h_i = Builder::new(PATTERN.parse().unwrap())
             .build_initiator().unwrap();
@@ -107,7 +151,7 @@ fn benchmarks(c: &mut Criterion) {
     }).throughput(Throughput::Bytes(MSG_SIZE as u32 * 2)));
 
     c.bench("transport", Benchmark::new("ChaChaPoly_BLAKE2s throughput", |b| {
-        static PATTERN: &'static str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
+        static PATTERN: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
 
         let mut h_i = Builder::new(PATTERN.parse().unwrap())
             .build_initiator().unwrap();
@@ -133,5 +177,47 @@ fn benchmarks(c: &mut Criterion) {
     }).throughput(Throughput::Bytes(MSG_SIZE as u32 * 2)));
 }
 
-criterion_group!(benches, benchmarks);
+fn handshake_benchmarks(c: &mut Criterion) {
+    for pattern in HANDSHAKES {
+        let params: NoiseParams = pattern.parse().unwrap();
+        c.bench("handshake", Benchmark::new(*pattern, move |b| {
+            let (t_i, t_r) = templates(&params);
+            let mut buffer_msg = [0u8; 1024];
+            let mut buffer_out = [0u8; 1024];
+            b.iter(|| {
+                let mut h_i = t_i.build_initiator().unwrap();
+                let mut h_r = t_r.build_responder().unwrap();
+                handshake(&mut h_i, &mut h_r, &mut buffer_msg, &mut buffer_out);
+            })
+        }).throughput(Throughput::Elements(1)));
+    }
+}
+
+fn transport_benchmarks(c: &mut Criterion) {
+    for pattern in &["Noise_NN_25519_AESGCM_SHA256", "Noise_NN_25519_ChaChaPoly_BLAKE2s"] {
+        let suite = &pattern["Noise_NN_25519_".len()..];
+
+        c.bench("transport", Benchmark::new(format!("{} in place", suite), move |b| {
+            let (mut h_i, mut h_r) = transport_pair(pattern);
+            let mut buffer = [0u8; MSG_SIZE * 2];
+            b.iter(move || {
+                let len = h_i.write_message_in_place(&mut buffer, MSG_SIZE).unwrap();
+                let _ = h_r.read_message_in_place(&mut buffer[..len]).unwrap();
+            })
+        }).throughput(Throughput::Bytes(MSG_SIZE as u32 * 2)));
+
+        c.bench("transport", Benchmark::new(format!("{} max size", suite), move |b| {
+            let (mut h_i, mut h_r) = transport_pair(pattern);
+            let payload_len = 65535 - h_i.get_next_message_overhead();
+            let mut buffer_msg = vec![0u8; 65535];
+            let mut buffer_out = vec![0u8; 65535];
+            b.iter(move || {
+                let len = h_i.write_message(&buffer_msg[..payload_len], &mut buffer_out).unwrap();
+                let _ = h_r.read_message(&buffer_out[..len], &mut buffer_msg).unwrap();
+            })
+        }).throughput(Throughput::Bytes((65535 - 16) * 2)));
+    }
+}
+
+criterion_group!(benches, benchmarks, handshake_benchmarks, transport_benchmarks);
 criterion_main!(benches);
@@ -15,7 +15,7 @@ use snow::params::NoiseParams;
 use std::io::{self, Read, Write};
 use std::net::{TcpListener, TcpStream};
 
-static SECRET: &'static [u8] = b"i don't care for fidget spinners";
+static SECRET: &[u8] = b"i don't care for fidget spinners";
 lazy_static! {
     static ref PARAMS: NoiseParams = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
 }
@@ -2,21 +2,33 @@
 #[macro_use] extern crate lazy_static;
 extern crate snow;
 
-use snow::params::NoiseParams;
+use snow::BuilderTemplate;
 
-static SECRET: &'static [u8] = b"i don't care for fidget spinners";
 lazy_static! {
-    static ref PARAMS: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    static ref TEMPLATES: Vec<BuilderTemplate> = [
+        "Noise_NN_25519_ChaChaPoly_BLAKE2s",
+        "Noise_XX_25519_AESGCM_SHA256",
+        "Noise_NNpsk0_25519_ChaChaPoly_SHA512",
+    ].iter().map(|name| {
+        let builder = snow::Builder::new(name.parse().unwrap())
+            .local_private_key(&[1u8; 32]);
+        let builder = if name.contains("psk0") { builder.psk(0, &[2u8; 32]) } else { builder };
+        builder.into_template().unwrap()
+    }).collect();
 }
 
 fn main() {
-    let mut out_buf = [0u8; 64000];
+    let mut payload = Vec::new();
     loop {
         fuzz!(|data: &[u8]| {
-            let builder = snow::Builder::new(PARAMS.clone());
-            let mut noise = builder.build_responder().unwrap();
+            if data.is_empty() {
+                return;
+            }
+            let template = &TEMPLATES[data[0] as usize % TEMPLATES.len()];
+            let mut noise = template.build_responder().unwrap();
 
-            let _ = noise.read_message(data, &mut out_buf);
+            // Any input may fail, but it must never panic.
+            let _ = noise.read_message_to_vec(&data[1..], &mut payload);
         });
     }
-}
\ No newline at end of file
+}
@@ -1,12 +1,20 @@
-use crate::constants::{PSKLEN, MAXDHLEN};
+use crate::constants::{PSKLEN, MAXDHLEN, MAXKEMSSLEN};
 use crate::handshakestate::HandshakeState;
 use crate::cipherstate::{CipherState, CipherStates};
+use crate::symmetricstate::SymmetricState;
 use crate::session::Session;
+#[cfg(feature = "risky-key-export")] use crate::transportstate::TransportState;
 use crate::utils::Toggle;
-use crate::params::NoiseParams;
+use crate::params::{CustomPattern, HandshakeModifier, NoiseParams, Role, Token};
 use crate::resolvers::CryptoResolver;
+use crate::types::{Cipher, Dh, Hash, Kem, Random};
+#[cfg(feature = "risky-trace")] use crate::types::SymmetricTrace;
+use crate::params::{CipherChoice, DHChoice, HashChoice, KemChoice};
+use crate::utils::secure_zero;
 use crate::error::{Error, InitStage, Prerequisite};
 use subtle::ConstantTimeEq;
+#[cfg(feature = "std")] use std::sync::Arc;
+#[cfg(not(feature = "std"))] use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
 
 /// A keypair object returned by [`generate_keypair()`]
 ///
@@ -46,12 +54,23 @@ impl PartialEq for Keypair {
 /// ```
 pub struct Builder<'builder> {
     params:   NoiseParams,
-    resolver: Box<CryptoResolver>,
+    resolver: Box<dyn CryptoResolver>,
     s:        Option<&'builder [u8]>,
+    s_dh:     Option<Box<dyn Dh>>,
+    e_dh:     Option<Box<dyn Dh>>,
     e_fixed:  Option<&'builder [u8]>,
     rs:       Option<&'builder [u8]>,
+    rs_pin:   Option<&'builder [u8]>,
+    custom:   Option<CustomPattern>,
+    auth:     (bool, bool),
+    reject_low_order: bool,
     psks:     [Option<&'builder [u8]>; 10],
+    psk_count: Option<usize>,
     plog:     Option<&'builder [u8]>,
+    protocol_id: Option<&'builder [u8]>,
+    #[cfg(feature = "risky-trace")]
+    trace:    Option<SymmetricTrace>,
+    reused:   Option<CipherStates>,
 }
 
 impl<'builder> Builder<'builder> {
@@ -60,7 +79,7 @@ impl<'builder> Builder<'builder> {
     pub fn new(params: NoiseParams) -> Self {
         use crate::resolvers::DefaultResolver;
 
-        Self::with_resolver(params, Box::new(DefaultResolver::default()))
+        Self::with_resolver(params, Box::new(DefaultResolver))
     }
 
     /// Create a Builder with the ring resolver and default resolver as a fallback.
@@ -80,24 +99,74 @@ impl<'builder> Builder<'builder> {
     }
 
     /// Create a Builder with a custom crypto resolver.
-    pub fn with_resolver(params: NoiseParams, resolver: Box<CryptoResolver>) -> Self {
+    pub fn with_resolver(params: NoiseParams, resolver: Box<dyn CryptoResolver>) -> Self {
         Builder {
             params,
             resolver,
             s: None,
+            s_dh: None,
+            e_dh: None,
             e_fixed: None,
             rs: None,
+            rs_pin: None,
+            custom: None,
+            auth: (false, false),
+            reject_low_order: false,
             plog: None,
+            protocol_id: None,
             psks: [None; 10],
+            psk_count: None,
+            #[cfg(feature = "risky-trace")]
+            trace: None,
+            reused: None,
         }
     }
 
-    /// Specify a PSK (only used with `NoisePSK` base parameter)
+    /// Specify the PSK for the `psk` modifier at `location`, e.g. `psk(2, key)` for
+    /// `Noise_XXpsk2_...`. Any PSK that's still missing can be set later with
+    /// `Session::set_psk()`, before the handshake gets to it.
+    ///
+    /// # Panics
+    ///
+    /// If `location` is 10 or more.
     pub fn psk(mut self, location: u8, key: &'builder [u8]) -> Self {
         self.psks[location as usize] = Some(key);
         self
     }
 
+    /// Specify all of the PSKs the handshake's `psk` modifiers call for at once, in the order
+    /// the modifiers appear in the protocol name, e.g. `psks(&[k0, k2])` for
+    /// `Noise_XXpsk0+psk2_...`.
+    ///
+    /// Building fails with `InitStage::ValidatePskCount` unless exactly one key is given per
+    /// `psk` modifier, and with `InitStage::ValidatePskLengths` unless every key is 32 bytes.
+    ///
+    /// With a [`custom_pattern()`](#method.custom_pattern), which has to be set first, the
+    /// keys go to its `Psk` tokens in the order they appear instead.
+    pub fn psks(mut self, keys: &[&'builder [u8]]) -> Self {
+        let locations: Vec<usize> = match self.custom {
+            Some(ref custom) => custom.messages.iter().flatten()
+                .filter_map(|token| match *token {
+                    Token::Psk(location) => Some(location as usize),
+                    _ => None,
+                })
+                .collect(),
+            None => self.params.handshake.modifiers.list.iter()
+                .filter_map(|modifier| match *modifier {
+                    HandshakeModifier::Psk(location) => Some(location as usize),
+                    _ => None,
+                })
+                .collect(),
+        };
+        for (&location, key) in locations.iter().zip(keys.iter()) {
+            if let Some(psk) = self.psks.get_mut(location) {
+                *psk = Some(key);
+            }
+        }
+        self.psk_count = Some(keys.len());
+        self
+    }
+
     /// Your static private key (can be generated with [`generate_keypair()`]).
     ///
     /// [`generate_keypair()`]: #method.generate_keypair
@@ -106,6 +175,32 @@ impl<'builder> Builder<'builder> {
         self
     }
 
+    /// Use `dh` as your static key, in place of a private key given to `local_private_key()`.
+    ///
+    /// The builder never calls `set()` or `generate()` on it, and nothing reads its private
+    /// key, so `dh` can be backed by an HSM or secure enclave that only exposes its public
+    /// key and performs the DH operations itself.
+    pub fn local_static_dh(mut self, dh: Box<dyn Dh>) -> Self {
+        self.s_dh = Some(dh);
+        self
+    }
+
+    /// Use `dh` as the ephemeral key pair instead of generating one when the handshake gets
+    /// to its `e` token, e.g. to draw ephemerals from a pool generated ahead of time.
+    ///
+    /// `dh` must already hold a freshly generated key pair, and is consumed by the session,
+    /// so an ephemeral can't be handed to more than one handshake this way. Whatever fills
+    /// the pool has to make sure it never hands out the same key pair twice.
+    pub fn local_ephemeral_dh(mut self, dh: Box<dyn Dh>) -> Self {
+        self.e_dh = Some(dh);
+        self
+    }
+
+    /// Use `key` as the ephemeral private key instead of generating one, so that the
+    /// handshake can be checked against test vectors.
+    ///
+    /// **Never use this outside of tests:** reusing an ephemeral key across sessions
+    /// breaks the security guarantees of every Noise pattern.
     #[doc(hidden)]
     pub fn fixed_ephemeral_key_for_testing_only(mut self, key: &'builder [u8]) -> Self {
         self.e_fixed = Some(key);
@@ -118,14 +213,150 @@ impl<'builder> Builder<'builder> {
         self
     }
 
+    /// An identifier for the application protocol running over Noise, for domain separation
+    /// when the same static key is used by more than one protocol: a handshake of one can't
+    /// be completed by (or replayed into) a peer of the other, even with identical params and
+    /// prologues.
+    ///
+    /// The id is hashed on its own right after the protocol name, as `MixHash(protocol_id)`
+    /// ahead of `MixHash(prologue)`, so it can't run into the prologue the way a prefix of the
+    /// prologue itself could. Both peers must configure the same id, and a peer that sets
+    /// none can't complete a handshake with one that does, which also means a handshake with
+    /// an id doesn't interoperate with other Noise implementations unless they do the same.
+    pub fn protocol_id(mut self, id: &'builder [u8]) -> Self {
+        self.protocol_id = Some(id);
+        self
+    }
+
     /// The responder's static public key.
     pub fn remote_public_key(mut self, pub_key: &'builder [u8]) -> Self {
         self.rs = Some(pub_key);
         self
     }
 
+    /// Pin the static public key the remote party is expected to send during the handshake,
+    /// for patterns like `XX` where it isn't known up front. If the peer's static key doesn't
+    /// match, `read_message()` fails with
+    /// [`StateProblem::RemoteStaticMismatch`](enum.StateProblem.html#variant.RemoteStaticMismatch)
+    /// as soon as the key is decrypted, before the message's payload is processed.
+    ///
+    /// Building fails with `Error::Input` if the remote static key is already known, either
+    /// from `remote_public_key()` or from the pattern's premessages.
+    pub fn pin_remote_public_key(mut self, pub_key: &'builder [u8]) -> Self {
+        self.rs_pin = Some(pub_key);
+        self
+    }
+
+    /// Use an explicit token schedule in place of the handshake pattern named in the params,
+    /// to try out a pattern the spec doesn't define. The params still pick the DH, cipher and
+    /// hash functions, and the custom pattern's name replaces the pattern's in the protocol
+    /// name, so `Noise_NN_25519_ChaChaPoly_BLAKE2s` with a pattern named `NNplus` hashes
+    /// `Noise_NNplus_25519_ChaChaPoly_BLAKE2s`. Any modifiers in the params are ignored.
+    ///
+    /// A custom pattern hasn't had the scrutiny the ones in the spec have, so don't rely on
+    /// one for anything but research.
+    pub fn custom_pattern(mut self, pattern: CustomPattern) -> Self {
+        self.custom = Some(pattern);
+        self
+    }
+
+    /// Refuse to build a session unless the handshake pattern authenticates the initiator
+    /// and/or the responder, so that an unauthenticated pattern like `NN` can't be picked by
+    /// accident. `require_authentication(true, true)` demands mutual authentication.
+    ///
+    /// Even authenticated patterns differ in how well they hold up against key compromise
+    /// impersonation, see the
+    /// [payload security properties](http://noiseprotocol.org/noise.html#payload-security-properties)
+    /// in the spec.
+    ///
+    /// Only static keys count here. PSK patterns like `NNpsk0` authenticate both parties
+    /// through the shared key alone and need no static keys at all, so they will be refused;
+    /// check [`HandshakeChoice::is_psk()`] instead if that's the authentication you want.
+    ///
+    /// [`HandshakeChoice::is_psk()`]: params/struct.HandshakeChoice.html#method.is_psk
+    pub fn require_authentication(mut self, initiator: bool, responder: bool) -> Self {
+        self.auth = (initiator, responder);
+        self
+    }
+
+    /// Fail the handshake with [`Error::Dh`](enum.Error.html#variant.Dh) if any DH operation
+    /// produces an all-zero shared secret, which is what a low-order (or all-zero) public key
+    /// from the remote party leads to with both `25519` and `448`.
+    ///
+    /// The Noise spec doesn't require this check, since such a key only lets the remote party
+    /// sabotage its own session, but protocols that rely on both parties contributing to the
+    /// shared secret should turn it on. Off by default.
+    pub fn reject_low_order_keys(mut self, reject: bool) -> Self {
+        self.reject_low_order = reject;
+        self
+    }
+
+    /// Call `hook` after every change to the handshake hash and chaining key, from the
+    /// protocol name and prologue through each token, so that `h` and `ck` can be compared
+    /// step by step with another implementation's when the two don't interoperate.
+    ///
+    /// The hook sees the chaining key and the DH results mixed into it, so this is for
+    /// debugging only, and the feature should never be turned on in a release build.
+    #[cfg(feature = "risky-trace")]
+    pub fn trace_symmetric_state(mut self, hook: SymmetricTrace) -> Self {
+        self.trace = Some(hook);
+        self
+    }
+
+    /// Turn the builder into a [`BuilderTemplate`] that owns a copy of its configuration, so a
+    /// server can stamp out a fresh session for every connection that uses the same pattern,
+    /// keys and prologue without setting up a `Builder` each time.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if a key object was given with `local_static_dh()`
+    /// or `local_ephemeral_dh()`, or a fixed ephemeral key was set, since none of those can
+    /// be shared between sessions.
+    ///
+    /// [`BuilderTemplate`]: struct.BuilderTemplate.html
+    pub fn into_template(self) -> Result<BuilderTemplate, Error> {
+        if self.s_dh.is_some() || self.e_dh.is_some() || self.e_fixed.is_some() {
+            bail!(Error::Input);
+        }
+
+        let mut psks: [Option<Vec<u8>>; 10] = Default::default();
+        for (psk, key) in psks.iter_mut().zip(self.psks.iter()) {
+            *psk = key.map(|key| key.to_vec());
+        }
+        Ok(BuilderTemplate {
+            params: self.params,
+            resolver: Arc::from(self.resolver),
+            s: self.s.map(|s| s.to_vec()),
+            rs: self.rs.map(|rs| rs.to_vec()),
+            rs_pin: self.rs_pin.map(|rs| rs.to_vec()),
+            custom: self.custom,
+            auth: self.auth,
+            reject_low_order: self.reject_low_order,
+            psks,
+            psk_count: self.psk_count,
+            plog: self.plog.map(|plog| plog.to_vec()),
+            protocol_id: self.protocol_id.map(|id| id.to_vec()),
+            #[cfg(feature = "risky-trace")]
+            trace: self.trace,
+        })
+    }
+
     // TODO: performance issue w/ creating a new RNG and DH instance per call.
     /// Generate a new asymmetric keypair (for use as a static key).
+    ///
+    /// The keypair is for the DH function named in the builder's params, and is drawn
+    /// from the same resolver-provided RNG that the handshake uses for ephemeral keys.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use snow::Builder;
+    /// let builder = Builder::new("Noise_XX_25519_ChaChaPoly_BLAKE2b".parse().unwrap());
+    /// let keypair = builder.generate_keypair().unwrap();
+    /// assert_eq!(keypair.private.len(), 32);
+    ///
+    /// let session = builder.local_private_key(&keypair.private).build_initiator();
+    /// ```
     pub fn generate_keypair(&self) -> Result<Keypair, Error> {
         let mut rng     = self.resolver.resolve_rng().ok_or(InitStage::GetRngImpl)?;
         let mut dh      = self.resolver.resolve_dh(&self.params.dh).ok_or(InitStage::GetDhImpl)?;
@@ -139,22 +370,107 @@ impl<'builder> Builder<'builder> {
         Ok(Keypair { private, public })
     }
 
+    /// Resume a transport session from the output of `Session::export_transport()`, e.g. in
+    /// another process after a connection migration. The builder needs the same params the
+    /// session was originally built with; nothing besides the params and the resolver is
+    /// used.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if `exported` isn't a valid export for these params.
+    #[cfg(feature = "risky-key-export")]
+    pub fn import_transport(self, exported: &[u8]) -> Result<Session, Error> {
+        let hash = self.resolver.resolve_hash(&self.params.hash).ok_or(InitStage::GetHashImpl)?;
+        let dh_len = self.resolver.resolve_dh(&self.params.dh).ok_or(InitStage::GetDhImpl)?.pub_len();
+        let cipher = self.resolver.resolve_cipher(&self.params.cipher).ok_or(InitStage::GetCipherImpl)?;
+        let cipher1 = self.resolver.resolve_cipher(&self.params.cipher).ok_or(InitStage::GetCipherImpl)?;
+        let cipher2 = self.resolver.resolve_cipher(&self.params.cipher).ok_or(InitStage::GetCipherImpl)?;
+        let cipherstates = CipherStates::new(CipherState::new(cipher1), CipherState::new(cipher2))?;
+        let mut symmetricstate = SymmetricState::new(CipherState::new(cipher), hash);
+        let protocol_name = self.protocol_name();
+        symmetricstate.initialize(&protocol_name);
+
+        let pattern = self.pattern()?;
+        let state = TransportState::import(protocol_name, pattern.is_oneway(), cipherstates, symmetricstate, dh_len, exported)?;
+        Ok(Session::Transport(state))
+    }
+
     /// Build a NoiseSession for the side who will initiate the handshake (send the first message)
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Prereq` if the pattern calls for a local static key or a
+    /// known remote static key that wasn't provided.
+    ///
+    /// Will result in `Error::Init` if a PSK was given for a location that the handshake
+    /// has no `psk` modifier for, or if `psks()` was given the wrong number of keys.
+    ///
+    /// Will result in `Error::Init` if the pattern doesn't provide the authentication asked
+    /// for with `require_authentication()`.
+    ///
+    /// Will result in `Error::Init` if the pattern has KEM tokens and the resolver has no
+    /// implementation of the KEM named in the params.
     pub fn build_initiator(self) -> Result<Session, Error> {
-        self.build(true)
+        self.build(Role::Initiator)
     }
 
     /// Build a NoiseSession for the side who will be responder (receive the first message)
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Prereq` if the pattern calls for a local static key or a
+    /// known remote static key that wasn't provided.
+    ///
+    /// Will result in `Error::Init` if a PSK was given for a location that the handshake
+    /// has no `psk` modifier for, or if `psks()` was given the wrong number of keys.
+    ///
+    /// Will result in `Error::Init` if the pattern doesn't provide the authentication asked
+    /// for with `require_authentication()`.
+    ///
+    /// Will result in `Error::Init` if the pattern has KEM tokens and the resolver has no
+    /// implementation of the KEM named in the params.
     pub fn build_responder(self) -> Result<Session, Error> {
-        self.build(false)
+        self.build(Role::Responder)
+    }
+
+    /// The token schedule to build with: the custom pattern if one was given, or else the
+    /// pattern named in the params.
+    fn pattern(&self) -> Result<CustomPattern, Error> {
+        match self.custom {
+            Some(ref custom) => Ok(custom.clone()),
+            None             => CustomPattern::from_choice(&self.params.name, &self.params.handshake),
+        }
     }
 
-    fn build(self, initiator: bool) -> Result<Session, Error> {
-        if self.s.is_none() && self.params.handshake.pattern.needs_local_static_key(initiator) {
+    /// The protocol name that gets hashed, with a custom pattern's name in place of the one
+    /// in the params.
+    fn protocol_name(&self) -> String {
+        match self.custom {
+            Some(ref custom) => {
+                let mut sections: Vec<&str> = self.params.name.split('_').collect();
+                if let Some(section) = sections.get_mut(1) {
+                    *section = &custom.name;
+                }
+                sections.join("_")
+            },
+            None => self.params.name.clone(),
+        }
+    }
+
+    /// Build a NoiseSession for the given `role`, for code that picks the side at runtime.
+    /// `build(Role::Initiator)` is the same as `build_initiator()`, and fails the same ways.
+    pub fn build(self, role: Role) -> Result<Session, Error> {
+        let pattern = self.pattern()?;
+        let protocol_name = self.protocol_name();
+        if (self.auth.0 && !pattern.authenticates_initiator()) || (self.auth.1 && !pattern.authenticates_responder()) {
+            bail!(InitStage::ValidateAuthentication);
+        }
+
+        if self.s.is_none() && self.s_dh.is_none() && pattern.needs_local_static_key(role) {
             bail!(Prerequisite::LocalPrivateKey);
         }
 
-        if self.rs.is_none() && self.params.handshake.pattern.need_known_remote_pubkey(initiator) {
+        if self.rs.is_none() && pattern.need_known_remote_pubkey(role) {
             bail!(Prerequisite::RemotePublicKey);
         }
 
@@ -163,30 +479,67 @@ impl<'builder> Builder<'builder> {
         let hash = self.resolver.resolve_hash(&self.params.hash).ok_or(InitStage::GetHashImpl)?;
         let mut s_dh = self.resolver.resolve_dh(&self.params.dh).ok_or(InitStage::GetDhImpl)?;
         let mut e_dh = self.resolver.resolve_dh(&self.params.dh).ok_or(InitStage::GetDhImpl)?;
-        let cipher1 = self.resolver.resolve_cipher(&self.params.cipher).ok_or(InitStage::GetCipherImpl)?;
-        let cipher2 = self.resolver.resolve_cipher(&self.params.cipher).ok_or(InitStage::GetCipherImpl)?;
+        let cipherstates = match self.reused {
+            Some(mut reused) if reused.0.name() == cipher.name() => {
+                reused.clear();
+                reused
+            },
+            _ => {
+                let cipher1 = self.resolver.resolve_cipher(&self.params.cipher).ok_or(InitStage::GetCipherImpl)?;
+                let cipher2 = self.resolver.resolve_cipher(&self.params.cipher).ok_or(InitStage::GetCipherImpl)?;
+                CipherStates::new(CipherState::new(cipher1), CipherState::new(cipher2))?
+            },
+        };
         let handshake_cipherstate = CipherState::new(cipher);
-        let cipherstates = CipherStates::new(CipherState::new(cipher1), CipherState::new(cipher2))?;
 
-        let s = match self.s {
-            Some(k) => {
-                (&mut *s_dh).set(k);
+        // A custom pattern can use a KEM without the params' `hfs` modifier, but never
+        // without a KEM named in them.
+        let kem = if pattern.messages.iter().flatten().any(|token| *token == Token::E1) {
+            let choice = self.params.kem.ok_or(InitStage::GetKemImpl)?;
+            let kem = self.resolver.resolve_kem(&choice).ok_or(InitStage::GetKemImpl)?;
+            if kem.shared_secret_len() > MAXKEMSSLEN {
+                bail!(InitStage::ValidateKemTypes);
+            }
+            Some(kem)
+        } else {
+            None
+        };
+
+        if self.s.is_some_and(|k| k.len() != s_dh.priv_len())
+            || self.e_fixed.is_some_and(|k| k.len() != e_dh.priv_len())
+            || self.rs.is_some_and(|k| k.len() != s_dh.pub_len())
+            || self.rs_pin.is_some_and(|k| k.len() != s_dh.pub_len())
+            || self.s_dh.as_ref().is_some_and(|dh| dh.pub_len() != s_dh.pub_len())
+            || self.e_dh.as_ref().is_some_and(|dh| dh.pub_len() != e_dh.pub_len())
+        {
+            bail!(InitStage::ValidateKeyLengths);
+        }
+
+        let s = match (self.s_dh, self.s) {
+            (Some(dh), _) => Toggle::on(dh),
+            (None, Some(k)) => {
+                (*s_dh).set(k);
                 Toggle::on(s_dh)
             },
-            None => {
+            (None, None) => {
                 Toggle::off(s_dh)
             }
         };
 
-        if let Some(fixed_k) = self.e_fixed {
-            (&mut *e_dh).set(fixed_k);
-        }
-        let e = Toggle::off(e_dh);
+        let fixed_ephemeral = self.e_dh.is_some() || self.e_fixed.is_some();
+        let e = match (self.e_dh, self.e_fixed) {
+            (Some(dh), _) => Toggle::off(dh),
+            (None, Some(k)) => {
+                (*e_dh).set(k);
+                Toggle::off(e_dh)
+            },
+            (None, None) => Toggle::off(e_dh),
+        };
 
         let mut rs_buf = [0u8; MAXDHLEN];
         let rs = match self.rs {
             Some(v) => {
-                rs_buf[..v.len()].copy_from_slice(&v[..]);
+                rs_buf[..v.len()].copy_from_slice(v);
                 Toggle::on(rs_buf)
             },
             None => Toggle::off(rs_buf),
@@ -194,11 +547,20 @@ impl<'builder> Builder<'builder> {
 
         let re = Toggle::off([0u8; MAXDHLEN]);
 
+        let psk_tokens = || pattern.messages.iter().flatten().filter(|token| matches!(token, Token::Psk(_)));
+        if let Some(count) = self.psk_count {
+            if count != psk_tokens().count() {
+                bail!(InitStage::ValidatePskCount);
+            }
+        }
+
         let mut psks = [None::<[u8; PSKLEN]>; 10];
         for (i, psk) in self.psks.iter().enumerate() {
             if let Some(key) = *psk {
                 if key.len() != PSKLEN {
                     bail!(InitStage::ValidatePskLengths);
+                } else if !psk_tokens().any(|token| *token == Token::Psk(i as u8)) {
+                    bail!(InitStage::ValidatePskPosition);
                 }
                 let mut k = [0u8; PSKLEN];
                 k.copy_from_slice(key);
@@ -206,20 +568,177 @@ impl<'builder> Builder<'builder> {
             }
         }
 
-        let hs = HandshakeState::new(rng, handshake_cipherstate, hash,
-                                     s, e, self.e_fixed.is_some(), rs, re,
-                                     initiator,
-                                     self.params,
+        let mut params = self.params;
+        params.name = protocol_name;
+        #[allow(unused_mut)]
+        let mut symmetricstate = SymmetricState::new(handshake_cipherstate, hash);
+        #[cfg(feature = "risky-trace")]
+        {
+            if let Some(hook) = self.trace {
+                symmetricstate.set_trace(hook);
+            }
+        }
+        let mut hs = HandshakeState::new(rng, symmetricstate,
+                                     s, e, fixed_ephemeral, rs, re,
+                                     role,
+                                     params,
+                                     pattern,
                                      psks,
-                                     self.plog.unwrap_or_else(|| &[0u8; 0] ),
+                                     self.plog.unwrap_or(&[0u8; 0]),
                                      cipherstates)?;
+        hs.reject_low_order = self.reject_low_order;
+        hs.kem = kem;
+        if let Some(id) = self.protocol_id {
+            hs.set_protocol_id(id)?;
+        }
+        if let Some(pin) = self.rs_pin {
+            hs.set_remote_static(pin)?;
+        }
         Ok(hs.into())
     }
 }
 
+/// An owned, cloneable copy of a [`Builder`]'s configuration, made with
+/// [`Builder::into_template()`]. Every session built from it gets its own RNG, ephemeral key
+/// and symmetric state, while the static key, prologue, PSKs and resolver are shared.
+///
+/// # Examples
+///
+/// ```
+/// # use snow::Builder;
+/// # let my_long_term_key = [0u8; 32];
+/// let template = Builder::new("Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap())
+///     .local_private_key(&my_long_term_key)
+///     .into_template()
+///     .unwrap();
+///
+/// // For every inbound connection:
+/// let noise = template.build_responder().unwrap();
+/// ```
+///
+/// [`Builder`]: struct.Builder.html
+/// [`Builder::into_template()`]: struct.Builder.html#method.into_template
+#[derive(Clone)]
+pub struct BuilderTemplate {
+    params:   NoiseParams,
+    resolver: Arc<dyn CryptoResolver>,
+    s:        Option<Vec<u8>>,
+    rs:       Option<Vec<u8>>,
+    rs_pin:   Option<Vec<u8>>,
+    custom:   Option<CustomPattern>,
+    auth:     (bool, bool),
+    reject_low_order: bool,
+    psks:     [Option<Vec<u8>>; 10],
+    psk_count: Option<usize>,
+    plog:     Option<Vec<u8>>,
+    protocol_id: Option<Vec<u8>>,
+    #[cfg(feature = "risky-trace")]
+    trace:    Option<SymmetricTrace>,
+}
+
+impl BuilderTemplate {
+    /// Build a fresh session for the initiator, exactly like `Builder::build_initiator()`
+    /// would have.
+    pub fn build_initiator(&self) -> Result<Session, Error> {
+        self.builder().build(Role::Initiator)
+    }
+
+    /// Build a fresh session for the responder, exactly like `Builder::build_responder()`
+    /// would have.
+    pub fn build_responder(&self) -> Result<Session, Error> {
+        self.builder().build(Role::Responder)
+    }
+
+    /// Build a fresh session for the initiator like `build_initiator()`, but reuse the cipher
+    /// states of `old`, a session that's done with, instead of resolving new ones. Their keys
+    /// are wiped and their nonces reset before the new handshake splits into them.
+    ///
+    /// A stateless transport session, or one using another cipher, has nothing to give, so
+    /// it's just dropped and new cipher states are resolved as usual.
+    pub fn rebuild_initiator(&self, old: Session) -> Result<Session, Error> {
+        self.rebuild(old, Role::Initiator)
+    }
+
+    /// Build a fresh session for the responder like `build_responder()`, reusing the cipher
+    /// states of `old` (see `rebuild_initiator()`).
+    pub fn rebuild_responder(&self, old: Session) -> Result<Session, Error> {
+        self.rebuild(old, Role::Responder)
+    }
+
+    fn rebuild(&self, old: Session, role: Role) -> Result<Session, Error> {
+        let mut builder = self.builder();
+        builder.reused = old.into_cipherstates();
+        builder.build(role)
+    }
+
+    fn builder(&self) -> Builder<'_> {
+        let mut psks = [None; 10];
+        for (psk, key) in psks.iter_mut().zip(self.psks.iter()) {
+            *psk = key.as_ref().map(|key| &key[..]);
+        }
+        Builder {
+            params: self.params.clone(),
+            resolver: Box::new(SharedResolver(self.resolver.clone())),
+            s: self.s.as_ref().map(|s| &s[..]),
+            s_dh: None,
+            e_dh: None,
+            e_fixed: None,
+            rs: self.rs.as_ref().map(|rs| &rs[..]),
+            rs_pin: self.rs_pin.as_ref().map(|rs| &rs[..]),
+            custom: self.custom.clone(),
+            auth: self.auth,
+            reject_low_order: self.reject_low_order,
+            psks,
+            psk_count: self.psk_count,
+            plog: self.plog.as_ref().map(|plog| &plog[..]),
+            protocol_id: self.protocol_id.as_ref().map(|id| &id[..]),
+            #[cfg(feature = "risky-trace")]
+            trace: self.trace,
+            reused: None,
+        }
+    }
+}
+
+impl Drop for BuilderTemplate {
+    fn drop(&mut self) {
+        if let Some(ref mut s) = self.s {
+            secure_zero(s);
+        }
+        for psk in self.psks.iter_mut().flatten() {
+            secure_zero(psk);
+        }
+    }
+}
+
+/// Lets every builder made from a template use the template's resolver.
+struct SharedResolver(Arc<dyn CryptoResolver>);
+
+impl CryptoResolver for SharedResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        self.0.resolve_rng()
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        self.0.resolve_dh(choice)
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        self.0.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        self.0.resolve_cipher(choice)
+    }
+
+    fn resolve_kem(&self, choice: &KemChoice) -> Option<Box<dyn Kem>> {
+        self.0.resolve_kem(choice)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::PatternProblem;
 
     #[test]
     fn test_builder() {
@@ -237,11 +756,31 @@ mod tests {
         assert!(key1.unwrap() != key2.unwrap());
     }
 
+    #[test]
+    fn test_builder_keygen_matches_dh() {
+        use crate::resolvers::DefaultResolver;
+
+        let suites: &[(&str, usize)] = &[
+            ("Noise_NN_25519_ChaChaPoly_SHA256", 32),
+            #[cfg(feature = "448")] ("Noise_NN_448_ChaChaPoly_SHA256", 56),
+        ];
+        for &(name, dh_len) in suites {
+            let params: NoiseParams = name.parse().unwrap();
+            let keypair = Builder::new(params.clone()).generate_keypair().unwrap();
+            assert_eq!(keypair.private.len(), dh_len);
+            assert_eq!(keypair.public.len(), dh_len);
+
+            let mut dh = DefaultResolver.resolve_dh(&params.dh).unwrap();
+            dh.set(&keypair.private);
+            assert_eq!(dh.pubkey(), &keypair.public[..]);
+        }
+    }
+
     #[test]
     fn test_builder_bad_spec() {
         let params: ::std::result::Result<NoiseParams, _> = "Noise_NK_25519_ChaChaPoly_BLAH256".parse();
 
-        if let Ok(_) = params {
+        if params.is_ok() {
             panic!("NoiseParams should have failed");
         }
     }
@@ -253,11 +792,145 @@ mod tests {
             .local_private_key(&[0u8; 32])
             .build_initiator(); // missing remote key, should result in Err
 
-        if let Ok(_) = noise {
+        if noise.is_ok() {
             panic!("builder should have failed on build");
         }
     }
 
+    #[test]
+    fn test_builder_require_authentication() {
+        let build = |pattern: &str, initiator: bool, responder: bool| {
+            Builder::new(format!("Noise_{}_25519_ChaChaPoly_SHA256", pattern).parse().unwrap())
+                .local_private_key(&[1u8; 32])
+                .remote_public_key(&[2u8; 32])
+                .require_authentication(initiator, responder)
+                .build_initiator()
+        };
+
+        for &(pattern, initiator, responder) in &[("NN", false, false), ("XX", true, true), ("NK", false, true),
+                                                  ("XN", true, false), ("K", true, false), ("IK1", true, true)] {
+            assert!(build(pattern, initiator, responder).is_ok(), "{}", pattern);
+            if !initiator {
+                match build(pattern, true, false) {
+                    Err(Error::Init(InitStage::ValidateAuthentication)) => {},
+                    res => panic!("{} doesn't authenticate the initiator: {:?}", pattern, res.map(|_| ())),
+                }
+            }
+            if !responder {
+                match build(pattern, false, true) {
+                    Err(Error::Init(InitStage::ValidateAuthentication)) => {},
+                    res => panic!("{} doesn't authenticate the responder: {:?}", pattern, res.map(|_| ())),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_builder_psk_positions() {
+        let key = [0u8; 32];
+        let build = |name: &str, location: u8| {
+            Builder::new(name.parse().unwrap())
+                .psk(location, &key)
+                .build_initiator()
+        };
+
+        assert!(build("Noise_NNpsk0_25519_ChaChaPoly_SHA256", 0).is_ok());
+        assert!(build("Noise_NNpsk2_25519_ChaChaPoly_SHA256", 2).is_ok());
+
+        for &(name, location) in &[("Noise_NNpsk0_25519_ChaChaPoly_SHA256", 1),
+                                   ("Noise_NN_25519_ChaChaPoly_SHA256", 0)] {
+            match build(name, location) {
+                Err(Error::Init(InitStage::ValidatePskPosition)) => {},
+                res => panic!("{}: psk{} wasn't rejected: {:?}", name, location, res.map(|_| ())),
+            }
+        }
+
+        // NN only has two messages to put a PSK at the end of.
+        match build("Noise_NNpsk3_25519_ChaChaPoly_SHA256", 3) {
+            Err(Error::Pattern(PatternProblem::InvalidPsk)) => {},
+            res => panic!("psk3 wasn't rejected for NN: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_builder_psk_list() {
+        let (k0, k2) = ([0u8; 32], [2u8; 32]);
+        let build = |name: &str, keys: &[&[u8]]| {
+            Builder::new(name.parse().unwrap())
+                .psks(keys)
+                .build_initiator()
+        };
+
+        assert!(build("Noise_NNpsk0+psk2_25519_ChaChaPoly_SHA256", &[&k0, &k2]).is_ok());
+        assert!(build("Noise_NN_25519_ChaChaPoly_SHA256", &[]).is_ok());
+
+        for keys in &[&[&k0[..]][..], &[&k0, &k2, &k2]] {
+            match build("Noise_NNpsk0+psk2_25519_ChaChaPoly_SHA256", keys) {
+                Err(Error::Init(InitStage::ValidatePskCount)) => {},
+                res => panic!("{} psks weren't rejected: {:?}", keys.len(), res.map(|_| ())),
+            }
+        }
+
+        match build("Noise_NNpsk0+psk2_25519_ChaChaPoly_SHA256", &[&k0, &k2[..16]]) {
+            Err(Error::Init(InitStage::ValidatePskLengths)) => {},
+            res => panic!("short psk wasn't rejected: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_builder_missing_prereqs_per_pattern() {
+        match Builder::new("Noise_IK_25519_ChaChaPoly_SHA256".parse().unwrap())
+            .local_private_key(&[0u8; 32])
+            .build_initiator()
+        {
+            Err(Error::Prereq(Prerequisite::RemotePublicKey)) => {},
+            _ => panic!("IK initiator needs the responder's static key"),
+        }
+
+        match Builder::new("Noise_KK_25519_ChaChaPoly_SHA256".parse().unwrap())
+            .remote_public_key(&[1u8; 32])
+            .build_responder()
+        {
+            Err(Error::Prereq(Prerequisite::LocalPrivateKey)) => {},
+            _ => panic!("KK responder needs its own static key"),
+        }
+
+        match Builder::new("Noise_KK_25519_ChaChaPoly_SHA256".parse().unwrap())
+            .local_private_key(&[0u8; 32])
+            .build_responder()
+        {
+            Err(Error::Prereq(Prerequisite::RemotePublicKey)) => {},
+            _ => panic!("KK responder needs the initiator's static key"),
+        }
+
+        // The responder's key in IK only arrives during the handshake.
+        Builder::new("Noise_IK_25519_ChaChaPoly_SHA256".parse().unwrap())
+            .local_private_key(&[0u8; 32])
+            .build_responder()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_builder_bad_key_lengths() {
+        let noise = Builder::new("Noise_XK_25519_ChaChaPoly_SHA256".parse().unwrap())
+            .local_private_key(&[0u8; 31])
+            .remote_public_key(&[0u8; 32])
+            .build_initiator();
+
+        if noise.is_ok() {
+            panic!("builder should have failed on short private key");
+        }
+
+        let noise = Builder::new("Noise_XK_25519_ChaChaPoly_SHA256".parse().unwrap())
+            .local_private_key(&[0u8; 32])
+            .remote_public_key(&[0u8; 64])
+            .build_initiator();
+
+        if noise.is_ok() {
+            panic!("builder should have failed on long remote public key");
+        }
+    }
+
     #[test]
     fn test_partialeq_impl() {
         let keypair_1 = Keypair {
@@ -271,18 +944,18 @@ mod tests {
         };
         
         // If both private and public are the same, return true
-        assert_eq!(keypair_1 == keypair_2, true);
+        assert!(keypair_1 == keypair_2);
 
         // If either public or private are different, return false
 
         // Wrong private
         keypair_2.private = vec![0x50; 32];
-        assert_eq!(keypair_1 == keypair_2, false);
+        assert!(keypair_1 != keypair_2);
         // Reset to original
         keypair_2.private = vec![0x01; 32];
         // Wrong public
         keypair_2.public = vec![0x50; 32];
-        assert_eq!(keypair_1 == keypair_2, false);
+        assert!(keypair_1 != keypair_2);
     }
 }
 
@@ -1,19 +1,39 @@
-use crate::constants::TAGLEN;
 use crate::error::{Error, InitStage, StateProblem};
+use crate::params::Role;
 use crate::types::Cipher;
+use crate::constants::{CIPHERKEYLEN, MAXTAGLEN};
+#[cfg(feature = "risky-key-export")] use crate::utils::secure_zero;
+#[cfg(not(feature = "std"))] use alloc::boxed::Box;
 
 pub struct CipherState {
-    cipher : Box<Cipher>,
+    cipher : Box<dyn Cipher>,
     n : u64,
     has_key : bool,
+    /// A copy of the key, as long as it's known. A cipher's own `rekey()` doesn't tell us
+    /// the new key, so it's forgotten then.
+    #[cfg(feature = "risky-key-export")]
+    key : Option<KnownKey>,
+}
+
+/// A copy of a cipher key that's wiped when dropped.
+#[cfg(feature = "risky-key-export")]
+struct KnownKey([u8; CIPHERKEYLEN]);
+
+#[cfg(feature = "risky-key-export")]
+impl Drop for KnownKey {
+    fn drop(&mut self) {
+        secure_zero(&mut self.0);
+    }
 }
 
 impl CipherState {
-    pub fn new(cipher: Box<Cipher>) -> Self {
+    pub fn new(cipher: Box<dyn Cipher>) -> Self {
         Self {
             cipher,
             n: 0,
-            has_key: false
+            has_key: false,
+            #[cfg(feature = "risky-key-export")]
+            key: None,
         }
     }
 
@@ -21,8 +41,12 @@ impl CipherState {
         self.cipher.name()
     }
 
+    pub fn tag_len(&self) -> usize {
+        self.cipher.tag_len()
+    }
+
     pub fn set(&mut self, key: &[u8], n: u64) {
-        self.cipher.set(key);
+        self.rekey_manually(key);
         self.n = n;
         self.has_key = true;
     }
@@ -30,45 +54,128 @@ impl CipherState {
     pub fn encrypt_ad(&mut self, authtext: &[u8], plaintext: &[u8], out: &mut[u8]) -> Result<usize, Error> {
         if !self.has_key {
             bail!(StateProblem::MissingKeyMaterial);
+        } else if self.n == u64::MAX {
+            bail!(StateProblem::Exhausted);
+        } else if out.len() < plaintext.len() + self.tag_len() {
+            bail!(Error::Input);
         }
 
         let len = self.cipher.encrypt(self.n, authtext, plaintext, out);
-        self.n = self.n.checked_add(1).unwrap();
+        self.n += 1;
+        Ok(len)
+    }
+
+    pub fn encrypt_ad_in_place(&mut self, authtext: &[u8], in_out: &mut [u8], plaintext_len: usize) -> Result<usize, Error> {
+        if !self.has_key {
+            bail!(StateProblem::MissingKeyMaterial);
+        } else if self.n == u64::MAX {
+            bail!(StateProblem::Exhausted);
+        } else if in_out.len() < plaintext_len + self.tag_len() {
+            bail!(Error::Input);
+        }
+
+        let len = self.cipher.encrypt_in_place(self.n, authtext, in_out, plaintext_len);
+        self.n += 1;
         Ok(len)
     }
 
-    pub fn decrypt_ad(&mut self, authtext: &[u8], ciphertext: &[u8], out: &mut[u8]) -> Result<usize, ()> {
-        if (ciphertext.len() < TAGLEN) || (out.len() < (ciphertext.len() - TAGLEN) || !self.has_key) {
-            return Err(());
+    pub fn decrypt_ad(&mut self, authtext: &[u8], ciphertext: &[u8], out: &mut[u8]) -> Result<usize, Error> {
+        let tag_len = self.tag_len();
+        if (ciphertext.len() < tag_len) || !self.has_key {
+            bail!(Error::Decrypt);
+        } else if out.len() < ciphertext.len() - tag_len {
+            bail!(Error::BufferTooSmall(ciphertext.len() - tag_len));
+        } else if self.n == u64::MAX {
+            bail!(StateProblem::Exhausted);
         }
 
-        let len = self.cipher.decrypt(self.n, authtext, ciphertext, out);
-        self.n = self.n.checked_add(1).unwrap();
-        len
+        // A message that doesn't authenticate doesn't use up the nonce, so a forged one can't
+        // desynchronize us from the sender.
+        let len = self.cipher.decrypt(self.n, authtext, ciphertext, out).map_err(|_| Error::Decrypt)?;
+        self.n += 1;
+        Ok(len)
+    }
+
+    pub fn decrypt_ad_in_place(&mut self, authtext: &[u8], in_out: &mut [u8]) -> Result<usize, Error> {
+        if in_out.len() < self.tag_len() || !self.has_key {
+            bail!(Error::Decrypt);
+        } else if self.n == u64::MAX {
+            bail!(StateProblem::Exhausted);
+        }
+
+        let len = self.cipher.decrypt_in_place(self.n, authtext, in_out).map_err(|_| Error::Decrypt)?;
+        self.n += 1;
+        Ok(len)
     }
 
     pub fn encrypt(&mut self, plaintext: &[u8], out: &mut[u8]) -> Result<usize, Error> {
         self.encrypt_ad(&[0u8;0], plaintext, out)
     }
 
-    pub fn decrypt(&mut self, ciphertext: &[u8], out: &mut[u8]) -> Result<usize, ()> {
+    pub fn decrypt(&mut self, ciphertext: &[u8], out: &mut[u8]) -> Result<usize, Error> {
         self.decrypt_ad(&[0u8;0], ciphertext, out)
     }
 
+    pub fn encrypt_in_place(&mut self, in_out: &mut [u8], plaintext_len: usize) -> Result<usize, Error> {
+        self.encrypt_ad_in_place(&[0u8;0], in_out, plaintext_len)
+    }
+
+    pub fn decrypt_in_place(&mut self, in_out: &mut [u8]) -> Result<usize, Error> {
+        self.decrypt_ad_in_place(&[0u8;0], in_out)
+    }
+
     pub fn rekey(&mut self) {
         self.cipher.rekey();
+        #[cfg(feature = "risky-key-export")]
+        {
+            self.key = None;
+        }
     }
 
     pub fn rekey_manually(&mut self, key: &[u8]) {
         self.cipher.set(key);
+        #[cfg(feature = "risky-key-export")]
+        {
+            self.key = None;
+            if key.len() == CIPHERKEYLEN {
+                let mut copy = KnownKey([0u8; CIPHERKEYLEN]);
+                copy.0.copy_from_slice(key);
+                self.key = Some(copy);
+            }
+        }
+    }
+
+    /// The current key, unless the cipher has been rekeyed with `rekey()` since it was set.
+    #[cfg(feature = "risky-key-export")]
+    pub fn key(&self) -> Option<&[u8]> {
+        if self.has_key { self.key.as_ref().map(|key| &key.0[..]) } else { None }
+    }
+
+    /// Overwrite the key with zeroes and reset the nonce, leaving the state as it was when
+    /// created, so it can be split into again by another handshake.
+    pub fn clear(&mut self) {
+        self.cipher.set(&[0u8; CIPHERKEYLEN]);
+        self.n = 0;
+        self.has_key = false;
+        #[cfg(feature = "risky-key-export")]
+        {
+            self.key = None;
+        }
     }
 
+    /// The nonce the next message will be encrypted or decrypted with.
     pub fn nonce(&self) -> u64 {
         self.n
     }
 
-    pub fn set_nonce(&mut self, nonce: u64) {
+    /// Move the nonce counter, for protocols that carry nonces explicitly or reset them at
+    /// defined boundaries. `u64::MAX` is reserved by the spec and can't be used.
+    pub fn set_nonce(&mut self, nonce: u64) -> Result<(), Error> {
+        if nonce == u64::MAX {
+            bail!(StateProblem::Exhausted);
+        }
         self.n = nonce;
+        Ok(())
     }
 }
 
@@ -76,13 +183,35 @@ pub struct CipherStates(pub CipherState, pub CipherState);
 
 impl CipherStates {
     pub fn new(initiator: CipherState, responder: CipherState) -> Result<Self, Error> {
-        if initiator.name() != responder.name() {
+        if initiator.name() != responder.name() || initiator.tag_len() > MAXTAGLEN {
             bail!(InitStage::ValidateCipherTypes);
         }
 
         Ok(CipherStates(initiator, responder))
     }
 
+    /// The cipher that `role` encrypts its messages with.
+    pub fn outgoing(&mut self, role: Role) -> &mut CipherState {
+        match role {
+            Role::Initiator => &mut self.0,
+            Role::Responder => &mut self.1,
+        }
+    }
+
+    /// The cipher that `role` decrypts its peer's messages with.
+    pub fn incoming(&mut self, role: Role) -> &mut CipherState {
+        match role {
+            Role::Initiator => &mut self.1,
+            Role::Responder => &mut self.0,
+        }
+    }
+
+    /// Clear both ciphers (see `CipherState::clear()`).
+    pub fn clear(&mut self) {
+        self.0.clear();
+        self.1.clear();
+    }
+
     pub fn rekey_initiator(&mut self) {
         self.0.rekey()
     }
@@ -101,48 +230,44 @@ impl CipherStates {
 }
 
 pub struct StatelessCipherState {
-    cipher : Box<Cipher>,
+    cipher : Box<dyn Cipher>,
     has_key : bool,
 }
 
 impl StatelessCipherState {
-    pub fn new(cipher: Box<Cipher>) -> Self {
-        Self {
-            cipher,
-            has_key: false
-        }
-    }
-
-    pub fn name(&self) -> &'static str {
-        self.cipher.name()
+    pub fn tag_len(&self) -> usize {
+        self.cipher.tag_len()
     }
 
-    pub fn set(&mut self, key: &[u8]) {
-        self.cipher.set(key);
-        self.has_key = true;
-    }
-
-    // TODO: don't panic
     pub fn encrypt_ad(&self, nonce: u64, authtext: &[u8], plaintext: &[u8], out: &mut[u8]) -> Result<usize, Error> {
         if !self.has_key {
             bail!(StateProblem::MissingKeyMaterial);
+        } else if nonce == u64::MAX {
+            bail!(StateProblem::Exhausted);
+        } else if out.len() < plaintext.len() + self.tag_len() {
+            bail!(Error::Input);
         }
         Ok(self.cipher.encrypt(nonce, authtext, plaintext, out))
     }
 
-    pub fn decrypt_ad(&self, nonce: u64, authtext: &[u8], ciphertext: &[u8], out: &mut[u8]) -> Result<usize, ()> {
-        if (ciphertext.len() < TAGLEN) || (out.len() < (ciphertext.len() - TAGLEN) || !self.has_key) {
-            return Err(())
+    pub fn decrypt_ad(&self, nonce: u64, authtext: &[u8], ciphertext: &[u8], out: &mut[u8]) -> Result<usize, Error> {
+        let tag_len = self.tag_len();
+        if (ciphertext.len() < tag_len) || !self.has_key {
+            bail!(Error::Decrypt);
+        } else if out.len() < ciphertext.len() - tag_len {
+            bail!(Error::BufferTooSmall(ciphertext.len() - tag_len));
+        } else if nonce == u64::MAX {
+            bail!(StateProblem::Exhausted);
         }
 
-        self.cipher.decrypt(nonce, authtext, ciphertext, out)
+        self.cipher.decrypt(nonce, authtext, ciphertext, out).map_err(|_| Error::Decrypt)
     }
 
     pub fn encrypt(&self, nonce: u64, plaintext: &[u8], out: &mut[u8]) -> Result<usize, Error> {
         self.encrypt_ad(nonce, &[], plaintext, out)
     }
 
-    pub fn decrypt(&self, nonce: u64, ciphertext: &[u8], out: &mut[u8]) -> Result<usize, ()> {
+    pub fn decrypt(&self, nonce: u64, ciphertext: &[u8], out: &mut[u8]) -> Result<usize, Error> {
         self.decrypt_ad(nonce, &[], ciphertext, out)
     }
 
@@ -173,12 +298,20 @@ impl From<CipherStates> for StatelessCipherStates {
 }
 
 impl StatelessCipherStates {
-    pub fn new(initiator: StatelessCipherState, responder: StatelessCipherState) -> Result<Self, Error> {
-        if initiator.name() != responder.name() {
-            bail!(InitStage::ValidateCipherTypes);
+    /// The cipher that `role` encrypts its messages with.
+    pub fn outgoing(&self, role: Role) -> &StatelessCipherState {
+        match role {
+            Role::Initiator => &self.0,
+            Role::Responder => &self.1,
         }
+    }
 
-        Ok(StatelessCipherStates(initiator, responder))
+    /// The cipher that `role` decrypts its peer's messages with.
+    pub fn incoming(&self, role: Role) -> &StatelessCipherState {
+        match role {
+            Role::Initiator => &self.1,
+            Role::Responder => &self.0,
+        }
     }
 
     pub fn rekey_initiator(&mut self) {
@@ -197,3 +330,116 @@ impl StatelessCipherStates {
         self.1.rekey_manually(key)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolvers::{CryptoResolver, DefaultResolver};
+    use crate::params::CipherChoice;
+
+    #[test]
+    fn test_nonce_exhaustion() {
+        let key = [0x0fu8; 32];
+        let mut sender = CipherState::new(DefaultResolver.resolve_cipher(&CipherChoice::ChaChaPoly).unwrap());
+        let mut receiver = CipherState::new(DefaultResolver.resolve_cipher(&CipherChoice::ChaChaPoly).unwrap());
+        sender.set(&key, u64::MAX - 1);
+        receiver.set(&key, u64::MAX - 1);
+
+        let mut message = [0u8; 64];
+        let mut payload = [0u8; 64];
+        let len = sender.encrypt(b"last one", &mut message).unwrap();
+        receiver.decrypt(&message[..len], &mut payload).unwrap();
+
+        match sender.encrypt(b"one too many", &mut message) {
+            Err(Error::State(StateProblem::Exhausted)) => {},
+            _ => panic!("encrypt with the reserved nonce should fail"),
+        }
+        match receiver.decrypt(&message[..len], &mut payload) {
+            Err(Error::State(StateProblem::Exhausted)) => {},
+            _ => panic!("decrypt with the reserved nonce should fail"),
+        }
+        assert_eq!(sender.nonce(), u64::MAX);
+        assert_eq!(receiver.nonce(), u64::MAX);
+    }
+
+    #[test]
+    fn test_set_nonce() {
+        let key = [0x0fu8; 32];
+        let mut sender = CipherState::new(DefaultResolver.resolve_cipher(&CipherChoice::ChaChaPoly).unwrap());
+        let mut receiver = CipherState::new(DefaultResolver.resolve_cipher(&CipherChoice::ChaChaPoly).unwrap());
+        sender.set(&key, 0);
+        receiver.set(&key, 0);
+
+        let mut message = [0u8; 64];
+        let mut payload = [0u8; 64];
+        sender.set_nonce(1000).unwrap();
+        let len = sender.encrypt(b"skipped ahead", &mut message).unwrap();
+        assert_eq!(sender.nonce(), 1001);
+        assert!(receiver.decrypt(&message[..len], &mut payload).is_err());
+
+        receiver.set_nonce(1000).unwrap();
+        let payload_len = receiver.decrypt(&message[..len], &mut payload).unwrap();
+        assert_eq!(&payload[..payload_len], b"skipped ahead");
+
+        match sender.set_nonce(u64::MAX) {
+            Err(Error::State(StateProblem::Exhausted)) => {},
+            _ => panic!("the reserved nonce shouldn't be settable"),
+        }
+        assert_eq!(sender.nonce(), 1001);
+    }
+
+    #[test]
+    fn test_encrypt_into_short_buffer() {
+        let mut cipher = CipherState::new(DefaultResolver.resolve_cipher(&CipherChoice::AESGCM).unwrap());
+        cipher.set(&[0x0fu8; 32], 0);
+
+        let mut message = [0u8; 64];
+        let needed = 5 + cipher.tag_len();
+        for short_len in 0..needed {
+            match cipher.encrypt(b"hello", &mut message[..short_len]) {
+                Err(Error::Input) => {},
+                res => panic!("{}-byte buffer wasn't rejected: {:?}", short_len, res),
+            }
+            match cipher.encrypt_in_place(&mut message[..short_len], short_len.min(5)) {
+                Err(Error::Input) => {},
+                res => panic!("{}-byte buffer wasn't rejected in place: {:?}", short_len, res),
+            }
+        }
+        assert_eq!(cipher.nonce(), 0);
+        assert_eq!(cipher.encrypt(b"hello", &mut message[..needed]).unwrap(), needed);
+    }
+
+    #[test]
+    fn test_clear() {
+        let key = [0x0fu8; 32];
+        let mut sender = CipherState::new(DefaultResolver.resolve_cipher(&CipherChoice::ChaChaPoly).unwrap());
+        let mut receiver = CipherState::new(DefaultResolver.resolve_cipher(&CipherChoice::ChaChaPoly).unwrap());
+        sender.set(&key, 0);
+        receiver.set(&key, 0);
+
+        let mut message = [0u8; 64];
+        let mut payload = [0u8; 64];
+        let first_len = sender.encrypt(b"first session", &mut message).unwrap();
+        let first = message;
+        receiver.decrypt(&message[..first_len], &mut payload).unwrap();
+
+        sender.clear();
+        receiver.clear();
+        assert_eq!(sender.nonce(), 0);
+        match sender.encrypt(b"no key", &mut message) {
+            Err(Error::State(StateProblem::MissingKeyMaterial)) => {},
+            _ => panic!("a cleared cipher shouldn't encrypt"),
+        }
+        assert!(receiver.decrypt(&first[..first_len], &mut payload).is_err());
+        #[cfg(feature = "risky-key-export")]
+        assert!(sender.key().is_none());
+
+        // Set up again as a fresh session would, starting back at nonce 0.
+        sender.set(&key, 0);
+        receiver.set(&key, 0);
+        let len = sender.encrypt(b"first session", &mut message).unwrap();
+        assert_eq!(&message[..len], &first[..first_len]);
+        let payload_len = receiver.decrypt(&message[..len], &mut payload).unwrap();
+        assert_eq!(&payload[..payload_len], b"first session");
+    }
+}
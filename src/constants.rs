@@ -2,8 +2,10 @@
 pub const PSKLEN : usize = 32;
 pub const CIPHERKEYLEN : usize = 32;
 pub const TAGLEN : usize = 16;
+pub const MAXTAGLEN : usize = 64;
 
 pub const MAXHASHLEN : usize = 64;
 pub const MAXBLOCKLEN : usize = 128;
 pub const MAXDHLEN : usize = 56;
+pub const MAXKEMSSLEN : usize = 64;
 pub const MAXMSGLEN : usize = 65535;
@@ -1,6 +1,6 @@
 //! All error types used by Snow operations.
 
-use std::fmt;
+use core::fmt;
 
 /// Exits a function early with an error.
 ///
@@ -12,12 +12,12 @@ use std::fmt;
 /// ```
 macro_rules! bail {
     ($e:expr) => {
-        return Err(($e).into());
+        return Err(($e).into())
     };
 }
 
 /// All errors in snow will include an `ErrorKind`.
-#[allow(missing_docs)]
+#[allow(missing_docs, clippy::manual_non_exhaustive)]
 #[derive(Debug)]
 pub enum Error {
     /// The noise pattern failed to parse.
@@ -32,7 +32,8 @@ pub enum Error {
     /// A state error.
     State(StateProblem),
 
-    /// Invalid input.
+    /// Invalid input, such as a message that is too short to be valid or an
+    /// output buffer that is too small to hold the result.
     Input,
 
     /// Diffie-hellman failed.
@@ -41,6 +42,12 @@ pub enum Error {
     /// Decryption failed.
     Decrypt,
 
+    /// Key encapsulation or decapsulation failed.
+    Kem,
+
+    /// The buffer given for a decrypted payload is too small to hold it. Carries the length the
+    /// buffer needs to be, so the read can be retried with a big enough one.
+    BufferTooSmall(usize),
 
     /// This enum may grow additional variants, so this makes sure clients
     /// don't count on exhaustive matching. (Otherwise, adding a new variant
@@ -55,13 +62,16 @@ pub enum Error {
 #[derive(Debug)]
 pub enum PatternProblem {
     TooFewParameters,
+    TooManyParameters,
     UnsupportedHandshakeType,
     UnsupportedBaseType,
     UnsupportedHashType,
     UnsupportedDhType,
     UnsupportedCipherType,
+    UnsupportedKemType,
     InvalidPsk,
     UnsupportedModifier,
+    InvalidCustomPattern,
 }
 
 impl From<PatternProblem> for Error {
@@ -82,7 +92,12 @@ pub enum InitStage {
     GetDhImpl,
     GetCipherImpl,
     GetHashImpl,
+    GetKemImpl,
+    ValidateKemTypes,
     ValidatePskPosition,
+    ValidatePskCount,
+    ValidateFallback,
+    ValidateAuthentication,
 }
 
 impl From<InitStage> for Error {
@@ -114,9 +129,19 @@ pub enum StateProblem {
     NotTurnToWrite,
     NotTurnToRead,
     HandshakeNotFinished,
+    HandshakeAlreadyStarted,
     HandshakeAlreadyFinished,
     OneWay,
     StatelessTransportMode,
+    /// The nonce counter has reached its reserved maximum value, so the cipher
+    /// can't be used any longer without risking nonce reuse.
+    Exhausted,
+    /// The remote party presented a different static key than the one pinned
+    /// with `set_remote_static()`.
+    RemoteStaticMismatch,
+    /// The handshake got to an `e` token with an ephemeral key that has already been sent,
+    /// which would destroy forward secrecy.
+    EphemeralReused,
 }
 
 impl From<StateProblem> for Error {
@@ -135,9 +160,12 @@ impl fmt::Display for Error {
             Error::Input => write!(f, "input error"),
             Error::Dh => write!(f, "diffie-hellman error"),
             Error::Decrypt => write!(f, "decrypt error"),
+            Error::Kem => write!(f, "kem error"),
+            Error::BufferTooSmall(len) => write!(f, "buffer too small, {} bytes needed", len),
             Error::__Nonexhaustive => write!(f, "Nonexhaustive"),
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for Error {}
@@ -1,13 +1,20 @@
-use crate::constants::{PSKLEN, TAGLEN, MAXMSGLEN, MAXDHLEN};
-use crate::utils::Toggle;
-use crate::types::{Dh, Hash, Random};
-use crate::cipherstate::{CipherState, CipherStates};
-#[cfg(feature = "nightly")] use std::convert::TryFrom;
-#[cfg(not(feature = "nightly"))] use crate::utils::TryFrom;
+use crate::constants::{PSKLEN, MAXMSGLEN, MAXDHLEN, MAXKEMSSLEN};
+use crate::utils::{secure_zero, Toggle};
+use crate::types::{Dh, Kem, Random};
+use crate::cipherstate::CipherStates;
 use crate::symmetricstate::SymmetricState;
-use crate::params::{HandshakeTokens, MessagePatterns, NoiseParams, Token};
+use crate::transportstate;
+use crate::params::{self, CustomPattern, MessagePatterns, NoiseParams, Role, Token};
 use crate::error::{Error, InitStage, StateProblem};
-use std::fmt;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ptr;
+use subtle::ConstantTimeEq;
+#[cfg(not(feature = "std"))] use alloc::{boxed::Box, vec, vec::Vec};
+
+/// What `HandshakeState::into_transport_parts()` hands over to a transport state.
+pub(crate) type TransportParts =
+    (CipherStates, SymmetricState, NoiseParams, Toggle<[u8; MAXDHLEN]>, Toggle<[u8; MAXDHLEN]>, Role, bool);
 
 /// A state machine encompassing the handshake phase of a Noise session.
 ///
@@ -16,35 +23,48 @@ use std::fmt;
 ///
 /// See: http://noiseprotocol.org/noise.html#the-handshakestate-object
 pub struct HandshakeState {
-    pub(crate) rng              : Box<Random>,
+    pub(crate) rng              : Box<dyn Random>,
     pub(crate) symmetricstate   : SymmetricState,
     pub(crate) cipherstates     : CipherStates,
-    pub(crate) s                : Toggle<Box<Dh>>,
-    pub(crate) e                : Toggle<Box<Dh>>,
+    pub(crate) s                : Toggle<Box<dyn Dh>>,
+    pub(crate) e                : Toggle<Box<dyn Dh>>,
     pub(crate) fixed_ephemeral  : bool,
     pub(crate) rs               : Toggle<[u8; MAXDHLEN]>,
+    pub(crate) rs_pinned        : bool,
+    pub(crate) reject_low_order : bool,
     pub(crate) re               : Toggle<[u8; MAXDHLEN]>,
-    pub(crate) initiator        : bool,
+    pub(crate) rejected_re      : Toggle<[u8; MAXDHLEN]>,
+    pub(crate) kem              : Option<Box<dyn Kem>>,
+    pub(crate) re1              : Vec<u8>,
+    pub(crate) role             : Role,
     pub(crate) params           : NoiseParams,
     pub(crate) psks             : [Option<[u8; PSKLEN]>; 10],
+    pub(crate) prologue         : Vec<u8>,
+    pub(crate) protocol_id      : Option<Vec<u8>>,
+    pub(crate) prologue_streaming : bool,
+    pub(crate) prologue_finished  : bool,
     pub(crate) my_turn          : bool,
+    pub(crate) premessages      : (Vec<Token>, Vec<Token>),
     pub(crate) message_patterns : MessagePatterns,
+    pub(crate) psk_mode         : bool,
+    pub(crate) oneway           : bool,
     pub(crate) pattern_position : usize,
+    pub(crate) token_position   : usize,
 }
 
 impl HandshakeState {
-    #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        rng             : Box<Random>,
-        cipherstate     : CipherState,
-        hasher          : Box<Hash>,
-        s               : Toggle<Box<Dh>>,
-        e               : Toggle<Box<Dh>>,
+        rng             : Box<dyn Random>,
+        symmetricstate  : SymmetricState,
+        s               : Toggle<Box<dyn Dh>>,
+        e               : Toggle<Box<dyn Dh>>,
         fixed_ephemeral : bool,
         rs              : Toggle<[u8; MAXDHLEN]>,
         re              : Toggle<[u8; MAXDHLEN]>,
-        initiator       : bool,
+        role            : Role,
         params          : NoiseParams,
+        pattern         : CustomPattern,
         psks            : [Option<[u8; PSKLEN]>; 10],
         prologue        : &[u8],
         cipherstates    : CipherStates) -> Result<HandshakeState, Error> {
@@ -56,47 +76,13 @@ impl HandshakeState {
             bail!(InitStage::ValidateKeyLengths);
         }
 
-        let tokens = HandshakeTokens::try_from(&params.handshake)?;
-
-        let mut symmetricstate = SymmetricState::new(cipherstate, hasher);
+        // A fallback handshake is started by the responder, since the initiator's
+        // first message has been turned into a pre-message.
+        let my_turn = role.is_initiator() != pattern.responder_first;
+        let psk_mode = pattern.is_psk();
+        let oneway = pattern.is_oneway();
 
-        symmetricstate.initialize(&params.name);
-        symmetricstate.mix_hash(prologue);
-
-        let dh_len = s.pub_len();
-        if initiator {
-            for token in tokens.premsg_pattern_i {
-                symmetricstate.mix_hash(match *token {
-                    Token::S => &s,
-                    Token::E => &e,
-                    _ => unreachable!()
-                }.get().ok_or(StateProblem::MissingKeyMaterial)?.pubkey());
-            }
-            for token in tokens.premsg_pattern_r {
-                symmetricstate.mix_hash(&match *token {
-                    Token::S => &rs,
-                    Token::E => &re,
-                    _ => unreachable!()
-                }.get().ok_or(StateProblem::MissingKeyMaterial)?[..dh_len]);
-            }
-        } else {
-            for token in tokens.premsg_pattern_i {
-                symmetricstate.mix_hash(&match *token {
-                    Token::S => &rs,
-                    Token::E => &re,
-                    _ => unreachable!()
-                }.get().ok_or(StateProblem::MissingKeyMaterial)?[..dh_len]);
-            }
-            for token in tokens.premsg_pattern_r {
-                symmetricstate.mix_hash(match *token {
-                    Token::S => &s,
-                    Token::E => &e,
-                    _ => unreachable!()
-                }.get().ok_or(StateProblem::MissingKeyMaterial)?.pubkey());
-            }
-        }
-
-        Ok(HandshakeState {
+        let mut state = HandshakeState {
             rng,
             symmetricstate,
             cipherstates,
@@ -104,20 +90,147 @@ impl HandshakeState {
             e,
             fixed_ephemeral,
             rs,
+            rs_pinned: false,
+            reject_low_order: false,
             re,
-            initiator,
+            rejected_re: Toggle::off([0u8; MAXDHLEN]),
+            kem: None,
+            re1: Vec::new(),
+            role,
             params,
             psks,
-            my_turn: initiator,
-            message_patterns: tokens.msg_patterns,
+            prologue: prologue.to_vec(),
+            protocol_id: None,
+            prologue_streaming: false,
+            prologue_finished: false,
+            my_turn,
+            premessages: pattern.premessages,
+            message_patterns: pattern.messages,
+            psk_mode,
+            oneway,
             pattern_position: 0,
-        })
+            token_position: 0,
+        };
+        state.mix_prologue_and_premessages()?;
+        Ok(state)
+    }
+
+    /// (Re)start the symmetric state from the protocol name, then mix in the prologue and
+    /// any pre-message public keys.
+    fn mix_prologue_and_premessages(&mut self) -> Result<(), Error> {
+        self.initialize_symmetric();
+        self.symmetricstate.mix_hash(&self.prologue);
+        self.mix_premessages()
+    }
+
+    /// Start the symmetric state from the protocol name, followed by the protocol id if any.
+    fn initialize_symmetric(&mut self) {
+        self.symmetricstate.initialize(&self.params.name);
+        if let Some(ref id) = self.protocol_id {
+            self.symmetricstate.mix_hash(id);
+        }
+    }
+
+    /// Set the application protocol id, see `Builder::protocol_id()`. Only allowed before
+    /// the prologue is streamed or the first handshake message has been written or read.
+    pub fn set_protocol_id(&mut self, id: &[u8]) -> Result<(), Error> {
+        if self.pattern_position != 0 || self.token_position != 0
+            || self.prologue_streaming || self.prologue_finished
+        {
+            bail!(StateProblem::HandshakeAlreadyStarted);
+        }
+
+        self.protocol_id = Some(id.to_vec());
+        self.mix_prologue_and_premessages()
+    }
+
+    fn mix_premessages(&mut self) -> Result<(), Error> {
+        let dh_len = self.dh_len();
+        let (premsg_pattern_i, premsg_pattern_r) = self.premessages.clone();
+        let premessages = [(premsg_pattern_i, self.role == Role::Initiator), (premsg_pattern_r, self.role == Role::Responder)];
+        for (premessage, local) in &premessages {
+            let local = *local;
+            for token in premessage {
+                let pubkey = match (*token, local) {
+                    (Token::S, true)  => self.s.get().map(|s| s.pubkey()),
+                    (Token::E, true)  => self.e.get().map(|e| e.pubkey()),
+                    (Token::S, false) => self.rs.get().map(|rs| &rs[..dh_len]),
+                    (Token::E, false) => self.re.get().map(|re| &re[..dh_len]),
+                    _ => unreachable!()
+                }.ok_or(StateProblem::MissingKeyMaterial)?;
+                self.symmetricstate.mix_hash(pubkey);
+                if *token == Token::E && self.psk_mode {
+                    self.symmetricstate.mix_key(pubkey);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Append `data` to the prologue. Only allowed before the first handshake message has
+    /// been written or read.
+    pub fn mix_prologue(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.pattern_position != 0 || self.token_position != 0 || self.prologue_finished {
+            bail!(StateProblem::HandshakeAlreadyStarted);
+        } else if self.prologue_streaming {
+            return self.stream_prologue(data);
+        }
+
+        self.prologue.extend_from_slice(data);
+        self.mix_prologue_and_premessages()
+    }
+
+    /// Hash `data` onto the end of the prologue without keeping a copy of it, so a large
+    /// prologue can be fed in pieces. The pre-messages are mixed in by `finish_prologue()`,
+    /// which the first handshake message calls if it hasn't been already.
+    pub fn stream_prologue(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.pattern_position != 0 || self.token_position != 0 || self.prologue_finished {
+            bail!(StateProblem::HandshakeAlreadyStarted);
+        }
+
+        if !self.prologue_streaming {
+            self.initialize_symmetric();
+            self.symmetricstate.begin_mix_hash();
+            self.symmetricstate.continue_mix_hash(&self.prologue);
+            self.prologue_streaming = true;
+        }
+        self.symmetricstate.continue_mix_hash(data);
+        Ok(())
+    }
+
+    /// Close a prologue fed in with `stream_prologue()`, after which it can't be added to.
+    /// Does nothing if the prologue isn't being streamed.
+    pub fn finish_prologue(&mut self) -> Result<(), Error> {
+        if !self.prologue_streaming {
+            return Ok(());
+        }
+
+        self.prologue_streaming = false;
+        self.prologue_finished = true;
+        self.symmetricstate.end_mix_hash();
+        self.mix_premessages()
     }
 
     pub(crate) fn dh_len(&self) -> usize {
         self.s.pub_len()
     }
 
+    /// The KEM's public key and ciphertext lengths, or zeros without one.
+    fn kem_lens(&self) -> (usize, usize) {
+        self.kem.as_ref().map_or((0, 0), |kem| (kem.pub_len(), kem.ciphertext_len()))
+    }
+
+    /// How many bytes an `S`, `E1` or `Ekem1` token takes up in the next message, with a tag
+    /// if there's a key to encrypt it with by then.
+    fn encrypted_len(&self, token: Token) -> usize {
+        let len = match token {
+            Token::E1    => self.kem_lens().0,
+            Token::Ekem1 => self.kem_lens().1,
+            _            => self.dh_len(),
+        };
+        if self.symmetricstate.has_key() { len + self.symmetricstate.tag_len() } else { len }
+    }
+
     fn dh(&self, local_s: bool, remote_s: bool) -> Result<[u8; MAXDHLEN], Error> {
         if !((!local_s  || self.s.is_on())  &&
              ( local_s  || self.e.is_on())  &&
@@ -134,221 +247,439 @@ impl HandshakeState {
             (false, false) => (&self.e, &self.re),
         };
         dh.dh(&**key, &mut dh_out).map_err(|_| Error::Dh)?;
+        if self.reject_low_order && bool::from(dh_out[..dh.pub_len()].ct_eq(&[0u8; MAXDHLEN][..dh.pub_len()])) {
+            bail!(Error::Dh);
+        }
         Ok(dh_out)
     }
 
-    pub fn was_write_payload_encrypted(&self) -> bool {
+    /// Calculate the exact size of the next handshake message given a payload of `payload_len`
+    /// bytes, walking the pending tokens to figure out which parts will be encrypted.
+    fn next_message_len(&self, payload_len: usize) -> usize {
+        let (overhead, _) = params::message_overhead(&self.message_patterns[self.pattern_position][self.token_position..],
+                                                     self.psk_mode,
+                                                     self.symmetricstate.has_key(),
+                                                     self.dh_len(),
+                                                     self.kem_lens(),
+                                                     self.symmetricstate.tag_len());
+        overhead + payload_len
+    }
+
+    /// Whether the payload of the last message written or read was encrypted. Once a key has
+    /// been mixed in during the handshake, every later payload is encrypted too.
+    pub fn was_payload_encrypted(&self) -> bool {
         self.symmetricstate.has_key()
     }
 
-    #[must_use]
     pub fn write_handshake_message(&mut self,
                                   message: &[u8],
                                   payload: &mut [u8]) -> Result<usize, Error> {
+        self.finish_prologue()?;
         let checkpoint = self.symmetricstate.checkpoint();
+        let token_position = self.token_position;
+        let e_was_on = self.e.is_on();
         match self._write_handshake_message(message, payload) {
             Ok(res) => {
                 self.pattern_position += 1;
+                self.token_position = 0;
                 Ok(res)
             },
             Err(err) => {
-                self.symmetricstate.restore(checkpoint);
+                self.symmetricstate.restore(&checkpoint);
+                self.token_position = token_position;
+                // An ephemeral from a message that never went out hasn't been used yet.
+                if !e_was_on {
+                    self.e.disable();
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Write just the next token of the current handshake message into `message`, returning
+    /// how many bytes it took, or `None` once only the payload is left to write.
+    pub fn write_handshake_token(&mut self, message: &mut [u8]) -> Result<Option<usize>, Error> {
+        self.finish_prologue()?;
+        self.check_write_turn()?;
+        let token = match self.message_patterns[self.pattern_position].get(self.token_position) {
+            Some(token) => *token,
+            None        => return Ok(None),
+        };
+
+        let checkpoint = self.symmetricstate.checkpoint();
+        match self.write_token(token, message) {
+            Ok(len) => {
+                self.token_position += 1;
+                Ok(Some(len))
+            },
+            Err(err) => {
+                self.symmetricstate.restore(&checkpoint);
                 Err(err)
             }
         }
     }
 
+    fn check_write_turn(&self) -> Result<(), Error> {
+        // Check for the end of the pattern first, so that a finished handshake never indexes
+        // past the last message and is reported as finished rather than as out of turn.
+        if self.pattern_position >= self.message_patterns.len() {
+            bail!(StateProblem::HandshakeAlreadyFinished);
+        } else if !self.my_turn {
+            bail!(StateProblem::NotTurnToWrite);
+        }
+        Ok(())
+    }
+
     fn _write_handshake_message(&mut self,
                          payload: &[u8],
                          message: &mut [u8]) -> Result<usize, Error> {
-        if !self.my_turn {
-            bail!(StateProblem::NotTurnToWrite);
-        } else if self.pattern_position >= self.message_patterns.len() {
-            bail!(StateProblem::HandshakeAlreadyFinished);
+        self.check_write_turn()?;
+
+        // Check the size of the whole message up front, so that nothing is written to the
+        // caller's buffer unless it will fit, and never exceeds the max message length.
+        let message_len = self.next_message_len(payload.len());
+        if message_len > MAXMSGLEN || message_len > message.len() {
+            bail!(Error::Input);
         }
 
         let mut byte_index = 0;
-        let dh_len = self.dh_len();
-        for token in self.message_patterns[self.pattern_position].iter() {
-            match token {
-                Token::E => {
-                    if byte_index + self.e.pub_len() > message.len() {
-                        bail!(Error::Input)
-                    }
+        while let Some(&token) = self.message_patterns[self.pattern_position].get(self.token_position) {
+            byte_index += self.write_token(token, &mut message[byte_index..])?;
+            self.token_position += 1;
+        }
 
-                    if !self.fixed_ephemeral {
-                        self.e.generate(&mut *self.rng);
-                    }
-                    let pubkey = self.e.pubkey();
-                    message[byte_index..byte_index+pubkey.len()].copy_from_slice(pubkey);
-                    byte_index += pubkey.len();
-                    self.symmetricstate.mix_hash(pubkey);
-                    if self.params.handshake.is_psk() {
-                        self.symmetricstate.mix_key(pubkey);
-                    }
-                    self.e.enable();
-                },
-                Token::S => {
-                    if !self.s.is_on() {
-                        bail!(StateProblem::MissingKeyMaterial);
-                    } else if byte_index + self.s.pub_len() > message.len() {
-                        bail!(Error::Input)
-                    }
+        byte_index += self.symmetricstate.encrypt_and_mix_hash(payload, &mut message[byte_index..])?;
+        if self.pattern_position == (self.message_patterns.len() - 1) {
+            self.split();
+        }
+        self.my_turn = false;
+        Ok(byte_index)
+    }
 
-                    byte_index += self.symmetricstate.encrypt_and_mix_hash(
-                        self.s.pubkey(),
-                        &mut message[byte_index..])?;
-                },
-                Token::Psk(n) => match self.psks[*n as usize] {
-                    Some(psk) => {
-                        self.symmetricstate.mix_key_and_hash(&psk);
-                    },
-                    None => {
-                        bail!(StateProblem::MissingPsk);
+    /// Process a single token of an outgoing message, returning how many bytes of `message`
+    /// it wrote.
+    fn write_token(&mut self, token: Token, message: &mut [u8]) -> Result<usize, Error> {
+        let dh_len = self.dh_len();
+        match token {
+            Token::E => {
+                if message.len() < dh_len {
+                    bail!(Error::Input);
+                }
+                if self.fixed_ephemeral {
+                    // A provided ephemeral can only be sent once, e.g. not again in a
+                    // fallback handshake after it went out in the original one.
+                    if self.e.is_on() {
+                        bail!(StateProblem::EphemeralReused);
                     }
-                },
-                Token::Dhee => {
-                    let dh_out = self.dh(false, false)?;
-                    self.symmetricstate.mix_key(&dh_out[..dh_len]);
-                },
-                Token::Dhes => {
-                    let dh_out = self.dh(false, true)?;
-                    self.symmetricstate.mix_key(&dh_out[..dh_len]);
+                } else {
+                    #[cfg(debug_assertions)]
+                    let previous = self.e.get().map(|e| e.pubkey().to_vec());
+                    self.e.generate(&mut *self.rng);
+                    #[cfg(debug_assertions)]
+                    debug_assert!(previous.as_deref() != Some(self.e.pubkey()),
+                                  "the RNG produced the same ephemeral key twice");
+                }
+                let pubkey = self.e.pubkey();
+                message[..pubkey.len()].copy_from_slice(pubkey);
+                self.symmetricstate.mix_hash(pubkey);
+                if self.psk_mode {
+                    self.symmetricstate.mix_key(pubkey);
                 }
-                Token::Dhse => {
-                    let dh_out = self.dh(true, false)?;
-                    self.symmetricstate.mix_key(&dh_out[..dh_len]);
+                self.e.enable();
+                Ok(dh_len)
+            },
+            Token::S => {
+                if !self.s.is_on() {
+                    bail!(StateProblem::MissingKeyMaterial);
                 }
-                Token::Dhss => {
-                    let dh_out = self.dh(true, true)?;
-                    self.symmetricstate.mix_key(&dh_out[..dh_len]);
+                let tag_len = if self.symmetricstate.has_key() { self.symmetricstate.tag_len() } else { 0 };
+                if message.len() < dh_len + tag_len {
+                    bail!(Error::Input);
                 }
-            }
+                self.symmetricstate.encrypt_and_mix_hash(self.s.pubkey(), message)
+            },
+            Token::E1 => {
+                let len = self.encrypted_len(token);
+                let kem = self.kem.as_mut().ok_or(StateProblem::MissingKeyMaterial)?;
+                if message.len() < len {
+                    bail!(Error::Input);
+                }
+                kem.generate(&mut *self.rng);
+                self.symmetricstate.encrypt_and_mix_hash(kem.pubkey(), message)
+            },
+            Token::Ekem1 => {
+                let len = self.encrypted_len(token);
+                let kem = self.kem.as_ref().ok_or(StateProblem::MissingKeyMaterial)?;
+                if self.re1.is_empty() {
+                    bail!(StateProblem::MissingKeyMaterial);
+                } else if message.len() < len {
+                    bail!(Error::Input);
+                }
+                let mut ciphertext = vec![0u8; kem.ciphertext_len()];
+                let mut shared_secret = [0u8; MAXKEMSSLEN];
+                let shared_secret = &mut shared_secret[..kem.shared_secret_len()];
+                let res = match kem.encapsulate(&self.re1, &mut *self.rng, shared_secret, &mut ciphertext) {
+                    Ok(()) => self.symmetricstate.encrypt_and_mix_hash(&ciphertext, message),
+                    Err(()) => Err(Error::Kem),
+                };
+                if res.is_ok() {
+                    self.symmetricstate.mix_key(shared_secret);
+                }
+                secure_zero(shared_secret);
+                res
+            },
+            Token::Psk(n) => match self.psks[n as usize] {
+                Some(psk) => {
+                    self.symmetricstate.mix_key_and_hash(&psk);
+                    Ok(0)
+                },
+                None => {
+                    bail!(StateProblem::MissingPsk);
+                }
+            },
+            Token::Dhee | Token::Dhes | Token::Dhse | Token::Dhss => self.mix_dh(token, true),
         }
+    }
 
-        if byte_index + payload.len() + TAGLEN > message.len() {
-            bail!(Error::Input);
-        }
-        byte_index += self.symmetricstate.encrypt_and_mix_hash(payload, &mut message[byte_index..])?;
-        if byte_index > MAXMSGLEN {
-            bail!(Error::Input);
-        }
-        if self.pattern_position == (self.message_patterns.len() - 1) {
-            self.symmetricstate.split(&mut self.cipherstates.0, &mut self.cipherstates.1);
-        }
-        self.my_turn = false;
-        Ok(byte_index)
+    /// Mix in the DH for `token`, as the party `sending` the message it's in or the one
+    /// receiving it.
+    fn mix_dh(&mut self, token: Token, sending: bool) -> Result<usize, Error> {
+        // Messages are written on our turn and read on theirs, so a mix-up here would
+        // interpret the token from the wrong side, e.g. doing `es` as `se`.
+        debug_assert_eq!(sending, self.my_turn, "{:?} processed as the wrong side of the message", token);
+        let (local_s, remote_s) = token.dh_keys(sending).ok_or(Error::Input)?;
+        let dh_len = self.dh_len();
+        let dh_out = self.dh(local_s, remote_s)?;
+        self.symmetricstate.mix_key(&dh_out[..dh_len]);
+        Ok(0)
     }
 
     pub fn read_handshake_message(&mut self,
                                   message: &[u8],
                                   payload: &mut [u8]) -> Result<usize, Error> {
+        self.finish_prologue()?;
         let checkpoint = self.symmetricstate.checkpoint();
+        let token_position = self.token_position;
+        let (rs, re) = (self.rs, self.re);
+        let had_re1 = !self.re1.is_empty();
         match self._read_handshake_message(message, payload) {
             Ok(res) => {
                 self.pattern_position += 1;
+                self.token_position = 0;
                 Ok(res)
             },
             Err(err) => {
-                self.symmetricstate.restore(checkpoint);
+                // Kept for `into_fallback()`, which needs the ephemeral of the message that
+                // failed, e.g. an `IK` one sent to a stale static key.
+                if self.re.is_on() && !re.is_on() {
+                    self.rejected_re = self.re;
+                }
+                self.symmetricstate.restore(&checkpoint);
+                self.token_position = token_position;
+                self.rs = rs;
+                self.re = re;
+                if !had_re1 {
+                    self.re1.clear();
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Read just the next token of the current handshake message from the start of
+    /// `message`, returning how many bytes it consumed, or `None` once only the payload is
+    /// left to read.
+    pub fn read_handshake_token(&mut self, message: &[u8]) -> Result<Option<usize>, Error> {
+        self.finish_prologue()?;
+        self.check_read_turn()?;
+        let token = match self.message_patterns[self.pattern_position].get(self.token_position) {
+            Some(token) => *token,
+            None        => return Ok(None),
+        };
+
+        let checkpoint = self.symmetricstate.checkpoint();
+        match self.read_token(token, message) {
+            Ok(len) => {
+                self.token_position += 1;
+                Ok(Some(len))
+            },
+            Err(err) => {
+                self.symmetricstate.restore(&checkpoint);
                 Err(err)
             }
         }
     }
 
+    fn check_read_turn(&self) -> Result<(), Error> {
+        if self.pattern_position >= self.message_patterns.len() {
+            bail!(StateProblem::HandshakeAlreadyFinished);
+        } else if self.my_turn {
+            bail!(StateProblem::NotTurnToRead);
+        }
+        Ok(())
+    }
+
     fn _read_handshake_message(&mut self,
                                message: &[u8],
                                payload: &mut [u8]) -> Result<usize, Error> {
+        self.check_read_turn()?;
         if message.len() > MAXMSGLEN {
             bail!(Error::Input);
         }
 
         let last = self.pattern_position == (self.message_patterns.len() - 1);
 
-        let dh_len = self.dh_len();
+        // A static (or KEM) key that doesn't decrypt doesn't end the read there: the rest of
+        // the message is still processed, with whatever the failed decryption left behind, and
+        // errors that follow from it are ignored. So the read takes about as long as one whose
+        // payload doesn't decrypt, and fails the same way.
+        let mut failed = false;
         let mut ptr = message;
-            for token in self.message_patterns[self.pattern_position].iter() {
-                match *token {
-                    Token::E => {
-                        if ptr.len() < dh_len {
-                            bail!(Error::Input);
-                        }
-                        self.re[..dh_len].copy_from_slice(&ptr[..dh_len]);
-                        ptr = &ptr[dh_len..];
-                        self.symmetricstate.mix_hash(&self.re[..dh_len]);
-                        if self.params.handshake.is_psk() {
-                            self.symmetricstate.mix_key(&self.re[..dh_len]);
-                        }
-                        self.re.enable();
-                    },
-                    Token::S => {
-                        let data = if self.symmetricstate.has_key() {
-                            if ptr.len() < dh_len + TAGLEN {
-                                bail!(Error::Input);
-                            }
-                            let temp = &ptr[..dh_len + TAGLEN];
-                            ptr = &ptr[dh_len + TAGLEN..];
-                            temp
-                        } else {
-                            if ptr.len() < dh_len {
-                                bail!(Error::Input);
-                            }
-                            let temp = &ptr[..dh_len];
-                            ptr = &ptr[dh_len..];
-                            temp
-                        };
-                        self.symmetricstate.decrypt_and_mix_hash(data, &mut self.rs[..dh_len]).map_err(|_| Error::Decrypt)?;
+        while let Some(&token) = self.message_patterns[self.pattern_position].get(self.token_position) {
+            let len = match self.read_token(token, ptr) {
+                Ok(len) => len,
+                Err(Error::Decrypt) => {
+                    failed = true;
+                    if token == Token::S {
                         self.rs.enable();
-                    },
-                    Token::Psk(n) => {
-                        match self.psks[n as usize] {
-                            Some(psk) => {
-                                self.symmetricstate.mix_key_and_hash(&psk);
-                            },
-                            None => {
-                                bail!(StateProblem::MissingPsk);
-                            }
-                        }
-                    },
-                Token::Dhee => {
-                    let dh_out = self.dh(false, false)?;
-                    self.symmetricstate.mix_key(&dh_out[..dh_len]);
+                    }
+                    self.encrypted_len(token)
                 },
-                Token::Dhes => {
-                    let dh_out = self.dh(true, false)?;
-                    self.symmetricstate.mix_key(&dh_out[..dh_len]);
-                }
-                Token::Dhse => {
-                    let dh_out = self.dh(false, true)?;
-                    self.symmetricstate.mix_key(&dh_out[..dh_len]);
-                }
-                Token::Dhss => {
-                    let dh_out = self.dh(true, true)?;
-                    self.symmetricstate.mix_key(&dh_out[..dh_len]);
-                }
-            }
+                Err(_) if failed => 0,
+                Err(err) => return Err(err),
+            };
+            ptr = &ptr[len..];
+            self.token_position += 1;
         }
 
-        self.symmetricstate.decrypt_and_mix_hash(ptr, payload).map_err(|_| Error::Decrypt)?;
+        if self.symmetricstate.has_key() && ptr.len() < self.symmetricstate.tag_len() {
+            bail!(if failed { Error::Decrypt } else { Error::Input });
+        }
+        let payload_len = self.symmetricstate.decrypt_and_mix_hash(ptr, payload);
+        if failed {
+            bail!(Error::Decrypt);
+        }
+        let payload_len = payload_len?;
         self.my_turn = true;
         if last {
-            self.symmetricstate.split(&mut self.cipherstates.0, &mut self.cipherstates.1);
+            self.split();
         }
-        let payload_len = if self.symmetricstate.has_key() { ptr.len() - TAGLEN } else { ptr.len() };
         Ok(payload_len)
     }
 
+    /// Split the transport ciphers off the symmetric state. A one-way pattern has no
+    /// responder->initiator traffic, so that cipher is discarded (Section 5.1 of the spec)
+    /// and anything that tries to use it fails for want of a key.
+    fn split(&mut self) {
+        self.symmetricstate.split(&mut self.cipherstates.0, &mut self.cipherstates.1);
+        if self.oneway {
+            self.cipherstates.1.clear();
+        }
+    }
+
+    /// Process a single token of an incoming message, returning how many bytes of `message`
+    /// it consumed.
+    fn read_token(&mut self, token: Token, message: &[u8]) -> Result<usize, Error> {
+        let dh_len = self.dh_len();
+        match token {
+            Token::E => {
+                if message.len() < dh_len {
+                    bail!(Error::Input);
+                }
+                self.re[..dh_len].copy_from_slice(&message[..dh_len]);
+                self.symmetricstate.mix_hash(&self.re[..dh_len]);
+                if self.psk_mode {
+                    self.symmetricstate.mix_key(&self.re[..dh_len]);
+                }
+                self.re.enable();
+                Ok(dh_len)
+            },
+            Token::S => {
+                let len = self.encrypted_len(token);
+                if message.len() < len {
+                    bail!(Error::Input);
+                }
+                let data = &message[..len];
+                if self.rs_pinned {
+                    let mut received = [0u8; MAXDHLEN];
+                    self.symmetricstate.decrypt_and_mix_hash(data, &mut received[..dh_len])?;
+                    if !bool::from(received[..dh_len].ct_eq(&self.rs[..dh_len])) {
+                        bail!(StateProblem::RemoteStaticMismatch);
+                    }
+                } else {
+                    self.symmetricstate.decrypt_and_mix_hash(data, &mut self.rs[..dh_len])?;
+                }
+                self.rs.enable();
+                Ok(len)
+            },
+            Token::E1 => {
+                let len = self.encrypted_len(token);
+                let pub_len = self.kem.as_ref().ok_or(StateProblem::MissingKeyMaterial)?.pub_len();
+                if message.len() < len {
+                    bail!(Error::Input);
+                }
+                self.re1.resize(pub_len, 0);
+                self.symmetricstate.decrypt_and_mix_hash(&message[..len], &mut self.re1)?;
+                Ok(len)
+            },
+            Token::Ekem1 => {
+                let len = self.encrypted_len(token);
+                let kem = self.kem.as_ref().ok_or(StateProblem::MissingKeyMaterial)?;
+                if message.len() < len {
+                    bail!(Error::Input);
+                }
+                // Decapsulated and mixed in even if the ciphertext doesn't decrypt, for the
+                // same reason the rest of the message is read after a static key that doesn't.
+                let mut ciphertext = vec![0u8; kem.ciphertext_len()];
+                let decrypted = self.symmetricstate.decrypt_and_mix_hash(&message[..len], &mut ciphertext);
+                let mut shared_secret = [0u8; MAXKEMSSLEN];
+                let shared_secret = &mut shared_secret[..kem.shared_secret_len()];
+                let decapsulated = kem.decapsulate(&ciphertext, shared_secret);
+                self.symmetricstate.mix_key(shared_secret);
+                secure_zero(shared_secret);
+                decrypted?;
+                decapsulated.map_err(|_| Error::Kem)?;
+                Ok(len)
+            },
+            Token::Psk(n) => match self.psks[n as usize] {
+                Some(psk) => {
+                    self.symmetricstate.mix_key_and_hash(&psk);
+                    Ok(0)
+                },
+                None => {
+                    bail!(StateProblem::MissingPsk);
+                }
+            },
+            Token::Dhee | Token::Dhes | Token::Dhse | Token::Dhss => self.mix_dh(token, false),
+        }
+    }
+
     /// Set the PSK at the specified position.
-    #[must_use]
     pub fn set_psk(&mut self, location: usize, key: &[u8]) -> Result<(), Error> {
         if key.len() != PSKLEN || self.psks.len() <= location {
             bail!(Error::Input);
         }
 
         let mut new_psk = [0u8; PSKLEN];
-        new_psk.copy_from_slice(&key[..]);
-        self.psks[location as usize] = Some(new_psk);
+        new_psk.copy_from_slice(key);
+        self.psks[location] = Some(new_psk);
+
+        Ok(())
+    }
 
+    /// Pin the static public key the remote party is expected to present later in the
+    /// handshake. Reading a message with any other static key fails with
+    /// `StateProblem::RemoteStaticMismatch`.
+    pub fn set_remote_static(&mut self, key: &[u8]) -> Result<(), Error> {
+        if self.is_finished() {
+            bail!(StateProblem::HandshakeAlreadyFinished);
+        } else if key.len() != self.dh_len() || self.rs.is_on() {
+            bail!(Error::Input);
+        }
+
+        self.rs[..key.len()].copy_from_slice(key);
+        self.rs.enable();
+        self.rs_pinned = true;
         Ok(())
     }
 
@@ -362,17 +693,223 @@ impl HandshakeState {
         self.rs.get().map(|rs| &rs[..self.dh_len()])
     }
 
+    /// The full name of the Noise protocol in use, as hashed into the handshake.
+    pub fn get_protocol_name(&self) -> &str {
+        &self.params.name
+    }
+
+    /// Get the remote party's ephemeral public key, once it has been received.
+    pub fn get_remote_ephemeral(&self) -> Option<&[u8]> {
+        self.re.get().map(|re| &re[..self.dh_len()])
+    }
+
+    /// The ephemeral key at the start of `message`, if what's left of the next message to
+    /// read starts with one. Nothing is read, so the state doesn't change.
+    pub fn peek_remote_ephemeral<'a>(&self, message: &'a [u8]) -> Option<&'a [u8]> {
+        let dh_len = self.dh_len();
+        match self.next_message_tokens().first() {
+            Some(Token::E) if !self.my_turn && message.len() >= dh_len => Some(&message[..dh_len]),
+            _ => None,
+        }
+    }
+
+    /// Get the current handshake hash (`h`).
+    ///
+    /// Note: this value only carries channel-binding meaning once the handshake
+    /// is finished.
     pub fn get_handshake_hash(&self) -> &[u8] {
         self.symmetricstate.handshake_hash()
     }
 
+    /// Derive key material for the application from a finished handshake, see
+    /// `SymmetricState::export_secret()`.
+    pub fn export_secret(&mut self, label: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        if !self.is_finished() {
+            bail!(StateProblem::HandshakeNotFinished);
+        }
+        self.symmetricstate.export_secret(label, out)
+    }
+
     pub fn is_initiator(&self) -> bool {
-        self.initiator
+        self.role.is_initiator()
     }
 
     pub fn is_finished(&self) -> bool {
         self.pattern_position == self.message_patterns.len()
     }
+
+    pub fn is_my_turn(&self) -> bool {
+        self.my_turn
+    }
+
+    /// How many handshake messages are left to write or read, counting one that's been
+    /// stepped into token by token as not done yet.
+    pub fn messages_remaining(&self) -> usize {
+        self.message_patterns.len() - self.pattern_position
+    }
+
+    /// The tokens of the next handshake message still to be processed, which is all of them
+    /// unless the message has been stepped into token by token.
+    pub fn next_message_tokens(&self) -> &[Token] {
+        match self.message_patterns.get(self.pattern_position) {
+            Some(tokens) => &tokens[self.token_position..],
+            None         => &[],
+        }
+    }
+
+    /// How many bytes the next message adds on top of its payload, whichever side writes it.
+    pub fn next_message_overhead(&self) -> usize {
+        if self.is_finished() {
+            self.cipherstates.0.tag_len()
+        } else {
+            self.next_message_len(0)
+        }
+    }
+
+    /// Encrypt a transport message with the split cipherstates of a finished handshake.
+    pub fn write_transport_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize, Error> {
+        self.write_transport_message_with_ad(&[], payload, message)
+    }
+
+    /// Decrypt a transport message with the split cipherstates of a finished handshake.
+    pub fn read_transport_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
+        self.read_transport_message_with_ad(&[], message, payload)
+    }
+
+    /// Encrypt a transport message with the split cipherstates of a finished handshake,
+    /// authenticating `authtext` as associated data.
+    pub fn write_transport_message_with_ad(&mut self, authtext: &[u8], payload: &[u8], message: &mut [u8]) -> Result<usize, Error> {
+        if !self.is_finished() {
+            bail!(StateProblem::HandshakeNotFinished);
+        }
+        transportstate::write_transport_message(&mut self.cipherstates, self.role,
+                                                self.oneway, authtext, payload, message)
+    }
+
+    /// Decrypt a transport message with the split cipherstates of a finished handshake,
+    /// checking `authtext` as associated data.
+    pub fn read_transport_message_with_ad(&mut self, authtext: &[u8], message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
+        if !self.is_finished() {
+            bail!(StateProblem::HandshakeNotFinished);
+        }
+        transportstate::read_transport_message(&mut self.cipherstates, self.role,
+                                               self.oneway, authtext, message, payload)
+    }
+
+    /// Encrypt a transport message in place with the split cipherstates of a finished handshake.
+    pub fn write_transport_message_in_place(&mut self, in_out: &mut [u8], payload_len: usize) -> Result<usize, Error> {
+        if !self.is_finished() {
+            bail!(StateProblem::HandshakeNotFinished);
+        }
+        transportstate::write_transport_message_in_place(&mut self.cipherstates, self.role,
+                                                         self.oneway, in_out, payload_len)
+    }
+
+    /// Decrypt a transport message in place with the split cipherstates of a finished handshake.
+    pub fn read_transport_message_in_place(&mut self, in_out: &mut [u8]) -> Result<usize, Error> {
+        if !self.is_finished() {
+            bail!(StateProblem::HandshakeNotFinished);
+        }
+        transportstate::read_transport_message_in_place(&mut self.cipherstates, self.role,
+                                                        self.oneway, in_out)
+    }
+
+    /// Tear down an abandoned handshake right away rather than whenever it happens to be
+    /// dropped. This is the same as dropping it: see the `Drop` implementation for what gets
+    /// wiped.
+    pub fn abort(self) {
+        drop(self);
+    }
+
+    /// Wipe the secrets that only the handshake holds: the PSKs, and a fixed ephemeral key,
+    /// which is overwritten with zeroes as well as wiped by its implementation on drop, since
+    /// it was handed in by the application.
+    fn wipe(&mut self) {
+        for psk in self.psks.iter_mut().flatten() {
+            secure_zero(psk);
+        }
+        if self.fixed_ephemeral {
+            let priv_len = self.e.priv_len();
+            self.e.set(&[0u8; MAXDHLEN][..priv_len]);
+        }
+    }
+
+    /// Hand over what a transport state keeps: the cipher states, the symmetric state, the
+    /// params, both remote keys, the role and whether the pattern is one-way. Everything else
+    /// is wiped and dropped, as dropping the handshake would have done.
+    pub(crate) fn into_transport_parts(self) -> TransportParts {
+        let mut state = ManuallyDrop::new(self);
+        state.wipe();
+        // Listing every field makes adding one without deciding its fate here a compile error.
+        let HandshakeState {
+            rng, symmetricstate, cipherstates, s, e, fixed_ephemeral: _, rs, rs_pinned: _,
+            reject_low_order: _, re, rejected_re: _, kem, re1, role, params, psks: _, prologue,
+            protocol_id, prologue_streaming: _, prologue_finished: _, my_turn: _, premessages,
+            message_patterns, psk_mode: _, oneway, pattern_position: _, token_position: _,
+        } = &mut *state;
+        // SAFETY: `state` is never used or dropped again, and each of its fields is either read
+        // out exactly once or dropped in place exactly once (the rest are `Copy`).
+        unsafe {
+            let parts = (ptr::read(cipherstates), ptr::read(symmetricstate), ptr::read(params),
+                         ptr::read(rs), ptr::read(re), *role, *oneway);
+            ptr::drop_in_place(rng);
+            ptr::drop_in_place(s);
+            ptr::drop_in_place(e);
+            ptr::drop_in_place(kem);
+            ptr::drop_in_place(re1);
+            ptr::drop_in_place(prologue);
+            ptr::drop_in_place(protocol_id);
+            ptr::drop_in_place(premessages);
+            ptr::drop_in_place(message_patterns);
+            parts
+        }
+    }
+
+    /// Restart as the fallback handshake described by `params`, keeping the ephemeral
+    /// key the initiator already sent, so it can be used as a pre-message.
+    pub fn into_fallback(mut self, params: NoiseParams, prologue: &[u8]) -> Result<HandshakeState, Error> {
+        if !params.handshake.is_fallback()
+            || params.dh != self.params.dh
+            || params.cipher != self.params.cipher
+            || params.hash != self.params.hash
+            || params.kem != self.params.kem
+        {
+            bail!(InitStage::ValidateFallback);
+        }
+
+        // Restarted in place, keeping the keys, PSKs, protocol id and low-order setting, and
+        // otherwise just like `new()` would have set it up.
+        let pattern = CustomPattern::from_choice(&params.name, &params.handshake)?;
+        self.cipherstates.clear();
+        self.rs_pinned = false;
+        if !self.re.is_on() {
+            self.re = self.rejected_re;
+        }
+        self.re1.clear();
+        self.params = params;
+        self.prologue = prologue.to_vec();
+        self.prologue_streaming = false;
+        self.prologue_finished = false;
+        self.my_turn = self.role.is_initiator() != pattern.responder_first;
+        self.psk_mode = pattern.is_psk();
+        self.oneway = pattern.is_oneway();
+        self.premessages = pattern.premessages;
+        self.message_patterns = pattern.messages;
+        self.pattern_position = 0;
+        self.token_position = 0;
+        self.mix_prologue_and_premessages()?;
+        Ok(self)
+    }
+}
+
+/// Wipes the PSKs and any fixed ephemeral key. The chaining key and handshake hash are wiped
+/// by the symmetric state, and the bundled DH and cipher implementations wipe the private keys
+/// and cipher keys as they're dropped. Implementations from a custom resolver are responsible
+/// for wiping their own secrets when dropped.
+impl Drop for HandshakeState {
+    fn drop(&mut self) {
+        self.wipe();
+    }
 }
 
 impl fmt::Debug for HandshakeState {
@@ -47,12 +47,24 @@
 //! # }
 //! ```
 //!
+//! # `no_std`
+//! Building without the default `std` feature gives a `no_std` crate that only needs `core`
+//! and `alloc` (sessions hold their crypto primitives as boxed trait objects). None of the
+//! bundled resolvers are available in that configuration, so you'll need to bring your own
+//! [`CryptoResolver`] and use [`Builder::with_resolver`].
+//!
 //! [`Builder`]: struct.Builder.html
+//! [`Builder::with_resolver`]: struct.Builder.html#method.with_resolver
+//! [`CryptoResolver`]: resolvers/trait.CryptoResolver.html
 //! [`Session`]: enum.Session.html
 
+#![cfg_attr(not(feature = "std"), no_std)]
 #![warn(missing_docs)]
 
-#[cfg(any(feature = "default-resolver", feature = "hacl-star-resolver"))]
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+
+#[cfg(any(feature = "25519", feature = "448", feature = "kyber1024", feature = "hacl-star-resolver"))]
 #[macro_use]
 extern crate arrayref;
 
@@ -74,11 +86,13 @@ mod builder;
 mod session;
 mod transportstate;
 mod stateless_transportstate;
+#[cfg(feature = "std")] mod stream;
 
 pub mod params;
 pub mod types;
 pub mod resolvers;
 
 pub use crate::error::Error;
-pub use crate::builder::{Builder, Keypair};
-pub use crate::session::Session;
+pub use crate::builder::{Builder, BuilderTemplate, Keypair};
+pub use crate::session::{Padding, Session};
+#[cfg(feature = "std")] pub use crate::stream::{MidHandshake, NoiseStream, StreamingTransport};
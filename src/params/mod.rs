@@ -3,29 +3,96 @@
 
 #[allow(unused_imports)]
 #[cfg(feature = "nightly")]
-use std::convert::TryFrom;
+use core::convert::TryFrom;
 
 #[allow(unused_imports)]
 #[cfg(not(feature = "nightly"))]
 use crate::utils::TryFrom;
 
+use crate::constants::TAGLEN;
 use crate::error::{Error, PatternProblem};
-use std::str::FromStr;
+use core::str::FromStr;
+#[cfg(not(feature = "std"))] use alloc::{borrow::ToOwned, string::String, vec::Vec};
 mod patterns;
 
 pub use self::patterns::{
+    CustomPattern,
     HandshakeChoice,
     HandshakeModifier,
     HandshakePattern,
+    Role,
     SUPPORTED_HANDSHAKE_PATTERNS,
+    Token,
 };
 
 pub(crate) use self::patterns::{
     HandshakeTokens,
     MessagePatterns,
-    Token,
+    SUPPORTED_HANDSHAKE_PATTERN_NAMES,
+    message_overhead,
 };
 
+/// The names of all of the base handshake patterns (before any modifiers), e.g. `XX`.
+pub fn supported_patterns() -> &'static [&'static str] {
+    SUPPORTED_HANDSHAKE_PATTERN_NAMES
+}
+
+/// The names of the DH functions that `Builder::new()` can build sessions with in this
+/// build, e.g. `25519`. These are the ones whose cargo features are enabled, and none without
+/// the `default-resolver` feature, in which case it's up to the resolver passed to
+/// `Builder::with_resolver()`.
+pub fn supported_dh() -> &'static [&'static str] {
+    &[
+        #[cfg(feature = "25519")] "25519",
+        #[cfg(feature = "448")]   "448",
+    ]
+}
+
+/// The names of the ciphers that `Builder::new()` can build sessions with in this build,
+/// e.g. `ChaChaPoly`. See `supported_dh()`.
+pub fn supported_ciphers() -> &'static [&'static str] {
+    &[
+        #[cfg(feature = "chachapoly")] "ChaChaPoly",
+        #[cfg(feature = "aesgcm")]     "AESGCM",
+    ]
+}
+
+/// The names of the hash functions that `Builder::new()` can build sessions with in this
+/// build, e.g. `BLAKE2s`. See `supported_dh()`.
+pub fn supported_hashes() -> &'static [&'static str] {
+    &[
+        #[cfg(feature = "sha2")]   "SHA256",
+        #[cfg(feature = "sha2")]   "SHA512",
+        #[cfg(feature = "blake2")] "BLAKE2s",
+        #[cfg(feature = "blake2")] "BLAKE2b",
+    ]
+}
+
+/// The names of the KEMs that `Builder::new()` can build hybrid (`hfs`) sessions with in this
+/// build, i.e. `Kyber1024` with the opt-in `kyber1024` feature. See `supported_dh()`.
+pub fn supported_kems() -> &'static [&'static str] {
+    &[
+        #[cfg(feature = "kyber1024")] "Kyber1024",
+    ]
+}
+
+/// The full protocol name for a handshake and set of primitives, e.g.
+/// `Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s`, without building any state. This is the name
+/// `NoiseParams` parses and the one that gets hashed into the handshake, so it can be used
+/// to advertise or check a configuration up front. A `kem` goes after the DH function, as
+/// in `Noise_XXhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s`.
+pub fn build_protocol_name(handshake: &HandshakeChoice,
+                           dh: DHChoice,
+                           kem: Option<KemChoice>,
+                           cipher: CipherChoice,
+                           hash: HashChoice) -> String {
+    let dh = match kem {
+        Some(kem) => [dh.as_str(), kem.as_str()].join("+"),
+        None      => dh.as_str().to_owned(),
+    };
+    [BaseChoice::Noise.as_str(), &handshake.name(), &dh, cipher.as_str(), hash.as_str()].join("_")
+}
+
 /// I recommend you choose `Noise`.
 #[allow(missing_docs)]
 #[derive(PartialEq, Copy, Clone, Debug)]
@@ -44,6 +111,15 @@ impl FromStr for BaseChoice {
     }
 }
 
+impl BaseChoice {
+    /// The equivalent of the `ToString` trait, but for `&'static str`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BaseChoice::Noise => "Noise",
+        }
+    }
+}
+
 /// One of `25519` or `448`, per the spec.
 #[allow(missing_docs)]
 #[derive(PartialEq, Copy, Clone, Debug)]
@@ -52,6 +128,24 @@ pub enum DHChoice {
     Ed448,
 }
 
+impl DHChoice {
+    /// The equivalent of the `ToString` trait, but for `&'static str`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DHChoice::Curve25519 => "25519",
+            DHChoice::Ed448      => "448",
+        }
+    }
+
+    /// The length in bytes of a public key (and of a shared secret) for this DH function.
+    pub fn pub_len(self) -> usize {
+        match self {
+            DHChoice::Curve25519 => 32,
+            DHChoice::Ed448      => 56,
+        }
+    }
+}
+
 impl FromStr for DHChoice {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -72,6 +166,23 @@ pub enum CipherChoice {
     AESGCM,
 }
 
+impl CipherChoice {
+    /// The equivalent of the `ToString` trait, but for `&'static str`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CipherChoice::ChaChaPoly => "ChaChaPoly",
+            CipherChoice::AESGCM     => "AESGCM",
+        }
+    }
+
+    /// The length in bytes of the authentication tag this cipher appends to each ciphertext.
+    pub fn tag_len(self) -> usize {
+        match self {
+            CipherChoice::ChaChaPoly | CipherChoice::AESGCM => TAGLEN,
+        }
+    }
+}
+
 impl FromStr for CipherChoice {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -94,6 +205,18 @@ pub enum HashChoice {
     Blake2b,
 }
 
+impl HashChoice {
+    /// The equivalent of the `ToString` trait, but for `&'static str`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HashChoice::SHA256  => "SHA256",
+            HashChoice::SHA512  => "SHA512",
+            HashChoice::Blake2s => "BLAKE2s",
+            HashChoice::Blake2b => "BLAKE2b",
+        }
+    }
+}
+
 impl FromStr for HashChoice {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -108,6 +231,50 @@ impl FromStr for HashChoice {
     }
 }
 
+/// The key encapsulation mechanism that makes up the post-quantum half of a hybrid DH
+/// choice, e.g. the `Kyber1024` of `25519+Kyber1024`, as used by the `hfs` modifier.
+///
+/// See: [Hybrid Forward Secrecy](https://github.com/noiseprotocol/noise_hfs_spec).
+#[allow(missing_docs)]
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum KemChoice {
+    Kyber1024,
+}
+
+impl KemChoice {
+    /// The equivalent of the `ToString` trait, but for `&'static str`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            KemChoice::Kyber1024 => "Kyber1024",
+        }
+    }
+
+    /// The length in bytes of a public key for this KEM.
+    pub fn pub_len(self) -> usize {
+        match self {
+            KemChoice::Kyber1024 => 1568,
+        }
+    }
+
+    /// The length in bytes of a ciphertext for this KEM.
+    pub fn ciphertext_len(self) -> usize {
+        match self {
+            KemChoice::Kyber1024 => 1568,
+        }
+    }
+}
+
+impl FromStr for KemChoice {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::KemChoice::*;
+        match s {
+            "Kyber1024" => Ok(Kyber1024),
+            _           => bail!(PatternProblem::UnsupportedKemType)
+        }
+    }
+}
+
 /// The set of choices (as specified in the Noise spec) that constitute a full protocol definition.
 ///
 /// See: [Chapter 11: Protocol Names](http://noiseprotocol.org/noise.html#protocol-names).
@@ -120,6 +287,10 @@ impl FromStr for HashChoice {
 /// # use snow::params::*;
 ///
 /// let params: NoiseParams = "Noise_XX_25519_AESGCM_SHA256".parse().unwrap();
+///
+/// // A hybrid handshake names its KEM after the DH function, and needs the `hfs` modifier.
+/// let params: NoiseParams = "Noise_XXhfs_25519+Kyber1024_AESGCM_SHA256".parse().unwrap();
+/// assert_eq!(params.kem, Some(KemChoice::Kyber1024));
 /// ```
 #[allow(missing_docs)]
 #[derive(PartialEq, Clone, Debug)]
@@ -130,11 +301,14 @@ pub struct NoiseParams {
     pub dh: DHChoice,
     pub cipher: CipherChoice,
     pub hash: HashChoice,
+    pub kem: Option<KemChoice>,
 }
 
 impl NoiseParams {
 
-    /// Construct a new NoiseParams via specifying enums directly.
+    /// Construct a new NoiseParams via specifying enums directly. `name` is hashed into the
+    /// handshake as is, so it should be the one `build_protocol_name()` gives for the rest.
+    /// There's no KEM, so set `kem` afterwards for a hybrid (`hfs`) handshake.
     pub fn new(name: String,
                base: BaseChoice,
                handshake: HandshakeChoice,
@@ -142,7 +316,40 @@ impl NoiseParams {
                cipher: CipherChoice,
                hash: HashChoice) -> Self
     {
-        NoiseParams { name, base, handshake, dh, cipher, hash }
+        NoiseParams { name, base, handshake, dh, cipher, hash, kem: None }
+    }
+
+    /// The number of bytes each handshake message adds on top of its payload, in order, so
+    /// that buffers for the whole handshake can be sized up front. A message carrying a
+    /// payload of `n` bytes is exactly `overheads[i] + n` bytes long.
+    ///
+    /// This assumes the key and tag lengths the spec defines for the chosen primitives, which
+    /// a custom resolver is free to deviate from.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Pattern` if the handshake's modifiers don't apply to its
+    /// pattern, or if it has the `hfs` modifier but no KEM.
+    pub fn handshake_overheads(&self) -> Result<Vec<usize>, Error> {
+        let tokens = HandshakeTokens::try_from(&self.handshake)?;
+        let kem_lens = match self.kem {
+            Some(kem) => (kem.pub_len(), kem.ciphertext_len()),
+            None if self.handshake.is_hfs() => bail!(PatternProblem::UnsupportedModifier),
+            None => (0, 0),
+        };
+        let is_psk = self.handshake.is_psk();
+        let mut has_key = is_psk && tokens.premsg_pattern_i.iter()
+            .chain(tokens.premsg_pattern_r.iter())
+            .any(|token| *token == Token::E);
+
+        let mut overheads = Vec::with_capacity(tokens.msg_patterns.len());
+        for message in tokens.msg_patterns.iter() {
+            let (overhead, key_after) = message_overhead(message, is_psk, has_key, self.dh.pub_len(),
+                                                         kem_lens, self.cipher.tag_len());
+            overheads.push(overhead);
+            has_key = key_after;
+        }
+        Ok(overheads)
     }
 }
 
@@ -151,12 +358,26 @@ impl FromStr for NoiseParams {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut split = s.split('_');
-        Ok(NoiseParams::new(s.to_owned(),
-                            split.next().ok_or(PatternProblem::TooFewParameters)?.parse()?,
-                            split.next().ok_or(PatternProblem::TooFewParameters)?.parse()?,
-                            split.next().ok_or(PatternProblem::TooFewParameters)?.parse()?,
-                            split.next().ok_or(PatternProblem::TooFewParameters)?.parse()?,
-                            split.next().ok_or(PatternProblem::TooFewParameters)?.parse()?))
+        let base = split.next().ok_or(PatternProblem::TooFewParameters)?.parse()?;
+        let handshake: HandshakeChoice = split.next().ok_or(PatternProblem::TooFewParameters)?.parse()?;
+        let mut dh_split = split.next().ok_or(PatternProblem::TooFewParameters)?.splitn(2, '+');
+        let dh = dh_split.next().unwrap_or_default().parse()?;
+        let kem = dh_split.next().map(str::parse).transpose()?;
+        let mut params = NoiseParams::new(s.to_owned(), base, handshake, dh,
+                                          split.next().ok_or(PatternProblem::TooFewParameters)?.parse()?,
+                                          split.next().ok_or(PatternProblem::TooFewParameters)?.parse()?);
+        if split.next().is_some() {
+            bail!(PatternProblem::TooManyParameters);
+        }
+
+        // A KEM only has a use in a hybrid handshake, and a hybrid handshake can't do without.
+        match (params.handshake.is_hfs(), kem) {
+            (true, None)     => bail!(PatternProblem::UnsupportedModifier),
+            (false, Some(_)) => bail!(PatternProblem::UnsupportedKemType),
+            _                => {},
+        }
+        params.kem = kem;
+        Ok(params)
     }
 }
 
@@ -164,6 +385,49 @@ impl FromStr for NoiseParams {
 mod tests {
     use super::*;
 
+    #[test]
+    #[cfg(feature = "default-resolver")]
+    fn test_supported_names_resolve() {
+        use crate::resolvers::{CryptoResolver, DefaultResolver};
+
+        assert_eq!(supported_patterns().len(), SUPPORTED_HANDSHAKE_PATTERNS.len());
+        for (name, pattern) in supported_patterns().iter().zip(SUPPORTED_HANDSHAKE_PATTERNS) {
+            assert_eq!(name.parse::<HandshakePattern>().unwrap(), *pattern);
+        }
+        for name in supported_dh() {
+            let dh = DefaultResolver.resolve_dh(&name.parse().unwrap()).unwrap();
+            assert_eq!(dh.name(), *name);
+        }
+        for name in supported_ciphers() {
+            let cipher = DefaultResolver.resolve_cipher(&name.parse().unwrap()).unwrap();
+            assert_eq!(cipher.name(), *name);
+        }
+        for name in supported_hashes() {
+            let hash = DefaultResolver.resolve_hash(&name.parse().unwrap()).unwrap();
+            assert_eq!(hash.name(), *name);
+        }
+        for name in supported_kems() {
+            let kem = DefaultResolver.resolve_kem(&name.parse().unwrap()).unwrap();
+            assert_eq!(kem.name(), *name);
+        }
+    }
+
+    #[test]
+    fn test_build_protocol_name() {
+        for name in ["Noise_XX_25519_AESGCM_SHA256",
+                     "Noise_NNpsk0_448_ChaChaPoly_BLAKE2b",
+                     "Noise_XXfallback+psk0_25519_ChaChaPoly_BLAKE2s",
+                     "Noise_Xpsk1+psk2_448_AESGCM_SHA512",
+                     "Noise_I1K1_25519_ChaChaPoly_SHA256",
+                     "Noise_XXhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s",
+                     "Noise_NNpsk0+hfs_448+Kyber1024_AESGCM_SHA512"].iter() {
+            let p: NoiseParams = name.parse().unwrap();
+            let built = build_protocol_name(&p.handshake, p.dh, p.kem, p.cipher, p.hash);
+            assert_eq!(built, *name);
+            assert_eq!(built.parse::<NoiseParams>().unwrap(), p);
+        }
+    }
+
     #[test]
     fn test_simple_handshake() {
         let _: HandshakePattern = "XX".parse().unwrap();
@@ -175,6 +439,14 @@ mod tests {
         assert!(p.handshake.modifiers.list.is_empty());
     }
 
+    #[test]
+    fn test_too_many_parameters() {
+        match "Noise_XX_25519_AESGCM_SHA256_SHA512".parse::<NoiseParams>() {
+            Err(Error::Pattern(PatternProblem::TooManyParameters)) => {},
+            res => panic!("unexpected parse result: {:?}", res),
+        }
+    }
+
     #[test]
     fn test_basic_deferred() {
         let p: NoiseParams = "Noise_X1X1_25519_AESGCM_SHA256".parse().unwrap();
@@ -241,4 +513,144 @@ mod tests {
             _ => panic!("missing token!")
         }
     }
+
+    #[test]
+    fn test_fallback_handshake_tokens() {
+        let p: NoiseParams = "Noise_XXfallback_25519_AESGCM_SHA256".parse().unwrap();
+        let tokens = HandshakeTokens::try_from(&p.handshake).unwrap();
+        assert_eq!(tokens.premsg_pattern_i, &[Token::E]);
+        assert!(tokens.premsg_pattern_r.is_empty());
+        assert_eq!(tokens.msg_patterns.len(), 2);
+        assert_eq!(&tokens.msg_patterns[0][..], &[Token::E, Token::Dhee, Token::S, Token::Dhse]);
+
+        let p: NoiseParams = "Noise_XXfallback+psk0_25519_AESGCM_SHA256".parse().unwrap();
+        let tokens = HandshakeTokens::try_from(&p.handshake).unwrap();
+        assert_eq!(tokens.msg_patterns[0][0], Token::Psk(0));
+
+        let p: NoiseParams = "Noise_IKfallback_25519_AESGCM_SHA256".parse().unwrap();
+        match HandshakeTokens::try_from(&p.handshake) {
+            Err(Error::Pattern(PatternProblem::UnsupportedModifier)) => {},
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_hfs_handshake_tokens() {
+        use Token::*;
+
+        let messages = |name: &str| {
+            let p: NoiseParams = name.parse().unwrap();
+            assert_eq!(p.kem, Some(KemChoice::Kyber1024));
+            assert_eq!(p.handshake.name(), name.split('_').nth(1).unwrap());
+            HandshakeTokens::try_from(&p.handshake).unwrap().msg_patterns
+        };
+        // e1 is held back until after a DH in the same message, so that it's encrypted.
+        let xx = messages("Noise_XXhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s");
+        assert_eq!(&xx[0][..], &[E, E1]);
+        assert_eq!(&xx[1][..], &[E, Dhee, Ekem1, S, Dhse]);
+        let ik = messages("Noise_IKhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s");
+        assert_eq!(&ik[0][..], &[E, Dhes, E1, S, Dhss]);
+        assert_eq!(&ik[1][..], &[E, Dhee, Ekem1, Dhes]);
+        let nn = messages("Noise_NNpsk0+hfs_25519+Kyber1024_ChaChaPoly_BLAKE2s");
+        assert_eq!(&nn[0][..], &[Psk(0), E, E1]);
+
+        let p: NoiseParams = "Noise_XXhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s".parse().unwrap();
+        assert_eq!(p.handshake_overheads().unwrap(), vec![32 + 1568, 32 + 1584 + 48 + 16, 48 + 16]);
+
+        for name in &["Noise_Nhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s",
+                      "Noise_XXfallback+hfs_25519+Kyber1024_ChaChaPoly_BLAKE2s",
+                      "Noise_XXhfs_25519_ChaChaPoly_BLAKE2s"] {
+            match name.parse::<NoiseParams>().and_then(|p| HandshakeTokens::try_from(&p.handshake)) {
+                Err(Error::Pattern(PatternProblem::UnsupportedModifier)) => {},
+                res => panic!("{}: unexpected result: {:?}", name, res),
+            }
+        }
+        for name in &["Noise_XX_25519+Kyber1024_ChaChaPoly_BLAKE2s",
+                      "Noise_XXhfs_25519+Kyber512_ChaChaPoly_BLAKE2s"] {
+            match name.parse::<NoiseParams>() {
+                Err(Error::Pattern(PatternProblem::UnsupportedKemType)) => {},
+                res => panic!("{}: unexpected result: {:?}", name, res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_requirements_match_tokens() {
+        for &pattern in SUPPORTED_HANDSHAKE_PATTERNS {
+            let choice = HandshakeChoice { pattern, modifiers: "".parse().unwrap() };
+            let tokens = HandshakeTokens::try_from(&choice).unwrap();
+            for &initiator in &[true, false] {
+                let (local_premsg, remote_premsg) = if initiator {
+                    (tokens.premsg_pattern_i, tokens.premsg_pattern_r)
+                } else {
+                    (tokens.premsg_pattern_r, tokens.premsg_pattern_i)
+                };
+                // Messages alternate, starting with the initiator.
+                let sends_static = tokens.msg_patterns.iter()
+                    .enumerate()
+                    .filter(|(i, _)| (i % 2 == 0) == initiator)
+                    .any(|(_, message)| message.contains(&Token::S));
+                let needs_local = local_premsg.contains(&Token::S) || sends_static;
+                let needs_remote = remote_premsg.contains(&Token::S);
+
+                assert_eq!(pattern.needs_local_static_key(initiator), needs_local,
+                           "{:?} (initiator: {})", pattern, initiator);
+                assert_eq!(pattern.need_known_remote_pubkey(initiator), needs_remote,
+                           "{:?} (initiator: {})", pattern, initiator);
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_pattern_validation() {
+        use Token::*;
+
+        // Every named pattern passes the same checks.
+        for &pattern in SUPPORTED_HANDSHAKE_PATTERNS {
+            let choice = HandshakeChoice { pattern, modifiers: "psk0".parse().unwrap() };
+            let tokens = HandshakeTokens::try_from(&choice).unwrap();
+            let messages: Vec<&[Token]> = tokens.msg_patterns.iter().map(|message| &message[..]).collect();
+            assert!(CustomPattern::new("custom", tokens.premsg_pattern_i, tokens.premsg_pattern_r, &messages).is_ok(),
+                    "{:?}", pattern);
+        }
+
+        type Schedule<'a> = (&'a [Token], &'a [Token], &'a [&'a [Token]]);
+        let invalid: &[Schedule] = &[
+            (&[], &[], &[]),                             // no messages
+            (&[Dhee], &[], &[&[E]]),                     // not a key in a pre-message
+            (&[], &[], &[&[E, E]]),                      // the same key twice
+            (&[E], &[], &[&[E]]),                        // sent after its pre-message
+            (&[], &[], &[&[E, Dhee]]),                   // the responder hasn't sent e
+            (&[], &[S], &[&[Dhes]]),                     // the initiator hasn't sent e
+            (&[], &[], &[&[E], &[E, Dhee, Dhee]]),       // the same DH twice
+            (&[], &[], &[&[E], &[E, Dhee], &[Dhee]]),    // ... even from the other side
+            (&[], &[], &[&[Psk(10), E]]),                // PSK location out of range
+            (&[E1], &[], &[&[E]]),                       // a KEM key in a pre-message
+            (&[], &[], &[&[E], &[E, Ekem1]]),            // the initiator hasn't sent e1
+            (&[], &[], &[&[E, E1], &[E, Ekem1, Ekem1]]), // encapsulated to twice
+        ];
+        for &(initiator_premessage, responder_premessage, messages) in invalid {
+            match CustomPattern::new("custom", initiator_premessage, responder_premessage, messages) {
+                Err(Error::Pattern(PatternProblem::InvalidCustomPattern)) => {},
+                res => panic!("{:?} {:?} {:?} wasn't rejected: {:?}", initiator_premessage, responder_premessage, messages, res),
+            }
+        }
+        assert!(CustomPattern::new("", &[], &[], &[&[E]]).is_err());
+        assert!(CustomPattern::new("N_N", &[], &[], &[&[E]]).is_err());
+        assert!(CustomPattern::new("custom", &[], &[], &[&[E][..]; 11]).is_err());
+
+        let pattern = CustomPattern::new("XKish", &[], &[S], &[&[E, Dhes], &[E, Dhee], &[S, Dhse, Psk(3)]]).unwrap();
+        assert_eq!(pattern.name(), "XKish");
+        assert!(pattern.is_psk() && !pattern.is_oneway());
+        assert!(pattern.needs_local_static_key(Role::Initiator) && pattern.needs_local_static_key(Role::Responder));
+        assert!(pattern.need_known_remote_pubkey(Role::Initiator) && !pattern.need_known_remote_pubkey(Role::Responder));
+        assert!(pattern.authenticates_initiator() && pattern.authenticates_responder());
+
+        // The responder's static key in a one-way pattern is known, but never proven.
+        let oneway = CustomPattern::new("Nish", &[], &[S], &[&[E, Dhes]]).unwrap();
+        assert!(oneway.needs_local_static_key(Role::Responder));
+        assert!(!oneway.authenticates_initiator() && !oneway.authenticates_responder());
+        assert!(!HandshakePattern::N.authenticates_responder() && HandshakePattern::K.authenticates_initiator());
+        assert!(HandshakePattern::NK.authenticates_responder() && !HandshakePattern::NK.authenticates_initiator());
+    }
 }
@@ -1,8 +1,9 @@
-#[cfg(feature = "nightly")] use std::convert::{TryFrom};
+#[cfg(feature = "nightly")] use core::convert::{TryFrom};
 #[cfg(not(feature = "nightly"))] use crate::utils::{TryFrom};
 use crate::error::{Error, PatternProblem};
-use std::str::FromStr;
+use core::{fmt::Write, str::FromStr};
 use smallvec::SmallVec;
+#[cfg(not(feature = "std"))] use alloc::{string::String, vec::Vec};
 
 /// A small helper macro that behaves similar to the `vec![]` standard macro,
 /// except it uses `SmallVec` to avoid heap allocations.
@@ -69,15 +70,59 @@ macro_rules! pattern_enum {
 
         #[doc(hidden)]
         pub const SUPPORTED_HANDSHAKE_PATTERNS: &'static [$name] = &[$($name::$variant),*];
+
+        pub(crate) const SUPPORTED_HANDSHAKE_PATTERN_NAMES: &'static [&'static str] = &[$(stringify!($variant)),*];
     }
 }
 
 /// The tokens which describe message patterns.
 ///
+/// Unlike the spec's notation, which always names the initiator's key first, the DH tokens
+/// name the sending party's key first, e.g. `Dhse` in a message from the responder is a DH
+/// between the responder's static and the initiator's ephemeral key (the spec's `es`).
+///
+/// `E1` and `Ekem1` are the KEM tokens added by the `hfs` modifier: the initiator's KEM
+/// public key, and the responder's encapsulation to it.
+///
 /// See: http://noiseprotocol.org/noise.html#handshake-patterns
 #[allow(missing_docs)]
 #[derive(Copy, Clone, PartialEq, Debug)]
-pub(crate) enum Token { E, S, Dhee, Dhes, Dhse, Dhss, Psk(u8) }
+pub enum Token { E, S, Dhee, Dhes, Dhse, Dhss, Psk(u8), E1, Ekem1 }
+
+impl Token {
+    /// For a DH token, whether the local and the remote key it uses are static (rather than
+    /// ephemeral) keys, for the party `sending` the message it's in or for the one receiving
+    /// it. Both sides go through here, so they can't disagree on what a token means.
+    pub(crate) fn dh_keys(self, sending: bool) -> Option<(bool, bool)> {
+        let (sender_s, receiver_s) = match self {
+            Token::Dhee => (false, false),
+            Token::Dhes => (false, true),
+            Token::Dhse => (true, false),
+            Token::Dhss => (true, true),
+            _ => return None,
+        };
+        Some(if sending { (sender_s, receiver_s) } else { (receiver_s, sender_s) })
+    }
+}
+
+/// Which side of the handshake a party is on.
+///
+/// The initiator sends the first message, except in a fallback handshake, where the roles
+/// are kept from the handshake that failed but the responder goes first.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Role {
+    /// The party that starts the handshake.
+    Initiator,
+    /// The party that answers it.
+    Responder,
+}
+
+impl Role {
+    /// Whether this is `Role::Initiator`.
+    pub fn is_initiator(self) -> bool {
+        self == Role::Initiator
+    }
+}
 
 // See the documentation in the macro above.
 pattern_enum! {
@@ -99,41 +144,48 @@ impl HandshakePattern {
     ///
     /// See: http://noiseprotocol.org/noise.html#one-way-patterns
     pub fn is_oneway(self) -> bool {
-        match self {
-            N | X | K => true,
-            _ => false
-        }
+        matches!(self, N | X | K)
+    }
+
+    /// Whether the initiator proves its identity (a static key) to the responder.
+    ///
+    /// See: http://noiseprotocol.org/noise.html#payload-security-properties
+    pub fn authenticates_initiator(self) -> bool {
+        self.schedule().authenticates_initiator()
+    }
+
+    /// Whether the responder proves its identity (a static key) to the initiator. This is
+    /// never the case for one-way patterns, since the responder doesn't send anything.
+    ///
+    /// See: http://noiseprotocol.org/noise.html#payload-security-properties
+    pub fn authenticates_responder(self) -> bool {
+        self.schedule().authenticates_responder()
+    }
+
+    /// The pattern's token schedule without any modifiers, so that named patterns answer
+    /// questions about themselves the same way custom ones do.
+    fn schedule(self) -> CustomPattern {
+        let choice = HandshakeChoice { pattern: self, modifiers: HandshakeModifierList { list: SmallVec::new() } };
+        CustomPattern::from_choice("", &choice).expect("every named pattern has a schedule")
     }
 
     /// Whether this pattern requires a long-term static key.
     pub fn needs_local_static_key(self, initiator: bool) -> bool {
         if initiator {
-            match self {
-                N | NN | NK | NX | NK1 | NX1 => false,
-                _ => true
-            }
+            !matches!(self, N | NN | NK | NX | NK1 | NX1)
         } else {
-            match self {
-                NN | XN | KN | IN | X1N | K1N | I1N => false,
-                _ => true
-            }
+            !matches!(self, NN | XN | KN | IN | X1N | K1N | I1N)
         }
     }
 
     /// Whether this pattern demands a remote public key pre-message.
     pub fn need_known_remote_pubkey(self, initiator: bool) -> bool {
         if initiator {
-            match self {
-                N | K | X | NK | XK | KK | IK | NK1 | X1K | XK1 | X1K1
-                  | K1K | KK1 | K1K1 | I1K | IK1 | I1K1 => true,
-                _ => false
-            }
+            matches!(self, N | K | X | NK | XK | KK | IK | NK1 | X1K | XK1 | X1K1
+                  | K1K | KK1 | K1K1 | I1K | IK1 | I1K1)
         } else {
-            match self {
-                K | KN | KK | KX | K1N | K1K | KK1 | K1K1 | K1X | KX1
-                  | K1X1 => true,
-                _ => false,
-            }
+            matches!(self, K | KN | KK | KX | K1N | K1K | KK1 | K1K1 | K1X | KX1
+                  | K1X1)
         }
     }
 }
@@ -145,19 +197,24 @@ pub enum HandshakeModifier {
     Psk(u8),
 
     /// Modify the base pattern to its "fallback" form
-    Fallback
+    Fallback,
+
+    /// Add a KEM exchange to the base pattern for hybrid forward secrecy
+    Hfs,
 }
 
 impl FromStr for HandshakeModifier {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.starts_with("psk") {
-            Ok(HandshakeModifier::Psk((&s[3..])
+        if let Some(psk_index) = s.strip_prefix("psk") {
+            Ok(HandshakeModifier::Psk(psk_index
                 .parse()
                 .map_err(|_| PatternProblem::InvalidPsk)?))
         } else if s == "fallback" {
             Ok(HandshakeModifier::Fallback)
+        } else if s == "hfs" {
+            Ok(HandshakeModifier::Hfs)
         } else {
             bail!(PatternProblem::UnsupportedModifier);
         }
@@ -198,6 +255,23 @@ pub struct HandshakeChoice {
 }
 
 impl HandshakeChoice {
+    /// The name of the pattern with its modifiers, as it appears in a protocol name, e.g.
+    /// `XXfallback+psk0`.
+    pub fn name(&self) -> String {
+        let mut name = String::from(self.pattern.as_str());
+        for (i, modifier) in self.modifiers.list.iter().enumerate() {
+            if i > 0 {
+                name.push('+');
+            }
+            match *modifier {
+                HandshakeModifier::Psk(n) => { let _ = write!(name, "psk{}", n); },
+                HandshakeModifier::Fallback => name.push_str("fallback"),
+                HandshakeModifier::Hfs => name.push_str("hfs"),
+            }
+        }
+        name
+    }
+
     /// Whether the handshake choice includes one or more PSK modifiers.
     pub fn is_psk(&self) -> bool {
         for modifier in &self.modifiers.list {
@@ -218,11 +292,35 @@ impl HandshakeChoice {
         false
     }
 
+    /// Whether the handshake choice includes the hfs modifier.
+    pub fn is_hfs(&self) -> bool {
+        self.modifiers.list.contains(&HandshakeModifier::Hfs)
+    }
+
+    /// The tokens of the initiator's and the responder's pre-messages, e.g. `([], [S])` for
+    /// `NK`, with any modifiers applied.
+    pub fn premessages(&self) -> Result<(&'static [Token], &'static [Token]), Error> {
+        let tokens = HandshakeTokens::try_from(self)?;
+        Ok((tokens.premsg_pattern_i, tokens.premsg_pattern_r))
+    }
+
+    /// The tokens of each handshake message in the order they're sent, with any modifiers
+    /// applied, each along with whether it's the initiator (`true`) or the responder who
+    /// sends it.
+    pub fn messages(&self) -> Result<Vec<(bool, Vec<Token>)>, Error> {
+        let tokens = HandshakeTokens::try_from(self)?;
+        let fallback = self.is_fallback();
+        Ok(tokens.msg_patterns.iter()
+            .enumerate()
+            .map(|(i, message)| ((i % 2 == 0) != fallback, message.to_vec()))
+            .collect())
+    }
+
     /// Parse and split a base HandshakePattern from its optional modifiers
     fn parse_pattern_and_modifier(s: &str) -> Result<(HandshakePattern, &str), Error> {
         for i in (1..=4).rev() {
             if s.len() > i-1 {
-                if let Ok(p) = (&s[..i]).parse() {
+                if let Ok(p) = s[..i].parse() {
                     return Ok((p, &s[i..]));
                 }
             }
@@ -245,6 +343,171 @@ impl FromStr for HandshakeChoice {
     }
 }
 
+/// A handshake pattern given as an explicit token schedule rather than by name, for
+/// prototyping patterns that the spec doesn't define. Build sessions with it through
+/// `Builder::custom_pattern()`.
+///
+/// As with the named patterns, tokens are relative to the sender of the message they're in:
+/// `Dhes` in the responder's message is the responder's ephemeral key with the initiator's
+/// static key.
+///
+/// # Examples
+///
+/// ```
+/// use snow::params::{CustomPattern, Token::*};
+///
+/// // Like NN, but with an extra round trip that doesn't carry any keys.
+/// let pattern = CustomPattern::new("NNplus", &[], &[], &[&[E], &[E, Dhee], &[]]).unwrap();
+/// ```
+#[derive(Clone, PartialEq, Debug)]
+pub struct CustomPattern {
+    pub(crate) name: String,
+    pub(crate) premessages: (Vec<Token>, Vec<Token>),
+    pub(crate) messages: MessagePatterns,
+    pub(crate) responder_first: bool,
+}
+
+impl CustomPattern {
+    /// Check and assemble a pattern from the initiator's and the responder's pre-message
+    /// tokens and the tokens of each message, which alternate between the initiator and the
+    /// responder, starting with the initiator. `name` takes the place of the pattern's name in
+    /// the protocol name, e.g. `Noise_<name>_25519_ChaChaPoly_BLAKE2s`.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `PatternProblem::InvalidCustomPattern` unless there are between 1 and 10
+    /// messages, the pre-messages only hold `E` and `S`, neither party sends the same key
+    /// twice, every DH (or `Ekem1`) uses keys that have already been sent, no DH is repeated,
+    /// there's at most one `Ekem1`, and every `Psk` location is below 10.
+    pub fn new(name: &str,
+               initiator_premessage: &[Token],
+               responder_premessage: &[Token],
+               messages: &[&[Token]]) -> Result<Self, Error> {
+        if name.is_empty() || name.contains('_') || messages.is_empty() || messages.len() > 10 {
+            bail!(PatternProblem::InvalidCustomPattern);
+        }
+
+        // Which of (e, s, e1) each party has sent so far, and which DHs (initiator key is
+        // static, responder key is static) and KEM encapsulations have been done.
+        let mut sent = [[false; 3]; 2];
+        let mut dhs = [[false; 2]; 2];
+        let mut encapsulated = false;
+        for (party, premessage) in [initiator_premessage, responder_premessage].iter().enumerate() {
+            for token in premessage.iter() {
+                let key = match *token {
+                    E => 0,
+                    S => 1,
+                    _ => bail!(PatternProblem::InvalidCustomPattern),
+                };
+                if sent[party][key] {
+                    bail!(PatternProblem::InvalidCustomPattern);
+                }
+                sent[party][key] = true;
+            }
+        }
+
+        for (i, message) in messages.iter().enumerate() {
+            let (sender, receiver) = (i % 2, 1 - i % 2);
+            for token in message.iter() {
+                let (local, remote) = match *token {
+                    E | S | E1 => {
+                        let key = match *token { E => 0, S => 1, _ => 2 };
+                        if sent[sender][key] {
+                            bail!(PatternProblem::InvalidCustomPattern);
+                        }
+                        sent[sender][key] = true;
+                        continue;
+                    },
+                    Ekem1 => {
+                        if !sent[receiver][2] || encapsulated {
+                            bail!(PatternProblem::InvalidCustomPattern);
+                        }
+                        encapsulated = true;
+                        continue;
+                    },
+                    Psk(n) if n < 10 => continue,
+                    Psk(_) => bail!(PatternProblem::InvalidCustomPattern),
+                    dh => match dh.dh_keys(true) {
+                        Some((local, remote)) => (local as usize, remote as usize),
+                        None => bail!(PatternProblem::InvalidCustomPattern),
+                    },
+                };
+                let dh = if sender == 0 { &mut dhs[local][remote] } else { &mut dhs[remote][local] };
+                if !sent[sender][local] || !sent[receiver][remote] || *dh {
+                    bail!(PatternProblem::InvalidCustomPattern);
+                }
+                *dh = true;
+            }
+        }
+
+        Ok(CustomPattern {
+            name: name.into(),
+            premessages: (initiator_premessage.to_vec(), responder_premessage.to_vec()),
+            messages: messages.iter().map(|message| message.iter().cloned().collect()).collect(),
+            responder_first: false,
+        })
+    }
+
+    /// The schedule of a named pattern and its modifiers, as used by the protocol named
+    /// `protocol_name`.
+    pub(crate) fn from_choice(protocol_name: &str, handshake: &HandshakeChoice) -> Result<Self, Error> {
+        let tokens = HandshakeTokens::try_from(handshake)?;
+        Ok(CustomPattern {
+            name: protocol_name.split('_').nth(1).unwrap_or_default().into(),
+            premessages: (tokens.premsg_pattern_i.to_vec(), tokens.premsg_pattern_r.to_vec()),
+            messages: tokens.msg_patterns,
+            responder_first: handshake.is_fallback(),
+        })
+    }
+
+    /// The name the pattern was given.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the responder never sends a message.
+    pub fn is_oneway(&self) -> bool {
+        self.messages.len() == 1
+    }
+
+    /// Whether any message mixes in a PSK, which makes ephemeral keys get mixed into the key
+    /// as well as the hash.
+    pub fn is_psk(&self) -> bool {
+        self.messages.iter().flatten().any(|token| matches!(token, Psk(_)))
+    }
+
+    /// Whether `role` has to have a static key, because it's in a pre-message or sent.
+    pub fn needs_local_static_key(&self, role: Role) -> bool {
+        let premessage = if role.is_initiator() { &self.premessages.0 } else { &self.premessages.1 };
+        premessage.contains(&S) || self.sent_by(role).any(|message| message.contains(&S))
+    }
+
+    /// Whether the initiator proves its identity (a static key) to the responder.
+    pub fn authenticates_initiator(&self) -> bool {
+        self.needs_local_static_key(Role::Initiator)
+    }
+
+    /// Whether the responder proves its identity (a static key) to the initiator, which it
+    /// can't do in a one-way pattern, since it never sends anything.
+    pub fn authenticates_responder(&self) -> bool {
+        !self.is_oneway() && self.needs_local_static_key(Role::Responder)
+    }
+
+    /// Whether `role` has to know the other party's static key up front.
+    pub fn need_known_remote_pubkey(&self, role: Role) -> bool {
+        let premessage = if role.is_initiator() { &self.premessages.1 } else { &self.premessages.0 };
+        premessage.contains(&S)
+    }
+
+    fn sent_by(&self, role: Role) -> impl Iterator<Item = &SmallVec<[Token; 10]>> {
+        let initiator_parity = self.responder_first as usize;
+        self.messages.iter()
+            .enumerate()
+            .filter(move |(i, _)| (i % 2 == initiator_parity) == role.is_initiator())
+            .map(|(_, message)| message)
+    }
+}
+
 type PremessagePatterns = &'static [Token];
 pub(crate) type MessagePatterns = SmallVec<[SmallVec<[Token; 10]>; 10]>;
 
@@ -258,6 +521,41 @@ pub(crate) struct HandshakeTokens {
     pub msg_patterns: MessagePatterns,
 }
 
+/// How many bytes the handshake `tokens` add to a message, including the tag on the payload
+/// if it ends up encrypted. `has_key` says whether a key has already been mixed in before
+/// these tokens, and the returned flag whether one has been after them. `kem_lens` are the
+/// KEM's public key and ciphertext lengths, for the `hfs` tokens.
+pub(crate) fn message_overhead(tokens: &[Token],
+                               is_psk: bool,
+                               mut has_key: bool,
+                               dh_len: usize,
+                               kem_lens: (usize, usize),
+                               tag_len: usize) -> (usize, bool) {
+    let mut len = 0;
+    for token in tokens {
+        match *token {
+            Token::E => {
+                len += dh_len;
+                if is_psk {
+                    has_key = true;
+                }
+            },
+            Token::S => {
+                len += if has_key { dh_len + tag_len } else { dh_len };
+            },
+            Token::E1 => {
+                len += if has_key { kem_lens.0 + tag_len } else { kem_lens.0 };
+            },
+            Token::Ekem1 => {
+                len += if has_key { kem_lens.1 + tag_len } else { kem_lens.1 };
+                has_key = true;
+            },
+            _ => has_key = true,
+        }
+    }
+    (len + if has_key { tag_len } else { 0 }, has_key)
+}
+
 use self::Token::*;
 use self::HandshakePattern::*;
 
@@ -460,8 +758,51 @@ impl<'a> TryFrom<&'a HandshakeChoice> for HandshakeTokens {
             ),
         };
 
+        // The fallback modifier turns the initiator's first message into a pre-message that the
+        // responder already received some other way, so it only applies to patterns whose first
+        // message carries nothing but public keys.
+        if handshake.is_fallback() {
+            if !patterns.0.is_empty() {
+                bail!(PatternProblem::UnsupportedModifier);
+            }
+            patterns.0 = match patterns.2[0][..] {
+                [E]    => static_slice![Token: E],
+                [E, S] => static_slice![Token: E, S],
+                _      => bail!(PatternProblem::UnsupportedModifier),
+            };
+            patterns.2.remove(0);
+        }
+
+        // Section 5 of the HFS spec: `e1` goes right after the first `e`, or after the first DH
+        // of the same message if it has one, so that the KEM public key gets encrypted, and
+        // `ekem1` goes right after the first `ee`. That leaves out one-way patterns, which
+        // have no `ee`, and fallback ones, whose first `e` is in a pre-message.
+        if handshake.is_hfs() {
+            if handshake.is_fallback() {
+                bail!(PatternProblem::UnsupportedModifier);
+            }
+            let e1 = patterns.2.iter().enumerate().find_map(|(i, message)| {
+                let e = message.iter().position(|token| *token == E)?;
+                let dh = message.iter().position(|token| matches!(token, Dhee | Dhes | Dhse | Dhss));
+                Some((i, dh.unwrap_or(e) + 1))
+            });
+            let ekem1 = patterns.2.iter()
+                .enumerate()
+                .find_map(|(i, message)| Some((i, message.iter().position(|token| *token == Dhee)? + 1)));
+            match (e1, ekem1) {
+                (Some((i, j)), Some((k, l))) => {
+                    patterns.2[i].insert(j, E1);
+                    patterns.2[k].insert(l, Ekem1);
+                },
+                _ => bail!(PatternProblem::UnsupportedModifier),
+            }
+        }
+
         for modifier in handshake.modifiers.list.iter() {
             if let HandshakeModifier::Psk(n) = modifier {
+                if *n as usize > patterns.2.len() {
+                    bail!(PatternProblem::InvalidPsk);
+                }
                 match n {
                     0 => { patterns.2[0].insert(0, Token::Psk(*n)); },
                     _ => {
@@ -1,65 +1,118 @@
+#[cfg(any(feature = "aesgcm", feature = "chachapoly", feature = "sha2", feature = "kyber1024"))]
 extern crate crypto;
-extern crate blake2_rfc;
-extern crate chacha20_poly1305_aead;
-extern crate x25519_dalek;
+#[cfg(feature = "blake2")]     extern crate blake2_rfc;
+#[cfg(feature = "chachapoly")] extern crate chacha20_poly1305_aead;
+#[cfg(feature = "25519")]      extern crate x25519_dalek;
 extern crate rand;
 
-use self::blake2_rfc::blake2b::Blake2b;
-use self::blake2_rfc::blake2s::Blake2s;
-use self::crypto::digest::Digest;
-use self::crypto::sha2::{Sha256, Sha512};
-use self::crypto::aes::KeySize;
-use self::crypto::aes_gcm::AesGcm;
-use self::crypto::aead::{AeadEncryptor, AeadDecryptor};
+#[cfg(feature = "448")]       mod x448;
+#[cfg(feature = "kyber1024")] mod kyber1024;
+
+#[cfg(feature = "blake2")] use self::blake2_rfc::blake2b::Blake2b;
+#[cfg(feature = "blake2")] use self::blake2_rfc::blake2s::Blake2s;
+#[cfg(feature = "sha2")]   use self::crypto::digest::Digest;
+#[cfg(feature = "sha2")]   use self::crypto::sha2::{Sha256, Sha512};
+#[cfg(feature = "aesgcm")] use self::crypto::aes::KeySize;
+#[cfg(feature = "aesgcm")] use self::crypto::aes_gcm::AesGcm;
+#[cfg(feature = "aesgcm")] use self::crypto::aead::{AeadEncryptor, AeadDecryptor};
+#[cfg(feature = "aesgcm")] use self::crypto::aessafe::AesSafe256EncryptorX8;
+#[cfg(all(feature = "aesgcm", any(target_arch = "x86", target_arch = "x86_64")))]
+use self::crypto::aesni::AesNiEncryptor;
+#[cfg(feature = "aesgcm")] use self::crypto::ghash::Ghash;
+#[cfg(feature = "aesgcm")] use self::crypto::symmetriccipher::BlockEncryptorX8;
+#[cfg(all(feature = "aesgcm", any(target_arch = "x86", target_arch = "x86_64")))]
+use self::crypto::symmetriccipher::BlockEncryptor;
+#[cfg(feature = "chachapoly")] use self::crypto::chacha20::ChaCha20;
+#[cfg(feature = "chachapoly")] use self::crypto::mac::Mac;
+#[cfg(feature = "chachapoly")] use self::crypto::poly1305::Poly1305;
+#[cfg(feature = "chachapoly")] use self::crypto::symmetriccipher::SynchronousStreamCipher;
 use self::rand::rngs::OsRng;
-use self::x25519_dalek as x25519;
+#[cfg(feature = "25519")]  use self::x25519_dalek as x25519;
 
-use byteorder::{ByteOrder, BigEndian, LittleEndian};
+#[cfg(any(feature = "aesgcm", feature = "chachapoly"))] use byteorder::ByteOrder;
+#[cfg(feature = "aesgcm")]     use byteorder::BigEndian;
+#[cfg(feature = "chachapoly")] use byteorder::LittleEndian;
 
 use crate::types::{Cipher, Dh, Hash, Random};
-use crate::constants::TAGLEN;
+#[cfg(feature = "kyber1024")] use crate::types::Kem;
+#[cfg(any(feature = "aesgcm", feature = "chachapoly"))] use crate::constants::TAGLEN;
+#[cfg(any(feature = "25519", feature = "448", feature = "aesgcm", feature = "chachapoly", feature = "kyber1024"))]
+use crate::utils::secure_zero;
 use crate::params::{CipherChoice, DHChoice, HashChoice};
-use std::io::{Cursor, Write};
+#[cfg(feature = "kyber1024")] use crate::params::KemChoice;
+#[cfg(feature = "chachapoly")] use std::io::{Cursor, Write};
+#[cfg(any(feature = "aesgcm", feature = "chachapoly"))] use subtle::ConstantTimeEq;
 use super::CryptoResolver;
 
 /// The default resolver provided by snow. This resolver is designed to
 /// support as many of the Noise spec primitives as possible with
 /// pure-Rust (or nearly pure-Rust) implementations.
+///
+/// Each primitive is behind a cargo feature (`25519`, `448`, `chachapoly`, `aesgcm`, `blake2`,
+/// `sha2` and `kyber1024`, all but `448` and `kyber1024` on by default), and resolves to `None`
+/// if its feature is off, which the `Builder` reports as e.g. `InitStage::GetCipherImpl`.
+///
+/// **Warning:** `448` is backed by snow's own X448 implementation. It passes the RFC 7748 and
+/// cacophony test vectors, but unlike the other primitives it hasn't been independently
+/// audited, which is why it has to be turned on explicitly. The same goes for `kyber1024`,
+/// snow's own Kyber1024 (round 3) KEM for hybrid (`hfs`) handshakes.
 #[derive(Default)]
 pub struct DefaultResolver;
 
 impl CryptoResolver for DefaultResolver {
-    fn resolve_rng(&self) -> Option<Box<Random>> {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
         match OsRng::new() {
             Ok(rng) => Some(Box::new(rng)),
             _       => None
         }
     }
 
-    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<Dh>> {
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
         match *choice {
+            #[cfg(feature = "25519")]
             DHChoice::Curve25519 => Some(Box::new(Dh25519::default())),
+            #[cfg(feature = "448")]
+            DHChoice::Ed448      => Some(Box::new(Dh448::default())),
+            #[allow(unreachable_patterns)]
             _                    => None,
         }
     }
 
-    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<Hash>> {
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
         match *choice {
+            #[cfg(feature = "sha2")]
             HashChoice::SHA256  => Some(Box::new(HashSHA256::default())),
+            #[cfg(feature = "sha2")]
             HashChoice::SHA512  => Some(Box::new(HashSHA512::default())),
+            #[cfg(feature = "blake2")]
             HashChoice::Blake2s => Some(Box::new(HashBLAKE2s::default())),
+            #[cfg(feature = "blake2")]
             HashChoice::Blake2b => Some(Box::new(HashBLAKE2b::default())),
+            #[allow(unreachable_patterns)]
+            _                   => None,
         }
     }
 
-    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<Cipher>> {
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
         match *choice {
+            #[cfg(feature = "chachapoly")]
             CipherChoice::ChaChaPoly => Some(Box::new(CipherChaChaPoly::default())),
+            #[cfg(feature = "aesgcm")]
             CipherChoice::AESGCM     => Some(Box::new(CipherAESGCM::default())),
+            #[allow(unreachable_patterns)]
+            _                        => None,
+        }
+    }
+
+    #[cfg(feature = "kyber1024")]
+    fn resolve_kem(&self, choice: &KemChoice) -> Option<Box<dyn Kem>> {
+        match *choice {
+            KemChoice::Kyber1024 => Some(Box::new(KemKyber1024::default())),
         }
     }
 }
 
+#[cfg(feature = "25519")]
 /// Wraps x25519-dalek.
 #[derive(Default)]
 struct Dh25519 {
@@ -67,33 +120,53 @@ struct Dh25519 {
     pubkey:  [u8; 32],
 }
 
+#[cfg(feature = "448")]
+/// Wraps snow's own X448 implementation.
+struct Dh448 {
+    privkey: [u8; 56],
+    pubkey:  [u8; 56],
+}
+
+#[cfg(feature = "kyber1024")]
+/// Wraps snow's own Kyber1024 implementation.
+struct KemKyber1024 {
+    privkey: [u8; kyber1024::SECRET_KEY_LEN],
+    pubkey:  [u8; kyber1024::PUBLIC_KEY_LEN],
+}
+
+#[cfg(feature = "aesgcm")]
 /// Wraps `rust-crypto`'s AES implementation.
 #[derive(Default)]
 struct CipherAESGCM {
     key: [u8; 32],
 }
 
+#[cfg(feature = "chachapoly")]
 /// Wraps `chacha20_poly1305_aead`'s ChaCha20Poly1305 implementation.
 #[derive(Default)]
 struct CipherChaChaPoly {
     key: [u8; 32],
 }
 
+#[cfg(feature = "sha2")]
 /// Wraps `rust-crypto`'s SHA-256 implementation.
 struct HashSHA256 {
     hasher: Sha256
 }
 
+#[cfg(feature = "sha2")]
 /// Wraps `rust-crypto`'s SHA-512 implementation.
 struct HashSHA512 {
     hasher: Sha512
 }
 
+#[cfg(feature = "blake2")]
 /// Wraps `blake2-rfc`'s implementation.
 struct HashBLAKE2b {
     hasher: Blake2b
 }
 
+#[cfg(feature = "blake2")]
 /// Wraps `blake2-rfc`'s implementation.
 struct HashBLAKE2s {
     hasher: Blake2s
@@ -101,10 +174,21 @@ struct HashBLAKE2s {
 
 impl Random for OsRng {}
 
+#[cfg(feature = "25519")]
+impl Dh25519 {
+    fn derive_pubkey(&mut self) {
+        // Same result as `x25519(privkey, basepoint)`, but uses dalek's precomputed basepoint
+        // table, which makes generating a key a few times faster than a full ladder.
+        let secret = x25519::StaticSecret::from(self.privkey);
+        self.pubkey = *x25519::PublicKey::from(&secret).as_bytes();
+    }
+}
+
+#[cfg(feature = "25519")]
 impl Dh for Dh25519 {
 
     fn name(&self) -> &'static str {
-        static NAME: &'static str = "25519";
+        static NAME: &str = "25519";
         NAME
     }
 
@@ -118,12 +202,12 @@ impl Dh for Dh25519 {
 
     fn set(&mut self, privkey: &[u8]) {
         copy_slices!(privkey, &mut self.privkey);
-        self.pubkey = x25519::x25519(self.privkey, x25519::X25519_BASEPOINT_BYTES);
+        self.derive_pubkey();
     }
 
-    fn generate(&mut self, rng: &mut Random) {
+    fn generate(&mut self, rng: &mut dyn Random) {
         rng.fill_bytes(&mut self.privkey);
-        self.pubkey = x25519::x25519(self.privkey, x25519::X25519_BASEPOINT_BYTES);
+        self.derive_pubkey();
     }
 
     fn pubkey(&self) -> &[u8] {
@@ -141,10 +225,150 @@ impl Dh for Dh25519 {
     }
 }
 
+#[cfg(feature = "25519")]
+impl Drop for Dh25519 {
+    fn drop(&mut self) {
+        secure_zero(&mut self.privkey);
+    }
+}
+
+#[cfg(feature = "448")]
+impl Default for Dh448 {
+    fn default() -> Dh448 {
+        Dh448 {
+            privkey: [0u8; 56],
+            pubkey:  [0u8; 56],
+        }
+    }
+}
+
+#[cfg(feature = "448")]
+impl Dh for Dh448 {
+
+    fn name(&self) -> &'static str {
+        "448"
+    }
+
+    fn pub_len(&self) -> usize {
+        56
+    }
+
+    fn priv_len(&self) -> usize {
+        56
+    }
+
+    fn set(&mut self, privkey: &[u8]) {
+        copy_slices!(privkey, &mut self.privkey);
+        self.pubkey = x448::x448(&self.privkey, &x448::BASEPOINT);
+    }
+
+    fn generate(&mut self, rng: &mut dyn Random) {
+        rng.fill_bytes(&mut self.privkey);
+        self.pubkey = x448::x448(&self.privkey, &x448::BASEPOINT);
+    }
+
+    fn pubkey(&self) -> &[u8] {
+        &self.pubkey
+    }
+
+    fn privkey(&self) -> &[u8] {
+        &self.privkey
+    }
+
+    fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> Result<(), ()> {
+        let result = x448::x448(&self.privkey, array_ref![pubkey, 0, 56]);
+        copy_slices!(&result, out);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "448")]
+impl Drop for Dh448 {
+    fn drop(&mut self) {
+        secure_zero(&mut self.privkey);
+    }
+}
+
+#[cfg(feature = "kyber1024")]
+impl Default for KemKyber1024 {
+    fn default() -> KemKyber1024 {
+        KemKyber1024 {
+            privkey: [0u8; kyber1024::SECRET_KEY_LEN],
+            pubkey:  [0u8; kyber1024::PUBLIC_KEY_LEN],
+        }
+    }
+}
+
+#[cfg(feature = "kyber1024")]
+impl Kem for KemKyber1024 {
+
+    fn name(&self) -> &'static str {
+        "Kyber1024"
+    }
+
+    fn pub_len(&self) -> usize {
+        kyber1024::PUBLIC_KEY_LEN
+    }
+
+    fn ciphertext_len(&self) -> usize {
+        kyber1024::CIPHERTEXT_LEN
+    }
+
+    fn shared_secret_len(&self) -> usize {
+        kyber1024::SHARED_SECRET_LEN
+    }
+
+    fn generate(&mut self, rng: &mut dyn Random) {
+        let mut seed = [0u8; 64];
+        rng.fill_bytes(&mut seed);
+        kyber1024::keypair(&seed, &mut self.pubkey, &mut self.privkey);
+        secure_zero(&mut seed);
+    }
+
+    fn pubkey(&self) -> &[u8] {
+        &self.pubkey
+    }
+
+    fn encapsulate(&self, pubkey: &[u8], rng: &mut dyn Random, shared_secret_out: &mut [u8], ciphertext_out: &mut [u8]) -> Result<(), ()> {
+        if pubkey.len() != kyber1024::PUBLIC_KEY_LEN
+            || shared_secret_out.len() < kyber1024::SHARED_SECRET_LEN
+            || ciphertext_out.len() < kyber1024::CIPHERTEXT_LEN {
+            return Err(());
+        }
+        let mut seed = [0u8; 32];
+        rng.fill_bytes(&mut seed);
+        kyber1024::encapsulate(array_ref![pubkey, 0, kyber1024::PUBLIC_KEY_LEN],
+                               &seed,
+                               array_mut_ref![ciphertext_out, 0, kyber1024::CIPHERTEXT_LEN],
+                               array_mut_ref![shared_secret_out, 0, kyber1024::SHARED_SECRET_LEN]);
+        secure_zero(&mut seed);
+        Ok(())
+    }
+
+    fn decapsulate(&self, ciphertext: &[u8], shared_secret_out: &mut [u8]) -> Result<(), ()> {
+        if ciphertext.len() != kyber1024::CIPHERTEXT_LEN
+            || shared_secret_out.len() < kyber1024::SHARED_SECRET_LEN {
+            return Err(());
+        }
+        kyber1024::decapsulate(array_ref![ciphertext, 0, kyber1024::CIPHERTEXT_LEN],
+                               &self.privkey,
+                               array_mut_ref![shared_secret_out, 0, kyber1024::SHARED_SECRET_LEN]);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "kyber1024")]
+impl Drop for KemKyber1024 {
+    fn drop(&mut self) {
+        secure_zero(&mut self.privkey);
+    }
+}
+
+#[cfg(feature = "aesgcm")]
 impl Cipher for CipherAESGCM {
 
     fn name(&self) -> &'static str {
-        static NAME: &'static str = "AESGCM";
+        static NAME: &str = "AESGCM";
         NAME
     }
 
@@ -169,14 +393,145 @@ impl Cipher for CipherAESGCM {
         let text_len = ciphertext.len() - TAGLEN;
         let mut tag = [0u8; TAGLEN];
         copy_slices!(&ciphertext[text_len..], &mut tag);
+        // rust-crypto compares the tag with `fixed_time_eq`.
         if cipher.decrypt(&ciphertext[..text_len], &mut out[..text_len], &tag) {
             Ok(text_len)
         } else {
+            // rust-crypto decrypts into `out` before checking the tag, so don't leave
+            // unauthenticated plaintext lying around for the caller.
+            for byte in out[..text_len].iter_mut() {
+                *byte = 0;
+            }
             Err(())
         }
     }
+
+    fn encrypt_in_place(&self, nonce: u64, authtext: &[u8], in_out: &mut [u8], plaintext_len: usize) -> usize {
+        let (text, tag) = in_out.split_at_mut(plaintext_len);
+        let mut gcm = AesGcmInPlace::new(&self.key, nonce, authtext);
+        gcm.apply_keystream(text);
+        tag[..TAGLEN].copy_from_slice(&gcm.tag(text));
+        plaintext_len + TAGLEN
+    }
+
+    fn decrypt_in_place(&self, nonce: u64, authtext: &[u8], in_out: &mut [u8]) -> Result<usize, ()> {
+        let text_len = in_out.len() - TAGLEN;
+        let (text, tag) = in_out.split_at_mut(text_len);
+        let mut gcm = AesGcmInPlace::new(&self.key, nonce, authtext);
+        // Unlike `AesGcm`, nothing is decrypted until the tag checks out.
+        if !bool::from(gcm.tag(text).ct_eq(tag)) {
+            return Err(());
+        }
+        gcm.apply_keystream(text);
+        Ok(text_len)
+    }
+}
+
+#[cfg(feature = "aesgcm")]
+impl Drop for CipherAESGCM {
+    fn drop(&mut self) {
+        secure_zero(&mut self.key);
+    }
+}
+
+#[cfg(feature = "aesgcm")]
+/// AES-256, picked the same way `crypto::aes::ctr` picks it, but without boxing it.
+// It only ever lives on the stack for the length of one message.
+#[allow(clippy::large_enum_variant)]
+enum Aes256 {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Ni(AesNiEncryptor),
+    Safe(AesSafe256EncryptorX8),
+}
+
+#[cfg(feature = "aesgcm")]
+impl Aes256 {
+    fn new(key: &[u8]) -> Aes256 {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if crypto::util::supports_aesni() {
+                return Aes256::Ni(AesNiEncryptor::new(KeySize::KeySize256, key));
+            }
+        }
+        Aes256::Safe(AesSafe256EncryptorX8::new(key))
+    }
+
+    /// Encrypts the first `blocks` (at most 8) blocks of `input` into `output`.
+    fn encrypt_blocks(&self, blocks: usize, input: &[u8; 128], output: &mut [u8; 128]) {
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Aes256::Ni(aes) => {
+                for (i, o) in input.chunks(16).zip(output.chunks_mut(16)).take(blocks) {
+                    aes.encrypt_block(i, o);
+                }
+            },
+            Aes256::Safe(aes) => aes.encrypt_block_x8(input, output),
+        }
+    }
+}
+
+#[cfg(feature = "aesgcm")]
+/// AES-GCM over a single buffer.
+///
+/// rust-crypto's `AesGcm` needs separate input and output buffers and boxes its CTR mode,
+/// so this puts the same pieces together by hand: GHASH keyed with E(K, 0), a tag masked
+/// with E(K, J0), and a CTR keystream starting at J0 + 1.
+struct AesGcmInPlace {
+    aes: Aes256,
+    counter: [u8; 16],
+    tag_mask: [u8; 16],
+    ghash: Ghash,
 }
 
+#[cfg(feature = "aesgcm")]
+impl AesGcmInPlace {
+    fn new(key: &[u8], nonce: u64, authtext: &[u8]) -> AesGcmInPlace {
+        let aes = Aes256::new(key);
+        let mut counter = [0u8; 16];
+        BigEndian::write_u64(&mut counter[4..12], nonce);
+        counter[15] = 1;
+
+        let mut blocks = [0u8; 128];
+        blocks[16..32].copy_from_slice(&counter);
+        let mut encrypted = [0u8; 128];
+        aes.encrypt_blocks(2, &blocks, &mut encrypted);
+        let ghash = Ghash::new(&encrypted[..16]).input_a(authtext);
+        let mut tag_mask = [0u8; 16];
+        tag_mask.copy_from_slice(&encrypted[16..32]);
+        secure_zero(&mut encrypted);
+
+        AesGcmInPlace { aes, counter, tag_mask, ghash }
+    }
+
+    fn tag(&self, ciphertext: &[u8]) -> [u8; TAGLEN] {
+        let mut tag = self.ghash.input_c(ciphertext).result();
+        for (t, m) in tag.iter_mut().zip(self.tag_mask.iter()) {
+            *t ^= m;
+        }
+        tag
+    }
+
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        let mut counters = [0u8; 128];
+        let mut keystream = [0u8; 128];
+        for chunk in data.chunks_mut(128) {
+            let blocks = chunk.len().div_ceil(16);
+            for counter in counters.chunks_mut(16).take(blocks) {
+                // GCM only ever increments the low 32 bits of the counter.
+                let next = BigEndian::read_u32(&self.counter[12..]).wrapping_add(1);
+                BigEndian::write_u32(&mut self.counter[12..], next);
+                counter.copy_from_slice(&self.counter);
+            }
+            self.aes.encrypt_blocks(blocks, &counters, &mut keystream);
+            for (byte, k) in chunk.iter_mut().zip(keystream.iter()) {
+                *byte ^= k;
+            }
+        }
+        secure_zero(&mut keystream);
+    }
+}
+
+#[cfg(feature = "chachapoly")]
 impl Cipher for CipherChaChaPoly {
 
     fn name(&self) -> &'static str {
@@ -195,7 +550,7 @@ impl Cipher for CipherChaChaPoly {
         let tag = chacha20_poly1305_aead::encrypt(&self.key, &nonce_bytes, authtext, plaintext, &mut buf);
         let tag = tag.unwrap();
         buf.write_all(&tag).unwrap();
-        if buf.position() > usize::max_value() as u64 {
+        if buf.position() > usize::MAX as u64 {
             panic!("usize overflow");
         } else {
             buf.position() as usize
@@ -206,6 +561,7 @@ impl Cipher for CipherChaChaPoly {
         let mut nonce_bytes = [0u8; 12];
         LittleEndian::write_u64(&mut nonce_bytes[4..], nonce);
 
+        // The tag is checked with `constant_time_eq` before anything is decrypted into `out`.
         let mut buf = Cursor::new(out);
         let result = chacha20_poly1305_aead::decrypt(
             &self.key,
@@ -216,7 +572,7 @@ impl Cipher for CipherChaChaPoly {
             &mut buf);
         match result {
             Ok(_) => {
-                if buf.position() > usize::max_value() as u64 {
+                if buf.position() > usize::MAX as u64 {
                     panic!("usize overflow");
                 } else {
                     Ok(buf.position() as usize)
@@ -225,14 +581,107 @@ impl Cipher for CipherChaChaPoly {
             Err(_) => Err(()),
         }
     }
+
+    fn encrypt_in_place(&self, nonce: u64, authtext: &[u8], in_out: &mut [u8], plaintext_len: usize) -> usize {
+        let (text, tag) = in_out.split_at_mut(plaintext_len);
+        let mut chachapoly = ChaChaPolyInPlace::new(&self.key, nonce);
+        chachapoly.apply_keystream(text);
+        tag[..TAGLEN].copy_from_slice(&chachapoly.tag(authtext, text));
+        plaintext_len + TAGLEN
+    }
+
+    fn decrypt_in_place(&self, nonce: u64, authtext: &[u8], in_out: &mut [u8]) -> Result<usize, ()> {
+        let text_len = in_out.len() - TAGLEN;
+        let (text, tag) = in_out.split_at_mut(text_len);
+        let mut chachapoly = ChaChaPolyInPlace::new(&self.key, nonce);
+        if !bool::from(chachapoly.tag(authtext, text).ct_eq(tag)) {
+            return Err(());
+        }
+        chachapoly.apply_keystream(text);
+        Ok(text_len)
+    }
+}
+
+#[cfg(feature = "chachapoly")]
+impl Drop for CipherChaChaPoly {
+    fn drop(&mut self) {
+        secure_zero(&mut self.key);
+    }
+}
+
+#[cfg(feature = "chachapoly")]
+/// ChaCha20-Poly1305 (RFC 7539) over a single buffer.
+///
+/// `chacha20_poly1305_aead` only works between separate buffers, so this builds the AEAD
+/// from rust-crypto's ChaCha20 and Poly1305 instead.
+struct ChaChaPolyInPlace {
+    chacha: ChaCha20,
+    poly_key: [u8; 32],
 }
 
+#[cfg(feature = "chachapoly")]
+impl ChaChaPolyInPlace {
+    fn new(key: &[u8], nonce: u64) -> ChaChaPolyInPlace {
+        let mut nonce_bytes = [0u8; 12];
+        LittleEndian::write_u64(&mut nonce_bytes[4..], nonce);
+        let mut chacha = ChaCha20::new(key, &nonce_bytes);
+
+        // The Poly1305 key is the start of block 0; the message is encrypted from block 1.
+        let mut block = [0u8; 64];
+        chacha.process(&[0u8; 64], &mut block);
+        let mut poly_key = [0u8; 32];
+        poly_key.copy_from_slice(&block[..32]);
+        secure_zero(&mut block);
+
+        ChaChaPolyInPlace { chacha, poly_key }
+    }
+
+    fn tag(&self, authtext: &[u8], ciphertext: &[u8]) -> [u8; TAGLEN] {
+        fn padding(len: usize) -> &'static [u8] {
+            &[0u8; 16][..(16 - len % 16) % 16]
+        }
+
+        let mut lengths = [0u8; 16];
+        LittleEndian::write_u64(&mut lengths[..8], authtext.len() as u64);
+        LittleEndian::write_u64(&mut lengths[8..], ciphertext.len() as u64);
+
+        let mut poly = Poly1305::new(&self.poly_key);
+        poly.input(authtext);
+        poly.input(padding(authtext.len()));
+        poly.input(ciphertext);
+        poly.input(padding(ciphertext.len()));
+        poly.input(&lengths);
+        let mut tag = [0u8; TAGLEN];
+        poly.raw_result(&mut tag);
+        tag
+    }
+
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        let mut buf = [0u8; 64];
+        for chunk in data.chunks_mut(64) {
+            let input = &mut buf[..chunk.len()];
+            input.copy_from_slice(chunk);
+            self.chacha.process(input, chunk);
+        }
+        secure_zero(&mut buf);
+    }
+}
+
+#[cfg(feature = "chachapoly")]
+impl Drop for ChaChaPolyInPlace {
+    fn drop(&mut self) {
+        secure_zero(&mut self.poly_key);
+    }
+}
+
+#[cfg(feature = "sha2")]
 impl Default for HashSHA256 {
     fn default() -> HashSHA256 {
         HashSHA256{hasher: Sha256::new()}
     }
 }
 
+#[cfg(feature = "sha2")]
 impl Hash for HashSHA256 {
 
     fn block_len(&self) -> usize {
@@ -260,12 +709,14 @@ impl Hash for HashSHA256 {
     }
 }
 
+#[cfg(feature = "sha2")]
 impl Default for HashSHA512 {
     fn default() -> HashSHA512 {
         HashSHA512{hasher:Sha512::new()}
     }
 }
 
+#[cfg(feature = "sha2")]
 impl Hash for HashSHA512 {
 
     fn name(&self) -> &'static str {
@@ -293,12 +744,14 @@ impl Hash for HashSHA512 {
     }
 }
 
+#[cfg(feature = "blake2")]
 impl Default for HashBLAKE2b {
     fn default() -> HashBLAKE2b {
         HashBLAKE2b { hasher: Blake2b::new(64) }
     }
 }
 
+#[cfg(feature = "blake2")]
 impl Hash for HashBLAKE2b {
 
     fn name(&self) -> &'static str {
@@ -327,12 +780,14 @@ impl Hash for HashBLAKE2b {
     }
 }
 
+#[cfg(feature = "blake2")]
 impl Default for HashBLAKE2s {
     fn default() -> HashBLAKE2s {
         HashBLAKE2s { hasher: Blake2s::new(32) }
     }
 }
 
+#[cfg(feature = "blake2")]
 impl Hash for HashBLAKE2s {
 
     fn name(&self) -> &'static str {
@@ -370,10 +825,30 @@ mod tests {
     use crate::types::*;
     use super::*;
     use self::hex::FromHex;
-    use super::crypto::poly1305::Poly1305;
-    use super::crypto::mac::Mac;
+    use crate::params::KemChoice;
+    #[cfg(feature = "kyber1024")] use super::crypto::digest::Digest;
+    #[cfg(feature = "kyber1024")] use super::crypto::sha3::Sha3;
+    #[cfg(any(feature = "aesgcm", feature = "sha2"))] use super::crypto::poly1305::Poly1305;
+    #[cfg(any(feature = "aesgcm", feature = "sha2"))] use super::crypto::mac::Mac;
 
     #[test]
+    fn test_resolves_enabled_primitives_only() {
+        let resolver = DefaultResolver;
+        assert_eq!(resolver.resolve_dh(&DHChoice::Curve25519).is_some(), cfg!(feature = "25519"));
+        assert_eq!(resolver.resolve_dh(&DHChoice::Ed448).is_some(), cfg!(feature = "448"));
+        assert_eq!(resolver.resolve_cipher(&CipherChoice::ChaChaPoly).is_some(), cfg!(feature = "chachapoly"));
+        assert_eq!(resolver.resolve_cipher(&CipherChoice::AESGCM).is_some(), cfg!(feature = "aesgcm"));
+        assert_eq!(resolver.resolve_kem(&KemChoice::Kyber1024).is_some(), cfg!(feature = "kyber1024"));
+        for choice in &[HashChoice::SHA256, HashChoice::SHA512] {
+            assert_eq!(resolver.resolve_hash(choice).is_some(), cfg!(feature = "sha2"));
+        }
+        for choice in &[HashChoice::Blake2s, HashChoice::Blake2b] {
+            assert_eq!(resolver.resolve_hash(choice).is_some(), cfg!(feature = "blake2"));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "sha2")]
     fn test_sha256() {
         let mut output = [0u8; 32];
         let mut hasher:HashSHA256 = Default::default();
@@ -383,6 +858,20 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "sha2")]
+    fn test_sha512() {
+        let mut output = [0u8; 64];
+        let mut hasher:HashSHA512 = Default::default();
+        hasher.input("abc".as_bytes());
+        hasher.result(&mut output);
+        assert!(hex::encode(&output[..]) == "ddaf35a193617abacc417349ae204131\
+                                             12e6fa4e89a97ea20a9eeee64b55d39a\
+                                             2192992a274fc1a836ba3c23a3feebbd\
+                                             454d4423643ce80e2a9ac94fa54ca49f");
+    }
+
+    #[test]
+    #[cfg(feature = "sha2")]
     fn test_hmac_sha256_sha512() {
         let key = Vec::<u8>::from_hex("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").unwrap();
         let data = Vec::<u8>::from_hex("dddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddd").unwrap();
@@ -394,26 +883,59 @@ mod tests {
         let mut output2 = [0u8; 64];
         let mut hasher: HashSHA512 = Default::default();
         hasher.hmac(&key, &data, &mut output2);
-        assert!(hex::encode(output2.to_vec()) == "fa73b0089d56a284efb0f0756c890be9\
+        assert!(hex::encode(output2) == "fa73b0089d56a284efb0f0756c890be9\
                                      b1b5dbdd8ee81a3655f83e33b2279d39\
                                      bf3e848279a722c806b485a47e67c807\
                                      b946a337bee8942674278859e13292fb");
     }
 
     #[test]
+    #[cfg(feature = "sha2")]
+    fn test_hkdf_sha256() {
+        // RFC 5869 Test Case 3 (zero-length salt and info), which lines up
+        // with Noise's HKDF when the chaining key is empty.
+        let ikm = [0x0bu8; 22];
+        let mut out1 = [0u8; 32];
+        let mut out2 = [0u8; 32];
+        let mut hasher: HashSHA256 = Default::default();
+        hasher.hkdf(&[], &ikm, 2, &mut out1, &mut out2, &mut []);
+        let okm = [&out1[..], &out2[..]].concat();
+        assert!(hex::encode(&okm[..42]) == "8da4e775a563c18f715f802a063c5a31\
+                                            b8a11f5c5ee1879ec3454e5f3c738d2d\
+                                            9d201395faa4b61a96c8");
+    }
+
+    #[test]
+    #[cfg(feature = "blake2")]
     fn test_blake2b() {
         // BLAKE2b test - draft-saarinen-blake2-06
         let mut output = [0u8; 64];
         let mut hasher:HashBLAKE2b = Default::default();
         hasher.input("abc".as_bytes());
         hasher.result(&mut output);
-        assert!(hex::encode(output.to_vec()) == "ba80a53f981c4d0d6a2797b69f12f6e9\
+        assert!(hex::encode(output) == "ba80a53f981c4d0d6a2797b69f12f6e9\
                                     4c212f14685ac4b74b12bb6fdbffa2d1\
                                     7d87c5392aab792dc252d5de4533cc95\
                                     18d38aa8dbf1925ab92386edd4009923");
     }
 
     #[test]
+    #[cfg(feature = "blake2")]
+    fn test_hmac_blake2b() {
+        // HMAC-BLAKE2b, with the same key/data as RFC 4231 Test Case 3
+        let key = Vec::<u8>::from_hex("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").unwrap();
+        let data = Vec::<u8>::from_hex("dddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddd").unwrap();
+        let mut output = [0u8; 64];
+        let mut hasher: HashBLAKE2b = Default::default();
+        hasher.hmac(&key, &data, &mut output);
+        assert!(hex::encode(&output[..]) == "f43bc62c7a99353c3b2c60e8ef24fbbd\
+                                             42e9547866dc9c5be4edc6f4a7d4bc0a\
+                                             c620c2c60034d040f0dbaf86f9e9cd78\
+                                             91a095595eed55e2a996215f0c15c018");
+    }
+
+    #[test]
+    #[cfg(feature = "blake2")]
     fn test_blake2s() {
         // BLAKE2s test - draft-saarinen-blake2-06
         let mut output = [0u8; 32];
@@ -425,6 +947,19 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "blake2")]
+    fn test_hmac_blake2s() {
+        // HMAC-BLAKE2s, with the same key/data as RFC 4231 Test Case 3
+        let key = Vec::<u8>::from_hex("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").unwrap();
+        let data = Vec::<u8>::from_hex("dddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddd").unwrap();
+        let mut output = [0u8; 32];
+        let mut hasher: HashBLAKE2s = Default::default();
+        hasher.hmac(&key, &data, &mut output);
+        assert!(hex::encode(output) == "fcc4f59529502e34c3d8da3ffdab82966a2cb637ff5e9bd701135c2e9469e790");
+    }
+
+    #[test]
+    #[cfg(feature = "25519")]
     fn test_curve25519() {
     // Curve25519 test - draft-curves-10
         let mut keypair:Dh25519 = Default::default();
@@ -437,6 +972,119 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "25519")]
+    fn test_curve25519_pubkey() {
+    // X25519 key pair - RFC 7748, section 6.1
+        let mut keypair:Dh25519 = Default::default();
+        let scalar = Vec::<u8>::from_hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a").unwrap();
+        keypair.set(&scalar);
+        assert_eq!(hex::encode(keypair.pubkey()), "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+
+        // The basepoint table agrees with the ladder for unclamped keys too.
+        for seed in 0..32u8 {
+            keypair.set(&[seed.wrapping_mul(37) ^ 0xa5; 32]);
+            assert_eq!(keypair.pubkey(), &x25519::x25519(keypair.privkey, x25519::X25519_BASEPOINT_BYTES)[..]);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "448")]
+    fn test_curve448() {
+    // X448 test - RFC 7748, section 5.2
+        let mut keypair:Dh448 = Default::default();
+        let scalar = Vec::<u8>::from_hex("3d262fddf9ec8e88495266fea19a34d28882acef045104d0d1aae121700a779c984c24f8cdd78fbff44943eba368f54b29259a4f1c600ad3").unwrap();
+        copy_slices!(&scalar, &mut keypair.privkey);
+        let public = Vec::<u8>::from_hex("06fce640fa3487bfda5f6cf2d5263f8aad88334cbd07437f020f08f9814dc031ddbdc38c19c6da2583fa5429db94ada18aa7a7fb4ef8a086").unwrap();
+        let mut output = [0u8; 56];
+        keypair.dh(&public, &mut output).unwrap();
+        assert!(hex::encode(&output[..]) == "ce3e4ff95a60dc6697da1db1d85e6afbdf79b50a2412d7546d5f239fe14fbaadeb445fc66a01b0779d98223961111e21766282f73dd96b6f");
+    }
+
+    #[test]
+    #[cfg(feature = "448")]
+    fn test_curve448_dh() {
+    // X448 Diffie-Hellman test - RFC 7748, section 6.2
+        let mut alice:Dh448 = Default::default();
+        alice.set(&Vec::<u8>::from_hex("9a8f4925d1519f5775cf46b04b5800d4ee9ee8bae8bc5565d498c28dd9c9baf574a9419744897391006382a6f127ab1d9ac2d8c0a598726b").unwrap());
+        assert!(hex::encode(alice.pubkey()) == "9b08f7cc31b7e3e67d22d5aea121074a273bd2b83de09c63faa73d2c22c5d9bbc836647241d953d40c5b12da88120d53177f80e532c41fa0");
+
+        let mut bob:Dh448 = Default::default();
+        bob.set(&Vec::<u8>::from_hex("1c306a7ac2a0e2e0990b294470cba339e6453772b075811d8fad0d1d6927c120bb5ee8972b0d3e21374c9c921b09d1b0366f10b65173992d").unwrap());
+        assert!(hex::encode(bob.pubkey()) == "3eb7a829b0cd20f5bcfc0b599b6feccf6da4627107bdb0d4f345b43027d8b972fc3e34fb4232a13ca706dcb57aec3dae07bdc1c67bf33609");
+
+        let mut shared_alice = [0u8; 56];
+        let mut shared_bob = [0u8; 56];
+        alice.dh(bob.pubkey(), &mut shared_alice).unwrap();
+        bob.dh(alice.pubkey(), &mut shared_bob).unwrap();
+        assert!(hex::encode(&shared_alice[..]) == "07fff4181ac6cc95ec1c16a94a0f74d12da232ce40a77552281d282bb60c0b56fd2464c335543936521c24403085d59a449a5037514a879d");
+        assert!(shared_alice[..] == shared_bob[..]);
+    }
+
+    #[test]
+    #[cfg(feature = "kyber1024")]
+    fn test_kyber1024_round_trip() {
+        let mut rng = OsRng::new().unwrap();
+        let mut kem:KemKyber1024 = Default::default();
+        kem.generate(&mut rng);
+
+        let mut ciphertext = [0u8; kyber1024::CIPHERTEXT_LEN];
+        let mut shared_sender = [0u8; 32];
+        let mut shared_receiver = [0u8; 32];
+        kem.encapsulate(kem.pubkey(), &mut rng, &mut shared_sender, &mut ciphertext).unwrap();
+        kem.decapsulate(&ciphertext, &mut shared_receiver).unwrap();
+        assert!(shared_sender == shared_receiver);
+        assert!(shared_sender != [0u8; 32]);
+
+        // A tampered ciphertext doesn't fail, but gives an unrelated secret.
+        ciphertext[0] ^= 1;
+        kem.decapsulate(&ciphertext, &mut shared_receiver).unwrap();
+        assert!(shared_sender != shared_receiver);
+
+        assert!(kem.encapsulate(&kem.pubkey()[1..], &mut rng, &mut shared_sender, &mut ciphertext).is_err());
+        assert!(kem.decapsulate(&ciphertext[1..], &mut shared_receiver).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "kyber1024")]
+    fn test_kyber1024_pke_matches_mlkem() {
+    // Kyber1024's IND-CPA scheme is ML-KEM-1024's K-PKE, so with ML-KEM's hashing around it
+    // it has to give the same keys and ciphertexts as OpenSSL 3.5's ML-KEM-1024.
+        let sha3_256 = |data: &[u8]| {
+            let mut hasher = Sha3::sha3_256();
+            let mut out = [0u8; 32];
+            hasher.input(data);
+            hasher.result(&mut out);
+            out
+        };
+        let sha3_512 = |data: &[u8]| {
+            let mut hasher = Sha3::sha3_512();
+            let mut out = [0u8; 64];
+            hasher.input(data);
+            hasher.result(&mut out);
+            out
+        };
+
+        let d: Vec<u8> = (0u8..32).chain(Some(4)).collect();
+        let seeds = sha3_512(&d);
+        let mut pubkey = [0u8; kyber1024::PUBLIC_KEY_LEN];
+        let mut privkey = [0u8; kyber1024::PUBLIC_KEY_LEN - 32];
+        kyber1024::cpa_keypair(&seeds[..32], &seeds[32..], &mut pubkey, &mut privkey);
+        assert!(hex::encode(sha3_256(&pubkey)) == "61349e5c131a7e116a0463861d7d18663c5627c38c7147ddaadfd48acd7a4535");
+
+        let message = [7u8; 32];
+        let key_and_coins = sha3_512(&[&message[..], &sha3_256(&pubkey)].concat());
+        let mut ciphertext = [0u8; kyber1024::CIPHERTEXT_LEN];
+        kyber1024::cpa_encrypt(&pubkey, &message, &key_and_coins[32..], &mut ciphertext);
+        assert!(hex::encode(sha3_256(&ciphertext)) == "e9eb5da54e5a5b7eeb94b853603499481be2eaa06c9a08dd989acdd2723cae8b");
+        assert!(hex::encode(&key_and_coins[..32]) == "0f569bf284efa80866fb11e6c3668b40390687f8be08fc46c7cc09ccdda44270");
+
+        let mut decrypted = [0u8; 32];
+        kyber1024::cpa_decrypt(&privkey, &ciphertext, &mut decrypted);
+        assert!(decrypted == message);
+    }
+
+    #[test]
+    #[cfg(feature = "aesgcm")]
     fn test_aes256_gcm() {
     //AES256-GCM tests - gcm-spec.pdf
         // Test Case 13
@@ -472,10 +1120,13 @@ mod tests {
         cipher4.decrypt(nonce, &authtext, &ciphertext2, &mut resulttext2).unwrap();
         assert!(plaintext2 == resulttext2);
         ciphertext2[0] ^= 1;
-        assert!(cipher4.decrypt(nonce, &authtext, &ciphertext2, &mut resulttext2).is_err());
+        let mut resulttext3 = [1u8; 16];
+        assert!(cipher4.decrypt(nonce, &authtext, &ciphertext2, &mut resulttext3).is_err());
+        assert!(resulttext3 == [0u8; 16]);
     }
 
     #[test]
+    #[cfg(any(feature = "aesgcm", feature = "sha2"))]
     fn test_poly1305() {
     // Poly1305 internal test - RFC 7539
         let key = Vec::<u8>::from_hex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b").unwrap();
@@ -490,6 +1141,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "chachapoly")]
     fn test_chachapoly_empty() {
     //ChaChaPoly round-trip test, empty plaintext
         let key = [0u8; 32];
@@ -511,6 +1163,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "chachapoly")]
     fn test_chachapoly_nonempty() {
     //ChaChaPoly round-trip test, non-empty plaintext
         let key = [0u8; 32];
@@ -526,10 +1179,11 @@ mod tests {
         let mut cipher2 : CipherChaChaPoly = Default::default();
         cipher2.set(&key);
         cipher2.decrypt(nonce, &authtext, &ciphertext, &mut resulttext).unwrap();
-        assert!(hex::encode(resulttext.to_vec()) == hex::encode(plaintext.to_vec()));
+        assert!(hex::encode(resulttext) == hex::encode(plaintext));
     }
 
     #[test]
+    #[cfg(feature = "chachapoly")]
     fn test_chachapoly_known_answer() {
     //ChaChaPoly known-answer test - RFC 7539
         let key =Vec::<u8>::from_hex("1c9240a5eb55d38af333888604f6b5f0\
@@ -579,6 +1233,193 @@ mod tests {
                                  6d206f74686572207468616e20617320\
                                  2fe2809c776f726b20696e2070726f67\
                                  726573732e2fe2809d";
-        assert!(hex::encode(out[..ciphertext.len()].to_owned()) == desired_plaintext);
+        assert!(hex::encode(&out[..ciphertext.len()]) == desired_plaintext);
+    }
+
+    #[test]
+    #[cfg(feature = "chachapoly")]
+    fn test_chachapoly_known_answer_encrypt() {
+    //ChaChaPoly known-answer test - RFC 7539, in the encrypt direction
+        let key =Vec::<u8>::from_hex("1c9240a5eb55d38af333888604f6b5f0\
+                  473917c1402b80099dca5cbc207075c0").unwrap();
+        let nonce = 0x0807060504030201u64;
+        let plaintext = Vec::<u8>::from_hex("496e7465726e65742d44726166747320\
+                                 61726520647261667420646f63756d65\
+                                 6e74732076616c696420666f72206120\
+                                 6d6178696d756d206f6620736978206d\
+                                 6f6e74687320616e64206d6179206265\
+                                 20757064617465642c207265706c6163\
+                                 65642c206f72206f62736f6c65746564\
+                                 206279206f7468657220646f63756d65\
+                                 6e747320617420616e792074696d652e\
+                                 20497420697320696e617070726f7072\
+                                 6961746520746f2075736520496e7465\
+                                 726e65742d4472616674732061732072\
+                                 65666572656e6365206d617465726961\
+                                 6c206f7220746f206369746520746865\
+                                 6d206f74686572207468616e20617320\
+                                 2fe2809c776f726b20696e2070726f67\
+                                 726573732e2fe2809d").unwrap();
+        let desired_ciphertext =Vec::<u8>::from_hex("64a0861575861af460f062c79be643bd\
+                         5e805cfd345cf389f108670ac76c8cb2\
+                         4c6cfc18755d43eea09ee94e382d26b0\
+                         bdb7b73c321b0100d4f03b7f355894cf\
+                         332f830e710b97ce98c8a84abd0b9481\
+                         14ad176e008d33bd60f982b1ff37c855\
+                         9797a06ef4f0ef61c186324e2b350638\
+                         3606907b6a7c02b0f9f6157b53c867e4\
+                         b9166c767b804d46a59b5216cde7a4e9\
+                         9040c5a40433225ee282a1b0a06c523e\
+                         af4534d7f83fa1155b0047718cbc546a\
+                         0d072b04b3564eea1b422273f548271a\
+                         0bb2316053fa76991955ebd63159434e\
+                         cebb4e466dae5a1073a6727627097a10\
+                         49e617d91d361094fa68f0ff77987130\
+                         305beaba2eda04df997b714d6c6f2c29\
+                         a6ad5cb4022b02709b").unwrap();
+        let tag = Vec::<u8>::from_hex("eead9d67890cbb22392336fea1851f38").unwrap();
+        let authtext = Vec::<u8>::from_hex("f33388860000000000004e91").unwrap();
+        let mut out = [0u8; 1024];
+
+        let mut cipher : CipherChaChaPoly = Default::default();
+        cipher.set(&key);
+        let len = cipher.encrypt(nonce, &authtext, &plaintext, &mut out);
+        assert_eq!(len, plaintext.len() + TAGLEN);
+        assert!(hex::encode(&out[..plaintext.len()]) == hex::encode(&desired_ciphertext));
+        assert!(hex::encode(&out[plaintext.len()..len]) == hex::encode(&tag));
+    }
+
+    #[test]
+    #[cfg(all(feature = "chachapoly", feature = "aesgcm"))]
+    fn test_tampered_ciphertext_is_rejected() {
+        let ciphers: Vec<Box<dyn Cipher>> = vec![
+            Box::new(CipherChaChaPoly::default()),
+            Box::new(CipherAESGCM::default()),
+        ];
+        let plaintext = b"hack the planet";
+
+        for mut cipher in ciphers {
+            cipher.set(&[0x0fu8; 32]);
+            let mut ciphertext = [0u8; 15 + TAGLEN];
+            let len = cipher.encrypt(7, b"ad", plaintext, &mut ciphertext);
+
+            for i in 0..len {
+                let mut tampered = ciphertext;
+                tampered[i] ^= 0x80;
+                let mut out = [0u8; 15];
+                assert!(cipher.decrypt(7, b"ad", &tampered, &mut out).is_err());
+                assert_eq!(out, [0u8; 15]);
+            }
+
+            let mut out = [0u8; 15];
+            assert!(cipher.decrypt(7, b"da", &ciphertext, &mut out).is_err());
+            assert_eq!(cipher.decrypt(7, b"ad", &ciphertext, &mut out), Ok(15));
+            assert_eq!(&out, plaintext);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "chachapoly", feature = "aesgcm"))]
+    fn test_decrypt_in_place_matches_decrypt() {
+        let ciphers: Vec<Box<dyn Cipher>> = vec![
+            Box::new(CipherChaChaPoly::default()),
+            Box::new(CipherAESGCM::default()),
+        ];
+        let plaintext: Vec<u8> = (0..1100u32).map(|i| (i * 7 + 3) as u8).collect();
+
+        for mut cipher in ciphers {
+            cipher.set(&[0x0fu8; 32]);
+            for &len in [0, 1, 15, 16, 17, 63, 64, 65, 127, 128, 129, 500, 1024, 1100].iter() {
+                let authtext = &plaintext[..len % 37];
+                let mut ciphertext = vec![0u8; len + TAGLEN];
+                cipher.encrypt(len as u64, authtext, &plaintext[..len], &mut ciphertext);
+
+                let mut in_out = ciphertext.clone();
+                assert_eq!(cipher.decrypt_in_place(len as u64, authtext, &mut in_out), Ok(len));
+                assert_eq!(&in_out[..len], &plaintext[..len]);
+
+                let mut tampered = ciphertext.clone();
+                tampered[len / 2] ^= 0x80;
+                let mut in_out = tampered.clone();
+                assert!(cipher.decrypt_in_place(len as u64, authtext, &mut in_out).is_err());
+                assert_eq!(in_out, tampered);
+                let mut in_out = ciphertext.clone();
+                assert!(cipher.decrypt_in_place(len as u64 + 1, authtext, &mut in_out).is_err());
+                assert!(cipher.decrypt_in_place(len as u64, b"other", &mut in_out).is_err());
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "chachapoly", feature = "aesgcm"))]
+    fn test_encrypt_in_place_matches_encrypt() {
+        let ciphers: Vec<Box<dyn Cipher>> = vec![
+            Box::new(CipherChaChaPoly::default()),
+            Box::new(CipherAESGCM::default()),
+        ];
+        let plaintext: Vec<u8> = (0..1100u32).map(|i| (i * 7 + 3) as u8).collect();
+
+        for mut cipher in ciphers {
+            cipher.set(&[0x0fu8; 32]);
+            for &len in [0, 1, 15, 16, 17, 63, 64, 65, 127, 128, 129, 500, 1024, 1100].iter() {
+                let authtext = &plaintext[..len % 37];
+                let mut ciphertext = vec![0u8; len + TAGLEN];
+                cipher.encrypt(len as u64, authtext, &plaintext[..len], &mut ciphertext);
+
+                // Room to spare past the tag is left alone.
+                let mut in_out = vec![0xaau8; len + TAGLEN + 3];
+                in_out[..len].copy_from_slice(&plaintext[..len]);
+                assert_eq!(cipher.encrypt_in_place(len as u64, authtext, &mut in_out, len), len + TAGLEN);
+                assert_eq!(&in_out[..len + TAGLEN], &ciphertext[..]);
+                assert_eq!(&in_out[len + TAGLEN..], &[0xaau8; 3]);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "aesgcm", any(target_arch = "x86", target_arch = "x86_64")))]
+    fn test_aes256_backends_agree() {
+        if !crypto::util::supports_aesni() {
+            return;
+        }
+        let key = [0x42u8; 32];
+        let ni = Aes256::Ni(AesNiEncryptor::new(KeySize::KeySize256, &key));
+        let safe = Aes256::Safe(AesSafe256EncryptorX8::new(&key));
+        let mut input = [0u8; 128];
+        for (i, byte) in input.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut ni_out = [0u8; 128];
+        let mut safe_out = [0u8; 128];
+        ni.encrypt_blocks(8, &input, &mut ni_out);
+        safe.encrypt_blocks(8, &input, &mut safe_out);
+        assert_eq!(&ni_out[..], &safe_out[..]);
+    }
+
+    #[test]
+    #[cfg(all(feature = "chachapoly", feature = "aesgcm"))]
+    fn test_rekey_matches_spec() {
+        // REKEY(k) is the first 32 bytes of ENCRYPT(k, 2^64-1, zerolen, zeros)
+        let ciphers: Vec<(Box<dyn Cipher>, Box<dyn Cipher>)> = vec![
+            (Box::new(CipherChaChaPoly::default()), Box::new(CipherChaChaPoly::default())),
+            (Box::new(CipherAESGCM::default()), Box::new(CipherAESGCM::default())),
+        ];
+        let key = [0x0fu8; 32];
+
+        for (mut rekeyed, mut expected) in ciphers {
+            rekeyed.set(&key);
+            rekeyed.rekey();
+
+            let mut new_key = [0u8; 32 + TAGLEN];
+            expected.set(&key);
+            expected.encrypt(u64::MAX, &[], &[0u8; 32], &mut new_key);
+            expected.set(&new_key[..32]);
+
+            let mut rekeyed_out = [0u8; 64];
+            let mut expected_out = [0u8; 64];
+            rekeyed.encrypt(0, &[], b"hack the planet", &mut rekeyed_out);
+            expected.encrypt(0, &[], b"hack the planet", &mut expected_out);
+            assert_eq!(&rekeyed_out[..], &expected_out[..]);
+        }
     }
 }
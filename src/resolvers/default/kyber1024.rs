@@ -0,0 +1,431 @@
+//! Kyber1024, the key encapsulation mechanism of the round 3 CRYSTALS-Kyber submission
+//! ([version 3.02](https://pq-crystals.org/kyber/data/kyber-specification-round3-20210804.pdf)),
+//! used for the `Kyber1024` KEM choice of hybrid (`hfs`) handshakes.
+//!
+//! Coefficients are kept fully reduced mod q instead of using the Montgomery and Barrett
+//! tricks of the reference code. The only divisions are by the constant q, which compile to
+//! multiplications, so nothing here branches on or divides by a secret.
+//!
+//! The IND-CPA scheme underneath is the same as the K-PKE of ML-KEM (FIPS 203), which the
+//! tests check it against, and only the hashing around it differs.
+//!
+//! This code hasn't been independently audited, so it's only built with the opt-in
+//! `kyber1024` feature.
+
+use super::crypto::digest::Digest;
+use super::crypto::sha3::Sha3;
+use crate::utils::secure_zero;
+use byteorder::{ByteOrder, LittleEndian};
+use core::sync::atomic::{self, Ordering};
+use core::{ptr, slice};
+use subtle::{ConditionallySelectable, ConstantTimeEq};
+
+const N: usize = 256;
+const Q: u32 = 3329;
+const K: usize = 4;
+const DU: usize = 11;
+const DV: usize = 5;
+
+const POLY_LEN: usize = N * 12 / 8;
+const POLYVEC_LEN: usize = K * POLY_LEN;
+const COMPRESSED_POLY_LEN: usize = N * DU / 8;
+const COMPRESSED_POLYVEC_LEN: usize = K * COMPRESSED_POLY_LEN;
+
+pub const PUBLIC_KEY_LEN: usize = POLYVEC_LEN + 32;
+pub const SECRET_KEY_LEN: usize = POLYVEC_LEN + PUBLIC_KEY_LEN + 64;
+pub const CIPHERTEXT_LEN: usize = COMPRESSED_POLYVEC_LEN + N * DV / 8;
+pub const SHARED_SECRET_LEN: usize = 32;
+
+/// The powers of 17, a primitive 256th root of unity mod q, that the NTT works with, in
+/// the order of their 7-bit reversed exponents.
+const ZETAS: [u32; 128] = zetas();
+
+const fn zetas() -> [u32; 128] {
+    let mut zetas = [0u32; 128];
+    let mut i = 0;
+    while i < 128 {
+        let mut exponent = 0;
+        let mut bit = 0;
+        while bit < 7 {
+            exponent |= ((i >> bit) & 1) << (6 - bit);
+            bit += 1;
+        }
+        let mut power = 1;
+        while exponent > 0 {
+            power = power * 17 % Q;
+            exponent -= 1;
+        }
+        zetas[i] = power;
+        i += 1;
+    }
+    zetas
+}
+
+/// 128^-1 mod q, the scaling factor of the inverse NTT.
+const N_INV: u32 = 3303;
+
+/// q/2 rounded up, which a 1 bit of the message is encoded as.
+const HALF_Q: u32 = 1665;
+
+/// A polynomial of Z_q[X]/(X^256 + 1), with every coefficient below q.
+type Poly = [u32; N];
+
+fn add(a: u32, b: u32) -> u32 {
+    (a + b) % Q
+}
+
+fn sub(a: u32, b: u32) -> u32 {
+    (a + Q - b) % Q
+}
+
+fn mul(a: u32, b: u32) -> u32 {
+    a * b % Q
+}
+
+/// The number theoretic transform, in place, with the output in bit-reversed order.
+fn ntt(r: &mut Poly) {
+    let mut k = 1;
+    let mut len = 128;
+    while len >= 2 {
+        for start in (0..N).step_by(2 * len) {
+            let zeta = ZETAS[k];
+            k += 1;
+            for j in start..start + len {
+                let t = mul(zeta, r[j + len]);
+                r[j + len] = sub(r[j], t);
+                r[j] = add(r[j], t);
+            }
+        }
+        len /= 2;
+    }
+}
+
+/// The inverse of `ntt()`, in place.
+fn invntt(r: &mut Poly) {
+    let mut k = 127;
+    let mut len = 2;
+    while len <= 128 {
+        for start in (0..N).step_by(2 * len) {
+            let zeta = ZETAS[k];
+            k -= 1;
+            for j in start..start + len {
+                let t = r[j];
+                r[j] = add(t, r[j + len]);
+                r[j + len] = mul(zeta, sub(r[j + len], t));
+            }
+        }
+        len *= 2;
+    }
+    for coefficient in r.iter_mut() {
+        *coefficient = mul(*coefficient, N_INV);
+    }
+}
+
+/// Add the product of `a` and `b`, both in the NTT domain, to `acc`: 128 products of
+/// degree-one polynomials modulo X^2 - zeta.
+fn basemul_acc(acc: &mut Poly, a: &Poly, b: &Poly) {
+    for i in 0..64 {
+        let zeta = ZETAS[64 + i];
+        for &(k, zeta) in &[(4 * i, zeta), (4 * i + 2, Q - zeta)] {
+            acc[k] = add(acc[k], add(mul(mul(a[k + 1], b[k + 1]), zeta), mul(a[k], b[k])));
+            acc[k + 1] = add(acc[k + 1], add(mul(a[k], b[k + 1]), mul(a[k + 1], b[k])));
+        }
+    }
+}
+
+fn add_assign(a: &mut Poly, b: &Poly) {
+    for (a, b) in a.iter_mut().zip(b.iter()) {
+        *a = add(*a, *b);
+    }
+}
+
+/// Pack `bits`-bit values into bytes, least significant bit first.
+fn pack(values: &[u32], bits: usize, out: &mut [u8]) {
+    let mut acc = 0u64;
+    let mut acc_bits = 0;
+    let mut out = out.iter_mut();
+    for &value in values {
+        acc |= u64::from(value) << acc_bits;
+        acc_bits += bits;
+        while acc_bits >= 8 {
+            *out.next().unwrap() = acc as u8;
+            acc >>= 8;
+            acc_bits -= 8;
+        }
+    }
+}
+
+/// The inverse of `pack()`.
+fn unpack(bytes: &[u8], bits: usize, values: &mut [u32]) {
+    let mut acc = 0u64;
+    let mut acc_bits = 0;
+    let mut bytes = bytes.iter();
+    for value in values.iter_mut() {
+        while acc_bits < bits {
+            acc |= u64::from(*bytes.next().unwrap()) << acc_bits;
+            acc_bits += 8;
+        }
+        *value = (acc & ((1 << bits) - 1)) as u32;
+        acc >>= bits;
+        acc_bits -= bits;
+    }
+}
+
+fn compress(poly: &mut Poly, bits: usize) {
+    for coefficient in poly.iter_mut() {
+        *coefficient = (((*coefficient << bits) + Q / 2) / Q) & ((1 << bits) - 1);
+    }
+}
+
+fn decompress(poly: &mut Poly, bits: usize) {
+    for coefficient in poly.iter_mut() {
+        *coefficient = (*coefficient * Q + (1 << (bits - 1))) >> bits;
+    }
+}
+
+fn encode(poly: &Poly, out: &mut [u8]) {
+    pack(poly, 12, &mut out[..POLY_LEN]);
+}
+
+/// The inverse of `encode()`, reducing the 12-bit values of a key that came off the wire.
+fn decode(bytes: &[u8]) -> Poly {
+    let mut poly = [0u32; N];
+    unpack(&bytes[..POLY_LEN], 12, &mut poly);
+    for coefficient in poly.iter_mut() {
+        *coefficient %= Q;
+    }
+    poly
+}
+
+/// The entry of the matrix A (in the NTT domain) that the XOF gives for `rho`, `x` and `y`:
+/// A[i][j] takes `x = j, y = i`, and its transpose `x = i, y = j`.
+fn sample_uniform(rho: &[u8], x: usize, y: usize) -> Poly {
+    let mut xof = Sha3::shake_128();
+    xof.input(rho);
+    xof.input(&[x as u8, y as u8]);
+
+    let mut poly = [0u32; N];
+    let mut filled = 0;
+    let mut block = [0u8; 168];
+    while filled < N {
+        xof.result(&mut block);
+        for chunk in block.chunks(3) {
+            let first = u32::from(chunk[0]) | (u32::from(chunk[1] & 0x0f) << 8);
+            let second = u32::from(chunk[1] >> 4) | (u32::from(chunk[2]) << 4);
+            for &candidate in &[first, second] {
+                if candidate < Q && filled < N {
+                    poly[filled] = candidate;
+                    filled += 1;
+                }
+            }
+        }
+    }
+    poly
+}
+
+/// A noise polynomial, from the centered binomial distribution with eta = 2 over the PRF's
+/// output for `seed` and `nonce`.
+fn sample_noise(seed: &[u8], nonce: usize) -> Poly {
+    let mut prf = Sha3::shake_256();
+    prf.input(seed);
+    prf.input(&[nonce as u8]);
+    let mut bytes = [0u8; 2 * N / 4];
+    prf.result(&mut bytes);
+
+    let mut poly = [0u32; N];
+    for (i, chunk) in bytes.chunks(4).enumerate() {
+        let t = LittleEndian::read_u32(chunk);
+        let d = (t & 0x5555_5555) + ((t >> 1) & 0x5555_5555);
+        for j in 0..8 {
+            let a = (d >> (4 * j)) & 3;
+            let b = (d >> (4 * j + 2)) & 3;
+            poly[8 * i + j] = sub(a, b);
+        }
+    }
+    secure_zero(&mut bytes);
+    poly
+}
+
+/// `secure_zero()` for polynomials.
+fn wipe(polys: &mut [Poly]) {
+    for poly in polys.iter_mut() {
+        for coefficient in poly.iter_mut() {
+            unsafe { ptr::write_volatile(coefficient, 0) };
+        }
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+fn hash_h(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha3::sha3_256();
+    let mut out = [0u8; 32];
+    for part in parts {
+        hasher.input(part);
+    }
+    hasher.result(&mut out);
+    out
+}
+
+fn hash_g(parts: &[&[u8]]) -> [u8; 64] {
+    let mut hasher = Sha3::sha3_512();
+    let mut out = [0u8; 64];
+    for part in parts {
+        hasher.input(part);
+    }
+    hasher.result(&mut out);
+    out
+}
+
+fn kdf(parts: &[&[u8]], out: &mut [u8; SHARED_SECRET_LEN]) {
+    let mut hasher = Sha3::shake_256();
+    for part in parts {
+        hasher.input(part);
+    }
+    hasher.result(out);
+}
+
+/// The IND-CPA key pair for the public seed `rho` and the noise seed `sigma`.
+pub(crate) fn cpa_keypair(rho: &[u8], sigma: &[u8], pk: &mut [u8], sk: &mut [u8]) {
+    let mut s = [[0u32; N]; K];
+    let mut e = [[0u32; N]; K];
+    for i in 0..K {
+        s[i] = sample_noise(sigma, i);
+        e[i] = sample_noise(sigma, K + i);
+        ntt(&mut s[i]);
+        ntt(&mut e[i]);
+    }
+
+    for i in 0..K {
+        let mut t = e[i];
+        for (j, s) in s.iter().enumerate() {
+            basemul_acc(&mut t, &sample_uniform(rho, j, i), s);
+        }
+        encode(&t, &mut pk[i * POLY_LEN..]);
+        encode(&s[i], &mut sk[i * POLY_LEN..]);
+    }
+    pk[POLYVEC_LEN..PUBLIC_KEY_LEN].copy_from_slice(rho);
+    wipe(&mut s);
+    wipe(&mut e);
+}
+
+/// Encrypt the 32-byte `message` to `pk`, with the randomness derived from `coins`.
+pub(crate) fn cpa_encrypt(pk: &[u8], message: &[u8], coins: &[u8], ct: &mut [u8]) {
+    let rho = &pk[POLYVEC_LEN..PUBLIC_KEY_LEN];
+    let mut r = [[0u32; N]; K];
+    let mut e1 = [[0u32; N]; K];
+    for i in 0..K {
+        r[i] = sample_noise(coins, i);
+        e1[i] = sample_noise(coins, K + i);
+        ntt(&mut r[i]);
+    }
+    let mut e2 = sample_noise(coins, 2 * K);
+
+    for i in 0..K {
+        let mut u = [0u32; N];
+        for (j, r) in r.iter().enumerate() {
+            basemul_acc(&mut u, &sample_uniform(rho, i, j), r);
+        }
+        invntt(&mut u);
+        add_assign(&mut u, &e1[i]);
+        compress(&mut u, DU);
+        pack(&u, DU, &mut ct[i * COMPRESSED_POLY_LEN..(i + 1) * COMPRESSED_POLY_LEN]);
+    }
+
+    let mut v = [0u32; N];
+    for (i, r) in r.iter().enumerate() {
+        basemul_acc(&mut v, &decode(&pk[i * POLY_LEN..]), r);
+    }
+    invntt(&mut v);
+    add_assign(&mut v, &e2);
+    let mut m = [0u32; N];
+    unpack(message, 1, &mut m);
+    for coefficient in m.iter_mut() {
+        *coefficient *= HALF_Q;
+    }
+    add_assign(&mut v, &m);
+    compress(&mut v, DV);
+    pack(&v, DV, &mut ct[COMPRESSED_POLYVEC_LEN..CIPHERTEXT_LEN]);
+
+    wipe(&mut r);
+    wipe(&mut e1);
+    wipe(slice::from_mut(&mut e2));
+    wipe(slice::from_mut(&mut m));
+    wipe(slice::from_mut(&mut v));
+}
+
+/// Decrypt the 32-byte message that `ct` carries with the IND-CPA secret key `sk`.
+pub(crate) fn cpa_decrypt(sk: &[u8], ct: &[u8], message: &mut [u8]) {
+    let mut w = [0u32; N];
+    for i in 0..K {
+        let mut u = [0u32; N];
+        unpack(&ct[i * COMPRESSED_POLY_LEN..(i + 1) * COMPRESSED_POLY_LEN], DU, &mut u);
+        decompress(&mut u, DU);
+        ntt(&mut u);
+        let mut s = decode(&sk[i * POLY_LEN..]);
+        basemul_acc(&mut w, &s, &u);
+        wipe(slice::from_mut(&mut s));
+    }
+    invntt(&mut w);
+
+    let mut v = [0u32; N];
+    unpack(&ct[COMPRESSED_POLYVEC_LEN..CIPHERTEXT_LEN], DV, &mut v);
+    decompress(&mut v, DV);
+    for (v, w) in v.iter_mut().zip(w.iter()) {
+        *v = sub(*v, *w);
+    }
+    compress(&mut v, 1);
+    pack(&v, 1, &mut message[..32]);
+    wipe(slice::from_mut(&mut v));
+    wipe(slice::from_mut(&mut w));
+}
+
+/// Generate a key pair from 64 random bytes.
+pub fn keypair(seed: &[u8; 64], pk: &mut [u8; PUBLIC_KEY_LEN], sk: &mut [u8; SECRET_KEY_LEN]) {
+    let mut seeds = hash_g(&[&seed[..32]]);
+    cpa_keypair(&seeds[..32], &seeds[32..], pk, &mut sk[..POLYVEC_LEN]);
+    secure_zero(&mut seeds);
+
+    sk[POLYVEC_LEN..POLYVEC_LEN + PUBLIC_KEY_LEN].copy_from_slice(pk);
+    sk[SECRET_KEY_LEN - 64..SECRET_KEY_LEN - 32].copy_from_slice(&hash_h(&[pk]));
+    sk[SECRET_KEY_LEN - 32..].copy_from_slice(&seed[32..]);
+}
+
+/// Encapsulate a shared secret to `pk`, from 32 random bytes.
+pub fn encapsulate(pk: &[u8; PUBLIC_KEY_LEN],
+                   seed: &[u8; 32],
+                   ct: &mut [u8; CIPHERTEXT_LEN],
+                   shared_secret: &mut [u8; SHARED_SECRET_LEN]) {
+    // The random bytes are hashed first, so that they never go out as they are.
+    let mut message = hash_h(&[seed]);
+    let mut kr = hash_g(&[&message, &hash_h(&[pk])]);
+    cpa_encrypt(pk, &message, &kr[32..], ct);
+    kdf(&[&kr[..32], &hash_h(&[ct])], shared_secret);
+    secure_zero(&mut message);
+    secure_zero(&mut kr);
+}
+
+/// Recover the shared secret `ct` carries with `sk`. A ciphertext that wasn't made by
+/// `encapsulate()` for the matching public key gives an unrelated secret instead of an error,
+/// so the only way to tell is that the handshake fails further on.
+pub fn decapsulate(ct: &[u8; CIPHERTEXT_LEN],
+                   sk: &[u8; SECRET_KEY_LEN],
+                   shared_secret: &mut [u8; SHARED_SECRET_LEN]) {
+    let pk = &sk[POLYVEC_LEN..POLYVEC_LEN + PUBLIC_KEY_LEN];
+    let z = &sk[SECRET_KEY_LEN - 32..];
+    let mut message = [0u8; 32];
+    cpa_decrypt(&sk[..POLYVEC_LEN], ct, &mut message);
+    let mut kr = hash_g(&[&message, &sk[SECRET_KEY_LEN - 64..SECRET_KEY_LEN - 32]]);
+    let mut reencrypted = [0u8; CIPHERTEXT_LEN];
+    cpa_encrypt(pk, &message, &kr[32..], &mut reencrypted);
+
+    let genuine = ct[..].ct_eq(&reencrypted[..]);
+    let mut key = [0u8; 32];
+    for i in 0..32 {
+        key[i] = u8::conditional_select(&z[i], &kr[i], genuine);
+    }
+    kdf(&[&key, &hash_h(&[ct])], shared_secret);
+    secure_zero(&mut message);
+    secure_zero(&mut kr);
+    secure_zero(&mut key);
+}
+
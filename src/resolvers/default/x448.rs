@@ -0,0 +1,232 @@
+//! A small, constant-time implementation of the X448 function from
+//! [RFC 7748](https://tools.ietf.org/html/rfc7748), used for the `448` DH choice.
+//!
+//! Field elements are stored as eight 56-bit limbs, so that a field element maps
+//! directly onto its 56-byte little-endian encoding (seven bytes per limb).
+//!
+//! This code hasn't been independently audited, so it's only built with the opt-in `448`
+//! feature.
+
+const MASK: u64 = (1 << 56) - 1;
+
+/// The `(A - 2) / 4` constant for curve448.
+const A24: u64 = 39081;
+
+/// p - 2, little-endian, for inversion by way of Fermat's little theorem.
+const P_MINUS_TWO: [u8; 56] = [
+    0xfd, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+
+/// The u-coordinate of the curve448 base point.
+pub const BASEPOINT: [u8; 56] = {
+    let mut point = [0u8; 56];
+    point[0] = 5;
+    point
+};
+
+/// An element of GF(2^448 - 2^224 - 1), in radix 2^56.
+#[derive(Clone, Copy)]
+struct Fe([u64; 8]);
+
+impl Fe {
+    const ZERO: Fe = Fe([0; 8]);
+    const ONE: Fe = Fe([1, 0, 0, 0, 0, 0, 0, 0]);
+
+    fn from_bytes(bytes: &[u8; 56]) -> Fe {
+        let mut limbs = [0u64; 8];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            for j in 0..7 {
+                *limb |= u64::from(bytes[i * 7 + j]) << (8 * j);
+            }
+        }
+        Fe(limbs)
+    }
+
+    fn to_bytes(self) -> [u8; 56] {
+        let mut limbs = self.0;
+        for _ in 0..2 {
+            Fe::carry_limbs(&mut limbs);
+        }
+
+        // The value is now below 2^448 < 2p, so at most one subtraction of p is needed.
+        let p = [MASK, MASK, MASK, MASK, MASK - 1, MASK, MASK, MASK];
+        let mut reduced = [0u64; 8];
+        let mut borrow = 0i64;
+        for i in 0..8 {
+            let diff = limbs[i] as i64 - p[i] as i64 + borrow;
+            reduced[i] = (diff as u64) & MASK;
+            borrow = diff >> 56;
+        }
+        // borrow is -1 if limbs < p (keep the original), or 0 otherwise (take the reduced value).
+        let keep = borrow as u64;
+        for i in 0..8 {
+            limbs[i] = (limbs[i] & keep) | (reduced[i] & !keep);
+        }
+
+        let mut bytes = [0u8; 56];
+        for (i, limb) in limbs.iter().enumerate() {
+            for j in 0..7 {
+                bytes[i * 7 + j] = (limb >> (8 * j)) as u8;
+            }
+        }
+        bytes
+    }
+
+    /// Propagate carries so that each limb fits in 56 bits, folding the overflow past
+    /// 2^448 back in as 2^224 + 1.
+    fn carry_limbs(limbs: &mut [u64; 8]) {
+        for i in 0..7 {
+            limbs[i + 1] += limbs[i] >> 56;
+            limbs[i] &= MASK;
+        }
+        let top = limbs[7] >> 56;
+        limbs[7] &= MASK;
+        limbs[0] += top;
+        limbs[4] += top;
+        for i in 0..7 {
+            limbs[i + 1] += limbs[i] >> 56;
+            limbs[i] &= MASK;
+        }
+    }
+
+    fn reduce_wide(mut wide: [u128; 15]) -> Fe {
+        // 2^448 = 2^224 + 1 (mod p), and 2^224 is exactly four limbs.
+        for k in (8..15).rev() {
+            let overflow = wide[k];
+            wide[k - 8] += overflow;
+            wide[k - 4] += overflow;
+        }
+
+        let mut narrow = [0u128; 8];
+        narrow.copy_from_slice(&wide[..8]);
+        for _ in 0..2 {
+            for i in 0..7 {
+                narrow[i + 1] += narrow[i] >> 56;
+                narrow[i] &= u128::from(MASK);
+            }
+            let top = narrow[7] >> 56;
+            narrow[7] &= u128::from(MASK);
+            narrow[0] += top;
+            narrow[4] += top;
+        }
+
+        let mut limbs = [0u64; 8];
+        for i in 0..8 {
+            limbs[i] = narrow[i] as u64;
+        }
+        Fe::carry_limbs(&mut limbs);
+        Fe(limbs)
+    }
+
+    fn add(&self, other: &Fe) -> Fe {
+        let mut limbs = [0u64; 8];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = self.0[i] + other.0[i];
+        }
+        Fe::carry_limbs(&mut limbs);
+        Fe(limbs)
+    }
+
+    fn sub(&self, other: &Fe) -> Fe {
+        // Add 4p before subtracting so that no limb can underflow.
+        let four_p = [
+            (MASK << 2), (MASK << 2), (MASK << 2), (MASK << 2),
+            ((MASK - 1) << 2), (MASK << 2), (MASK << 2), (MASK << 2),
+        ];
+        let mut limbs = [0u64; 8];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = self.0[i] + four_p[i] - other.0[i];
+        }
+        Fe::carry_limbs(&mut limbs);
+        Fe(limbs)
+    }
+
+    fn mul(&self, other: &Fe) -> Fe {
+        let mut wide = [0u128; 15];
+        for i in 0..8 {
+            for j in 0..8 {
+                wide[i + j] += u128::from(self.0[i]) * u128::from(other.0[j]);
+            }
+        }
+        Fe::reduce_wide(wide)
+    }
+
+    fn square(&self) -> Fe {
+        self.mul(self)
+    }
+
+    fn mul_small(&self, small: u64) -> Fe {
+        let mut wide = [0u128; 15];
+        for (wide_limb, limb) in wide.iter_mut().zip(self.0.iter()) {
+            *wide_limb = u128::from(*limb) * u128::from(small);
+        }
+        Fe::reduce_wide(wide)
+    }
+
+    fn invert(&self) -> Fe {
+        // The exponent is public, so walking its bits doesn't leak anything.
+        let mut result = Fe::ONE;
+        for byte in P_MINUS_TWO.iter().rev() {
+            for bit in (0..8).rev() {
+                result = result.square();
+                if (byte >> bit) & 1 == 1 {
+                    result = result.mul(self);
+                }
+            }
+        }
+        result
+    }
+
+    fn conditional_swap(a: &mut Fe, b: &mut Fe, swap: u64) {
+        let mask = 0u64.wrapping_sub(swap);
+        for i in 0..8 {
+            let t = mask & (a.0[i] ^ b.0[i]);
+            a.0[i] ^= t;
+            b.0[i] ^= t;
+        }
+    }
+}
+
+/// Compute X448(scalar, u) as specified in section 5 of RFC 7748.
+pub fn x448(scalar: &[u8; 56], u: &[u8; 56]) -> [u8; 56] {
+    let mut k = *scalar;
+    k[0] &= 252;
+    k[55] |= 128;
+
+    let x_1 = Fe::from_bytes(u);
+    let mut x_2 = Fe::ONE;
+    let mut z_2 = Fe::ZERO;
+    let mut x_3 = x_1;
+    let mut z_3 = Fe::ONE;
+    let mut swap = 0u64;
+
+    for t in (0..448).rev() {
+        let k_t = u64::from((k[t / 8] >> (t % 8)) & 1);
+        swap ^= k_t;
+        Fe::conditional_swap(&mut x_2, &mut x_3, swap);
+        Fe::conditional_swap(&mut z_2, &mut z_3, swap);
+        swap = k_t;
+
+        let a = x_2.add(&z_2);
+        let aa = a.square();
+        let b = x_2.sub(&z_2);
+        let bb = b.square();
+        let e = aa.sub(&bb);
+        let c = x_3.add(&z_3);
+        let d = x_3.sub(&z_3);
+        let da = d.mul(&a);
+        let cb = c.mul(&b);
+        x_3 = da.add(&cb).square();
+        z_3 = x_1.mul(&da.sub(&cb).square());
+        x_2 = aa.mul(&bb);
+        z_2 = e.mul(&aa.add(&e.mul_small(A24)));
+    }
+
+    Fe::conditional_swap(&mut x_2, &mut x_3, swap);
+    Fe::conditional_swap(&mut z_2, &mut z_3, swap);
+
+    x_2.mul(&z_2.invert()).to_bytes()
+}
@@ -2,6 +2,7 @@ use std::mem;
 use super::CryptoResolver;
 use crate::params::{DHChoice, HashChoice, CipherChoice};
 use crate::types::{Random, Dh, Hash, Cipher};
+use crate::utils::secure_zero;
 use hacl_star::curve25519::{self, SecretKey, PublicKey};
 use hacl_star::sha2::{Sha256, Sha512};
 use hacl_star::chacha20poly1305;
@@ -109,6 +110,14 @@ impl Dh for Dh25519 {
 
 }
 
+impl Drop for Dh25519 {
+    fn drop(&mut self) {
+        if let Some(privkey) = self.privkey.as_mut() {
+            secure_zero(&mut privkey.0);
+        }
+    }
+}
+
 impl Cipher for CipherChaChaPoly {
     fn name(&self) -> &'static str {
         "ChaChaPoly"
@@ -154,6 +163,12 @@ impl Cipher for CipherChaChaPoly {
     }
 }
 
+impl Drop for CipherChaChaPoly {
+    fn drop(&mut self) {
+        secure_zero(&mut self.key);
+    }
+}
+
 impl Hash for HashSHA256 {
     fn block_len(&self) -> usize {
         Sha256::BLOCK_LENGTH
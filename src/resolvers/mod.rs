@@ -8,57 +8,85 @@
 /// A ring primitive resolver.
 #[cfg(feature = "ring-resolver")]      mod ring;
 
-use crate::params::{CipherChoice, DHChoice, HashChoice};
-use crate::types::{Cipher, Dh, Hash, Random};
+use crate::params::{CipherChoice, DHChoice, HashChoice, KemChoice};
+use crate::types::{Cipher, Dh, Hash, Kem, Random};
+#[cfg(not(feature = "std"))] use alloc::boxed::Box;
 
 #[cfg(feature = "default-resolver")]   pub use self::default::DefaultResolver;
 #[cfg(feature = "hacl-star-resolver")] pub use self::hacl_star::HaclStarResolver;
 #[cfg(feature = "ring-resolver")]      pub use self::ring::RingResolver;
 
 /// An object that resolves the providers of Noise crypto choices
-pub trait CryptoResolver {
+///
+/// The [`Builder`] asks its resolver for each primitive named by the `NoiseParams` it was
+/// given, so implementing this trait (and handing it to `Builder::with_resolver()`) is how
+/// your own implementations get plugged in. Wrap it in a [`FallbackResolver`] to only provide
+/// some of the primitives yourself.
+///
+/// Like the primitives it hands out, a resolver has to be `Send + Sync`, so that a
+/// [`BuilderTemplate`] holding one can be shared between the threads accepting connections.
+///
+/// [`Builder`]: ../struct.Builder.html
+/// [`BuilderTemplate`]: ../struct.BuilderTemplate.html
+/// [`FallbackResolver`]: struct.FallbackResolver.html
+pub trait CryptoResolver : Send + Sync {
     /// Provide an implementation of the Random trait or None if none available.
-    fn resolve_rng(&self) -> Option<Box<Random>>;
+    ///
+    /// This is the place to plug in a specific RNG, see the caveats on [`Random`].
+    ///
+    /// [`Random`]: ../types/trait.Random.html
+    fn resolve_rng(&self) -> Option<Box<dyn Random>>;
 
     /// Provide an implementation of the Dh trait for the given DHChoice or None if unavailable.
-    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<Dh>>;
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>>;
 
     /// Provide an implementation of the Hash trait for the given HashChoice or None if unavailable.
-    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<Hash>>;
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>>;
 
     /// Provide an implementation of the Cipher trait for the given CipherChoice or None if unavailable.
-    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<Cipher>>;
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>>;
+
+    /// Provide an implementation of the Kem trait for the given KemChoice or None if
+    /// unavailable. Only the `DefaultResolver` has one, with the opt-in `kyber1024` feature,
+    /// so without it a hybrid (`hfs`) handshake needs a resolver that overrides this.
+    fn resolve_kem(&self, _choice: &KemChoice) -> Option<Box<dyn Kem>> {
+        None
+    }
 }
 
 /// A helper struct that helps to opportunistically use one resolver, but
 /// can fallback to another if the first didn't have an implementation for
 /// a given primitive.
 pub struct FallbackResolver {
-    preferred: Box<CryptoResolver>,
-    fallback: Box<CryptoResolver>,
+    preferred: Box<dyn CryptoResolver>,
+    fallback: Box<dyn CryptoResolver>,
 }
 
 impl FallbackResolver {
     /// Create a new `FallbackResolver` that holds the primary and secondary resolver.
-    pub fn new(preferred: Box<CryptoResolver>, fallback: Box<CryptoResolver>) -> Self {
+    pub fn new(preferred: Box<dyn CryptoResolver>, fallback: Box<dyn CryptoResolver>) -> Self {
         Self { preferred, fallback }
     }
 }
 
 impl CryptoResolver for FallbackResolver {
-    fn resolve_rng(&self) -> Option<Box<Random>> {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
         self.preferred.resolve_rng().or_else(|| self.fallback.resolve_rng())
     }
 
-    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<Dh>> {
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
         self.preferred.resolve_dh(choice).or_else(|| self.fallback.resolve_dh(choice))
     }
 
-    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<Hash>> {
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
         self.preferred.resolve_hash(choice).or_else(|| self.fallback.resolve_hash(choice))
     }
 
-    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<Cipher>> {
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
         self.preferred.resolve_cipher(choice).or_else(|| self.fallback.resolve_cipher(choice))
     }
+
+    fn resolve_kem(&self, choice: &KemChoice) -> Option<Box<dyn Kem>> {
+        self.preferred.resolve_kem(choice).or_else(|| self.fallback.resolve_kem(choice))
+    }
 }
@@ -73,6 +73,15 @@ impl Cipher for CipherAESGCM {
         plaintext.len() + TAGLEN
     }
 
+    fn encrypt_in_place(&self, nonce: u64, authtext: &[u8], in_out: &mut [u8], plaintext_len: usize) -> usize {
+        let mut nonce_bytes = [0u8; 12];
+        BigEndian::write_u64(&mut nonce_bytes[4..], nonce);
+        let nonce = aead::Nonce::assume_unique_for_key(nonce_bytes);
+
+        aead::seal_in_place(&self.sealing, nonce, aead::Aad::from(authtext), &mut in_out[..plaintext_len+TAGLEN], 16).unwrap();
+        plaintext_len + TAGLEN
+    }
+
     fn decrypt(&self, nonce: u64, authtext: &[u8], ciphertext: &[u8], out: &mut [u8]) -> Result<usize, ()> {
         let mut nonce_bytes = [0u8; 12];
         BigEndian::write_u64(&mut nonce_bytes[4..], nonce);
@@ -94,6 +103,16 @@ impl Cipher for CipherAESGCM {
             Ok(out0.len())
         }
     }
+
+    fn decrypt_in_place(&self, nonce: u64, authtext: &[u8], in_out: &mut [u8]) -> Result<usize, ()> {
+        let mut nonce_bytes = [0u8; 12];
+        BigEndian::write_u64(&mut nonce_bytes[4..], nonce);
+        let nonce = aead::Nonce::assume_unique_for_key(nonce_bytes);
+
+        let len = aead::open_in_place(&self.opening, nonce, aead::Aad::from(authtext), 0, in_out).map_err(|_| ())?
+            .len();
+        Ok(len)
+    }
 }
 
 struct CipherChaChaPoly {
@@ -131,6 +150,15 @@ impl Cipher for CipherChaChaPoly {
         plaintext.len() + TAGLEN
     }
 
+    fn encrypt_in_place(&self, nonce: u64, authtext: &[u8], in_out: &mut [u8], plaintext_len: usize) -> usize {
+        let mut nonce_bytes = [0u8; 12];
+        LittleEndian::write_u64(&mut nonce_bytes[4..], nonce);
+        let nonce = aead::Nonce::assume_unique_for_key(nonce_bytes);
+
+        aead::seal_in_place(&self.sealing, nonce, aead::Aad::from(authtext), &mut in_out[..plaintext_len+TAGLEN], 16).unwrap();
+        plaintext_len + TAGLEN
+    }
+
     fn decrypt(&self, nonce: u64, authtext: &[u8], ciphertext: &[u8], out: &mut [u8]) -> Result<usize, ()> {
         let mut nonce_bytes = [0u8; 12];
         LittleEndian::write_u64(&mut nonce_bytes[4..], nonce);
@@ -152,6 +180,16 @@ impl Cipher for CipherChaChaPoly {
             Ok(out0.len())
         }
     }
+
+    fn decrypt_in_place(&self, nonce: u64, authtext: &[u8], in_out: &mut [u8]) -> Result<usize, ()> {
+        let mut nonce_bytes = [0u8; 12];
+        LittleEndian::write_u64(&mut nonce_bytes[4..], nonce);
+        let nonce = aead::Nonce::assume_unique_for_key(nonce_bytes);
+
+        let len = aead::open_in_place(&self.opening, nonce, aead::Aad::from(authtext), 0, in_out).map_err(|_| ())?
+            .len();
+        Ok(len)
+    }
 }
 struct HashSHA256 {
     context: digest::Context,
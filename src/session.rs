@@ -1,10 +1,16 @@
+use crate::constants::MAXMSGLEN;
 use crate::error::{Error, StateProblem};
+use byteorder::{ByteOrder, BigEndian};
+use subtle::ConstantTimeEq;
+use crate::cipherstate::CipherStates;
 use crate::handshakestate::HandshakeState;
-#[cfg(feature = "nightly")] use std::convert::{TryFrom, TryInto};
+use crate::params::{NoiseParams, Token};
+#[cfg(feature = "nightly")] use core::convert::{TryFrom, TryInto};
 #[allow(unused_imports)]
 #[cfg(not(feature = "nightly"))] use crate::utils::{TryFrom, TryInto};
 use crate::transportstate::TransportState;
 use crate::stateless_transportstate::StatelessTransportState;
+#[cfg(not(feature = "std"))] use alloc::vec::Vec;
 
 /// A state machine for the entire Noise session.
 ///
@@ -16,15 +22,29 @@ use crate::stateless_transportstate::StatelessTransportState;
 /// To transition away from the Handshake variant to a Transport or StatelessTransport,
 /// you first need to call [`Session::read_message`] and [`Session::write_message`] in a sequence corresponding to your handshaking pattern.
 /// 
+/// Once the handshake is finished, [`Session::read_message`] and [`Session::write_message`] carry
+/// on with transport messages by themselves, so calling `into_transport_mode()` is optional. It's
+/// still worth doing, since it frees the handshake state and is needed for rekeying and nonces.
+/// 
 /// See the `examples` directory.
 /// 
+/// # Ownership
+/// 
+/// A `Session` owns all of its state, including the boxed crypto primitives it got from the
+/// resolver, so it has no lifetime parameters and can live in a connection struct for as long
+/// as the connection does. Only the [`Builder`](struct.Builder.html) borrows its inputs, and
+/// it copies them into the session on `build_initiator()` / `build_responder()`.
+/// 
+/// Sessions are also `Send` and `Sync`, so they can be handed between threads, e.g. by a
+/// work-stealing runtime.
+/// 
 /// It is probable that `snow` will be more tighly integrated with I/O to automatically perform handshaking implied by the Noise pattern string in the future.
 /// 
 /// [`Session::read_message`]: struct.Session.html#method.read_message
 /// [`Session::write_message`]: struct.Session.html#method.write_message
 /// 
 // TODO: check up on memory usage, since this clippy warning seems like a legit perf issue.
-#[cfg_attr(feature = "cargo-clippy", allow(large_enum_variant))]
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum Session {
     /// A session in the handshake stage (the starting state).
@@ -37,6 +57,22 @@ pub enum Session {
     StatelessTransport(StatelessTransportState),
 }
 
+/// How `Session::write_message_padded()` pads a payload before encrypting it, so that the
+/// length of a transport message gives away less about the length of its payload.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Padding {
+    /// Pad every payload to exactly this many bytes, counting the two-byte length field.
+    /// Payloads that don't fit are refused.
+    Fixed(usize),
+
+    /// Pad payloads (plus the two-byte length field) up to the next power of two, capped at
+    /// the largest payload a transport message can carry.
+    PowerOfTwo,
+}
+
+/// The size of the length field at the start of a padded payload.
+const PADDING_LEN_FIELD: usize = 2;
+
 impl Session {
     /// This method will return `true` if the *previous* write payload was encrypted.
     ///
@@ -55,7 +91,31 @@ impl Session {
     /// ```
     pub fn was_write_payload_encrypted(&self) -> bool {
         match *self {
-            Session::Handshake(ref state)  => state.was_write_payload_encrypted(),
+            Session::Handshake(ref state)  => state.was_payload_encrypted(),
+            Session::Transport(_)          => true,
+            Session::StatelessTransport(_) => true,
+        }
+    }
+
+    /// This method will return `true` if the *previous* read payload was encrypted, and
+    /// `false` if it was only authenticated (or not even that, early in some patterns).
+    ///
+    /// See [Payload Security Properties](http://noiseprotocol.org/noise.html#payload-security-properties)
+    /// for more information on the specific properties of your chosen handshake pattern.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// let mut session = Builder::new("Noise_NN_25519_AESGCM_SHA256".parse()?)
+    ///     .build_responder()?;
+    ///
+    /// // read the first message...
+    ///
+    /// assert!(!session.was_read_payload_encrypted());
+    /// ```
+    pub fn was_read_payload_encrypted(&self) -> bool {
+        match *self {
+            Session::Handshake(ref state)  => state.was_payload_encrypted(),
             Session::Transport(_)          => true,
             Session::StatelessTransport(_) => true,
         }
@@ -82,6 +142,117 @@ impl Session {
         }
     }
 
+    /// True if the next handshake message is ours to write, and false if we're expecting to read
+    /// one. This function also returns a vacuous true if already in transport mode.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// let mut session = Builder::new("Noise_NN_25519_AESGCM_SHA256".parse()?)
+    ///     .build_initiator()?;
+    ///
+    /// while !session.is_handshake_finished() {
+    ///     if session.is_my_turn() {
+    ///         // write_message...
+    ///     } else {
+    ///         // read_message...
+    ///     }
+    /// }
+    /// ```
+    pub fn is_my_turn(&self) -> bool {
+        match *self {
+            Session::Handshake(ref state)  => state.is_my_turn(),
+            Session::Transport(_)          => true,
+            Session::StatelessTransport(_) => true,
+        }
+    }
+
+    /// The number of handshake messages still to be written or read, in either direction,
+    /// before the handshake is finished, e.g. for progress reporting. A result of `1` means
+    /// the next message completes the handshake, and `0` that it's finished (which is always
+    /// the case in transport mode).
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// let session = Builder::new("Noise_XX_25519_AESGCM_SHA256".parse()?)
+    ///     .local_private_key(&key)
+    ///     .build_initiator()?;
+    ///
+    /// assert_eq!(session.messages_remaining(), 3);
+    /// ```
+    pub fn messages_remaining(&self) -> usize {
+        match *self {
+            Session::Handshake(ref state)  => state.messages_remaining(),
+            Session::Transport(_)          => 0,
+            Session::StatelessTransport(_) => 0,
+        }
+    }
+
+    /// The tokens the next handshake message (in either direction) will process, so it can
+    /// be told ahead of time, say, whether a static key is about to go over the wire. Tokens
+    /// already handled by `write_message_token()` / `read_message_token()` are left out, and
+    /// the slice is empty once the handshake is finished.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// let session = Builder::new("Noise_XX_25519_AESGCM_SHA256".parse()?)
+    ///     .local_private_key(&key)
+    ///     .build_initiator()?;
+    ///
+    /// assert_eq!(session.next_message_tokens(), &[Token::E]);
+    /// ```
+    pub fn next_message_tokens(&self) -> &[Token] {
+        match *self {
+            Session::Handshake(ref state)  => state.next_message_tokens(),
+            Session::Transport(_)          => &[],
+            Session::StatelessTransport(_) => &[],
+        }
+    }
+
+    /// The number of bytes the next message (in either direction) will take on top of its
+    /// payload: public keys, and authentication tags for whatever gets encrypted. For
+    /// transport messages, that's just the tag.
+    ///
+    /// This is useful for delimiting messages on a byte stream when the payload length is
+    /// known by other means.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// let mut session = Builder::new("Noise_NN_25519_AESGCM_SHA256".parse()?)
+    ///     .build_initiator()?;
+    ///
+    /// assert_eq!(session.get_next_message_overhead(), 32);
+    /// ```
+    pub fn get_next_message_overhead(&self) -> usize {
+        match *self {
+            Session::Handshake(ref state)          => state.next_message_overhead(),
+            Session::Transport(ref state)          => state.message_overhead(),
+            Session::StatelessTransport(ref state) => state.message_overhead(),
+        }
+    }
+
+    /// The size of the payload that reading the next message of `message_len` bytes will
+    /// produce, without decrypting anything, so the `payload` buffer can be sized exactly.
+    /// This takes into account the public keys and tags of the next handshake message, or
+    /// just the tag of a transport message.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if `message_len` is too short to be a valid message.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// let mut payload = vec![0u8; session.get_payload_len(message.len())?];
+    /// session.read_message(&message, &mut payload)?;
+    /// ```
+    pub fn get_payload_len(&self, message_len: usize) -> Result<usize, Error> {
+        message_len.checked_sub(self.get_next_message_overhead()).ok_or(Error::Input)
+    }
+
     /// Will report if the session has the initiator role (i.e. was built with [`Builder.build_initiator()`]).
     ///
     /// [`Builder.build_initiator()`]: struct.Builder.html#method.build_initiator
@@ -94,26 +265,225 @@ impl Session {
     }
 
     /// Construct a message from `payload` (and pending handshake tokens if in handshake state),
-    /// and writes it to the `output` buffer.
+    /// and writes it to the `output` buffer. After the last handshake message, this writes
+    /// transport messages even before `into_transport_mode()` is called.
     ///
     /// Returns the size of the written payload.
     ///
     /// # Errors
     ///
     /// Will result in `Error::Input` if the size of the output exceeds the max message
-    /// length in the Noise Protocol (65535 bytes).
-    #[must_use]
+    /// length in the Noise Protocol (65535 bytes), or if the `output` buffer is too small
+    /// to hold the message. Nothing is written to `output` in either case.
+    ///
+    /// Will result in `StateProblem::Exhausted` if the outgoing nonce has reached its
+    /// reserved maximum value, after which the session can't be used to send anymore.
     pub fn write_message(&mut self, payload: &[u8], output: &mut [u8]) -> Result<usize, Error> {
         match *self {
+            Session::Handshake(ref mut state) if state.is_finished() => state.write_transport_message(payload, output),
             Session::Handshake(ref mut state) => state.write_handshake_message(payload, output),
             Session::Transport(ref mut state) => state.write_transport_message(payload, output),
             Session::StatelessTransport(_)    => bail!(StateProblem::StatelessTransportMode),
         }
     }
 
+    /// Like `write_message()`, but writes into a `Vec` that is cleared first and grown only as
+    /// needed, so a long-lived session can keep reusing the same allocation.
+    ///
+    /// A transport message is never longer than the max message length in the Noise Protocol
+    /// (65535 bytes), and carries at most 65535 minus the tag length (16 bytes for the
+    /// bundled ciphers) of payload, so a `Vec::with_capacity(65535)` never needs to grow.
+    ///
+    /// # Errors
+    ///
+    /// Fails the same ways as `write_message()`, leaving `message` empty.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// let mut message = Vec::with_capacity(65535);
+    /// for payload in payloads {
+    ///     session.write_message_to_vec(payload, &mut message)?;
+    ///     socket.send(&message)?;
+    /// }
+    /// ```
+    pub fn write_message_to_vec(&mut self, payload: &[u8], message: &mut Vec<u8>) -> Result<(), Error> {
+        message.clear();
+        message.resize(payload.len() + self.get_next_message_overhead(), 0);
+        match self.write_message(payload, message) {
+            Ok(len) => {
+                message.truncate(len);
+                Ok(())
+            },
+            Err(err) => {
+                message.clear();
+                Err(err)
+            }
+        }
+    }
+
+    /// Like `read_message()`, but reads into a `Vec` that is cleared first and grown only as
+    /// needed, so a long-lived session can keep reusing the same allocation. The payload is
+    /// never longer than `input`, so that's all the capacity it will ever need.
+    ///
+    /// # Errors
+    ///
+    /// Fails the same ways as `read_message()`, leaving `payload` empty.
+    pub fn read_message_to_vec(&mut self, input: &[u8], payload: &mut Vec<u8>) -> Result<(), Error> {
+        payload.clear();
+        payload.resize(input.len(), 0);
+        match self.read_message(input, payload) {
+            Ok(len) => {
+                payload.truncate(len);
+                Ok(())
+            },
+            Err(err) => {
+                payload.clear();
+                Err(err)
+            }
+        }
+    }
+
+    /// Like `write_message()`, but prefixes the message with its length as a 2-byte big-endian
+    /// integer, which is the usual framing for running Noise over a stream like TCP.
+    ///
+    /// Returns the size of the whole frame, including the prefix.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if the message would exceed the max message length in
+    /// the Noise Protocol (65535 bytes), or if `output` can't hold the whole frame.
+    pub fn write_framed(&mut self, payload: &[u8], output: &mut [u8]) -> Result<usize, Error> {
+        if output.len() < 2 {
+            bail!(Error::Input);
+        }
+        let len = self.write_message(payload, &mut output[2..])?;
+        BigEndian::write_u16(&mut output[..2], len as u16);
+        Ok(len + 2)
+    }
+
+    /// Reads a frame written by `write_framed()` from the start of `input`. Anything after the
+    /// frame is left alone, so this can be called on a buffer of bytes read off a stream.
+    ///
+    /// Returns the size of the whole frame that was consumed from `input`, and the size of the
+    /// payload written to `payload`.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if `input` doesn't hold a complete frame yet.
+    ///
+    /// Otherwise fails the same ways as `read_message()`.
+    pub fn read_framed(&mut self, input: &[u8], payload: &mut [u8]) -> Result<(usize, usize), Error> {
+        if input.len() < 2 {
+            bail!(Error::Input);
+        }
+        let frame_len = 2 + BigEndian::read_u16(&input[..2]) as usize;
+        if input.len() < frame_len {
+            bail!(Error::Input);
+        }
+        let len = self.read_message(&input[2..frame_len], payload)?;
+        Ok((frame_len, len))
+    }
+
+    /// Encrypt a `payload` of any length into a sequence of transport messages, written back to
+    /// back into `output`. Every message but the last is exactly the max message length of the
+    /// Noise Protocol (65535 bytes), which is how `read_message_chunked()` splits them again.
+    ///
+    /// Returns the combined size of the messages.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if the handshake isn't finished yet.
+    ///
+    /// Will result in `Error::Input` if `output` is too small to hold all of the messages, in
+    /// which case nothing is written.
+    pub fn write_message_chunked(&mut self, payload: &[u8], output: &mut [u8]) -> Result<usize, Error> {
+        if !self.is_handshake_finished() {
+            bail!(StateProblem::HandshakeNotFinished);
+        }
+
+        let overhead = self.get_next_message_overhead();
+        let chunk_len = MAXMSGLEN - overhead;
+        let chunks = if payload.is_empty() { 1 } else { payload.len().div_ceil(chunk_len) };
+        if payload.len() + chunks * overhead > output.len() {
+            bail!(Error::Input);
+        }
+
+        let mut written = 0;
+        for i in 0..chunks {
+            let chunk = &payload[i * chunk_len..payload.len().min((i + 1) * chunk_len)];
+            written += self.write_message(chunk, &mut output[written..])?;
+        }
+        Ok(written)
+    }
+
+    /// Decrypt the sequence of transport messages written by `write_message_chunked()`, which
+    /// must make up all of `input`, reassembling the payload into `payload`.
+    ///
+    /// Returns the size of the reassembled payload.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if the handshake isn't finished yet.
+    ///
+    /// Will result in `Error::Input` if `input` is empty, and otherwise fails the same ways
+    /// as `read_message()` for any of the messages.
+    pub fn read_message_chunked(&mut self, input: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
+        if !self.is_handshake_finished() {
+            bail!(StateProblem::HandshakeNotFinished);
+        } else if input.is_empty() {
+            bail!(Error::Input);
+        }
+
+        let mut read = 0;
+        for message in input.chunks(MAXMSGLEN) {
+            read += self.read_message(message, &mut payload[read..])?;
+        }
+        Ok(read)
+    }
+
+    /// Like `write_message()` for transport messages, but also authenticates `ad` as associated
+    /// data, binding application headers (a sequence number, a routing header, ...) sent in the
+    /// clear to the encrypted payload. `ad` isn't included in the message, and the receiver has
+    /// to pass the same bytes to `read_message_with_ad()`.
+    ///
+    /// With an empty `ad` this is the same as `write_message()`.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if the handshake isn't finished, or in stateless
+    /// transport mode. Otherwise fails just like `write_message()`.
+    pub fn write_message_with_ad(&mut self, ad: &[u8], payload: &[u8], output: &mut [u8]) -> Result<usize, Error> {
+        match *self {
+            Session::Handshake(ref mut state) => state.write_transport_message_with_ad(ad, payload, output),
+            Session::Transport(ref mut state) => state.write_transport_message_with_ad(ad, payload, output),
+            Session::StatelessTransport(_)    => bail!(StateProblem::StatelessTransportMode),
+        }
+    }
+
+    /// Like `read_message()` for transport messages, but also checks `ad` as associated data,
+    /// which has to match what the sender passed to `write_message_with_ad()`.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Decrypt` if `ad` doesn't match, and in `Error::State` if the
+    /// handshake isn't finished, or in stateless transport mode. Otherwise fails just like
+    /// `read_message()`.
+    pub fn read_message_with_ad(&mut self, ad: &[u8], input: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
+        match *self {
+            Session::Handshake(ref mut state) => state.read_transport_message_with_ad(ad, input, payload),
+            Session::Transport(ref mut state) => state.read_transport_message_with_ad(ad, input, payload),
+            Session::StatelessTransport(_)    => bail!(StateProblem::StatelessTransportMode),
+        }
+    }
+
     /// Construct a message from `payload` with an explicitly provided nonce and write it to the
     /// `output` buffer.
     ///
+    /// The nonce isn't included in the message, so it's up to the application to send it
+    /// alongside. Since no counter is kept, the application is also responsible for never
+    /// reusing a nonce, and for rejecting replayed messages on the receiving side.
+    ///
     /// Returns the size of the written payload.
     ///
     /// # Errors
@@ -121,8 +491,8 @@ impl Session {
     /// Will result in `Error::Input` if the size of the output exceeds the max message
     /// length in the Noise Protocol (65535 bytes).
     ///
-    /// Will result in `Error::StateProblem` if not in stateless transport mode.
-    #[must_use]
+    /// Will result in `Error::StateProblem` if not in stateless transport mode, or if `nonce`
+    /// is the reserved value `u64::MAX`.
     pub fn write_message_with_nonce(&self, nonce: u64, payload: &[u8], output: &mut [u8]) -> Result<usize, Error> {
         match *self {
             Session::StatelessTransport(ref state) => state.write_transport_message(nonce, payload, output),
@@ -130,39 +500,223 @@ impl Session {
         }
     }
 
-    /// Reads a noise message from `input`
+    /// Lower-level handshake API: writes just the next token of the current handshake message
+    /// (an ephemeral or static public key, or nothing for DH and PSK tokens) to `output`.
+    ///
+    /// Returns the number of bytes written, or `None` once every token of the message has
+    /// been written. The rest of the message, the payload, is then written with
+    /// `write_message()`, which also processes any tokens that weren't stepped through.
+    ///
+    /// This is for experimenting with extensions that interleave their own data between
+    /// tokens. Anything interleaved this way isn't part of the handshake transcript, so the
+    /// handshake doesn't authenticate it, and the peer needs to step through the tokens with
+    /// `read_message_token()` to skip over it.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if `output` is too small to hold the token.
+    ///
+    /// Will result in `Error::State` if it's not our turn to write, or the handshake is
+    /// finished.
+    pub fn write_message_token(&mut self, output: &mut [u8]) -> Result<Option<usize>, Error> {
+        match *self {
+            Session::Handshake(ref mut state) => state.write_handshake_token(output),
+            _                                 => bail!(StateProblem::HandshakeAlreadyFinished),
+        }
+    }
+
+    /// Lower-level handshake API: reads just the next token of the current handshake message
+    /// from the start of `input`. The counterpart of `write_message_token()`.
+    ///
+    /// Returns the number of bytes consumed from `input`, or `None` once every token of the
+    /// message has been read, at which point the payload is read with `read_message()`.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if `input` is too short to hold the token, or
+    /// `Error::Decrypt` if an encrypted static key can't be decrypted.
+    ///
+    /// Will result in `Error::State` if it's not our turn to read, or the handshake is
+    /// finished.
+    pub fn read_message_token(&mut self, input: &[u8]) -> Result<Option<usize>, Error> {
+        match *self {
+            Session::Handshake(ref mut state) => state.read_handshake_token(input),
+            _                                 => bail!(StateProblem::HandshakeAlreadyFinished),
+        }
+    }
+
+    /// Reads a noise message from `input`. After the last handshake message, this reads
+    /// transport messages even before `into_transport_mode()` is called.
     ///
     /// Returns the size of the payload written to `payload`.
     ///
     /// # Errors
     ///
     /// Will result in `Error::Decrypt` if the contents couldn't be decrypted and/or the
-    /// authentication tag didn't verify.
+    /// authentication tag didn't verify. That's the same error whether it was an encrypted
+    /// static key or the payload that failed, tags are checked in constant time, and the rest
+    /// of a message is still processed after a static key that doesn't decrypt, so neither
+    /// the result nor its timing tells a tampering peer which part of the message gave it
+    /// away. A failed read leaves the session as it was before the message, so the genuine
+    /// message can still be read after a tampered copy of it.
+    ///
+    /// Will result in `StateProblem::Exhausted` if the incoming nonce has reached its
+    /// reserved maximum value.
+    ///
+    /// Will result in `Error::Input` if `input` is longer than the 65535 bytes a Noise message
+    /// can be.
     ///
-    /// # Panics
+    /// Will result in `Error::BufferTooSmall` if `payload` can't hold the decrypted payload. The
+    /// error carries the length needed and the session is left as it was, so the same `input`
+    /// can be read again into a bigger buffer.
     ///
-    /// This function will panic if there is no key, or if there is a nonce overflow.
-    #[must_use]
+    /// Every malformed `input` ends in an `Err`, never a panic, whatever the size of `payload`,
+    /// so this is a safe entry point for a fuzzer to throw arbitrary bytes at.
     pub fn read_message(&mut self, input: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
         match *self {
+            Session::Handshake(ref mut state) if state.is_finished() => state.read_transport_message(input, payload),
             Session::Handshake(ref mut state) => state.read_handshake_message(input, payload),
             Session::Transport(ref mut state) => state.read_transport_message(input, payload),
             Session::StatelessTransport(_)    => bail!(StateProblem::StatelessTransportMode),
         }
     }
 
-    /// Construct a message from `payload` (and pending handshake tokens if in handshake state),
-    /// and writes it to the `output` buffer.
+    /// Encrypts the first `payload_len` bytes of `message` into a transport message in place,
+    /// so the payload doesn't need to be copied from a buffer of its own. `message` needs room
+    /// for the authentication tag after the payload (see `get_next_message_overhead()`).
     ///
-    /// Returns the size of the written payload.
+    /// Returns the size of the message written to `message`.
     ///
     /// # Errors
     ///
-    /// Will result in `Error::Input` if the size of the output exceeds the max message
-    /// length in the Noise Protocol (65535 bytes).
+    /// Will result in `Error::State` if the handshake isn't finished yet, or if in stateless
+    /// transport mode.
+    ///
+    /// Will result in `Error::Input` if `message` doesn't have room for the tag, or if the
+    /// message would exceed the max message length in the Noise Protocol (65535 bytes).
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// let payload_len = file.read(&mut buf[..65535 - 16])?;
+    /// let len = session.write_message_in_place(&mut buf, payload_len)?;
+    /// socket.send(&buf[..len])?;
+    /// ```
+    pub fn write_message_in_place(&mut self, message: &mut [u8], payload_len: usize) -> Result<usize, Error> {
+        match *self {
+            Session::Handshake(ref mut state) => state.write_transport_message_in_place(message, payload_len),
+            Session::Transport(ref mut state) => state.write_transport_message_in_place(message, payload_len),
+            Session::StatelessTransport(_)    => bail!(StateProblem::StatelessTransportMode),
+        }
+    }
+
+    /// Decrypts the transport message in `message` in place, so the payload doesn't need a
+    /// buffer of its own. The payload is left at the start of `message`, and whatever follows
+    /// it (the space the authentication tag took up) is unspecified.
+    ///
+    /// Returns the size of the payload.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if the handshake isn't finished yet, or if in stateless
+    /// transport mode.
+    ///
+    /// Otherwise fails the same ways as `read_message()` does for transport messages.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// let len = socket.recv(&mut buf)?;
+    /// let payload_len = session.read_message_in_place(&mut buf[..len])?;
+    /// handle(&buf[..payload_len]);
+    /// ```
+    pub fn read_message_in_place(&mut self, message: &mut [u8]) -> Result<usize, Error> {
+        match *self {
+            Session::Handshake(ref mut state) => state.read_transport_message_in_place(message),
+            Session::Transport(ref mut state) => state.read_transport_message_in_place(message),
+            Session::StatelessTransport(_)    => bail!(StateProblem::StatelessTransportMode),
+        }
+    }
+
+    /// Like `write_message()` for transport messages, but pads `payload` as described by
+    /// `padding` first. The payload's real length goes in a two-byte field ahead of it, and
+    /// both the length and the zero padding are encrypted and authenticated along with it.
+    /// Read messages like this with `read_message_padded()`.
+    ///
+    /// Returns the size of the message written to `output`.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if the handshake isn't finished yet, or if in stateless
+    /// transport mode.
+    ///
+    /// Will result in `Error::Input` if `payload` doesn't fit in the padded size, or if
+    /// `output` isn't big enough for the padded message.
+    pub fn write_message_padded(&mut self, payload: &[u8], padding: Padding, output: &mut [u8]) -> Result<usize, Error> {
+        if !self.is_handshake_finished() {
+            bail!(StateProblem::HandshakeNotFinished);
+        }
+        let max_len = MAXMSGLEN - self.get_next_message_overhead();
+        let unpadded_len = PADDING_LEN_FIELD + payload.len();
+        let padded_len = match padding {
+            Padding::Fixed(len)  => len,
+            Padding::PowerOfTwo  => core::cmp::min(unpadded_len.next_power_of_two(), max_len),
+        };
+        if unpadded_len > padded_len || padded_len > max_len || padded_len > output.len() {
+            bail!(Error::Input);
+        }
+
+        BigEndian::write_u16(&mut output[..PADDING_LEN_FIELD], payload.len() as u16);
+        output[PADDING_LEN_FIELD..unpadded_len].copy_from_slice(payload);
+        for byte in &mut output[unpadded_len..padded_len] {
+            *byte = 0;
+        }
+        self.write_message_in_place(output, padded_len)
+    }
+
+    /// Reads a transport message written with `write_message_padded()`, stripping the
+    /// padding. `payload` needs to be big enough for the padded payload, that is the message
+    /// minus the authentication tag, but only the real payload is left at its start.
+    ///
+    /// Returns the size of the real payload.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if the decrypted length field is inconsistent with the
+    /// message. Otherwise fails the same ways as `read_message()` does for transport messages.
+    pub fn read_message_padded(&mut self, input: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
+        if !self.is_handshake_finished() {
+            bail!(StateProblem::HandshakeNotFinished);
+        }
+        let padded_len = self.read_message(input, payload)?;
+        if padded_len < PADDING_LEN_FIELD {
+            bail!(Error::Input);
+        }
+        let len = BigEndian::read_u16(&payload[..PADDING_LEN_FIELD]) as usize;
+        if len > padded_len - PADDING_LEN_FIELD {
+            bail!(Error::Input);
+        }
+        payload.copy_within(PADDING_LEN_FIELD..PADDING_LEN_FIELD + len, 0);
+        Ok(len)
+    }
+
+    /// Reads a noise message from `input` that was encrypted with the explicitly provided nonce.
+    ///
+    /// Messages can be read in any order, so this is suitable for transports that reorder or drop
+    /// packets. Nothing stops the same message from being read twice, though: replay protection
+    /// (e.g. a sliding window of seen nonces) is the application's responsibility.
+    ///
+    /// Returns the size of the payload written to `payload`.
+    ///
+    /// # Errors
     ///
-    /// Will result in `Error::StateProblem` if not in stateless transport mode.
-    #[must_use]
+    /// Will result in `Error::Decrypt` if the contents couldn't be decrypted and/or the
+    /// authentication tag didn't verify.
+    ///
+    /// Will result in `Error::StateProblem` if not in stateless transport mode, or if `nonce`
+    /// is the reserved value `u64::MAX`.
+    ///
+    /// Will result in `Error::BufferTooSmall` if `payload` can't hold the decrypted payload.
     pub fn read_message_with_nonce(&self, nonce: u64, input: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
         match *self {
             Session::StatelessTransport(ref state) => state.read_transport_message(nonce, input, payload),
@@ -177,19 +731,13 @@ impl Session {
     ///
     /// # Errors
     ///
-    /// Will result in `Error::State` if not in transport mode.
-    #[must_use]
+    /// Will result in `Error::State` if not in transport mode, or if this is the responder of
+    /// a one-way pattern, which never sends.
     pub fn rekey_outgoing(&mut self) -> Result<(), Error> {
         match *self {
             Session::Handshake(_) => bail!(StateProblem::HandshakeNotFinished),
-            Session::Transport(ref mut state) => {
-                state.rekey_outgoing();
-                Ok(())
-            },
-            Session::StatelessTransport(ref mut state) => {
-                state.rekey_outgoing();
-                Ok(())
-            },
+            Session::Transport(ref mut state)          => state.rekey_outgoing(),
+            Session::StatelessTransport(ref mut state) => state.rekey_outgoing(),
         }
     }
 
@@ -200,19 +748,13 @@ impl Session {
     ///
     /// # Errors
     ///
-    /// Will result in `Error::State` if not in transport mode.
-    #[must_use]
+    /// Will result in `Error::State` if not in transport mode, or if this is the initiator of
+    /// a one-way pattern, which never receives.
     pub fn rekey_incoming(&mut self) -> Result<(), Error> {
         match *self {
             Session::Handshake(_) => bail!(StateProblem::HandshakeNotFinished),
-            Session::Transport(ref mut state) => {
-                state.rekey_incoming();
-                Ok(())
-            },
-            Session::StatelessTransport(ref mut state) => {
-                state.rekey_incoming();
-                Ok(())
-            },
+            Session::Transport(ref mut state)          => state.rekey_incoming(),
+            Session::StatelessTransport(ref mut state) => state.rekey_incoming(),
         }
     }
 
@@ -220,27 +762,27 @@ impl Session {
     ///
     /// # Errors
     ///
-    /// Will result in `Error::State` if not in transport mode.
-    #[must_use]
+    /// Will result in `Error::State` if not in transport mode, or if given a responder key
+    /// for a one-way pattern, in which case neither key is changed.
     pub fn rekey_manually(&mut self, initiator: Option<&[u8]>, responder: Option<&[u8]>) -> Result<(), Error> {
         match *self {
             Session::Handshake(_) => bail!(StateProblem::HandshakeNotFinished),
             Session::Transport(ref mut state) => {
+                if let Some(key) = responder {
+                    state.rekey_responder_manually(key)?;
+                }
                 if let Some(key) = initiator {
                     state.rekey_initiator_manually(key);
                 }
-                if let Some(key) = responder {
-                    state.rekey_responder_manually(key);
-                }
                 Ok(())
             },
             Session::StatelessTransport(ref mut state) => {
+                if let Some(key) = responder {
+                    state.rekey_responder_manually(key)?;
+                }
                 if let Some(key) = initiator {
                     state.rekey_initiator_manually(key);
                 }
-                if let Some(key) = responder {
-                    state.rekey_responder_manually(key);
-                }
                 Ok(())
             },
         }
@@ -275,6 +817,10 @@ impl Session {
     /// Get the remote static key that was possibly encrypted in the first payload.
     ///
     /// Returns a slice of length `Dh.pub_len()` (i.e. DHLEN for the chosen DH function).
+    ///
+    /// This is available as soon as the remote static key has been received (or if it was
+    /// provided up front), including after transitioning into transport mode, which makes it
+    /// possible to reject unknown peers mid-handshake.
     pub fn get_remote_static(&self) -> Option<&[u8]> {
         match *self {
             Session::Handshake(ref state)          => state.get_remote_static(),
@@ -283,13 +829,254 @@ impl Session {
         }
     }
 
+    /// Get the full name of the Noise protocol this session runs, e.g.
+    /// `Noise_XX_25519_ChaChaPoly_BLAKE2s`. This is the name that gets hashed into the
+    /// handshake, so two peers that disagree on it can never complete one.
+    pub fn get_protocol_name(&self) -> &str {
+        match *self {
+            Session::Handshake(ref state)          => state.get_protocol_name(),
+            Session::Transport(ref state)          => state.get_protocol_name(),
+            Session::StatelessTransport(ref state) => state.get_protocol_name(),
+        }
+    }
+
+    /// Abandon the session, wiping its key material immediately instead of whenever it
+    /// happens to be dropped. Useful when a handshake fails halfway, or a peer disconnects.
+    ///
+    /// Any PSKs, the chaining key, the handshake hash, the ephemeral private key and the
+    /// cipher keys are all wiped, as long as the crypto implementations in use wipe their own
+    /// secrets on drop (the bundled ones do).
+    pub fn abort(self) {
+        if let Session::Handshake(state) = self {
+            state.abort();
+        }
+    }
+
+    /// Give up the cipher states for `BuilderTemplate` to reuse, dropping everything else.
+    pub(crate) fn into_cipherstates(self) -> Option<CipherStates> {
+        match self {
+            Session::Handshake(state)        => Some(state.into_transport_parts().0),
+            Session::Transport(state)        => Some(state.cipherstates),
+            Session::StatelessTransport(_)   => None,
+        }
+    }
+
+    /// **Dangerous:** write the keys and nonces of a transport session to `out` **in the
+    /// clear**, so it can be resumed elsewhere with `Builder::import_transport()`, e.g. after a
+    /// connection migration or a graceful restart. Anyone who gets hold of the exported bytes
+    /// can read and forge every later message of the session, so they need to be protected
+    /// at least as well as a private key, and wiped as soon as they've been imported. Stop
+    /// using this session once it has been exported, since reusing a nonce on both copies
+    /// breaks the cipher.
+    ///
+    /// The handshake hash, the chaining key behind `export_secret()` and the remote static
+    /// key are carried along, the remote ephemeral key isn't.
+    ///
+    /// Returns the number of bytes written.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if not in transport mode, or if a cipher has been rekeyed
+    /// with `rekey_outgoing()` / `rekey_incoming()`, since its new key isn't known then.
+    ///
+    /// Will result in `Error::Input` if `out` is too small.
+    #[cfg(feature = "risky-key-export")]
+    pub fn export_transport(&self, out: &mut [u8]) -> Result<usize, Error> {
+        match *self {
+            Session::Handshake(_)          => bail!(StateProblem::HandshakeNotFinished),
+            Session::Transport(ref state)  => state.export(out),
+            Session::StatelessTransport(_) => bail!(StateProblem::StatelessTransportMode),
+        }
+    }
+
+    /// Get copies of the raw transport keys (initiator->responder, then responder->initiator),
+    /// to drive an external AEAD with its own record framing and nonces, as a DTLS-style record
+    /// layer would. This is only available with the `risky-key-export` feature.
+    ///
+    /// # Security
+    ///
+    /// Once the keys leave the session, snow can't protect them any more. They aren't zeroed
+    /// when the session is dropped, and anyone who gets hold of them can decrypt (and forge)
+    /// all traffic under them, so forward secrecy only holds if the caller wipes them as soon
+    /// as they're done. The external AEAD must also never reuse a nonce with either key, and
+    /// messages built with it can't be read by this session, since the nonces won't line up.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if not in transport mode, or if a cipher has been rekeyed
+    /// with `rekey_outgoing()` / `rekey_incoming()`, since its new key isn't known then.
+    #[cfg(feature = "risky-key-export")]
+    pub fn dangerously_get_raw_split(&self) -> Result<([u8; 32], [u8; 32]), Error> {
+        match *self {
+            Session::Handshake(_)          => bail!(StateProblem::HandshakeNotFinished),
+            Session::Transport(ref state)  => state.dangerously_get_raw_split(),
+            Session::StatelessTransport(_) => bail!(StateProblem::StatelessTransportMode),
+        }
+    }
+
+    /// Get the remote party's ephemeral public key, if it has been received yet.
+    ///
+    /// Unlike the remote static key, this is never known up front (except in a fallback
+    /// handshake, where it's the pre-message), and is unique to this session, so it's handy
+    /// for logging or as a key for anti-replay bookkeeping.
+    pub fn get_remote_ephemeral(&self) -> Option<&[u8]> {
+        match *self {
+            Session::Handshake(ref state)          => state.get_remote_ephemeral(),
+            Session::Transport(ref state)          => state.get_remote_ephemeral(),
+            Session::StatelessTransport(ref state) => state.get_remote_ephemeral(),
+        }
+    }
+
+    /// Get the remote party's ephemeral public key from `input` without reading it, if the
+    /// next handshake message we're due to read starts with one.
+    ///
+    /// This is meant for anti-replay on the responder: a replayed first message carries an
+    /// ephemeral key that's been seen before, and peeking lets it be turned away before any
+    /// DH work is done. It's cooperative, not automatic. Snow doesn't remember anything
+    /// across sessions, so keeping a (short-lived) cache of seen keys and rejecting
+    /// duplicates is up to the application. Ephemeral keys go over the wire in the clear,
+    /// so the result is unauthenticated until the message has been read.
+    ///
+    /// Returns `None` when it's our turn to write, the next message doesn't start with an
+    /// `e` token, `input` is too short to hold a key, or the handshake is finished.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// if let Some(re) = responder.peek_remote_ephemeral(&message) {
+    ///     if !seen.insert(re.to_vec()) {
+    ///         return Err(Replayed);
+    ///     }
+    /// }
+    /// responder.read_message(&message, &mut payload)?;
+    /// ```
+    pub fn peek_remote_ephemeral<'a>(&self, input: &'a [u8]) -> Option<&'a [u8]> {
+        match *self {
+            Session::Handshake(ref state) => state.peek_remote_ephemeral(input),
+            _                             => None,
+        }
+    }
+
     /// Get the handshake hash.
     ///
     /// Returns a slice of length `Hasher.hash_len()` (i.e. HASHLEN for the chosen Hash function).
+    ///
+    /// During the handshake this is the running transcript hash as of the last message
+    /// written or read, so it can be snapshotted after any step (e.g. to check for downgrades
+    /// against an earlier connection). The hash is only suitable for channel binding once the
+    /// handshake is finished, after which it remains available in transport mode.
     pub fn get_handshake_hash(&self) -> Result<&[u8], Error> {
         match *self {
-            Session::Handshake(ref state) => Ok(state.get_handshake_hash()),
-            _                             => bail!(StateProblem::HandshakeAlreadyFinished),
+            Session::Handshake(ref state)          => Ok(state.get_handshake_hash()),
+            Session::Transport(ref state)          => Ok(state.get_handshake_hash()),
+            Session::StatelessTransport(ref state) => Ok(state.get_handshake_hash()),
+        }
+    }
+
+    /// Compare the current handshake hash against `expected` (e.g. a snapshot taken from
+    /// `get_handshake_hash()` at the same step of another handshake), in constant time.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// // ... write the first handshake message ...
+    ///
+    /// if !session.handshake_hash_matches(&expected_transcript) {
+    ///     // the peer saw a different first message, bail
+    /// }
+    /// ```
+    pub fn handshake_hash_matches(&self, expected: &[u8]) -> bool {
+        let hash = match *self {
+            Session::Handshake(ref state)          => state.get_handshake_hash(),
+            Session::Transport(ref state)          => state.get_handshake_hash(),
+            Session::StatelessTransport(ref state) => state.get_handshake_hash(),
+        };
+        hash.ct_eq(expected).into()
+    }
+
+    /// Derive `out.len()` bytes of key material bound to this session for use outside of it
+    /// (e.g. keying a side channel), in the spirit of TLS exporters. The secret is derived
+    /// from the final chaining key and handshake hash with HKDF, and then from `label` with
+    /// HKDF again, so the transport ciphers aren't touched and every label gives an
+    /// independent secret.
+    ///
+    /// Both peers get the same secret as long as they use the same `label`. A shorter `out`
+    /// gets a prefix of what a longer one would, so use a different label for every key
+    /// rather than different lengths.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if the handshake isn't finished yet.
+    ///
+    /// Will result in `Error::Input` if `out` is empty or longer than the hash length.
+    pub fn export_secret(&mut self, label: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        match *self {
+            Session::Handshake(ref mut state)          => state.export_secret(label, out),
+            Session::Transport(ref mut state)          => state.export_secret(label, out),
+            Session::StatelessTransport(ref mut state) => state.export_secret(label, out),
+        }
+    }
+
+    /// Pin the static public key the remote party is expected to send during the handshake,
+    /// for when it's learned out-of-band after the session was built. If the remote party
+    /// then presents a different static key, reading that message fails and the handshake
+    /// can't continue.
+    ///
+    /// While pinned, `get_remote_static()` returns the pinned key even before it's been
+    /// received.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if the key is not the right length, or if the remote
+    /// static key is already known (e.g. from the builder or an earlier message).
+    /// Will result in `Error::State` if the handshake is finished.
+    pub fn set_remote_static(&mut self, key: &[u8]) -> Result<(), Error> {
+        match *self {
+            Session::Handshake(ref mut state) => state.set_remote_static(key),
+            _                                 => bail!(StateProblem::HandshakeAlreadyFinished)
+        }
+    }
+
+    /// Append `data` to the prologue given to the builder, for protocols that assemble their
+    /// prologue from fields negotiated at different times. Calling this repeatedly is the same
+    /// as having passed all of the fragments, concatenated, to `Builder::prologue()`.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `StateProblem::HandshakeAlreadyStarted` once the first handshake message
+    /// has been written or read.
+    pub fn mix_prologue(&mut self, data: &[u8]) -> Result<(), Error> {
+        match *self {
+            Session::Handshake(ref mut state) => state.mix_prologue(data),
+            _                                 => bail!(StateProblem::HandshakeAlreadyFinished)
+        }
+    }
+
+    /// Like `mix_prologue()`, but hashes `data` straight away instead of keeping a copy, so a
+    /// prologue that's megabytes long can be fed in pieces without ever holding all of it in
+    /// memory. The resulting handshake hash is the same as for the concatenated prologue.
+    ///
+    /// The handshake hash doesn't include the streamed prologue until `finish_prologue()` is
+    /// called, which the first handshake message does automatically.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `StateProblem::HandshakeAlreadyStarted` once the first handshake message
+    /// has been written or read, or the prologue has been finished.
+    pub fn stream_prologue(&mut self, data: &[u8]) -> Result<(), Error> {
+        match *self {
+            Session::Handshake(ref mut state) => state.stream_prologue(data),
+            _                                 => bail!(StateProblem::HandshakeAlreadyFinished)
+        }
+    }
+
+    /// Finish a prologue fed in with `stream_prologue()`, bringing the handshake hash up to
+    /// date. After this, the prologue can't be added to. Does nothing if no prologue is being
+    /// streamed.
+    pub fn finish_prologue(&mut self) -> Result<(), Error> {
+        match *self {
+            Session::Handshake(ref mut state) => state.finish_prologue(),
+            _                                 => bail!(StateProblem::HandshakeAlreadyFinished)
         }
     }
 
@@ -301,7 +1088,6 @@ impl Session {
     ///
     /// Will result in `Error::Input` if the PSK is not the right length or the location is out of bounds.
     /// Will result in `Error::State` if in transport mode.
-    #[must_use]
     pub fn set_psk(&mut self, location: usize, key: &[u8]) -> Result<(), Error> {
         match *self {
             Session::Handshake(ref mut state) => state.set_psk(location, key),
@@ -343,8 +1129,41 @@ impl Session {
         }
     }
 
+    /// Restart a handshake as the fallback handshake described by `params`, e.g. to
+    /// switch from `IK` to `XXfallback` as in
+    /// [Noise Pipes](http://noiseprotocol.org/noise.html#noise-pipes).
+    ///
+    /// The initiator's ephemeral key becomes a pre-message of the new handshake, so the
+    /// initiator must have written its first message and the responder must have read at
+    /// least the ephemeral key out of it (reading may well have failed after that).
+    /// Which side is the initiator doesn't change, but the responder writes first. A protocol
+    /// id set with `Builder::protocol_id()` carries over to the new handshake.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Init` if `params` isn't a fallback pattern using the same
+    /// primitives as this session.
+    ///
+    /// Will result in `Error::State` if the session isn't in handshake mode, or if the
+    /// ephemeral key isn't available.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// // ... reading an IK first message failed, so the responder falls back ...
+    ///
+    /// session = session.into_fallback("Noise_XXfallback_25519_AESGCM_SHA256".parse()?, &[])?;
+    /// ```
+    pub fn into_fallback(self, params: NoiseParams, prologue: &[u8]) -> Result<Self, Error> {
+        match self {
+            Session::Handshake(state) => Ok(state.into_fallback(params, prologue)?.into()),
+            _ => bail!(StateProblem::HandshakeAlreadyFinished),
+        }
+    }
+
     /// Transition the session into stateless (explicit nonce) transport mode.
-    /// This is useful when using Noise over lossy transports.
+    /// This is useful when using Noise over lossy transports. See `write_message_with_nonce()`
+    /// and `read_message_with_nonce()` for what this leaves up to the application.
     /// Like `into_transport_mode()`, this can only be done once the handshake has finished.
     ///
     /// Consumes the previous state, and returns the new transport state object, thereby freeing
@@ -379,9 +1198,9 @@ impl Session {
     }
 }
 
-impl Into<Session> for HandshakeState {
-    fn into(self) -> Session {
-        Session::Handshake(self)
+impl From<HandshakeState> for Session {
+    fn from(val: HandshakeState) -> Self {
+        Session::Handshake(val)
     }
 }
 
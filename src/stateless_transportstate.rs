@@ -1,10 +1,12 @@
-use crate::params::HandshakePattern;
+use crate::params::Role;
 use crate::error::{Error, StateProblem};
 use crate::cipherstate::StatelessCipherStates;
-use crate::constants::{MAXDHLEN, MAXMSGLEN, TAGLEN};
+use crate::constants::{MAXDHLEN, MAXMSGLEN};
 use crate::handshakestate::HandshakeState;
+use crate::symmetricstate::SymmetricState;
 use crate::utils::Toggle;
-use std::fmt;
+use core::fmt;
+#[cfg(not(feature = "std"))] use alloc::string::String;
 
 /// A state machine encompassing the transport phase of a Noise session, using the two
 /// `CipherState`s (for sending and receiving) that were spawned from the `SymmetricState`'s
@@ -12,11 +14,14 @@ use std::fmt;
 ///
 /// See: http://noiseprotocol.org/noise.html#the-handshakestate-object
 pub struct StatelessTransportState {
-    pub cipherstates: StatelessCipherStates,
-    pattern: HandshakePattern,
+    pub(crate) cipherstates: StatelessCipherStates,
+    oneway: bool,
+    protocol_name: String,
     dh_len: usize,
     rs: Toggle<[u8; MAXDHLEN]>,
-    initiator: bool,
+    re: Toggle<[u8; MAXDHLEN]>,
+    symmetricstate: SymmetricState,
+    role: Role,
 }
 
 impl StatelessTransportState {
@@ -26,15 +31,18 @@ impl StatelessTransportState {
         }
 
         let dh_len = handshake.dh_len();
-        let HandshakeState {cipherstates, params, rs, initiator, ..} = handshake;
-        let pattern = params.handshake.pattern;
+        let (cipherstates, symmetricstate, params, rs, re, role, oneway) = handshake.into_transport_parts();
+        let protocol_name = params.name;
 
         Ok(Self {
             cipherstates: cipherstates.into(),
-            pattern,
+            oneway,
+            protocol_name,
             dh_len,
             rs,
-            initiator,
+            re,
+            symmetricstate,
+            role,
         })
     }
 
@@ -42,57 +50,93 @@ impl StatelessTransportState {
         self.rs.get().map(|rs| &rs[..self.dh_len])
     }
 
+    pub fn get_remote_ephemeral(&self) -> Option<&[u8]> {
+        self.re.get().map(|re| &re[..self.dh_len])
+    }
+
+    /// How many bytes each transport message adds on top of its payload.
+    pub fn message_overhead(&self) -> usize {
+        self.cipherstates.0.tag_len()
+    }
+
+    /// The full name of the Noise protocol in use.
+    pub fn get_protocol_name(&self) -> &str {
+        &self.protocol_name
+    }
+
+    /// The final handshake hash, suitable for channel binding.
+    pub fn get_handshake_hash(&self) -> &[u8] {
+        self.symmetricstate.handshake_hash()
+    }
+
+    /// Derive key material for the application, see `SymmetricState::export_secret()`.
+    pub fn export_secret(&mut self, label: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        self.symmetricstate.export_secret(label, out)
+    }
+
+    /// Encrypt `payload` into `message` with the outgoing cipher for our role, using `nonce`.
     pub fn write_transport_message(&self,
                                    nonce: u64,
                                    payload: &[u8],
                                    message: &mut [u8]) -> Result<usize, Error> {
-        if !self.initiator && self.pattern.is_oneway() {
+        if self.role == Role::Responder && self.oneway {
             bail!(StateProblem::OneWay);
-        } else if payload.len() + TAGLEN > MAXMSGLEN || payload.len() + TAGLEN > message.len() {
-            bail!(Error::Input);
         }
 
-        let cipher = if self.initiator { &self.cipherstates.0 } else { &self.cipherstates.1 };
-        Ok(cipher.encrypt(nonce, payload, message)?)
+        let cipher = self.cipherstates.outgoing(self.role);
+        let tag_len = cipher.tag_len();
+        if payload.len() + tag_len > MAXMSGLEN || payload.len() + tag_len > message.len() {
+            bail!(Error::Input);
+        }
+        cipher.encrypt(nonce, payload, message)
     }
 
+    /// Decrypt `payload` into `message` with the incoming cipher for our role, using `nonce`.
     pub fn read_transport_message(&self,
                                   nonce: u64,
                                   payload: &[u8],
                                   message: &mut [u8]) -> Result<usize, Error> {
-        if self.initiator && self.pattern.is_oneway() {
+        if self.role == Role::Initiator && self.oneway {
             bail!(StateProblem::OneWay);
+        } else if payload.len() > MAXMSGLEN {
+            bail!(Error::Input);
         }
-        let cipher = if self.initiator { &self.cipherstates.1 } else { &self.cipherstates.0 };
-        cipher.decrypt(nonce, payload, message).map_err(|_| Error::Decrypt)
+        let cipher = self.cipherstates.incoming(self.role);
+        cipher.decrypt(nonce, payload, message)
     }
 
-    pub fn rekey_outgoing(&mut self) {
-        if self.initiator {
-            self.cipherstates.rekey_initiator()
-        } else {
-            self.cipherstates.rekey_responder()
+    pub fn rekey_outgoing(&mut self) -> Result<(), Error> {
+        match self.role {
+            Role::Initiator => self.cipherstates.rekey_initiator(),
+            Role::Responder if self.oneway => bail!(StateProblem::OneWay),
+            Role::Responder => self.cipherstates.rekey_responder(),
         }
+        Ok(())
     }
 
-    pub fn rekey_incoming(&mut self) {
-        if self.initiator {
-            self.cipherstates.rekey_responder()
-        } else {
-            self.cipherstates.rekey_initiator()
+    pub fn rekey_incoming(&mut self) -> Result<(), Error> {
+        match self.role {
+            Role::Initiator if self.oneway => bail!(StateProblem::OneWay),
+            Role::Initiator => self.cipherstates.rekey_responder(),
+            Role::Responder => self.cipherstates.rekey_initiator(),
         }
+        Ok(())
     }
 
     pub fn rekey_initiator_manually(&mut self, key: &[u8]) {
         self.cipherstates.rekey_initiator_manually(key)
     }
 
-    pub fn rekey_responder_manually(&mut self, key: &[u8]) {
-        self.cipherstates.rekey_responder_manually(key)
+    pub fn rekey_responder_manually(&mut self, key: &[u8]) -> Result<(), Error> {
+        if self.oneway {
+            bail!(StateProblem::OneWay);
+        }
+        self.cipherstates.rekey_responder_manually(key);
+        Ok(())
     }
 
     pub fn is_initiator(&self) -> bool {
-        self.initiator
+        self.role.is_initiator()
     }
 }
 
@@ -0,0 +1,475 @@
+//! A byte-stream adapter for transport sessions, see `StreamingTransport`.
+
+use crate::constants::MAXMSGLEN;
+use crate::error::{Error, StateProblem};
+use crate::session::Session;
+use byteorder::{ByteOrder, BigEndian};
+use std::io::{self, Read, Write};
+
+/// Runs an arbitrarily long byte stream over a finished Noise session, the way one would
+/// tunnel a TCP connection.
+///
+/// Bytes written to it are collected into chunks of the largest payload that fits in a
+/// transport message, and every full chunk goes out straight away as a frame: a 2-byte
+/// big-endian length followed by the message, just like `Session::write_framed()`.
+/// `flush()` sends whatever is left as a shorter, final frame, so call it before handing the
+/// stream off or dropping it. Reading reassembles the byte stream from the frames, and a
+/// clean end of the underlying stream between two frames is the end of the byte stream.
+///
+/// It implements `Write` if the underlying stream does, and `Read` if the underlying stream
+/// does, so the same session can carry both directions over e.g. a `TcpStream`.
+///
+/// The nonces are tracked by the session as usual, and `rekey_every()` adds a rekey schedule
+/// for both directions.
+///
+/// Short reads and writes of the underlying stream are fine, and so is a non-blocking stream:
+/// a frame that has only been partly written or read when the stream fails with
+/// `io::ErrorKind::WouldBlock` or `io::ErrorKind::Interrupted` is picked up where it left off
+/// by the next call. Any other failure, whether reading, writing or decrypting, leaves the
+/// session out of step with its peer, and nothing more should be sent or received.
+///
+/// A `write()` that returns an error hasn't taken any of its bytes, so retrying it never
+/// sends them twice. Once a chunk is sealed into a frame its bytes are taken, so a failure
+/// to send that frame is reported by the next `write()` or `flush()` instead.
+///
+/// # Examples
+///
+/// ```rust,ignore
+/// let stream = TcpStream::connect(addr)?;
+/// // ... complete the handshake over `stream` ...
+///
+/// let mut tunnel = StreamingTransport::new(session, stream)?.rekey_every(1 << 16);
+/// io::copy(&mut file, &mut tunnel)?;
+/// tunnel.flush()?;
+/// ```
+pub struct StreamingTransport<S> {
+    session        : Session,
+    stream         : S,
+    rekey_interval : Option<u64>,
+    chunk_len      : usize,
+    pending        : Vec<u8>,
+    outgoing       : Vec<u8>,
+    outgoing_pos   : usize,
+    incoming       : Vec<u8>,
+    incoming_len   : usize,
+    received       : Vec<u8>,
+    received_pos   : usize,
+    write_error    : Option<io::Error>,
+}
+
+impl<S> StreamingTransport<S> {
+    /// Wrap a `session` whose handshake is finished, switching it over to transport mode if
+    /// it isn't already, along with the `stream` that carries its frames.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if the handshake isn't finished, or if the session is in
+    /// stateless transport mode, where the nonces aren't tracked for us.
+    pub fn new(session: Session, stream: S) -> Result<Self, Error> {
+        let session = session.into_transport_mode()?;
+        if let Session::StatelessTransport(_) = session {
+            bail!(StateProblem::StatelessTransportMode);
+        }
+
+        let chunk_len = MAXMSGLEN - session.get_next_message_overhead();
+        Ok(StreamingTransport {
+            session,
+            stream,
+            rekey_interval: None,
+            chunk_len,
+            pending: Vec::with_capacity(chunk_len),
+            outgoing: Vec::with_capacity(2 + MAXMSGLEN),
+            outgoing_pos: 0,
+            incoming: vec![0u8; 2 + MAXMSGLEN],
+            incoming_len: 0,
+            received: Vec::with_capacity(chunk_len),
+            received_pos: 0,
+            write_error: None,
+        })
+    }
+
+    /// Rekey a direction with `Session::rekey_outgoing()` / `Session::rekey_incoming()`
+    /// after every `messages` frames that went that way, counted by nonce so that both peers
+    /// agree on when it happens. Both peers need the same schedule. `0` turns rekeying off,
+    /// which is the default.
+    pub fn rekey_every(mut self, messages: u64) -> Self {
+        self.rekey_interval = if messages == 0 { None } else { Some(messages) };
+        self
+    }
+
+    /// The session underneath, e.g. to check the remote static key or the nonces.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// The stream underneath.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// The stream underneath, e.g. to set a read timeout on a socket. Reading from or writing
+    /// to it directly breaks the framing.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Take back the session and the stream. Anything written since the last full chunk that
+    /// hasn't been flushed, and anything received that hasn't been read yet, is dropped.
+    pub fn into_inner(self) -> (Session, S) {
+        (self.session, self.stream)
+    }
+}
+
+impl<S: Write> StreamingTransport<S> {
+    /// Encrypt the pending chunk into a frame, once the last one has been written out.
+    fn seal_pending(&mut self) -> io::Result<()> {
+        self.outgoing.resize(2 + MAXMSGLEN, 0);
+        let len = self.session.write_framed(&self.pending, &mut self.outgoing).map_err(io_error)?;
+        self.outgoing.truncate(len);
+        self.outgoing_pos = 0;
+        self.pending.clear();
+        if let Some(interval) = self.rekey_interval {
+            if self.session.sending_nonce().map_err(io_error)? % interval == 0 {
+                self.session.rekey_outgoing().map_err(io_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn drain_outgoing(&mut self) -> io::Result<()> {
+        drain(&mut self.stream, &self.outgoing, &mut self.outgoing_pos)
+    }
+
+    /// Report the error that came up after a `write()` had already taken its bytes.
+    fn take_write_error(&mut self) -> io::Result<()> {
+        match self.write_error.take() {
+            Some(err) => Err(err),
+            None      => Ok(()),
+        }
+    }
+}
+
+impl<S: Write> Write for StreamingTransport<S> {
+    /// Takes as much of `buf` as fits in the current chunk, and sends the chunk once it's full.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.take_write_error()?;
+        self.drain_outgoing()?;
+        let len = buf.len().min(self.chunk_len - self.pending.len());
+        self.pending.extend_from_slice(&buf[..len]);
+        if self.pending.len() == self.chunk_len {
+            match self.seal_pending() {
+                // Not sealed, so the bytes aren't taken after all.
+                Err(err) if !self.pending.is_empty() => {
+                    self.pending.truncate(self.chunk_len - len);
+                    return Err(err);
+                },
+                // Sealed, so the bytes are taken, and a failure to send them is left for the
+                // next call to report. The rest of the frame goes out with it too.
+                res => match res.and_then(|()| self.drain_outgoing()) {
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {},
+                    Err(err) => self.write_error = Some(err),
+                    Ok(()) => {},
+                },
+            }
+        }
+        Ok(len)
+    }
+
+    /// Sends whatever has been written since the last full chunk, then flushes the stream.
+    fn flush(&mut self) -> io::Result<()> {
+        self.take_write_error()?;
+        self.drain_outgoing()?;
+        if !self.pending.is_empty() {
+            self.seal_pending()?;
+            self.drain_outgoing()?;
+        }
+        self.stream.flush()
+    }
+}
+
+impl<S: Read> StreamingTransport<S> {
+    /// Read and decrypt the next frame, returning `false` if the stream ended cleanly first.
+    fn receive_frame(&mut self) -> io::Result<bool> {
+        let frame_len = match fill_frame(&mut self.stream, &mut self.incoming, &mut self.incoming_len)? {
+            Some(frame_len) => frame_len,
+            None            => return Ok(false),
+        };
+        self.incoming_len = 0;
+
+        self.received.resize(frame_len, 0);
+        let len = self.session.read_message(&self.incoming[2..frame_len], &mut self.received)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.received.truncate(len);
+        self.received_pos = 0;
+        if let Some(interval) = self.rekey_interval {
+            if self.session.receiving_nonce().map_err(io_error)? % interval == 0 {
+                self.session.rekey_incoming().map_err(io_error)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<S: Read> Read for StreamingTransport<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // Frames can be empty, so keep going until there's something to hand out.
+        while self.received_pos == self.received.len() {
+            if !self.receive_frame()? {
+                return Ok(0);
+            }
+        }
+
+        let available = &self.received[self.received_pos..];
+        let len = buf.len().min(available.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.received_pos += len;
+        Ok(len)
+    }
+}
+
+/// An encrypted socket: runs the handshake of a `Session` over any `Read + Write` byte stream
+/// (e.g. a `TcpStream`), and then reads and writes through a `StreamingTransport`, so it can
+/// be dropped in wherever the plain stream was used.
+///
+/// Handshake messages are framed the same way as transport messages, with a 2-byte big-endian
+/// length, and carry empty payloads. Errors from the session come out as `io::Error`s, with
+/// the `snow::Error` as their inner error.
+///
+/// # Examples
+///
+/// ```rust,ignore
+/// let session = Builder::new(params).local_private_key(&key).build_initiator()?;
+/// let mut socket = NoiseStream::handshake(session, TcpStream::connect(addr)?)?;
+/// socket.write_all(b"GET / HTTP/1.0\r\n\r\n")?;
+/// socket.flush()?;
+/// ```
+pub struct NoiseStream<S> {
+    transport: StreamingTransport<S>,
+}
+
+impl<S: Read + Write> NoiseStream<S> {
+    /// Run what's left of `session`'s handshake over `stream`, taking turns writing and
+    /// reading, and flushing `stream` after every message written.
+    ///
+    /// This needs a blocking stream, since the handshake is driven to the end before this
+    /// returns. Use `MidHandshake` to drive it over a non-blocking one.
+    ///
+    /// # Errors
+    ///
+    /// Fails with the error of the underlying stream, or with an `io::Error` wrapping the
+    /// `snow::Error` if a handshake message can't be written or read (e.g. because the peer's
+    /// static key fails authentication), or the session is in stateless transport mode.
+    pub fn handshake(session: Session, stream: S) -> io::Result<Self> {
+        let mut handshake = MidHandshake::new(session, stream);
+        handshake.advance()?;
+        handshake.into_stream()
+    }
+}
+
+impl<S> NoiseStream<S> {
+    /// See `StreamingTransport::rekey_every()`.
+    pub fn rekey_every(self, messages: u64) -> Self {
+        NoiseStream { transport: self.transport.rekey_every(messages) }
+    }
+
+    /// The session underneath, e.g. to check the remote static key.
+    pub fn session(&self) -> &Session {
+        self.transport.session()
+    }
+
+    /// The stream underneath.
+    pub fn get_ref(&self) -> &S {
+        self.transport.get_ref()
+    }
+
+    /// The stream underneath, e.g. to set a read timeout on a socket. Reading from or writing
+    /// to it directly breaks the framing.
+    pub fn get_mut(&mut self) -> &mut S {
+        self.transport.get_mut()
+    }
+
+    /// Take back the session and the stream, see `StreamingTransport::into_inner()`.
+    pub fn into_inner(self) -> (Session, S) {
+        self.transport.into_inner()
+    }
+}
+
+impl<S: Read> Read for NoiseStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.transport.read(buf)
+    }
+}
+
+impl<S: Write> Write for NoiseStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.transport.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.transport.flush()
+    }
+}
+
+/// A handshake being run over a stream that may not be ready to read or write at any given
+/// time, such as a non-blocking socket, or an async one behind a thin adapter that turns
+/// `Poll::Pending` into `io::ErrorKind::WouldBlock`.
+///
+/// Every call to `advance()` gets as far as the stream allows, keeping any partly written or
+/// read message around, so it can be called again whenever the stream is ready, e.g. from
+/// a `poll` function.
+///
+/// # Examples
+///
+/// ```rust,ignore
+/// fn poll_handshake(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+///     match self.handshake.advance() {
+///         Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+///         res => Poll::Ready(res),
+///     }
+/// }
+/// ```
+pub struct MidHandshake<S> {
+    session      : Session,
+    stream       : S,
+    outgoing     : Vec<u8>,
+    outgoing_pos : usize,
+    incoming     : Vec<u8>,
+    incoming_len : usize,
+    payload      : Vec<u8>,
+}
+
+impl<S> MidHandshake<S> {
+    /// Get ready to run what's left of `session`'s handshake over `stream`. Nothing is read
+    /// or written until `advance()`.
+    pub fn new(session: Session, stream: S) -> Self {
+        MidHandshake {
+            session,
+            stream,
+            outgoing: Vec::with_capacity(2 + MAXMSGLEN),
+            outgoing_pos: 0,
+            incoming: vec![0u8; 2 + MAXMSGLEN],
+            incoming_len: 0,
+            payload: vec![0u8; MAXMSGLEN],
+        }
+    }
+
+    /// The session underneath, e.g. to check the remote static key as soon as it's known.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// The stream underneath.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// The stream underneath. Reading from or writing to it directly breaks the framing.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Whether the handshake is over and every message of it has been written out.
+    pub fn is_finished(&self) -> bool {
+        self.outgoing.is_empty() && self.session.is_handshake_finished()
+    }
+
+    /// Move on to transport mode once the handshake is finished.
+    ///
+    /// # Errors
+    ///
+    /// Will result in an `io::Error` wrapping `StateProblem::HandshakeNotFinished` if it isn't,
+    /// or `StateProblem::StatelessTransportMode` if the session is in stateless transport mode.
+    pub fn into_stream(self) -> io::Result<NoiseStream<S>> {
+        if !self.is_finished() {
+            return Err(io_error(StateProblem::HandshakeNotFinished.into()));
+        }
+        let transport = StreamingTransport::new(self.session, self.stream).map_err(io_error)?;
+        Ok(NoiseStream { transport })
+    }
+}
+
+impl<S: Read + Write> MidHandshake<S> {
+    /// Write and read handshake messages until the handshake is finished, or the stream fails.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `io::ErrorKind::WouldBlock` if the stream wasn't ready, in which case
+    /// this can be called again later. Any other error is fatal, just like for
+    /// `NoiseStream::handshake()`.
+    pub fn advance(&mut self) -> io::Result<()> {
+        loop {
+            if !self.outgoing.is_empty() {
+                drain(&mut self.stream, &self.outgoing, &mut self.outgoing_pos)?;
+                self.stream.flush()?;
+                self.outgoing.clear();
+                self.outgoing_pos = 0;
+            }
+
+            if self.session.is_handshake_finished() {
+                return Ok(());
+            } else if self.session.is_my_turn() {
+                self.outgoing.resize(2 + MAXMSGLEN, 0);
+                match self.session.write_framed(&[], &mut self.outgoing) {
+                    Ok(len) => self.outgoing.truncate(len),
+                    Err(err) => {
+                        self.outgoing.clear();
+                        return Err(io_error(err));
+                    },
+                }
+            } else {
+                let frame_len = fill_frame(&mut self.stream, &mut self.incoming, &mut self.incoming_len)?
+                    .ok_or(io::ErrorKind::UnexpectedEof)?;
+                self.incoming_len = 0;
+                self.session.read_message(&self.incoming[2..frame_len], &mut self.payload)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            }
+        }
+    }
+}
+
+/// Write out what's left of `buf` after the first `*pos` bytes.
+fn drain<S: Write>(stream: &mut S, buf: &[u8], pos: &mut usize) -> io::Result<()> {
+    while *pos < buf.len() {
+        match stream.write(&buf[*pos..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(len) => *pos += len,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Read the rest of a 2-byte length-prefixed frame into `buf`, which already holds the first
+/// `*filled` bytes of it. Returns the size of the whole frame, or `None` if the stream ended
+/// cleanly before the frame started.
+fn fill_frame<S: Read>(stream: &mut S, buf: &mut [u8], filled: &mut usize) -> io::Result<Option<usize>> {
+    if !fill(stream, buf, filled, 2)? {
+        return Ok(None);
+    }
+    let frame_len = 2 + BigEndian::read_u16(&buf[..2]) as usize;
+    fill(stream, buf, filled, frame_len)?;
+    Ok(Some(frame_len))
+}
+
+/// Read until the first `target` bytes of `buf` are filled, returning `false` if the stream
+/// ended cleanly before anything was read.
+fn fill<S: Read>(stream: &mut S, buf: &mut [u8], filled: &mut usize, target: usize) -> io::Result<bool> {
+    while *filled < target {
+        match stream.read(&mut buf[*filled..target]) {
+            Ok(0) if *filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => *filled += len,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+fn io_error(err: Error) -> io::Error {
+    io::Error::other(err)
+}
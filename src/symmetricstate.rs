@@ -1,12 +1,16 @@
 use crate::error::Error;
 use crate::constants::{CIPHERKEYLEN, MAXHASHLEN};
 use crate::types::Hash;
+#[cfg(feature = "risky-trace")] use crate::types::SymmetricTrace;
 use crate::cipherstate::CipherState;
+use crate::utils::secure_zero;
+#[cfg(not(feature = "std"))] use alloc::boxed::Box;
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub(crate) struct SymmetricStateData {
     h       : [u8; MAXHASHLEN],
     ck      : [u8; MAXHASHLEN],
+    k       : [u8; CIPHERKEYLEN],
     has_key : bool,
 }
 
@@ -15,27 +19,68 @@ impl Default for SymmetricStateData {
         SymmetricStateData {
             h: [0u8; MAXHASHLEN],
             ck: [0u8; MAXHASHLEN],
+            k: [0u8; CIPHERKEYLEN],
             has_key: false
         }
     }
 }
 
+/// Everything a handshake message can change in a `SymmetricState`, including the key and
+/// nonce of its cipher, so that a message which fails part way through can be undone. It
+/// holds the same secrets, so it's wiped when dropped just the same.
+pub(crate) struct Checkpoint {
+    inner : SymmetricStateData,
+    n     : u64,
+}
+
+impl Drop for Checkpoint {
+    fn drop(&mut self) {
+        secure_zero(&mut self.inner.ck);
+        secure_zero(&mut self.inner.h);
+        secure_zero(&mut self.inner.k);
+    }
+}
+
 pub struct SymmetricState {
     cipherstate : CipherState,
     hasher      : Box<dyn Hash>,
     inner       : SymmetricStateData,
+    #[cfg(feature = "risky-trace")]
+    trace       : Option<SymmetricTrace>,
 }
 
 impl SymmetricState {
-    pub fn new(cipherstate: CipherState, hasher: Box<Hash>) -> SymmetricState {
+    pub fn new(cipherstate: CipherState, hasher: Box<dyn Hash>) -> SymmetricState {
         SymmetricState {
             cipherstate,
             hasher,
             inner: SymmetricStateData::default(),
+            #[cfg(feature = "risky-trace")]
+            trace: None,
+        }
+    }
+
+    #[cfg(feature = "risky-trace")]
+    pub fn set_trace(&mut self, hook: SymmetricTrace) {
+        self.trace = Some(hook);
+    }
+
+    /// Report the new `h` and `ck` to the trace hook, if any. Compiles to nothing without
+    /// the `risky-trace` feature.
+    #[inline(always)]
+    #[allow(unused_variables)]
+    fn trace(&self, label: &str, data: &[u8]) {
+        #[cfg(feature = "risky-trace")]
+        {
+            if let Some(hook) = self.trace {
+                let hash_len = self.hasher.hash_len();
+                hook(label, data, &self.inner.h[..hash_len], &self.inner.ck[..hash_len]);
+            }
         }
     }
 
     pub fn initialize(&mut self, handshake_name: &str) {
+        self.inner.h = [0u8; MAXHASHLEN];
         if handshake_name.len() <= self.hasher.hash_len() {
             copy_slices!(handshake_name.as_bytes(), self.inner.h);
         } else {
@@ -45,6 +90,7 @@ impl SymmetricState {
         }
         copy_slices!(&self.inner.h, &mut self.inner.ck);
         self.inner.has_key = false;
+        self.trace("initialize", handshake_name.as_bytes());
     }
 
     pub fn mix_key(&mut self, data: &[u8]) {
@@ -52,11 +98,16 @@ impl SymmetricState {
         let mut hkdf_output = ([0u8; MAXHASHLEN], [0u8; MAXHASHLEN]);
         self.hasher.hkdf(&self.inner.ck[..hash_len], data, 2, &mut hkdf_output.0, &mut hkdf_output.1, &mut []);
         copy_slices!(&hkdf_output.0, &mut self.inner.ck);
-        self.cipherstate.set(&hkdf_output.1[..CIPHERKEYLEN], 0);
-        self.inner.has_key = true;
+        self.set_key(&hkdf_output.1[..CIPHERKEYLEN]);
+        self.trace("mix_key", data);
     }
 
     pub fn mix_hash(&mut self, data: &[u8]) {
+        self.hash_into_h(data);
+        self.trace("mix_hash", data);
+    }
+
+    fn hash_into_h(&mut self, data: &[u8]) {
         let hash_len = self.hasher.hash_len();
         self.hasher.reset();
         self.hasher.input(&self.inner.h[..hash_len]);
@@ -64,25 +115,57 @@ impl SymmetricState {
         self.hasher.result(&mut self.inner.h);
     }
 
+    /// Start a `mix_hash()` whose data is fed in pieces with `continue_mix_hash()`. Nothing
+    /// else may use the hasher until `end_mix_hash()` has been called.
+    pub fn begin_mix_hash(&mut self) {
+        let hash_len = self.hasher.hash_len();
+        self.hasher.reset();
+        self.hasher.input(&self.inner.h[..hash_len]);
+    }
+
+    pub fn continue_mix_hash(&mut self, data: &[u8]) {
+        self.hasher.input(data);
+    }
+
+    pub fn end_mix_hash(&mut self) {
+        self.hasher.result(&mut self.inner.h);
+        self.trace("mix_hash (streamed)", &[]);
+    }
+
     pub fn mix_key_and_hash(&mut self, data: &[u8]) {
         let hash_len = self.hasher.hash_len();
         let mut hkdf_output = ([0u8; MAXHASHLEN], [0u8; MAXHASHLEN], [0u8; MAXHASHLEN]);
         self.hasher.hkdf(&self.inner.ck[..hash_len], data, 3, &mut hkdf_output.0, &mut hkdf_output.1, &mut hkdf_output.2);
         copy_slices!(&hkdf_output.0, &mut self.inner.ck);
-        self.mix_hash(&hkdf_output.1[..hash_len]);
-        self.cipherstate.set(&hkdf_output.2[..CIPHERKEYLEN], 0);
+        self.hash_into_h(&hkdf_output.1[..hash_len]);
+        self.set_key(&hkdf_output.2[..CIPHERKEYLEN]);
+        self.trace("mix_key_and_hash", data);
+    }
+
+    /// Key the cipher afresh, keeping a copy of the key for `restore()`.
+    fn set_key(&mut self, key: &[u8]) {
+        self.inner.k.copy_from_slice(key);
+        self.cipherstate.set(key, 0);
+        self.inner.has_key = true;
     }
 
     pub fn has_key(&self) -> bool {
         self.inner.has_key
     }
 
+    pub fn tag_len(&self) -> usize {
+        self.cipherstate.tag_len()
+    }
+
     /// Encrypt a message and mixes in the hash of the output
     pub fn encrypt_and_mix_hash(&mut self, plaintext: &[u8], out: &mut [u8]) -> Result<usize, Error> {
         let hash_len = self.hasher.hash_len();
         let output_len = if self.inner.has_key {
             self.cipherstate.encrypt_ad(&self.inner.h[..hash_len], plaintext, out)?
         } else {
+            if out.len() < plaintext.len() {
+                bail!(Error::Input);
+            }
             copy_slices!(plaintext, out);
             plaintext.len()
         };
@@ -90,19 +173,21 @@ impl SymmetricState {
         Ok(output_len)
     }
 
-    pub fn decrypt_and_mix_hash(&mut self, data: &[u8], out: &mut [u8]) -> Result<usize, ()> {
+    /// Decrypt a message and mix in the hash of the input. The hash is mixed in even if
+    /// decryption fails, so that a failure costs the same work as a success.
+    pub fn decrypt_and_mix_hash(&mut self, data: &[u8], out: &mut [u8]) -> Result<usize, Error> {
         let hash_len = self.hasher.hash_len();
         let payload_len = if self.inner.has_key {
-            self.cipherstate.decrypt_ad(&self.inner.h[..hash_len], data, out)?
+            self.cipherstate.decrypt_ad(&self.inner.h[..hash_len], data, out)
         } else {
             if out.len() < data.len() {
-                return Err(())
+                bail!(Error::BufferTooSmall(data.len()));
             }
             copy_slices!(data, out);
-            data.len()
+            Ok(data.len())
         };
         self.mix_hash(data);
-        Ok(payload_len)
+        payload_len
     }
 
     pub fn split(&mut self, child1: &mut CipherState, child2: &mut CipherState) {
@@ -116,16 +201,202 @@ impl SymmetricState {
         child2.set(&hkdf_output.1[..CIPHERKEYLEN], 0);
     }
 
-    pub(crate) fn checkpoint(&mut self) -> SymmetricStateData {
-        self.inner
+    /// Derive `out.len()` bytes (at most the hash length) of key material bound to `label`,
+    /// by way of an exporter secret taken from the chaining key and the handshake hash. Nothing
+    /// else in the state changes, so this can be called any number of times.
+    pub fn export_secret(&mut self, label: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        let hash_len = self.hasher.hash_len();
+        if out.is_empty() || out.len() > hash_len {
+            bail!(Error::Input);
+        }
+
+        let mut secret = [0u8; MAXHASHLEN];
+        let mut output = [0u8; MAXHASHLEN];
+        self.hasher.hkdf(&self.inner.ck[..hash_len], &self.inner.h[..hash_len], 1, &mut secret, &mut [], &mut []);
+        self.hasher.hkdf(&secret[..hash_len], label, 1, &mut output, &mut [], &mut []);
+        out.copy_from_slice(&output[..out.len()]);
+        secure_zero(&mut secret);
+        secure_zero(&mut output);
+        Ok(())
+    }
+
+    /// The chaining key, so that `export_secret()` keeps working in an exported session.
+    #[cfg(feature = "risky-key-export")]
+    pub(crate) fn chaining_key(&self) -> &[u8] {
+        &self.inner.ck[..self.hasher.hash_len()]
+    }
+
+    /// Pick up from the chaining key and handshake hash of an exported session.
+    #[cfg(feature = "risky-key-export")]
+    pub(crate) fn resume(&mut self, ck: &[u8], h: &[u8]) {
+        copy_slices!(ck, &mut self.inner.ck);
+        copy_slices!(h, &mut self.inner.h);
+        self.trace("resume", &[]);
+    }
+
+    pub(crate) fn checkpoint(&mut self) -> Checkpoint {
+        Checkpoint { inner: self.inner.clone(), n: self.cipherstate.nonce() }
     }
 
-    pub(crate) fn restore(&mut self, checkpoint: SymmetricStateData) {
-        self.inner = checkpoint;
+    pub(crate) fn restore(&mut self, checkpoint: &Checkpoint) {
+        self.inner.clone_from(&checkpoint.inner);
+        if self.inner.has_key {
+            self.cipherstate.set(&self.inner.k, checkpoint.n);
+        } else {
+            self.cipherstate.clear();
+        }
+        self.trace("restore", &[]);
     }
 
     pub fn handshake_hash(&self) -> &[u8] {
         let hash_len = self.hasher.hash_len();
         &self.inner.h[..hash_len]
     }
-}
\ No newline at end of file
+}
+
+impl Drop for SymmetricState {
+    fn drop(&mut self) {
+        secure_zero(&mut self.inner.ck);
+        secure_zero(&mut self.inner.h);
+        secure_zero(&mut self.inner.k);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolvers::{CryptoResolver, DefaultResolver};
+    use crate::params::{CipherChoice, HashChoice};
+
+    fn symmetricstate() -> SymmetricState {
+        let cipher = DefaultResolver.resolve_cipher(&CipherChoice::ChaChaPoly).unwrap();
+        let hasher = DefaultResolver.resolve_hash(&HashChoice::SHA256).unwrap();
+        let mut state = SymmetricState::new(CipherState::new(cipher), hasher);
+        state.initialize("Noise_NNpsk0_25519_ChaChaPoly_SHA256");
+        state
+    }
+
+    #[test]
+    fn test_mix_key_and_hash() {
+        let psk = [7u8; 32];
+        let mut state = symmetricstate();
+        let h = state.inner.h;
+        let ck = state.inner.ck;
+        state.mix_key_and_hash(&psk);
+        assert!(state.has_key());
+
+        let mut hasher = DefaultResolver.resolve_hash(&HashChoice::SHA256).unwrap();
+        let mut outputs = ([0u8; MAXHASHLEN], [0u8; MAXHASHLEN], [0u8; MAXHASHLEN]);
+        hasher.hkdf(&ck[..32], &psk, 3, &mut outputs.0, &mut outputs.1, &mut outputs.2);
+        assert_eq!(&state.inner.ck[..32], &outputs.0[..32]);
+
+        let mut expected_h = [0u8; MAXHASHLEN];
+        hasher.reset();
+        hasher.input(&h[..32]);
+        hasher.input(&outputs.1[..32]);
+        hasher.result(&mut expected_h);
+        assert_eq!(&state.inner.h[..32], &expected_h[..32]);
+
+        let mut cipher = DefaultResolver.resolve_cipher(&CipherChoice::ChaChaPoly).unwrap();
+        cipher.set(&outputs.2[..CIPHERKEYLEN]);
+        let mut expected = [0u8; 64];
+        let len = cipher.encrypt(0, &expected_h[..32], b"payload", &mut expected);
+        let mut actual = [0u8; 64];
+        assert_eq!(state.encrypt_and_mix_hash(b"payload", &mut actual).unwrap(), len);
+        assert_eq!(&actual[..len], &expected[..len]);
+    }
+
+    #[test]
+    fn test_export_secret() {
+        let mut state = symmetricstate();
+        state.mix_key(&[7u8; 32]);
+        let mut first = [0u8; 32];
+        let mut second = [0u8; 32];
+        state.export_secret(b"first", &mut first).unwrap();
+        state.export_secret(b"second", &mut second).unwrap();
+        assert_ne!(first, second);
+
+        // The same label always gives the same secret, and a shorter one is its prefix.
+        let mut again = [0u8; 16];
+        state.export_secret(b"first", &mut again).unwrap();
+        assert_eq!(&first[..16], &again[..]);
+
+        let mut hasher = DefaultResolver.resolve_hash(&HashChoice::SHA256).unwrap();
+        let mut secret = [0u8; MAXHASHLEN];
+        let mut expected = [0u8; MAXHASHLEN];
+        hasher.hkdf(&state.inner.ck[..32], &state.inner.h[..32], 1, &mut secret, &mut [], &mut []);
+        hasher.hkdf(&secret[..32], b"first", 1, &mut expected, &mut [], &mut []);
+        assert_eq!(&first[..], &expected[..32]);
+
+        assert!(state.export_secret(b"first", &mut []).is_err());
+        assert!(state.export_secret(b"first", &mut [0u8; 33]).is_err());
+    }
+
+    #[test]
+    fn test_mix_hash_in_pieces() {
+        let mut whole = symmetricstate();
+        let mut pieces = symmetricstate();
+        whole.mix_hash(b"a rather long prologue");
+        pieces.begin_mix_hash();
+        for piece in &[&b"a rather"[..], b"", b" long", b" prologue"] {
+            pieces.continue_mix_hash(piece);
+        }
+        pieces.end_mix_hash();
+        assert_eq!(whole.handshake_hash(), pieces.handshake_hash());
+    }
+
+    #[test]
+    fn test_restore_undoes_the_cipher() {
+        let mut state = symmetricstate();
+        state.mix_key(&[7u8; 32]);
+        let checkpoint = state.checkpoint();
+        let mut first = [0u8; 64];
+        let len = state.encrypt_and_mix_hash(b"payload", &mut first).unwrap();
+
+        // Both the nonce the first encryption used up and the key a later DH replaced it
+        // with are undone, so the same message comes out again.
+        state.mix_key(&[8u8; 32]);
+        state.restore(&checkpoint);
+        let mut again = [0u8; 64];
+        assert_eq!(state.encrypt_and_mix_hash(b"payload", &mut again).unwrap(), len);
+        assert_eq!(&first[..len], &again[..len]);
+
+        // And a checkpoint from before there was a key takes the key away again.
+        let mut state = symmetricstate();
+        let checkpoint = state.checkpoint();
+        state.mix_key(&[7u8; 32]);
+        state.restore(&checkpoint);
+        assert!(!state.has_key());
+        assert_eq!(state.encrypt_and_mix_hash(b"payload", &mut again).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_empty_payloads() {
+        let mut sender = symmetricstate();
+        let mut receiver = symmetricstate();
+        let mut message = [0u8; 64];
+        let mut payload = [0u8; 64];
+
+        // Without a key, an empty payload takes up no bytes at all.
+        assert_eq!(sender.encrypt_and_mix_hash(&[], &mut message).unwrap(), 0);
+        assert_eq!(receiver.decrypt_and_mix_hash(&[], &mut payload).unwrap(), 0);
+
+        // With one, it's just the tag.
+        sender.mix_key_and_hash(&[7u8; 32]);
+        receiver.mix_key_and_hash(&[7u8; 32]);
+        let len = sender.encrypt_and_mix_hash(&[], &mut message).unwrap();
+        assert_eq!(len, sender.tag_len());
+        let checkpoint = receiver.checkpoint();
+        assert_eq!(receiver.decrypt_and_mix_hash(&message[..len], &mut []).unwrap(), 0);
+        assert_eq!(sender.handshake_hash(), receiver.handshake_hash());
+
+        // Anything shorter than the tag can't be a ciphertext, and mustn't underflow.
+        for short_len in 0..len {
+            receiver.restore(&checkpoint);
+            match receiver.decrypt_and_mix_hash(&message[..short_len], &mut payload) {
+                Err(Error::Decrypt) => {},
+                res => panic!("{}-byte ciphertext wasn't rejected: {:?}", short_len, res),
+            }
+        }
+    }
+}
@@ -1,10 +1,14 @@
-use crate::params::HandshakePattern;
+use crate::params::Role;
 use crate::error::{Error, StateProblem};
 use crate::cipherstate::CipherStates;
-use crate::constants::{MAXDHLEN, MAXMSGLEN, TAGLEN};
+use crate::constants::{MAXDHLEN, MAXMSGLEN};
 use crate::utils::Toggle;
 use crate::handshakestate::HandshakeState;
-use std::fmt;
+use crate::symmetricstate::SymmetricState;
+use core::fmt;
+#[cfg(not(feature = "std"))] use alloc::string::String;
+#[cfg(feature = "risky-key-export")] use crate::constants::CIPHERKEYLEN;
+#[cfg(feature = "risky-key-export")] use byteorder::{ByteOrder, BigEndian};
 
 /// A state machine encompassing the transport phase of a Noise session, using the two
 /// `CipherState`s (for sending and receiving) that were spawned from the `SymmetricState`'s
@@ -12,11 +16,14 @@ use std::fmt;
 ///
 /// See: http://noiseprotocol.org/noise.html#the-handshakestate-object
 pub struct TransportState {
-    pub cipherstates : CipherStates,
-    pattern          : HandshakePattern,
+    pub(crate) cipherstates : CipherStates,
+    oneway           : bool,
+    protocol_name    : String,
     dh_len           : usize,
     rs               : Toggle<[u8; MAXDHLEN]>,
-    initiator        : bool,
+    re               : Toggle<[u8; MAXDHLEN]>,
+    symmetricstate   : SymmetricState,
+    role             : Role,
 }
 
 impl TransportState {
@@ -26,15 +33,124 @@ impl TransportState {
         }
 
         let dh_len = handshake.dh_len();
-        let HandshakeState {cipherstates, params, rs, initiator, ..} = handshake;
-        let pattern = params.handshake.pattern;
+        let (cipherstates, symmetricstate, params, rs, re, role, oneway) = handshake.into_transport_parts();
+        let protocol_name = params.name;
 
         Ok(TransportState {
             cipherstates,
-            pattern,
+            oneway,
+            protocol_name,
             dh_len,
             rs,
-            initiator,
+            re,
+            symmetricstate,
+            role,
+        })
+    }
+
+    /// The size of an exported transport state, see `export()`.
+    #[cfg(feature = "risky-key-export")]
+    pub fn export_len(hash_len: usize, dh_len: usize) -> usize {
+        1 + 2 * (CIPHERKEYLEN + 8) + 2 * hash_len + 1 + dh_len
+    }
+
+    /// Write our role, both keys and their nonces, the handshake hash, the chaining key (for
+    /// `export_secret()`) and the remote static key (if any) to `out`, in that order, so that
+    /// `import()` can resume the session. A one-way pattern's unused responder key is written
+    /// as zeroes.
+    #[cfg(feature = "risky-key-export")]
+    pub fn export(&self, out: &mut [u8]) -> Result<usize, Error> {
+        let hash_len = self.symmetricstate.handshake_hash().len();
+        let len = Self::export_len(hash_len, self.dh_len);
+        if out.len() < len {
+            bail!(Error::Input);
+        }
+        let (initiator_key, responder_key) = match (self.cipherstates.0.key(), self.cipherstates.1.key()) {
+            (Some(initiator_key), Some(responder_key)) => (initiator_key, responder_key),
+            (Some(initiator_key), None) if self.oneway => (initiator_key, &[0u8; CIPHERKEYLEN][..]),
+            _ => bail!(StateProblem::MissingKeyMaterial),
+        };
+
+        let out = &mut out[..len];
+        out[0] = self.role.is_initiator() as u8;
+        let (keys, rest) = out[1..].split_at_mut(2 * (CIPHERKEYLEN + 8));
+        for (chunk, (key, nonce)) in keys.chunks_mut(CIPHERKEYLEN + 8)
+            .zip(&[(initiator_key, self.cipherstates.0.nonce()), (responder_key, self.cipherstates.1.nonce())])
+        {
+            chunk[..CIPHERKEYLEN].copy_from_slice(key);
+            BigEndian::write_u64(&mut chunk[CIPHERKEYLEN..], *nonce);
+        }
+        let (handshake_hash, rest) = rest.split_at_mut(hash_len);
+        handshake_hash.copy_from_slice(self.symmetricstate.handshake_hash());
+        let (chaining_key, rs) = rest.split_at_mut(hash_len);
+        chaining_key.copy_from_slice(self.symmetricstate.chaining_key());
+        rs[0] = self.rs.is_on() as u8;
+        rs[1..].copy_from_slice(&self.rs[..self.dh_len]);
+        Ok(len)
+    }
+
+    /// Copies of the initiator->responder and responder->initiator keys, in that order,
+    /// regardless of our own role. One-way patterns have no responder->initiator key, so
+    /// this fails for them.
+    #[cfg(feature = "risky-key-export")]
+    pub fn dangerously_get_raw_split(&self) -> Result<([u8; CIPHERKEYLEN], [u8; CIPHERKEYLEN]), Error> {
+        match (self.cipherstates.0.key(), self.cipherstates.1.key()) {
+            (Some(initiator_key), Some(responder_key)) => {
+                let mut keys = ([0u8; CIPHERKEYLEN], [0u8; CIPHERKEYLEN]);
+                keys.0.copy_from_slice(initiator_key);
+                keys.1.copy_from_slice(responder_key);
+                Ok(keys)
+            },
+            _ => bail!(StateProblem::MissingKeyMaterial),
+        }
+    }
+
+    /// Rebuild a transport state from the output of `export()`, setting up `cipherstates`
+    /// and `symmetricstate` (fresh from the resolver) with the exported keys and nonces.
+    #[cfg(feature = "risky-key-export")]
+    pub fn import(protocol_name: String,
+                  oneway: bool,
+                  mut cipherstates: CipherStates,
+                  mut symmetricstate: SymmetricState,
+                  dh_len: usize,
+                  exported: &[u8]) -> Result<Self, Error> {
+        let hash_len = symmetricstate.handshake_hash().len();
+        if exported.len() != Self::export_len(hash_len, dh_len) || exported[0] > 1 {
+            bail!(Error::Input);
+        }
+
+        let (keys, rest) = exported[1..].split_at(2 * (CIPHERKEYLEN + 8));
+        let mut chunks = keys.chunks(CIPHERKEYLEN + 8);
+        for cipherstate in &mut [&mut cipherstates.0, &mut cipherstates.1] {
+            let chunk = chunks.next().ok_or(Error::Input)?;
+            let nonce = BigEndian::read_u64(&chunk[CIPHERKEYLEN..]);
+            cipherstate.set(&chunk[..CIPHERKEYLEN], 0);
+            cipherstate.set_nonce(nonce)?;
+        }
+        if oneway {
+            cipherstates.1.clear();
+        }
+
+        let (exported_hash, rest) = rest.split_at(hash_len);
+        let (exported_ck, exported_rs) = rest.split_at(hash_len);
+        symmetricstate.resume(exported_ck, exported_hash);
+        let mut rs = Toggle::off([0u8; MAXDHLEN]);
+        rs[..dh_len].copy_from_slice(&exported_rs[1..]);
+        match exported_rs[0] {
+            0 => {},
+            1 => rs.enable(),
+            _ => bail!(Error::Input),
+        }
+
+        Ok(TransportState {
+            cipherstates,
+            oneway,
+            protocol_name,
+            dh_len,
+            rs,
+            re: Toggle::off([0u8; MAXDHLEN]),
+            symmetricstate,
+            role: if exported[0] == 1 { Role::Initiator } else { Role::Responder },
         })
     }
 
@@ -42,82 +158,198 @@ impl TransportState {
         self.rs.get().map(|rs| &rs[..self.dh_len])
     }
 
+    pub fn get_remote_ephemeral(&self) -> Option<&[u8]> {
+        self.re.get().map(|re| &re[..self.dh_len])
+    }
+
+    /// How many bytes each transport message adds on top of its payload.
+    pub fn message_overhead(&self) -> usize {
+        self.cipherstates.0.tag_len()
+    }
+
+    /// The full name of the Noise protocol in use.
+    pub fn get_protocol_name(&self) -> &str {
+        &self.protocol_name
+    }
+
+    /// The final handshake hash, suitable for channel binding.
+    pub fn get_handshake_hash(&self) -> &[u8] {
+        self.symmetricstate.handshake_hash()
+    }
+
+    /// Derive key material for the application, see `SymmetricState::export_secret()`.
+    pub fn export_secret(&mut self, label: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        self.symmetricstate.export_secret(label, out)
+    }
+
+    /// Encrypt `payload` into `message` with the outgoing cipher for our role.
     pub fn write_transport_message(&mut self,
                                    payload: &[u8],
                                    message: &mut [u8]) -> Result<usize, Error> {
-        if !self.initiator && self.pattern.is_oneway() {
-            bail!(StateProblem::OneWay);
-        } else if payload.len() + TAGLEN > MAXMSGLEN || payload.len() + TAGLEN > message.len() {
-            bail!(Error::Input);
-        }
-
-        let cipher = if self.initiator { &mut self.cipherstates.0 } else { &mut self.cipherstates.1 };
-        Ok(cipher.encrypt(payload, message)?)
+        write_transport_message(&mut self.cipherstates, self.role, self.oneway, &[], payload, message)
     }
 
+    /// Decrypt `payload` into `message` with the incoming cipher for our role.
     pub fn read_transport_message(&mut self,
                                    payload: &[u8],
                                    message: &mut [u8]) -> Result<usize, Error> {
-        if self.initiator && self.pattern.is_oneway() {
-            bail!(StateProblem::OneWay);
-        }
-        let cipher = if self.initiator { &mut self.cipherstates.1 } else { &mut self.cipherstates.0 };
-        cipher.decrypt(payload, message).map_err(|_| Error::Decrypt)
+        read_transport_message(&mut self.cipherstates, self.role, self.oneway, &[], payload, message)
+    }
+
+    /// Like `write_transport_message()`, but also authenticates `authtext` as associated data.
+    pub fn write_transport_message_with_ad(&mut self,
+                                           authtext: &[u8],
+                                           payload: &[u8],
+                                           message: &mut [u8]) -> Result<usize, Error> {
+        write_transport_message(&mut self.cipherstates, self.role, self.oneway, authtext, payload, message)
+    }
+
+    /// Like `read_transport_message()`, but also authenticates `authtext` as associated data.
+    pub fn read_transport_message_with_ad(&mut self,
+                                          authtext: &[u8],
+                                          payload: &[u8],
+                                          message: &mut [u8]) -> Result<usize, Error> {
+        read_transport_message(&mut self.cipherstates, self.role, self.oneway, authtext, payload, message)
+    }
+
+    /// Encrypt the first `payload_len` bytes of `in_out` in place with the outgoing cipher for
+    /// our role.
+    pub fn write_transport_message_in_place(&mut self, in_out: &mut [u8], payload_len: usize) -> Result<usize, Error> {
+        write_transport_message_in_place(&mut self.cipherstates, self.role, self.oneway, in_out, payload_len)
+    }
+
+    /// Decrypt the message in `in_out` in place with the incoming cipher for our role.
+    pub fn read_transport_message_in_place(&mut self, in_out: &mut [u8]) -> Result<usize, Error> {
+        read_transport_message_in_place(&mut self.cipherstates, self.role, self.oneway, in_out)
     }
 
-    pub fn rekey_outgoing(&mut self) {
-        if self.initiator {
-            self.cipherstates.rekey_initiator()
-        } else {
-            self.cipherstates.rekey_responder()
+    pub fn rekey_outgoing(&mut self) -> Result<(), Error> {
+        if self.role == Role::Responder && self.oneway {
+            bail!(StateProblem::OneWay);
         }
+        self.cipherstates.outgoing(self.role).rekey();
+        Ok(())
     }
 
-    pub fn rekey_incoming(&mut self) {
-        if self.initiator {
-            self.cipherstates.rekey_responder()
-        } else {
-            self.cipherstates.rekey_initiator()
+    pub fn rekey_incoming(&mut self) -> Result<(), Error> {
+        if self.role == Role::Initiator && self.oneway {
+            bail!(StateProblem::OneWay);
         }
+        self.cipherstates.incoming(self.role).rekey();
+        Ok(())
     }
 
     pub fn rekey_initiator_manually(&mut self, key: &[u8]) {
         self.cipherstates.rekey_initiator_manually(key)
     }
 
-    pub fn rekey_responder_manually(&mut self, key: &[u8]) {
-        self.cipherstates.rekey_responder_manually(key)
+    pub fn rekey_responder_manually(&mut self, key: &[u8]) -> Result<(), Error> {
+        if self.oneway {
+            bail!(StateProblem::OneWay);
+        }
+        self.cipherstates.rekey_responder_manually(key);
+        Ok(())
     }
 
     /// Sets the *receiving* CipherState's nonce. Useful for using noise on lossy transports.
-    pub fn set_receiving_nonce(&mut self, nonce: u64) {
-        if self.initiator {
-            self.cipherstates.1.set_nonce(nonce);
-        } else {
-            self.cipherstates.0.set_nonce(nonce);
+    pub fn set_receiving_nonce(&mut self, nonce: u64) -> Result<(), Error> {
+        if self.role == Role::Initiator && self.oneway {
+            bail!(StateProblem::OneWay);
         }
+        self.cipherstates.incoming(self.role).set_nonce(nonce)
     }
 
     /// Gets the *receiving* CipherState's nonce. Useful for using noise on lossy transports.
     pub fn receiving_nonce(&self) -> u64 {
-        if self.initiator {
-            self.cipherstates.1.nonce()
-        } else {
-            self.cipherstates.0.nonce()
+        match self.role {
+            Role::Initiator => self.cipherstates.1.nonce(),
+            Role::Responder => self.cipherstates.0.nonce(),
         }
     }
 
     pub fn sending_nonce(&self) -> u64 {
-        if self.initiator {
-            self.cipherstates.0.nonce()
-        } else {
-            self.cipherstates.1.nonce()
+        match self.role {
+            Role::Initiator => self.cipherstates.0.nonce(),
+            Role::Responder => self.cipherstates.1.nonce(),
         }
     }
 
     pub fn is_initiator(&self) -> bool {
-        self.initiator
+        self.role.is_initiator()
+    }
+}
+
+/// Encrypt `payload` into `message` with the outgoing cipher of `cipherstates` for our role.
+///
+/// This lives outside of `TransportState` so a finished `HandshakeState` can send transport
+/// messages before it's been turned into one. `authtext` is authenticated as associated data.
+pub(crate) fn write_transport_message(cipherstates: &mut CipherStates,
+                                      role: Role,
+                                      oneway: bool,
+                                      authtext: &[u8],
+                                      payload: &[u8],
+                                      message: &mut [u8]) -> Result<usize, Error> {
+    if role == Role::Responder && oneway {
+        bail!(StateProblem::OneWay);
+    }
+
+    let cipher = cipherstates.outgoing(role);
+    let tag_len = cipher.tag_len();
+    if payload.len() + tag_len > MAXMSGLEN || payload.len() + tag_len > message.len() {
+        bail!(Error::Input);
+    }
+    cipher.encrypt_ad(authtext, payload, message)
+}
+
+/// Decrypt `payload` into `message` with the incoming cipher of `cipherstates` for our role,
+/// checking `authtext` as associated data.
+pub(crate) fn read_transport_message(cipherstates: &mut CipherStates,
+                                     role: Role,
+                                     oneway: bool,
+                                     authtext: &[u8],
+                                     payload: &[u8],
+                                     message: &mut [u8]) -> Result<usize, Error> {
+    if role == Role::Initiator && oneway {
+        bail!(StateProblem::OneWay);
+    } else if payload.len() > MAXMSGLEN {
+        bail!(Error::Input);
+    }
+    let cipher = cipherstates.incoming(role);
+    cipher.decrypt_ad(authtext, payload, message)
+}
+
+/// Encrypt the first `payload_len` bytes of `in_out` in place with the outgoing cipher of
+/// `cipherstates` for our role.
+pub(crate) fn write_transport_message_in_place(cipherstates: &mut CipherStates,
+                                               role: Role,
+                                               oneway: bool,
+                                               in_out: &mut [u8],
+                                               payload_len: usize) -> Result<usize, Error> {
+    if role == Role::Responder && oneway {
+        bail!(StateProblem::OneWay);
+    }
+
+    let cipher = cipherstates.outgoing(role);
+    let tag_len = cipher.tag_len();
+    if payload_len + tag_len > MAXMSGLEN || payload_len + tag_len > in_out.len() {
+        bail!(Error::Input);
+    }
+    cipher.encrypt_in_place(in_out, payload_len)
+}
+
+/// Decrypt the message in `in_out` in place with the incoming cipher of `cipherstates` for
+/// our role.
+pub(crate) fn read_transport_message_in_place(cipherstates: &mut CipherStates,
+                                              role: Role,
+                                              oneway: bool,
+                                              in_out: &mut [u8]) -> Result<usize, Error> {
+    if role == Role::Initiator && oneway {
+        bail!(StateProblem::OneWay);
+    } else if in_out.len() > MAXMSGLEN {
+        bail!(Error::Input);
     }
+    let cipher = cipherstates.incoming(role);
+    cipher.decrypt_in_place(in_out)
 }
 
 impl fmt::Debug for TransportState {
@@ -1,12 +1,24 @@
 //! The traits for cryptographic implementations that can be used by Noise.
+//!
+//! All of them require `Send + Sync`, which is what makes a `Session` built from them `Send`
+//! and `Sync` too. Implementations wrapping a thread-bound handle (some hardware tokens, for
+//! instance) need to serialize access to it themselves, e.g. behind a `Mutex`.
 
-use crate::constants::{CIPHERKEYLEN, MAXBLOCKLEN, MAXHASHLEN, TAGLEN};
+use crate::constants::{CIPHERKEYLEN, MAXBLOCKLEN, MAXHASHLEN, MAXTAGLEN, TAGLEN};
 use rand_core::{CryptoRng, RngCore};
 
 /// CSPRNG operations
+///
+/// Ephemeral keys (and keys from `Builder::generate_keypair()`) are drawn from this, so any
+/// implementation used outside of tests must be a cryptographically secure RNG. A seeded,
+/// deterministic implementation is handy for reproducing test vectors, but using one in
+/// production makes every session trivially breakable.
 pub trait Random : CryptoRng + RngCore + Send + Sync {}
 
 /// Diffie-Hellman operations
+///
+/// A static key passed to `Builder::local_static_dh()` only ever has `name()`, `pub_len()`,
+/// `pubkey()` and `dh()` called on it, so it doesn't have to hold its private key in memory.
 pub trait Dh : Send + Sync {
     /// The string that the Noise spec defines for the primitive
     fn name(&self) -> &'static str;
@@ -21,7 +33,7 @@ pub trait Dh : Send + Sync {
     fn set(&mut self, privkey: &[u8]);
 
     /// Generate a new private key
-    fn generate(&mut self, rng: &mut Random);
+    fn generate(&mut self, rng: &mut dyn Random);
 
     /// Get the public key
     fn pubkey(&self) -> &[u8];
@@ -30,30 +42,96 @@ pub trait Dh : Send + Sync {
     fn privkey(&self) -> &[u8];
 
     /// Calculate a Diffie-Hellman exchange.
-    #[must_use]
+    #[allow(clippy::result_unit_err)]
     fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> Result<(), ()>;
 }
 
+/// Key encapsulation operations, for the post-quantum half of a hybrid (`hfs`) handshake.
+///
+/// A `Kem` is the `e1` key pair of the party sending `e1`, and is used by the other party
+/// to encapsulate a shared secret to it for `ekem1`. Unlike `Dh`, nothing but the private
+/// key it generates is ever secret, so it can't be given a fixed key.
+pub trait Kem : Send + Sync {
+    /// The string that the Noise spec defines for the primitive
+    fn name(&self) -> &'static str;
+
+    /// The length in bytes of a public key for this primitive
+    fn pub_len(&self) -> usize;
+
+    /// The length in bytes of a ciphertext for this primitive
+    fn ciphertext_len(&self) -> usize;
+
+    /// The length in bytes of a shared secret for this primitive, at most 64
+    fn shared_secret_len(&self) -> usize;
+
+    /// Generate a new key pair
+    fn generate(&mut self, rng: &mut dyn Random);
+
+    /// Get the public key
+    fn pubkey(&self) -> &[u8];
+
+    /// Encapsulate a new shared secret to `pubkey`, writing the secret to `shared_secret_out`
+    /// and the ciphertext that carries it to `ciphertext_out`.
+    #[allow(clippy::result_unit_err)]
+    fn encapsulate(&self, pubkey: &[u8], rng: &mut dyn Random, shared_secret_out: &mut [u8], ciphertext_out: &mut [u8]) -> Result<(), ()>;
+
+    /// Recover the shared secret carried by `ciphertext` with the private key.
+    #[allow(clippy::result_unit_err)]
+    fn decapsulate(&self, ciphertext: &[u8], shared_secret_out: &mut [u8]) -> Result<(), ()>;
+}
+
 /// Cipher operations
 pub trait Cipher : Send + Sync {
     /// The string that the Noise spec defines for the primitive
     fn name(&self) -> &'static str;
 
+    /// The length of the authentication tag appended to each ciphertext. Ciphers with tags
+    /// over 64 bytes are refused by the `Builder` with `InitStage::ValidateCipherTypes`.
+    fn tag_len(&self) -> usize {
+        TAGLEN
+    }
+
     /// Set the key
     fn set(&mut self, key: &[u8]);
 
     /// Encrypt (with associated data) a given plaintext.
     fn encrypt(&self, nonce: u64, authtext: &[u8], plaintext: &[u8], out: &mut[u8]) -> usize;
 
-    #[must_use]
+    /// Encrypt (with associated data) the first `plaintext_len` bytes of `in_out`, writing the
+    /// ciphertext and tag back over it. Returns the ciphertext length.
+    ///
+    /// The default implementation copies the plaintext aside and calls `encrypt()`, so
+    /// implementations that can work truly in place should override it.
+    fn encrypt_in_place(&self, nonce: u64, authtext: &[u8], in_out: &mut [u8], plaintext_len: usize) -> usize {
+        let plaintext = in_out[..plaintext_len].to_vec();
+        self.encrypt(nonce, authtext, &plaintext, in_out)
+    }
+
     /// Decrypt (with associated data) a given ciphertext.
+    ///
+    /// Implementations must verify the authentication tag in constant time, since the
+    /// ciphertext usually comes from an untrusted peer, and must fail without handing back
+    /// any unauthenticated plaintext in `out`.
+    #[allow(clippy::result_unit_err)]
     fn decrypt(&self, nonce: u64, authtext: &[u8], ciphertext: &[u8], out: &mut[u8]) -> Result<usize, ()>;
 
+    /// Decrypt (with associated data) the ciphertext in `in_out`, leaving the plaintext at the
+    /// start of it. Returns the plaintext length.
+    ///
+    /// The default implementation copies the ciphertext aside and calls `decrypt()`, so
+    /// implementations that can work truly in place should override it.
+    #[allow(clippy::result_unit_err)]
+    fn decrypt_in_place(&self, nonce: u64, authtext: &[u8], in_out: &mut [u8]) -> Result<usize, ()> {
+        let ciphertext = in_out.to_vec();
+        self.decrypt(nonce, authtext, &ciphertext, in_out)
+    }
+
     /// Rekey according to Section 4.2 of the Noise Specification, with a default
     /// implementation guaranteed to be secure for all ciphers.
     fn rekey(&mut self) {
-        let mut ciphertext = [0; CIPHERKEYLEN + TAGLEN];
-        let ciphertext_len = self.encrypt(u64::max_value(), &[], &[0; CIPHERKEYLEN], &mut ciphertext);
+        let mut buf = [0; CIPHERKEYLEN + MAXTAGLEN];
+        let ciphertext = &mut buf[..CIPHERKEYLEN + self.tag_len()];
+        let ciphertext_len = self.encrypt(u64::MAX, &[], &[0; CIPHERKEYLEN], ciphertext);
         assert_eq!(ciphertext_len, ciphertext.len());
         self.set(&ciphertext[..CIPHERKEYLEN]);
     }
@@ -129,3 +207,12 @@ pub trait Hash : Send + Sync {
         self.hmac(&temp_key, &in3[..hash_len+1], out3);
     }
 }
+
+/// A hook called after every change to a handshake's symmetric state, see
+/// `Builder::trace_symmetric_state()`.
+///
+/// It's given a label for the operation (`"initialize"`, `"mix_hash"`, `"mix_key"`, ...),
+/// the data that was mixed in, and then the new handshake hash `h` and chaining key `ck`.
+/// The data and `ck` are secret for most operations, so this is for debugging only.
+#[cfg(feature = "risky-trace")]
+pub type SymmetricTrace = fn(label: &str, data: &[u8], h: &[u8], ck: &[u8]);
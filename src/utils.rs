@@ -1,4 +1,6 @@
-use std::ops::{Deref, DerefMut};
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{self, Ordering};
 
 macro_rules! copy_slices {
     ($inslice:expr, $outslice:expr) => {
@@ -6,9 +8,19 @@ macro_rules! copy_slices {
     };
 }
 
+/// Overwrite secret material with zeros, using volatile writes so that the compiler
+/// can't optimize the stores away when the memory is about to be freed.
+pub fn secure_zero(data: &mut [u8]) {
+    for byte in data.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
 /// Toggle is similar to Option, except that even in the Off/"None" case, there is still
 /// an owned allocated inner object. This is useful for holding onto pre-allocated objects
 /// that can be toggled as enabled.
+#[derive(Clone, Copy)]
 pub struct Toggle<T> {
     inner: T,
     on: bool,
@@ -37,6 +49,10 @@ impl<T> Toggle<T> {
         self.on = true;
     }
 
+    pub fn disable(&mut self) {
+        self.on = false;
+    }
+
     pub fn is_on(&self) -> bool {
         self.on
     }
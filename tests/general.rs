@@ -1,4 +1,4 @@
-#![cfg_attr(feature = "cargo-clippy", allow(needless_range_loop))]
+#![allow(clippy::needless_range_loop)]
 #![allow(non_snake_case)]
 extern crate hex;
 extern crate snow;
@@ -6,7 +6,7 @@ extern crate x25519_dalek;
 extern crate rand_core;
 
 use hex::FromHex;
-use snow::{Builder, resolvers::{CryptoResolver, DefaultResolver}};
+use snow::{Builder, MidHandshake, NoiseStream, Padding, Session, StreamingTransport, resolvers::{CryptoResolver, DefaultResolver, FallbackResolver}};
 use snow::error::*;
 use snow::params::*;
 use snow::types::*;
@@ -31,7 +31,8 @@ impl RngCore for CountingRng {
     }
 
     fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
-        Ok(self.fill_bytes(dest))
+        self.fill_bytes(dest);
+        Ok(())
     }
 }
 
@@ -55,7 +56,7 @@ struct TestResolver {
 #[allow(unused)]
 impl TestResolver {
     pub fn new(next_byte: u8) -> Self {
-        TestResolver{ next_byte: next_byte, parent: DefaultResolver }
+        TestResolver{ next_byte, parent: DefaultResolver }
     }
 
     pub fn next_byte(&mut self, next_byte: u8) {
@@ -64,29 +65,206 @@ impl TestResolver {
 }
 
 impl CryptoResolver for TestResolver {
-    fn resolve_rng(&self) -> Option<Box<Random>> {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
         let rng = CountingRng(self.next_byte as u64);
         Some(Box::new(rng))
     }
 
-    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<Dh>> {
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
         self.parent.resolve_dh(choice)
     }
 
-    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<Hash>> {
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
         self.parent.resolve_hash(choice)
     }
 
-    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<Cipher>> {
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
         self.parent.resolve_cipher(choice)
     }
 }
 
+/// A ChaChaPoly wrapper with a padded, 24-byte tag, to make sure nothing assumes 16-byte tags.
+struct LongTagCipher(Box<dyn Cipher>);
+
+impl Cipher for LongTagCipher {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    fn tag_len(&self) -> usize {
+        24
+    }
+
+    fn set(&mut self, key: &[u8]) {
+        self.0.set(key)
+    }
+
+    fn encrypt(&self, nonce: u64, authtext: &[u8], plaintext: &[u8], out: &mut [u8]) -> usize {
+        let len = self.0.encrypt(nonce, authtext, plaintext, out);
+        for byte in &mut out[len..len + 8] {
+            *byte = 0;
+        }
+        len + 8
+    }
+
+    fn decrypt(&self, nonce: u64, authtext: &[u8], ciphertext: &[u8], out: &mut [u8]) -> Result<usize, ()> {
+        let (inner, padding) = ciphertext.split_at(ciphertext.len() - 8);
+        if padding.iter().any(|&byte| byte != 0) {
+            return Err(());
+        }
+        self.0.decrypt(nonce, authtext, inner, out)
+    }
+}
+
+struct LongTagResolver;
+
+impl CryptoResolver for LongTagResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        DefaultResolver.resolve_rng()
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        DefaultResolver.resolve_dh(choice)
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        DefaultResolver.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        DefaultResolver.resolve_cipher(choice).map(|cipher| Box::new(LongTagCipher(cipher)) as Box<dyn Cipher>)
+    }
+}
+
+/// Only knows about the long-tag cipher, so it has to be paired with another resolver.
+struct LongTagOnlyResolver;
+
+impl CryptoResolver for LongTagOnlyResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        None
+    }
+
+    fn resolve_dh(&self, _choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        None
+    }
+
+    fn resolve_hash(&self, _choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        None
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        LongTagResolver.resolve_cipher(choice)
+    }
+}
+
+/// Stands in for a key held by an HSM: DH operations are delegated to the "device", and the
+/// private key can't be read back.
+struct HardwareDh {
+    device: Box<dyn Dh>,
+}
+
+impl Dh for HardwareDh {
+    fn name(&self) -> &'static str {
+        self.device.name()
+    }
+
+    fn pub_len(&self) -> usize {
+        self.device.pub_len()
+    }
+
+    fn priv_len(&self) -> usize {
+        self.device.priv_len()
+    }
+
+    fn set(&mut self, _privkey: &[u8]) {
+        panic!("a hardware key can't be overwritten");
+    }
+
+    fn generate(&mut self, _rng: &mut dyn Random) {
+        panic!("a hardware key can't be regenerated");
+    }
+
+    fn pubkey(&self) -> &[u8] {
+        self.device.pubkey()
+    }
+
+    fn privkey(&self) -> &[u8] {
+        panic!("a hardware key never leaves the device");
+    }
+
+    fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> Result<(), ()> {
+        self.device.dh(pubkey, out)
+    }
+}
+
 pub fn copy_memory(data: &[u8], out: &mut [u8]) -> usize {
-    for count in 0..data.len() {out[count] = data[count];}
+    out[..data.len()].copy_from_slice(data);
     data.len()
 }
 
+/// Build both sides of `params` with whatever static keys the pattern calls for, run the
+/// handshake to completion, and check that transport messages make it across.
+fn assert_handshake_round_trip(params: NoiseParams) {
+    let pattern = params.handshake.pattern;
+    let init_keypair = Builder::new(params.clone()).generate_keypair().unwrap();
+    let resp_keypair = Builder::new(params.clone()).generate_keypair().unwrap();
+    let (init_static, init_public) = (&init_keypair.private[..], &init_keypair.public[..]);
+    let (resp_static, resp_public) = (&resp_keypair.private[..], &resp_keypair.public[..]);
+
+    let mut init_builder = Builder::new(params.clone());
+    if pattern.needs_local_static_key(true) {
+        init_builder = init_builder.local_private_key(init_static);
+    }
+    if pattern.need_known_remote_pubkey(true) {
+        init_builder = init_builder.remote_public_key(resp_public);
+    }
+    let mut resp_builder = Builder::new(params.clone());
+    if pattern.needs_local_static_key(false) {
+        resp_builder = resp_builder.local_private_key(resp_static);
+    }
+    if pattern.need_known_remote_pubkey(false) {
+        resp_builder = resp_builder.remote_public_key(init_public);
+    }
+    let mut h_i = init_builder.build_initiator().unwrap();
+    let mut h_r = resp_builder.build_responder().unwrap();
+
+    let overheads = params.handshake_overheads().unwrap();
+    let mut buffer_msg = [0u8; 1024];
+    let mut buffer_out = [0u8; 1024];
+    let mut turn = 0u8;
+    while !(h_i.is_handshake_finished() && h_r.is_handshake_finished()) {
+        let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+        let payload = [turn; 16];
+        let len = sender.write_message(&payload, &mut buffer_msg).unwrap();
+        assert_eq!(len, overheads[turn as usize] + payload.len(), "{}: handshake message {}", params.name, turn);
+        let len = receiver.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert_eq!(&buffer_out[..len], &payload[..], "{}: handshake message {}", params.name, turn);
+        turn += 1;
+    }
+
+    if pattern.need_known_remote_pubkey(false) || pattern.needs_local_static_key(true) {
+        assert_eq!(h_r.get_remote_static().unwrap(), init_public, "{}", params.name);
+    }
+    if pattern.needs_local_static_key(false) {
+        assert_eq!(h_i.get_remote_static().unwrap(), resp_public, "{}", params.name);
+    }
+
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+
+    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet", "{}", params.name);
+
+    if pattern.is_oneway() {
+        assert!(h_r.write_message(b"hack the planet", &mut buffer_msg).is_err());
+    } else {
+        let len = h_r.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+        let len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert_eq!(&buffer_out[..len], b"hack the planet", "{}", params.name);
+    }
+}
+
 #[test]
 fn test_protocol_name() {
 
@@ -114,11 +292,19 @@ fn test_noise_session_transition_change() {
     let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
     let mut h_r = Builder::new(params).build_responder().unwrap();
 
+    assert!(h_i.is_my_turn());
+    assert!(!h_r.is_my_turn());
+    assert!(!h_i.is_handshake_finished());
+    assert!(!h_r.is_handshake_finished());
+
     let mut buffer_msg = [0u8; 200];
     let mut buffer_out = [0u8; 200];
     let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
     h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
 
+    assert!(!h_i.is_my_turn());
+    assert!(h_r.is_my_turn());
+
     let len = h_r.write_message(b"defg", &mut buffer_msg).unwrap();
     h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
 
@@ -128,6 +314,34 @@ fn test_noise_session_transition_change() {
     let _ = h_r.into_transport_mode().unwrap();
 }
 
+#[test]
+fn test_build_with_role() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap();
+    assert!(Role::Initiator.is_initiator());
+    assert!(!Role::Responder.is_initiator());
+
+    let mut h_i = Builder::new(params.clone()).build(Role::Initiator).unwrap();
+    let mut h_r = Builder::new(params).build(Role::Responder).unwrap();
+    assert!(h_i.is_initiator() && h_i.is_my_turn());
+    assert!(!h_r.is_initiator() && !h_r.is_my_turn());
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"defg", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // Each side sends with its own key and receives with the other's.
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+    let len = h_i.write_message(b"from the initiator", &mut buffer_msg).unwrap();
+    assert!(h_i.read_message(&buffer_msg[..len], &mut buffer_out).is_err());
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"from the initiator");
+    assert_eq!((h_i.sending_nonce().unwrap(), h_r.receiving_nonce().unwrap()), (1, 1));
+}
+
 #[test]
 fn test_sanity_session() {
     let params: NoiseParams = "Noise_NN_25519_AESGCM_SHA256".parse().unwrap();
@@ -261,6 +475,87 @@ fn test_NNpsk0_sanity_session() {
     assert_eq!(&buffer_out[..len], b"hack the planet");
 }
 
+#[test]
+fn test_NNpsk0_authenticates_with_psk_only() {
+    let params: NoiseParams = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    assert!(params.handshake.is_psk());
+    assert!(!params.handshake.pattern.needs_local_static_key(true));
+    assert!(!params.handshake.pattern.needs_local_static_key(false));
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    // Neither side has a static key, so the shared PSK is all that lets either party
+    // tell the other apart from an impostor.
+    for &(init_psk, resp_psk, succeeds) in &[(7u8, 7u8, true), (7, 8, false)] {
+        let mut h_i = Builder::new(params.clone())
+            .psk(0, &[init_psk; 32])
+            .build_initiator()
+            .unwrap();
+        let mut h_r = Builder::new(params.clone())
+            .psk(0, &[resp_psk; 32])
+            .build_responder()
+            .unwrap();
+
+        let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+        let res = h_r.read_message(&buffer_msg[..len], &mut buffer_out);
+        if !succeeds {
+            assert!(matches!(res, Err(Error::Decrypt)));
+            continue;
+        }
+        assert_eq!(&buffer_out[..res.unwrap()], b"abc");
+
+        let len = h_r.write_message(b"defg", &mut buffer_msg).unwrap();
+        let len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert_eq!(&buffer_out[..len], b"defg");
+        assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap());
+        assert!(h_i.get_remote_static().is_none());
+        assert!(h_r.get_remote_static().is_none());
+
+        let mut h_i = h_i.into_transport_mode().unwrap();
+        let mut h_r = h_r.into_transport_mode().unwrap();
+        let len = h_r.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+        let len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert_eq!(&buffer_out[..len], b"hack the planet");
+    }
+
+    // Leaving the PSK out isn't caught until it's needed, since it can be set later.
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    assert!(matches!(h_i.write_message(b"", &mut buffer_msg),
+                     Err(Error::State(StateProblem::MissingPsk))));
+}
+
+#[test]
+fn test_NKpsk0_sanity_session() {
+    let params: NoiseParams = "Noise_NKpsk0_25519_ChaChaPoly_SHA256".parse().unwrap();
+    let static_r = Builder::new(params.clone()).generate_keypair().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .psk(0, &[9u8; 32])
+        .remote_public_key(&static_r.public)
+        .build_initiator()
+        .unwrap();
+    let mut h_r = Builder::new(params)
+        .psk(0, &[9u8; 32])
+        .local_private_key(&static_r.private)
+        .build_responder()
+        .unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let len = h_r.write_message(b"defg", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+
+    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+}
+
 #[test]
 fn test_XXpsk3_sanity_session() {
     let params: NoiseParams = "Noise_XXpsk3_25519_AESGCM_SHA256".parse().unwrap();
@@ -300,6 +595,37 @@ fn test_XXpsk3_sanity_session() {
     assert_eq!(&buffer_out[..len], b"hack the planet");
 }
 
+#[test]
+fn test_XXpsk0_psk3_mismatched_psk() {
+    let params: NoiseParams = "Noise_XXpsk0+psk3_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+
+    // Both PSKs have to match for the handshake to go through.
+    for &(resp_psk0, resp_psk3, succeeds) in &[(1u8, 3u8, true), (2, 3, false), (1, 4, false)] {
+        let mut h_i = Builder::new(params.clone())
+            .psk(0, &[1u8; 32])
+            .psk(3, &[3u8; 32])
+            .local_private_key(&get_inc_key(0))
+            .build_initiator()
+            .unwrap();
+        let mut h_r = Builder::new(params.clone())
+            .psk(0, &[resp_psk0; 32])
+            .psk(3, &[resp_psk3; 32])
+            .local_private_key(&get_inc_key(32))
+            .build_responder()
+            .unwrap();
+
+        let mut buffer_msg = [0u8; 200];
+        let mut buffer_out = [0u8; 200];
+        let mut result = Ok(0);
+        while result.is_ok() && !h_r.is_handshake_finished() {
+            let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+            let len = sender.write_message(b"abc", &mut buffer_msg).unwrap();
+            result = receiver.read_message(&buffer_msg[..len], &mut buffer_out);
+        }
+        assert_eq!(result.is_ok(), succeeds, "psk0: {}, psk3: {}", resp_psk0, resp_psk3);
+    }
+}
+
 #[test]
 fn test_rekey() {
     let params: NoiseParams = "Noise_NN_25519_AESGCM_SHA256".parse().unwrap();
@@ -332,9 +658,8 @@ fn test_rekey() {
     let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
     assert!(h_r.read_message(&buffer_msg[..len], &mut buffer_out).is_err());
 
-    // rekey incoming on responder
+    // rekey incoming on responder, after which the rejected message reads fine
     h_r.rekey_incoming().unwrap();
-    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
     let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
     assert_eq!(&buffer_out[..len], b"hack the planet");
 
@@ -345,11 +670,43 @@ fn test_rekey() {
 
     // rekey incoming on initiator
     h_i.rekey_incoming().unwrap();
-    let len = h_r.write_message(b"hack the planet", &mut buffer_msg).unwrap();
     let len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
     assert_eq!(&buffer_out[..len], b"hack the planet");
 }
 
+#[test]
+fn test_rekey_keeps_nonces() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let len = h_r.write_message(b"defg", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+    for _ in 0..3 {
+        let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+        h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    }
+
+    // Rekeying only replaces the key, so both sides carry on from the same nonce.
+    h_i.rekey_outgoing().unwrap();
+    h_r.rekey_incoming().unwrap();
+    assert_eq!(h_i.sending_nonce().unwrap(), 3);
+    assert_eq!(h_r.receiving_nonce().unwrap(), 3);
+    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+    assert_eq!(h_i.sending_nonce().unwrap(), 4);
+    assert_eq!(h_r.receiving_nonce().unwrap(), 4);
+}
+
 #[test]
 fn test_rekey_manually() {
     let params: NoiseParams = "Noise_NN_25519_AESGCM_SHA256".parse().unwrap();
@@ -380,9 +737,8 @@ fn test_rekey_manually() {
     let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
     assert!(h_r.read_message(&buffer_msg[..len], &mut buffer_out).is_err());
 
-    // rekey initiator (on responder)
+    // rekey initiator (on responder), after which the rejected message reads fine
     h_r.rekey_manually(Some(&[1u8; 32]), None).unwrap();
-    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
     let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
     assert_eq!(&buffer_out[..len], b"hack the planet");
 
@@ -393,7 +749,6 @@ fn test_rekey_manually() {
 
     // rekey responder (on initiator)
     h_i.rekey_manually(None, Some(&[1u8; 32])).unwrap();
-    let len = h_r.write_message(b"hack the planet", &mut buffer_msg).unwrap();
     let len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
     assert_eq!(&buffer_out[..len], b"hack the planet");
 }
@@ -416,6 +771,21 @@ fn test_handshake_message_undersized_output_buffer() {
     assert!(h_i.write_message(&[0u8; 400], &mut buffer_out).is_err());
 }
 
+#[test]
+fn test_handshake_message_undersized_output_buffer_untouched() {
+    let params: NoiseParams = "Noise_NN_25519_AESGCM_SHA256".parse().unwrap();
+    let mut h_i = Builder::new(params).build_initiator().unwrap();
+
+    // -> e (32 bytes) + unencrypted "abc" payload
+    let mut buffer_out = [0u8; 34];
+    assert!(h_i.write_message(b"abc", &mut buffer_out).is_err());
+    assert_eq!(&buffer_out[..], &[0u8; 34][..]);
+
+    let mut buffer_out = [0u8; 35];
+    let len = h_i.write_message(b"abc", &mut buffer_out).unwrap();
+    assert_eq!(len, 35);
+}
+
 #[test]
 fn test_transport_message_exceeds_max_len() {
     let params: NoiseParams = "Noise_N_25519_AESGCM_SHA256".parse().unwrap();
@@ -438,6 +808,35 @@ fn test_transport_message_undersized_output_buffer() {
     assert!(noise.write_message(&[0u8; 300], &mut buffer_out).is_err());
 }
 
+#[test]
+fn test_forged_transport_message_keeps_nonce() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+
+    // A forged packet is dropped without using up the nonce the genuine one needs.
+    for _ in 0..2 {
+        let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+        let mut forged = buffer_msg;
+        forged[len - 1] ^= 1;
+        assert!(matches!(h_r.read_message(&forged[..len], &mut buffer_out), Err(Error::Decrypt)));
+        assert!(matches!(h_r.read_message_in_place(&mut forged[..len]), Err(Error::Decrypt)));
+        assert_eq!(h_r.receiving_nonce().unwrap(), h_i.sending_nonce().unwrap() - 1);
+
+        let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert_eq!(&buffer_out[..len], b"hack the planet");
+    }
+}
+
 #[test]
 fn test_oneway_initiator_enforcements() {
     let params: NoiseParams = "Noise_N_25519_AESGCM_SHA256".parse().unwrap();
@@ -469,6 +868,43 @@ fn test_oneway_responder_enforcements() {
     assert!(resp.write_message(&[0u8; 1024], &mut buffer_resp).is_err());
 }
 
+#[test]
+fn test_oneway_reverse_direction_unavailable() {
+    let params: NoiseParams = "Noise_N_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let resp_builder = Builder::new(params.clone());
+    let rpk = resp_builder.generate_keypair().unwrap();
+
+    let mut resp = resp_builder.local_private_key(&rpk.private).build_responder().unwrap();
+    let mut init = Builder::new(params).remote_public_key(&rpk.public).build_initiator().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = init.write_message(&[], &mut buffer_msg).unwrap();
+    resp.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let mut init = init.into_transport_mode().unwrap();
+    let mut resp = resp.into_transport_mode().unwrap();
+
+    for res in [init.rekey_incoming(),
+                resp.rekey_outgoing(),
+                init.rekey_manually(Some(&[1u8; 32]), Some(&[2u8; 32])),
+                resp.rekey_manually(None, Some(&[2u8; 32])),
+                resp.write_message(b"back", &mut buffer_msg).map(|_| ())] {
+        match res {
+            Err(Error::State(StateProblem::OneWay)) => {},
+            res => panic!("the responder->initiator direction was usable: {:?}", res),
+        }
+    }
+    #[cfg(feature = "risky-key-export")]
+    assert!(init.dangerously_get_raw_split().is_err());
+
+    // The initiator->responder direction is unaffected, including by the refused rekey.
+    init.rekey_outgoing().unwrap();
+    resp.rekey_incoming().unwrap();
+    let len = init.write_message(b"forward", &mut buffer_msg).unwrap();
+    let len = resp.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"forward");
+}
+
 #[test]
 fn test_buffer_issues() {
     let params: NoiseParams = "Noise_NN_25519_AESGCM_SHA256".parse().unwrap();
@@ -483,6 +919,36 @@ fn test_buffer_issues() {
     assert!(res.is_err());
 }
 
+#[test]
+fn test_write_into_short_buffer() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&get_inc_key(32))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // "<- e, ee, s, es" with a payload, cut short anywhere in the ephemeral, the static key,
+    // or the payload and its tag.
+    let needed = 32 + (32 + 16) + (3 + 16);
+    for short_len in 0..needed {
+        match h_r.write_message(b"abc", &mut buffer_msg[..short_len]) {
+            Err(Error::Input) => {},
+            res => panic!("{}-byte buffer wasn't rejected: {:?}", short_len, res),
+        }
+    }
+    let len = h_r.write_message(b"abc", &mut buffer_msg[..needed]).unwrap();
+    assert_eq!(len, needed);
+    let len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"abc");
+}
+
 #[test]
 fn test_read_buffer_issues() {
     let params: NoiseParams = "Noise_XK_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
@@ -521,52 +987,320 @@ fn test_read_buffer_issues() {
 }
 
 #[test]
-fn test_buffer_issues_encrypted_handshake() {
-    let params: NoiseParams = "Noise_IKpsk2_25519_AESGCM_SHA256".parse().unwrap();
-
-    let b_i = Builder::new(params.clone());
-    let b_r = Builder::new(params);
+fn test_read_truncated_handshake_message() {
+    let params: NoiseParams = "Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
 
-    let static_i = b_i.generate_keypair().unwrap();
-    let static_r = b_r.generate_keypair().unwrap();
+    let builder_r = Builder::new(params.clone());
+    let keypair_r = builder_r.generate_keypair().unwrap();
+    let mut h_r = builder_r
+        .local_private_key(&keypair_r.private)
+        .build_responder()
+        .unwrap();
 
-    let mut h_i = b_i
-        .psk(2, &[32u8; 32])
-        .local_private_key(&static_i.private)
-        .remote_public_key(&static_r.public)
+    let builder_i = Builder::new(params);
+    let key_i = builder_i.generate_keypair().unwrap().private;
+    let mut h_i = builder_i
+        .local_private_key(&key_i)
+        .remote_public_key(&keypair_r.public)
         .build_initiator()
         .unwrap();
-    let mut h_r = b_r
-        .psk(2, &[32u8; 32])
-        .local_private_key(&static_r.private)
-        .remote_public_key(&static_i.public)
-        .build_responder()
-        .unwrap();
 
-    let mut buffer_msg = [0u8; 200];
-    let mut buffer_out = [0u8; 2];
+    let mut buffer_msg = [0u8; 1024];
+    let mut buffer_out = [0u8; 1024];
     let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
-    let res = h_r.read_message(&buffer_msg[..len], &mut buffer_out);
 
-    assert!(res.is_err());
+    for truncated_len in 0..len {
+        match h_r.read_message(&buffer_msg[..truncated_len], &mut buffer_out) {
+            Err(Error::Input) | Err(Error::Decrypt) => {},
+            res => panic!("truncated message wasn't rejected: {:?}", res),
+        }
+    }
+
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"abc");
 }
 
 #[test]
-fn test_send_trait() {
-    use std::thread;
-    use std::sync::mpsc::channel;
+fn test_read_into_short_buffer() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
 
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
 
-    let (tx, rx) = channel();
-    thread::spawn(move|| {
-        let session = Builder::new("Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap())
-                        .build_initiator().unwrap();
-        tx.send(session).unwrap();
-    });
-    let _session = rx.recv().expect("failed to receive noise session");
-}
+    // Unencrypted handshake payload.
+    let len = h_i.write_message(b"hello world", &mut buffer_msg).unwrap();
+    match h_r.read_message(&buffer_msg[..len], &mut buffer_out[..4]) {
+        Err(Error::BufferTooSmall(11)) => {},
+        res => panic!("short buffer wasn't rejected: {:?}", res),
+    }
+    let payload_len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..payload_len], b"hello world");
+
+    // Encrypted handshake payload.
+    let len = h_r.write_message(b"hello world", &mut buffer_msg).unwrap();
+    match h_i.read_message(&buffer_msg[..len], &mut buffer_out[..10]) {
+        Err(Error::BufferTooSmall(11)) => {},
+        res => panic!("short buffer wasn't rejected: {:?}", res),
+    }
+    let payload_len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..payload_len], b"hello world");
 
-#[test]
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+
+    let len = h_i.write_message(b"hello world", &mut buffer_msg).unwrap();
+    match h_r.read_message(&buffer_msg[..len], &mut []) {
+        Err(Error::BufferTooSmall(11)) => {},
+        res => panic!("short buffer wasn't rejected: {:?}", res),
+    }
+    // The nonce didn't move, so the same message still reads.
+    let payload_len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..payload_len], b"hello world");
+}
+
+#[test]
+fn test_peek_remote_ephemeral() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(32))
+        .build_responder().unwrap();
+    let replay_target = Builder::new(params)
+        .local_private_key(&get_inc_key(32))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    assert!(h_i.peek_remote_ephemeral(&buffer_msg).is_none());
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    let first = buffer_msg[..len].to_vec();
+
+    assert!(h_r.peek_remote_ephemeral(&first[..31]).is_none());
+    let peeked = h_r.peek_remote_ephemeral(&first).unwrap().to_vec();
+    assert!(h_r.get_remote_ephemeral().is_none());
+    h_r.read_message(&first, &mut buffer_out).unwrap();
+    assert_eq!(h_r.get_remote_ephemeral().unwrap(), &peeked[..]);
+
+    // A second responder sees the same ephemeral in a replayed first message.
+    assert_eq!(replay_target.peek_remote_ephemeral(&first).unwrap(), &peeked[..]);
+
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    let peeked = h_i.peek_remote_ephemeral(&buffer_msg[..len]).unwrap().to_vec();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(h_i.get_remote_ephemeral().unwrap(), &peeked[..]);
+
+    // "-> s, se" has no ephemeral.
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    assert!(h_r.peek_remote_ephemeral(&buffer_msg[..len]).is_none());
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert!(h_r.peek_remote_ephemeral(&buffer_msg[..len]).is_none());
+}
+
+#[test]
+fn test_tampered_handshake_message() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&get_inc_key(32))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // "<- e, ee, s, es" with a payload: whether the flipped bit lands in the ephemeral, the
+    // encrypted static key or the payload, the initiator sees the same error.
+    let len = h_r.write_message(b"payload", &mut buffer_msg).unwrap();
+    for i in 0..len {
+        let mut tampered = buffer_msg;
+        tampered[i] ^= 0x01;
+        match h_i.read_message(&tampered[..len], &mut buffer_out) {
+            Err(Error::Decrypt) => {},
+            res => panic!("flipping byte {} wasn't rejected as a decrypt error: {:?}", i, res),
+        }
+    }
+
+    let payload_len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..payload_len], b"payload");
+}
+
+#[test]
+fn test_tampered_last_handshake_message() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&get_inc_key(32))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // "-> s, se" reads with a key the responder already had, and a tampered payload fails
+    // after `se` has replaced it, so all of the cipher's state has to be put back for the
+    // genuine message to read after the tampered ones.
+    let len = h_i.write_message(b"payload", &mut buffer_msg).unwrap();
+    for i in 0..len {
+        let mut tampered = buffer_msg;
+        tampered[i] ^= 0x01;
+        match h_r.read_message(&tampered[..len], &mut buffer_out) {
+            Err(Error::Decrypt) => {},
+            res => panic!("flipping byte {} wasn't rejected as a decrypt error: {:?}", i, res),
+        }
+        assert!(h_r.get_remote_static().is_none());
+    }
+
+    let payload_len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..payload_len], b"payload");
+    assert_eq!(h_r.get_remote_static().unwrap(), &x25519::x25519(get_inc_key(0), x25519::X25519_BASEPOINT_BYTES)[..]);
+
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+}
+
+#[test]
+fn test_message_without_ephemeral() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&get_inc_key(32))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    // The responder can't get to its `ee` before it has the initiator's ephemeral.
+    match h_r.write_message(b"", &mut buffer_msg) {
+        Err(Error::State(StateProblem::NotTurnToWrite)) => {},
+        res => panic!("responder wrote before reading: {:?}", res),
+    }
+    match h_r.read_message(&[], &mut buffer_out) {
+        Err(Error::Input) => {},
+        res => panic!("message without an ephemeral wasn't rejected: {:?}", res),
+    }
+
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+
+    // Drop the responder's ephemeral, leaving its encrypted static key where it should be.
+    match h_i.read_message(&buffer_msg[32..len], &mut buffer_out) {
+        Err(Error::Input) | Err(Error::Decrypt) => {},
+        res => panic!("message without an ephemeral wasn't rejected: {:?}", res),
+    }
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+}
+
+#[test]
+fn test_read_after_handshake_finished() {
+    let params: NoiseParams = "Noise_N_25519_AESGCM_SHA256".parse().unwrap();
+    let mut h_i = Builder::new(params).remote_public_key(&[1u8; 32]).build_initiator().unwrap();
+
+    let mut buffer_msg = [0u8; 1024];
+    let mut buffer_out = [0u8; 1024];
+    h_i.write_message(&[], &mut buffer_msg).unwrap();
+
+    // Reads are now transport messages, which the initiator of a one-way pattern never gets.
+    match h_i.read_message(&buffer_msg[..48], &mut buffer_out) {
+        Err(Error::State(StateProblem::OneWay)) => {},
+        res => panic!("read after finished handshake wasn't rejected: {:?}", res),
+    }
+}
+
+#[test]
+fn test_transport_without_explicit_transition() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"defg", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // Still in handshake mode on both sides, but the session knows the handshake is done.
+    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    assert_eq!(len, 15 + 16);
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+
+    // Switching over afterwards keeps the nonces going.
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+    assert_eq!(h_i.sending_nonce().unwrap(), 1);
+    assert_eq!(h_r.receiving_nonce().unwrap(), 1);
+
+    let len = h_r.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    let len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+}
+
+#[test]
+fn test_buffer_issues_encrypted_handshake() {
+    let params: NoiseParams = "Noise_IKpsk2_25519_AESGCM_SHA256".parse().unwrap();
+
+    let b_i = Builder::new(params.clone());
+    let b_r = Builder::new(params);
+
+    let static_i = b_i.generate_keypair().unwrap();
+    let static_r = b_r.generate_keypair().unwrap();
+
+    let mut h_i = b_i
+        .psk(2, &[32u8; 32])
+        .local_private_key(&static_i.private)
+        .remote_public_key(&static_r.public)
+        .build_initiator()
+        .unwrap();
+    let mut h_r = b_r
+        .psk(2, &[32u8; 32])
+        .local_private_key(&static_r.private)
+        .remote_public_key(&static_i.public)
+        .build_responder()
+        .unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 2];
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+    let res = h_r.read_message(&buffer_msg[..len], &mut buffer_out);
+
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_send_trait() {
+    use std::thread;
+    use std::sync::mpsc::channel;
+
+
+    let (tx, rx) = channel();
+    thread::spawn(move|| {
+        let session = Builder::new("Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap())
+                        .build_initiator().unwrap();
+        tx.send(session).unwrap();
+    });
+    let _session = rx.recv().expect("failed to receive noise session");
+}
+
+#[test]
 fn test_checkpointing() {
     let params: NoiseParams = "Noise_XXpsk2_25519_AESGCM_SHA256".parse().unwrap();
 
@@ -646,6 +1380,67 @@ fn test_get_remote_static() {
     assert_eq!(h_r.get_remote_static().unwrap(), &x25519::x25519(get_inc_key(0), x25519::X25519_BASEPOINT_BYTES));
 }
 
+#[test]
+fn test_handshake_hash_survives_transport_mode() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let len = h_r.write_message(b"defg", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let hash_i = h_i.get_handshake_hash().unwrap().to_vec();
+    let hash_r = h_r.get_handshake_hash().unwrap().to_vec();
+    assert_eq!(hash_i.len(), 32);
+    assert_eq!(hash_i, hash_r);
+
+    let h_i = h_i.into_transport_mode().unwrap();
+    let h_r = h_r.into_stateless_transport_mode().unwrap();
+    assert_eq!(h_i.get_handshake_hash().unwrap(), &hash_i[..]);
+    assert_eq!(h_r.get_handshake_hash().unwrap(), &hash_r[..]);
+}
+
+#[test]
+fn test_get_remote_static_ik_and_transport() {
+    let params: NoiseParams = "Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let pub_i = x25519::x25519(get_inc_key(0), x25519::X25519_BASEPOINT_BYTES);
+    let pub_r = x25519::x25519(get_inc_key(1), x25519::X25519_BASEPOINT_BYTES);
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .remote_public_key(&pub_r)
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&get_inc_key(1))
+        .build_responder().unwrap();
+
+    let mut buf  = [0u8; 1024];
+    let mut buf2 = [0u8; 1024];
+
+    assert_eq!(h_i.get_remote_static().unwrap(), &pub_r);
+    assert!(h_r.get_remote_static().is_none());
+
+    // -> e, es, s, ss
+    let len = h_i.write_message(&[], &mut buf).unwrap();
+    let _   = h_r.read_message(&buf[..len], &mut buf2).unwrap();
+
+    // the responder can vet the initiator's identity before replying
+    assert_eq!(h_r.get_remote_static().unwrap(), &pub_i);
+
+    // <- e, ee, se
+    let len = h_r.write_message(&[], &mut buf).unwrap();
+    let _   = h_i.read_message(&buf[..len], &mut buf2).unwrap();
+
+    let h_i = h_i.into_transport_mode().unwrap();
+    let h_r = h_r.into_stateless_transport_mode().unwrap();
+    assert_eq!(h_i.get_remote_static().unwrap(), &pub_r);
+    assert_eq!(h_r.get_remote_static().unwrap(), &pub_i);
+}
+
 #[test]
 fn test_set_psk() {
     let params: NoiseParams = "Noise_XXpsk3_25519_AESGCM_SHA256".parse().unwrap();
@@ -736,3 +1531,2841 @@ fn test_stateless_sanity_session() {
     let len = h_r.read_message_with_nonce(1337, &buffer_msg[..len], &mut buffer_out).unwrap();
     assert_eq!(&buffer_out[..len], b"hack the planet");
 }
+
+#[test]
+fn test_stateless_out_of_order() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let h_i = h_i.into_stateless_transport_mode().unwrap();
+    let h_r = h_r.into_stateless_transport_mode().unwrap();
+
+    let mut messages = vec![];
+    for nonce in 0..4u64 {
+        let mut msg = [0u8; 64];
+        let len = h_i.write_message_with_nonce(nonce, &[nonce as u8; 8], &mut msg).unwrap();
+        messages.push((nonce, msg[..len].to_vec()));
+    }
+
+    for &i in &[3usize, 1, 0, 2] {
+        let (nonce, ref msg) = messages[i];
+        let len = h_r.read_message_with_nonce(nonce, msg, &mut buffer_out).unwrap();
+        assert_eq!(&buffer_out[..len], &[nonce as u8; 8]);
+    }
+
+    // The wrong nonce doesn't authenticate.
+    assert!(h_r.read_message_with_nonce(1, &messages[2].1, &mut buffer_out).is_err());
+}
+
+#[test]
+fn test_cipher_tag_len_is_respected() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::with_resolver(params.clone(), Box::new(LongTagResolver))
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::with_resolver(params, Box::new(LongTagResolver))
+        .local_private_key(&get_inc_key(1))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 512];
+    let mut buffer_out = [0u8; 512];
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+    assert_eq!(len, 32 + 3);
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let len = h_r.write_message(b"defg", &mut buffer_msg).unwrap();
+    assert_eq!(len, 32 + (32 + 24) + (4 + 24));
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    assert_eq!(len, (32 + 24) + 24);
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+
+    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    assert_eq!(len, 15 + 24);
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+    assert!(h_i.write_message(b"hack the planet", &mut buffer_msg[..15 + 23]).is_err());
+    assert!(h_r.read_message(&buffer_msg[..23], &mut buffer_out).is_err());
+
+    // The default rekey encrypts with the cipher's own tag length too.
+    h_i.rekey_outgoing().unwrap();
+    h_r.rekey_incoming().unwrap();
+    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+}
+
+#[test]
+fn test_stateless_reserved_nonce() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let len = h_r.write_message(b"defg", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let h_i = h_i.into_stateless_transport_mode().unwrap();
+    let h_r = h_r.into_stateless_transport_mode().unwrap();
+
+    match h_i.write_message_with_nonce(u64::MAX, b"hack the planet", &mut buffer_msg) {
+        Err(Error::State(StateProblem::Exhausted)) => {},
+        _ => panic!("the maximum nonce is reserved"),
+    }
+
+    let msg_len = h_i.write_message_with_nonce(u64::MAX - 1, b"hack the planet", &mut buffer_msg).unwrap();
+    let len = h_r.read_message_with_nonce(u64::MAX - 1, &buffer_msg[..msg_len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+
+    match h_r.read_message_with_nonce(u64::MAX, &buffer_msg[..msg_len], &mut buffer_out) {
+        Err(Error::State(StateProblem::Exhausted)) => {},
+        _ => panic!("the maximum nonce is reserved"),
+    }
+}
+
+#[test]
+fn test_fundamental_interactive_patterns() {
+    for pattern in &["NN", "NK", "NX", "XN", "XK", "XX", "KN", "KK", "KX", "IN", "IK", "IX"] {
+        let params: NoiseParams = format!("Noise_{}_25519_ChaChaPoly_BLAKE2s", pattern).parse().unwrap();
+        assert!(!params.handshake.pattern.is_oneway());
+        assert_handshake_round_trip(params);
+    }
+}
+
+#[test]
+fn test_deferred_interactive_patterns() {
+    for pattern in &["NK1", "NX1", "X1N", "X1K", "XK1", "X1K1", "X1X", "XX1", "X1X1", "K1N", "K1K",
+                     "KK1", "K1K1", "K1X", "KX1", "K1X1", "I1N", "I1K", "IK1", "I1K1", "I1X", "IX1",
+                     "I1X1"] {
+        let params: NoiseParams = format!("Noise_{}_25519_ChaChaPoly_BLAKE2s", pattern).parse().unwrap();
+        assert_handshake_round_trip(params);
+    }
+
+    // Deferring the `es` in NK1 means its first payload goes out unencrypted, unlike NK's.
+    let resp_public = x25519::x25519(get_inc_key(32), x25519::X25519_BASEPOINT_BYTES);
+    let mut buffer_msg = [0u8; 200];
+    for &(pattern, encrypted) in &[("NK", true), ("NK1", false)] {
+        let mut h_i = Builder::new(format!("Noise_{}_25519_ChaChaPoly_BLAKE2s", pattern).parse().unwrap())
+            .remote_public_key(&resp_public)
+            .build_initiator().unwrap();
+        h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+        assert_eq!(h_i.was_write_payload_encrypted(), encrypted, "{}", pattern);
+    }
+}
+
+#[test]
+fn test_oneway_patterns() {
+    for pattern in &["N", "K", "X"] {
+        let params: NoiseParams = format!("Noise_{}_25519_ChaChaPoly_BLAKE2s", pattern).parse().unwrap();
+        assert!(params.handshake.pattern.is_oneway());
+        assert_handshake_round_trip(params);
+    }
+}
+
+#[test]
+fn test_every_supported_protocol() {
+    // Every pattern with every primitive, with both peers built independently of each other.
+    let mut count = 0;
+    for pattern in snow::params::supported_patterns() {
+        for dh in snow::params::supported_dh() {
+            for cipher in snow::params::supported_ciphers() {
+                for hash in snow::params::supported_hashes() {
+                    let params: NoiseParams = format!("Noise_{}_{}_{}_{}", pattern, dh, cipher, hash).parse().unwrap();
+                    assert_handshake_round_trip(params);
+                    count += 1;
+                }
+            }
+        }
+    }
+    assert_eq!(count, snow::params::supported_patterns().len() * snow::params::supported_dh().len()
+                      * snow::params::supported_ciphers().len() * snow::params::supported_hashes().len());
+    assert!(count > 0);
+}
+
+#[test]
+fn test_custom_pattern() {
+    use snow::params::Token::*;
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let init_keypair = Builder::new(params.clone()).generate_keypair().unwrap();
+    let resp_keypair = Builder::new(params.clone()).generate_keypair().unwrap();
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    // Spelling out XX under its own name interoperates with the named pattern.
+    let xx = CustomPattern::new("XX", &[], &[], &[&[E], &[E, Dhee, S, Dhse], &[S, Dhse]]).unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .custom_pattern(xx)
+        .local_private_key(&init_keypair.private)
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params.clone())
+        .local_private_key(&resp_keypair.private)
+        .build_responder().unwrap();
+    assert_eq!(h_i.get_protocol_name(), "Noise_XX_25519_ChaChaPoly_BLAKE2s");
+    while !(h_i.is_handshake_finished() && h_r.is_handshake_finished()) {
+        let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+        let len = sender.write_message(b"", &mut buffer_msg).unwrap();
+        receiver.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    }
+    assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap());
+
+    // A pattern of our own, with a fourth message and a PSK, run between two custom peers.
+    let custom = CustomPattern::new("XXplus", &[], &[], &[&[E], &[E, Dhee, S, Dhse], &[S, Dhse], &[Psk(0)]]).unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .custom_pattern(custom.clone())
+        .psks(&[&[7u8; 32]])
+        .local_private_key(&init_keypair.private)
+        .require_authentication(true, true)
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params.clone())
+        .custom_pattern(custom.clone())
+        .psk(0, &[7u8; 32])
+        .local_private_key(&resp_keypair.private)
+        .build_responder().unwrap();
+    assert_eq!(h_r.get_protocol_name(), "Noise_XXplus_25519_ChaChaPoly_BLAKE2s");
+    for turn in 0..4u8 {
+        let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+        assert!(!sender.is_handshake_finished());
+        let len = sender.write_message(&[turn], &mut buffer_msg).unwrap();
+        let len = receiver.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert_eq!(&buffer_out[..len], &[turn]);
+    }
+    assert_eq!(h_i.get_remote_static().unwrap(), &resp_keypair.public[..]);
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+    let len = h_r.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    let len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+
+    // The builder checks its inputs against the custom pattern, not the named one.
+    assert!(matches!(Builder::new(params.clone()).custom_pattern(custom.clone()).build_initiator(),
+                     Err(Error::Prereq(Prerequisite::LocalPrivateKey))));
+    assert!(matches!(Builder::new(params.clone()).custom_pattern(custom.clone())
+                         .local_private_key(&init_keypair.private)
+                         .psk(1, &[7u8; 32])
+                         .build_initiator(),
+                     Err(Error::Init(InitStage::ValidatePskPosition))));
+    let oneway = CustomPattern::new("Nish", &[], &[S], &[&[E, Dhes]]).unwrap();
+    assert!(matches!(Builder::new(params).custom_pattern(oneway)
+                         .remote_public_key(&resp_keypair.public)
+                         .require_authentication(false, true)
+                         .build_initiator(),
+                     Err(Error::Init(InitStage::ValidateAuthentication))));
+}
+
+#[test]
+fn test_dh_tokens_per_role() {
+    use snow::params::Token::*;
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let init_keypair = Builder::new(params.clone()).generate_keypair().unwrap();
+    let resp_keypair = Builder::new(params.clone()).generate_keypair().unwrap();
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    // Each DH token, sent once by each party, in a pattern where only the keys it names exist
+    // by then. Reading a token from the wrong side's point of view (`es` as `se`, say) would
+    // reach for a key that isn't there and fail with MissingKeyMaterial.
+    type Pattern<'a> = (&'a str, &'a [Token], &'a [Token], &'a [&'a [Token]]);
+    let patterns: [Pattern; 8] = [
+        ("eeI", &[], &[], &[&[E], &[E], &[Dhee]]),
+        ("eeR", &[], &[], &[&[E], &[E, Dhee]]),
+        ("esI", &[], &[S], &[&[E, Dhes]]),
+        ("esR", &[S], &[], &[&[E], &[E, Dhes]]),
+        ("seI", &[], &[], &[&[S], &[E], &[Dhse]]),
+        ("seR", &[], &[S], &[&[E], &[Dhse]]),
+        ("ssI", &[S], &[S], &[&[Dhss]]),
+        ("ssR", &[], &[], &[&[S], &[S, Dhss]]),
+    ];
+    for (name, init_premessage, resp_premessage, messages) in patterns.iter() {
+        let pattern = CustomPattern::new(name, init_premessage, resp_premessage, messages).unwrap();
+        let mut builder_i = Builder::new(params.clone()).custom_pattern(pattern.clone());
+        let mut builder_r = Builder::new(params.clone()).custom_pattern(pattern.clone());
+        if pattern.needs_local_static_key(Role::Initiator) {
+            builder_i = builder_i.local_private_key(&init_keypair.private);
+        }
+        if pattern.needs_local_static_key(Role::Responder) {
+            builder_r = builder_r.local_private_key(&resp_keypair.private);
+        }
+        if pattern.need_known_remote_pubkey(Role::Initiator) {
+            builder_i = builder_i.remote_public_key(&resp_keypair.public);
+        }
+        if pattern.need_known_remote_pubkey(Role::Responder) {
+            builder_r = builder_r.remote_public_key(&init_keypair.public);
+        }
+        let mut h_i = builder_i.build_initiator().unwrap();
+        let mut h_r = builder_r.build_responder().unwrap();
+
+        while !(h_i.is_handshake_finished() && h_r.is_handshake_finished()) {
+            let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+            let len = sender.write_message(b"", &mut buffer_msg).unwrap_or_else(|e| panic!("{}: {:?}", name, e));
+            receiver.read_message(&buffer_msg[..len], &mut buffer_out).unwrap_or_else(|e| panic!("{}: {:?}", name, e));
+        }
+        assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap(), "{}", name);
+
+        let mut h_i = h_i.into_transport_mode().unwrap();
+        let mut h_r = h_r.into_transport_mode().unwrap();
+        let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+        let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap_or_else(|e| panic!("{}: {:?}", name, e));
+        assert_eq!(&buffer_out[..len], b"hack the planet");
+    }
+
+    // The same reach for a missing key is how the pattern checks catch a DH on unsent keys.
+    assert!(CustomPattern::new("seBad", &[], &[], &[&[E], &[E], &[Dhse]]).is_err());
+    assert!(CustomPattern::new("esBad", &[], &[], &[&[S], &[E], &[Dhes]]).is_err());
+}
+
+#[test]
+fn test_noise_pipes_fallback() {
+    let params: NoiseParams = "Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let fallback_params: NoiseParams = "Noise_XXfallback_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let init_static = get_inc_key(0);
+    let resp_static = get_inc_key(32);
+    let init_public = x25519::x25519(init_static, x25519::X25519_BASEPOINT_BYTES);
+    let resp_public = x25519::x25519(resp_static, x25519::X25519_BASEPOINT_BYTES);
+    let stale_resp_public = x25519::x25519(get_inc_key(64), x25519::X25519_BASEPOINT_BYTES);
+
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&init_static)
+        .remote_public_key(&stale_resp_public)
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&resp_static)
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    // The initiator's cached copy of the responder's static key is out of date, so IK fails.
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+    assert!(h_r.read_message(&buffer_msg[..len], &mut buffer_out).is_err());
+
+    let mut h_r = h_r.into_fallback(fallback_params.clone(), &[]).unwrap();
+    assert!(h_r.is_my_turn());
+    assert!(!h_r.is_initiator());
+    let len = h_r.write_message(b"defg", &mut buffer_msg).unwrap();
+
+    assert!(h_i.read_message(&buffer_msg[..len], &mut buffer_out).is_err());
+    let mut h_i = h_i.into_fallback(fallback_params, &[]).unwrap();
+    assert!(!h_i.is_my_turn());
+    assert!(h_i.is_initiator());
+    let len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"defg");
+
+    let len = h_i.write_message(b"hij", &mut buffer_msg).unwrap();
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hij");
+
+    assert_eq!(h_i.get_remote_static().unwrap(), &resp_public[..]);
+    assert_eq!(h_r.get_remote_static().unwrap(), &init_public[..]);
+    assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap());
+
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+
+    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+
+    let len = h_r.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    let len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+}
+
+#[test]
+fn test_fallback_requires_fallback_params() {
+    let params: NoiseParams = "Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .remote_public_key(&get_inc_key(1))
+        .build_initiator().unwrap();
+    match h_i.into_fallback("Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap(), &[]) {
+        Err(Error::Init(InitStage::ValidateFallback)) => {},
+        _ => panic!("fallback to a pattern without the fallback modifier"),
+    }
+
+    // The initiator hasn't sent an ephemeral yet, so there's nothing to fall back with.
+    let h_i = Builder::new(params)
+        .local_private_key(&get_inc_key(0))
+        .remote_public_key(&get_inc_key(1))
+        .build_initiator().unwrap();
+    match h_i.into_fallback("Noise_XXfallback_25519_ChaChaPoly_BLAKE2s".parse().unwrap(), &[]) {
+        Err(Error::State(StateProblem::MissingKeyMaterial)) => {},
+        _ => panic!("fallback without an ephemeral key"),
+    }
+}
+
+#[test]
+fn test_deterministic_rng() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut first_messages = vec![];
+    for &seed in &[1u8, 1, 2] {
+        let mut h_i = Builder::with_resolver(params.clone(), Box::new(TestResolver::new(seed)))
+            .build_initiator().unwrap();
+        let mut buffer_msg = [0u8; 200];
+        let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+        first_messages.push(buffer_msg[..len].to_vec());
+    }
+
+    assert_eq!(first_messages[0], first_messages[1]);
+    assert!(first_messages[0] != first_messages[2]);
+}
+
+#[test]
+fn test_fixed_ephemeral() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let ephemeral = get_inc_key(0);
+    let ephemeral_public = x25519::x25519(ephemeral, x25519::X25519_BASEPOINT_BYTES);
+
+    let mut h_i = Builder::new(params)
+        .fixed_ephemeral_key_for_testing_only(&ephemeral)
+        .build_initiator().unwrap();
+    let mut buffer_msg = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    assert_eq!(&buffer_msg[..len], &ephemeral_public[..]);
+}
+
+#[test]
+fn test_next_message_overhead() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&get_inc_key(32))
+        .build_responder().unwrap();
+
+    let expected = [32, 32 + (32 + 16) + 16, (32 + 16) + 16];
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    for &overhead in &expected {
+        assert_eq!(h_i.get_next_message_overhead(), overhead);
+        assert_eq!(h_r.get_next_message_overhead(), overhead);
+        let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+        let len = sender.write_message(b"abc", &mut buffer_msg).unwrap();
+        assert_eq!(len, overhead + 3);
+        receiver.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    }
+
+    assert_eq!(h_i.get_next_message_overhead(), 16);
+    let h_i = h_i.into_transport_mode().unwrap();
+    let h_r = h_r.into_stateless_transport_mode().unwrap();
+    assert_eq!(h_i.get_next_message_overhead(), 16);
+    assert_eq!(h_r.get_next_message_overhead(), 16);
+}
+
+#[test]
+fn test_framed_messages() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    // Write a few frames back to back, as they'd show up on a stream.
+    let mut stream = vec![0u8; 1024];
+    let mut buffer_out = [0u8; 1024];
+    let len = h_i.write_framed(b"abc", &mut stream).unwrap();
+    assert_eq!(&stream[..2], &[0, 32 + 3]);
+    let (consumed, payload_len) = h_r.read_framed(&stream[..len], &mut buffer_out).unwrap();
+    assert_eq!((consumed, &buffer_out[..payload_len]), (len, &b"abc"[..]));
+
+    let len = h_r.write_framed(b"defg", &mut stream).unwrap();
+    h_i.read_framed(&stream[..len], &mut buffer_out).unwrap();
+
+    let mut offset = 0;
+    for payload in &[&b"hack"[..], b"the", b"planet"] {
+        offset += h_i.write_framed(payload, &mut stream[offset..]).unwrap();
+    }
+
+    // An incomplete frame is rejected without being consumed.
+    match h_r.read_framed(&stream[..2 + 4 + 16 - 1], &mut buffer_out) {
+        Err(Error::Input) => {},
+        res => panic!("incomplete frame wasn't rejected: {:?}", res),
+    }
+
+    let mut read = 0;
+    for payload in &[&b"hack"[..], b"the", b"planet"] {
+        let (consumed, len) = h_r.read_framed(&stream[read..offset], &mut buffer_out).unwrap();
+        assert_eq!(&buffer_out[..len], *payload);
+        read += consumed;
+    }
+    assert_eq!(read, offset);
+
+    // The output buffer has to hold the length prefix too.
+    assert!(h_i.write_framed(b"abc", &mut stream[..2 + 3 + 15]).is_err());
+    assert!(h_i.write_framed(&[0u8; 65535 - 15], &mut vec![0u8; 70000]).is_err());
+}
+
+#[test]
+fn test_chunked_messages() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = vec![0u8; 300_000];
+    let mut buffer_out = vec![0u8; 300_000];
+    match h_i.write_message_chunked(b"abc", &mut buffer_msg) {
+        Err(Error::State(StateProblem::HandshakeNotFinished)) => {},
+        res => panic!("chunked write during the handshake wasn't rejected: {:?}", res),
+    }
+
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+
+    let chunk_len: usize = 65535 - 16;
+    for &payload_len in &[0, 1, chunk_len, chunk_len + 1, 3 * chunk_len, 200_000] {
+        let payload: Vec<u8> = (0..payload_len).map(|i| i as u8).collect();
+        let chunks = std::cmp::max(1, payload_len.div_ceil(chunk_len));
+
+        let len = h_i.write_message_chunked(&payload, &mut buffer_msg).unwrap();
+        assert_eq!(len, payload_len + chunks * 16);
+        assert_eq!(h_i.sending_nonce().unwrap(), h_r.receiving_nonce().unwrap() + chunks as u64);
+
+        let len = h_r.read_message_chunked(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert_eq!(&buffer_out[..len], &payload[..]);
+    }
+
+    // Nothing is written, and no nonces are used up, if the output can't hold every chunk.
+    let nonce = h_i.sending_nonce().unwrap();
+    assert!(h_i.write_message_chunked(&[0u8; 70_000], &mut buffer_msg[..70_000 + 16]).is_err());
+    assert_eq!(h_i.sending_nonce().unwrap(), nonce);
+}
+
+#[test]
+fn test_errors_compose_with_std_error() {
+    fn parse(name: &str) -> Result<NoiseParams, Box<dyn std::error::Error>> {
+        Ok(name.parse()?)
+    }
+
+    let err = parse("Noise_NK_25519_ChaChaPoly_BLAH256").unwrap_err();
+    assert_eq!(err.to_string(), "pattern error: UnsupportedHashType");
+    assert!(err.downcast_ref::<Error>().is_some());
+
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"abc", &mut buffer_msg).unwrap();
+    buffer_msg[len - 1] ^= 1;
+
+    let err: Box<dyn std::error::Error> = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap_err().into();
+    assert_eq!(err.to_string(), "decrypt error");
+}
+
+#[test]
+fn test_out_of_turn_messages() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&get_inc_key(32))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    match h_i.read_message(&[0u8; 32], &mut buffer_out) {
+        Err(Error::State(StateProblem::NotTurnToRead)) => {},
+        res => panic!("initiator read before writing: {:?}", res),
+    }
+
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    match h_i.write_message(b"", &mut buffer_msg) {
+        Err(Error::State(StateProblem::NotTurnToWrite)) => {},
+        res => panic!("initiator wrote twice in a row: {:?}", res),
+    }
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    match h_r.read_message(&buffer_msg[..len], &mut buffer_out) {
+        Err(Error::State(StateProblem::NotTurnToRead)) => {},
+        res => panic!("responder read twice in a row: {:?}", res),
+    }
+
+    // The rejected calls didn't disturb the handshake.
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert!(h_i.is_handshake_finished() && h_r.is_handshake_finished());
+}
+
+#[test]
+fn test_export_secret() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA512".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let mut secret_i = [0u8; 64];
+    let mut secret_r = [0u8; 64];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    match h_r.export_secret(b"side channel", &mut secret_r) {
+        Err(Error::State(StateProblem::HandshakeNotFinished)) => {},
+        res => panic!("secret exported mid-handshake: {:?}", res),
+    }
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // Both sides agree on a label's secret, in handshake mode as well as in either transport mode.
+    h_i.export_secret(b"side channel", &mut secret_i).unwrap();
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_stateless_transport_mode().unwrap();
+    h_r.export_secret(b"side channel", &mut secret_r).unwrap();
+    assert_eq!(secret_i, secret_r);
+    h_i.export_secret(b"side channel", &mut secret_i).unwrap();
+    assert_eq!(secret_i, secret_r);
+    h_i.export_secret(b"another channel", &mut secret_i).unwrap();
+    assert_ne!(secret_i, secret_r);
+    assert!(h_i.export_secret(b"side channel", &mut [0u8; 65]).is_err());
+
+    // Exporting leaves the transport ciphers alone.
+    let len = h_i.write_message(b"hello", &mut buffer_msg).unwrap();
+    let len = h_r.read_message_with_nonce(0, &buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hello");
+}
+
+#[test]
+fn test_protocol_id() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap();
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    // The id is hashed right after the (here unhashed) protocol name, before the prologue.
+    let h_i = Builder::new(params.clone()).protocol_id(b"chat v1").prologue(b"prologue").build_initiator().unwrap();
+    let mut hasher = DefaultResolver.resolve_hash(&HashChoice::SHA256).unwrap();
+    let mut h = [0u8; 32];
+    hasher.input(b"Noise_NN_25519_ChaChaPoly_SHA256");
+    hasher.input(b"chat v1");
+    hasher.result(&mut h);
+    let mut expected = [0u8; 32];
+    hasher.reset();
+    hasher.input(&h);
+    hasher.input(b"prologue");
+    hasher.result(&mut expected);
+    assert_eq!(h_i.get_handshake_hash().unwrap(), &expected[..]);
+
+    let template = Builder::new(params.clone()).protocol_id(b"chat v1").into_template().unwrap();
+    for (id_i, id_r, agree) in &[(Some(&b"chat v1"[..]), Some(&b"chat v1"[..]), true),
+                                 (Some(b"chat v1"), Some(b"file v1"), false),
+                                 (Some(b"chat v1"), None, false),
+                                 (Some(b""), None, false)] {
+        let mut h_i = Builder::new(params.clone());
+        if let Some(id) = id_i {
+            h_i = h_i.protocol_id(id);
+        }
+        let mut h_i = h_i.build_initiator().unwrap();
+        let mut h_r = match id_r {
+            Some(b"chat v1") => template.build_responder().unwrap(),
+            Some(id) => Builder::new(params.clone()).protocol_id(id).build_responder().unwrap(),
+            None => Builder::new(params.clone()).build_responder().unwrap(),
+        };
+
+        let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+        h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+        let res = h_i.read_message(&buffer_msg[..len], &mut buffer_out);
+        assert_eq!(res.is_ok(), *agree, "{:?} {:?}", id_i, id_r);
+    }
+}
+
+#[test]
+fn test_messages_remaining() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&get_inc_key(32))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    for remaining in (1..=3).rev() {
+        assert_eq!(h_i.messages_remaining(), remaining);
+        assert_eq!(h_r.messages_remaining(), remaining);
+        let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+
+        // A message that's only been partly stepped through is still to go.
+        let len = sender.write_message_token(&mut buffer_msg).unwrap().unwrap();
+        assert_eq!(sender.messages_remaining(), remaining);
+        let len = len + sender.write_message(b"", &mut buffer_msg[len..]).unwrap();
+        assert_eq!(sender.messages_remaining(), remaining - 1);
+        receiver.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    }
+    assert_eq!(h_r.messages_remaining(), 0);
+    let h_i = h_i.into_transport_mode().unwrap();
+    assert_eq!(h_i.messages_remaining(), 0);
+
+    let oneway: NoiseParams = "Noise_N_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let h_i = Builder::new(oneway).remote_public_key(&x25519::x25519(get_inc_key(32), x25519::X25519_BASEPOINT_BYTES))
+        .build_initiator().unwrap();
+    assert_eq!(h_i.messages_remaining(), 1);
+}
+
+#[test]
+fn test_next_message_tokens() {
+    use snow::params::Token::*;
+
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&get_inc_key(32))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let expected: [&[Token]; 3] = [&[E], &[E, Dhee, S, Dhse], &[S, Dhse]];
+    for tokens in expected.iter() {
+        assert_eq!(h_i.next_message_tokens(), *tokens);
+        assert_eq!(h_r.next_message_tokens(), *tokens);
+        let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+
+        let len = sender.write_message_token(&mut buffer_msg).unwrap().unwrap();
+        assert_eq!(sender.next_message_tokens(), &tokens[1..]);
+        let len = len + sender.write_message(b"", &mut buffer_msg[len..]).unwrap();
+        receiver.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    }
+    assert!(h_i.next_message_tokens().is_empty());
+    let h_r = h_r.into_transport_mode().unwrap();
+    assert!(h_r.next_message_tokens().is_empty());
+}
+
+#[test]
+fn test_overdriven_handshake() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // Stepping past the last message is an error whichever side's turn it would have been.
+    for session in [&mut h_i, &mut h_r] {
+        match session.write_message_token(&mut buffer_msg) {
+            Err(Error::State(StateProblem::HandshakeAlreadyFinished)) => {},
+            res => panic!("token written after the last message: {:?}", res),
+        }
+        match session.read_message_token(&buffer_msg) {
+            Err(Error::State(StateProblem::HandshakeAlreadyFinished)) => {},
+            res => panic!("token read after the last message: {:?}", res),
+        }
+    }
+
+    // Whole messages carry on as transport messages.
+    let len = h_i.write_message(b"still here", &mut buffer_msg).unwrap();
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"still here");
+}
+
+#[test]
+fn test_payload_encryption_is_reported() {
+    let params: NoiseParams = "Noise_XN_25519_ChaChaPoly_SHA256".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    // -> e
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+    assert!(!h_i.was_write_payload_encrypted());
+    assert_eq!(&buffer_msg[32..len], b"abc");
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert!(!h_r.was_read_payload_encrypted());
+
+    // <- e, ee
+    let len = h_r.write_message(b"abc", &mut buffer_msg).unwrap();
+    assert!(h_r.was_write_payload_encrypted());
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert!(h_i.was_read_payload_encrypted());
+
+    // A rejected message doesn't change what's reported for the last good one.
+    let mut h_r2 = Builder::new("Noise_XN_25519_ChaChaPoly_SHA256".parse().unwrap())
+        .build_responder().unwrap();
+    assert!(h_r2.read_message(&[0u8; 8], &mut buffer_out).is_err());
+    assert!(!h_r2.was_read_payload_encrypted());
+
+    // -> s, se
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let h_i = h_i.into_transport_mode().unwrap();
+    assert!(h_i.was_write_payload_encrypted() && h_i.was_read_payload_encrypted());
+}
+
+#[test]
+fn test_set_remote_static() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let resp_public = x25519::x25519(get_inc_key(32), x25519::X25519_BASEPOINT_BYTES);
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    for &(pinned, should_succeed) in &[(resp_public, true), ([9u8; 32], false)] {
+        let mut h_i = Builder::new(params.clone())
+            .local_private_key(&get_inc_key(0))
+            .build_initiator().unwrap();
+        let mut h_r = Builder::new(params.clone())
+            .local_private_key(&get_inc_key(32))
+            .build_responder().unwrap();
+
+        assert!(h_i.set_remote_static(&pinned[..31]).is_err());
+        h_i.set_remote_static(&pinned).unwrap();
+        assert_eq!(h_i.get_remote_static().unwrap(), &pinned[..]);
+        assert!(h_i.set_remote_static(&pinned).is_err());
+
+        let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+        h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+
+        match h_i.read_message(&buffer_msg[..len], &mut buffer_out) {
+            Ok(_) if should_succeed => {},
+            Err(Error::State(StateProblem::RemoteStaticMismatch)) if !should_succeed => {},
+            res => panic!("unexpected result reading a pinned static key: {:?}", res),
+        }
+        assert_eq!(h_i.get_remote_static().unwrap(), &pinned[..]);
+    }
+
+    // The static key carried by a premessage is always known already.
+    let mut h_i = Builder::new("Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap())
+        .local_private_key(&get_inc_key(0))
+        .remote_public_key(&resp_public)
+        .build_initiator().unwrap();
+    assert!(h_i.set_remote_static(&resp_public).is_err());
+}
+
+#[test]
+fn test_builder_pinned_remote_static() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let resp_public = x25519::x25519(get_inc_key(32), x25519::X25519_BASEPOINT_BYTES);
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    for &(resp_private, should_succeed) in &[(get_inc_key(32), true), (get_inc_key(64), false)] {
+        let mut h_i = Builder::new(params.clone())
+            .local_private_key(&get_inc_key(0))
+            .pin_remote_public_key(&resp_public)
+            .build_initiator().unwrap();
+        let mut h_r = Builder::new(params.clone())
+            .local_private_key(&resp_private)
+            .build_responder().unwrap();
+
+        let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+        h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        let len = h_r.write_message(b"server hello", &mut buffer_msg).unwrap();
+
+        match h_i.read_message(&buffer_msg[..len], &mut buffer_out) {
+            Ok(n) if should_succeed => assert_eq!(&buffer_out[..n], b"server hello"),
+            Err(Error::State(StateProblem::RemoteStaticMismatch)) if !should_succeed => {},
+            res => panic!("unexpected result reading a pinned static key: {:?}", res),
+        }
+    }
+
+    assert!(matches!(Builder::new(params.clone())
+                         .local_private_key(&get_inc_key(0))
+                         .pin_remote_public_key(&resp_public[..31])
+                         .build_initiator(),
+                     Err(Error::Init(InitStage::ValidateKeyLengths))));
+
+    // Pinning a key that the premessages already provide is a mistake.
+    assert!(matches!(Builder::new("Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap())
+                         .local_private_key(&get_inc_key(0))
+                         .remote_public_key(&resp_public)
+                         .pin_remote_public_key(&resp_public)
+                         .build_initiator(),
+                     Err(Error::Input)));
+}
+
+#[test]
+fn test_fallback_resolver() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+
+    match Builder::with_resolver(params.clone(), Box::new(LongTagOnlyResolver)).build_initiator() {
+        Err(Error::Init(InitStage::GetRngImpl)) => {},
+        res => panic!("built without an rng: {:?}", res.map(|_| ())),
+    }
+
+    let resolver = || Box::new(FallbackResolver::new(Box::new(LongTagOnlyResolver), Box::new(DefaultResolver)));
+    let mut h_i = Builder::with_resolver(params.clone(), resolver()).build_initiator().unwrap();
+    let mut h_r = Builder::with_resolver(params, resolver()).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // The preferred resolver's cipher is the one in use.
+    let len = h_r.write_message(b"abc", &mut buffer_msg).unwrap();
+    assert_eq!(len, 32 + 3 + 24);
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..3], b"abc");
+}
+
+#[test]
+fn test_hardware_static_key() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut device = DefaultResolver.resolve_dh(&params.dh).unwrap();
+    device.set(&get_inc_key(0));
+    let device_public = device.pubkey().to_vec();
+
+    let mut h_i = Builder::new(params.clone())
+        .local_static_dh(Box::new(HardwareDh { device }))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(1))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    assert_eq!(h_r.get_remote_static().unwrap(), &device_public[..]);
+    assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap());
+
+    // A key for a different DH function is caught up front.
+    #[cfg(feature = "448")]
+    {
+        let wrong_params: NoiseParams = "Noise_XX_448_ChaChaPoly_BLAKE2s".parse().unwrap();
+        let device = DefaultResolver.resolve_dh(&params.dh).unwrap();
+        match Builder::new(wrong_params).local_static_dh(Box::new(HardwareDh { device })).build_initiator() {
+            Err(Error::Init(InitStage::ValidateKeyLengths)) => {},
+            res => panic!("mismatched static key wasn't rejected: {:?}", res.map(|_| ())),
+        }
+    }
+}
+
+#[test]
+fn test_handshake_hash_snapshots() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params.clone()).build_responder().unwrap();
+    let mut h_other = Builder::new(params).prologue(b"v2").build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    let snapshot = h_i.get_handshake_hash().unwrap().to_vec();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    h_other.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert!(h_r.handshake_hash_matches(&snapshot));
+    assert!(!h_other.handshake_hash_matches(&snapshot));
+    assert!(!h_r.handshake_hash_matches(&snapshot[..16]));
+
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    assert!(!h_r.handshake_hash_matches(&snapshot));
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let snapshot = h_r.get_handshake_hash().unwrap().to_vec();
+
+    let h_i = h_i.into_transport_mode().unwrap();
+    assert!(h_i.handshake_hash_matches(&snapshot));
+}
+
+#[test]
+fn test_read_message_in_place() {
+    let params: NoiseParams = "Noise_NN_25519_AESGCM_SHA256".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    match h_r.read_message_in_place(&mut buffer_msg[..len]) {
+        Err(Error::State(StateProblem::HandshakeNotFinished)) => {},
+        res => panic!("handshake message read in place: {:?}", res),
+    }
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // Works straight from a finished handshake, and from transport mode.
+    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    let payload_len = h_r.read_message_in_place(&mut buffer_msg[..len]).unwrap();
+    assert_eq!(&buffer_msg[..payload_len], b"hack the planet");
+
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    let genuine = buffer_msg;
+    buffer_msg[0] ^= 1;
+    assert!(h_r.read_message_in_place(&mut buffer_msg[..len]).is_err());
+    assert!(h_r.read_message_in_place(&mut buffer_msg[..15]).is_err());
+    buffer_msg = genuine;
+    assert_eq!(h_r.read_message_in_place(&mut buffer_msg[..len]).unwrap(), 15);
+
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    assert_eq!(h_r.read_message_in_place(&mut buffer_msg[..len]).unwrap(), 0);
+}
+
+#[test]
+fn test_write_message_in_place() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    match h_i.write_message_in_place(&mut buffer_msg, 0) {
+        Err(Error::State(StateProblem::HandshakeNotFinished)) => {},
+        res => panic!("handshake message written in place: {:?}", res),
+    }
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+
+    // No room for the tag.
+    buffer_msg[..15].copy_from_slice(b"hack the planet");
+    assert!(h_i.write_message_in_place(&mut buffer_msg[..30], 15).is_err());
+
+    let len = h_i.write_message_in_place(&mut buffer_msg[..31], 15).unwrap();
+    assert_eq!(len, 31);
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+
+    // Mixes freely with the copying variants, in both directions.
+    buffer_msg[..3].copy_from_slice(b"abc");
+    let len = h_r.write_message_in_place(&mut buffer_msg, 3).unwrap();
+    let len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"abc");
+
+    let len = h_i.write_message(b"defg", &mut buffer_msg).unwrap();
+    let len = h_r.read_message_in_place(&mut buffer_msg[..len]).unwrap();
+    assert_eq!(&buffer_msg[..len], b"defg");
+}
+
+#[test]
+fn test_messages_to_vec() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&get_inc_key(1))
+        .build_responder().unwrap();
+
+    let mut message = Vec::with_capacity(65535);
+    let mut payload = Vec::with_capacity(65535);
+
+    h_i.write_message_to_vec(b"abc", &mut message).unwrap();
+    assert_eq!(message.len(), 32 + 3);
+    h_r.read_message_to_vec(&message, &mut payload).unwrap();
+    assert_eq!(&payload[..], b"abc");
+    h_r.write_message_to_vec(b"defg", &mut message).unwrap();
+    assert_eq!(message.len(), 32 + 48 + 4 + 16);
+    h_i.read_message_to_vec(&message, &mut payload).unwrap();
+    assert_eq!(&payload[..], b"defg");
+    h_i.write_message_to_vec(b"", &mut message).unwrap();
+    h_r.read_message_to_vec(&message, &mut payload).unwrap();
+    assert!(payload.is_empty());
+
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+    let data = vec![0x42u8; 65535 - 16];
+    for _ in 0..3 {
+        h_i.write_message_to_vec(&data, &mut message).unwrap();
+        assert_eq!(message.len(), 65535);
+        h_r.read_message_to_vec(&message, &mut payload).unwrap();
+        assert_eq!(payload, data);
+    }
+    assert_eq!(message.capacity(), 65535);
+
+    assert!(h_i.write_message_to_vec(&[0u8; 65535], &mut message).is_err());
+    assert!(message.is_empty());
+    assert!(h_r.read_message_to_vec(&[0u8; 32], &mut payload).is_err());
+    assert!(payload.is_empty());
+}
+
+#[test]
+fn test_get_protocol_name() {
+    let name = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+    let mut h_i = Builder::new(name.parse().unwrap()).psk(0, &[32u8; 32]).build_initiator().unwrap();
+    let mut h_r = Builder::new(name.parse().unwrap()).psk(0, &[32u8; 32]).build_responder().unwrap();
+    assert_eq!(h_i.get_protocol_name(), name);
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    assert_eq!(h_i.into_transport_mode().unwrap().get_protocol_name(), name);
+    assert_eq!(h_r.into_stateless_transport_mode().unwrap().get_protocol_name(), name);
+}
+
+#[test]
+fn test_incremental_prologue() {
+    let resp_static = get_inc_key(1);
+    let resp_public = x25519::x25519(resp_static, x25519::X25519_BASEPOINT_BYTES);
+    let psk = [7u8; 32];
+
+    for name in &["Noise_NK_25519_ChaChaPoly_SHA256", "Noise_NKpsk0_25519_ChaChaPoly_SHA256"] {
+        let params: NoiseParams = name.parse().unwrap();
+        let mut init_builder = Builder::new(params.clone()).remote_public_key(&resp_public).prologue(b"ab");
+        let mut resp_builder = Builder::new(params.clone()).local_private_key(&resp_static).prologue(b"abcdef");
+        if params.handshake.is_psk() {
+            init_builder = init_builder.psk(0, &psk);
+            resp_builder = resp_builder.psk(0, &psk);
+        }
+        let mut h_i = init_builder.build_initiator().unwrap();
+        let mut h_r = resp_builder.build_responder().unwrap();
+
+        h_i.mix_prologue(b"cd").unwrap();
+        h_i.mix_prologue(b"").unwrap();
+        h_i.mix_prologue(b"ef").unwrap();
+        assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap(), "{}", name);
+
+        let mut buffer_msg = [0u8; 200];
+        let mut buffer_out = [0u8; 200];
+        let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+        match h_i.mix_prologue(b"gh") {
+            Err(Error::State(StateProblem::HandshakeAlreadyStarted)) => {},
+            res => panic!("prologue changed mid-handshake: {:?}", res),
+        }
+        h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        let len = h_r.write_message(b"defg", &mut buffer_msg).unwrap();
+        h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap(), "{}", name);
+    }
+}
+
+#[test]
+fn test_stream_prologue() {
+    let prologue: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    let resp_static = get_inc_key(32);
+    let resp_public = x25519::x25519(resp_static, x25519::X25519_BASEPOINT_BYTES);
+    for name in &["Noise_NN_25519_ChaChaPoly_BLAKE2s", "Noise_NK_25519_AESGCM_SHA256"] {
+        let params: NoiseParams = name.parse().unwrap();
+        let mut init_builder = Builder::new(params.clone()).prologue(&prologue[..10]);
+        let mut resp_builder = Builder::new(params.clone()).prologue(&prologue);
+        if params.handshake.pattern.need_known_remote_pubkey(true) {
+            init_builder = init_builder.remote_public_key(&resp_public);
+            resp_builder = resp_builder.local_private_key(&resp_static);
+        }
+        let mut h_i = init_builder.build_initiator().unwrap();
+        let mut h_r = resp_builder.build_responder().unwrap();
+
+        h_i.mix_prologue(&prologue[10..20]).unwrap();
+        for chunk in prologue[20..].chunks(4096) {
+            h_i.stream_prologue(chunk).unwrap();
+        }
+        // Plain appends go into the stream once it's open.
+        h_i.mix_prologue(&[]).unwrap();
+        h_i.finish_prologue().unwrap();
+        assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap(), "{}", name);
+        assert!(h_i.stream_prologue(b"more").is_err());
+        assert!(h_i.mix_prologue(b"more").is_err());
+        h_r.finish_prologue().unwrap();
+
+        let mut buffer_msg = [0u8; 200];
+        let mut buffer_out = [0u8; 200];
+        let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+        h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        let len = h_r.write_message(b"defg", &mut buffer_msg).unwrap();
+        h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap(), "{}", name);
+    }
+
+    // The first message finishes a streamed prologue by itself, and a mismatch is caught.
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    for &(streamed, succeeds) in &[(&b"ge"[..], true), (&b"gy"[..], false)] {
+        let mut h_i = Builder::new(params.clone()).prologue(b"bad").build_initiator().unwrap();
+        let mut h_r = Builder::new(params.clone()).prologue(b"badge").build_responder().unwrap();
+        h_i.stream_prologue(&streamed[..1]).unwrap();
+        h_i.mix_prologue(&streamed[1..]).unwrap();
+
+        let mut buffer_msg = [0u8; 200];
+        let mut buffer_out = [0u8; 200];
+        let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+        h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+        assert_eq!(h_i.read_message(&buffer_msg[..len], &mut buffer_out).is_ok(), succeeds);
+    }
+}
+
+#[test]
+fn test_get_payload_len() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&get_inc_key(1))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    for &payload_len in &[0, 5] {
+        let len = h_i.write_message(&vec![1u8; payload_len], &mut buffer_msg).unwrap();
+        assert_eq!(h_r.get_payload_len(len).unwrap(), payload_len);
+        let mut payload = vec![0u8; h_r.get_payload_len(len).unwrap()];
+        h_r.read_message(&buffer_msg[..len], &mut payload).unwrap();
+
+        let len = h_r.write_message(&vec![2u8; payload_len], &mut buffer_msg).unwrap();
+        assert!(h_i.get_payload_len(len - payload_len - 1).is_err());
+        let mut payload = vec![0u8; h_i.get_payload_len(len).unwrap()];
+        h_i.read_message(&buffer_msg[..len], &mut payload).unwrap();
+        assert_eq!(payload, vec![2u8; payload_len]);
+
+        if !h_i.is_handshake_finished() {
+            let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+            assert_eq!(h_r.get_payload_len(len).unwrap(), 0);
+            h_r.read_message(&buffer_msg[..len], &mut []).unwrap();
+        }
+    }
+
+    let h_r = h_r.into_transport_mode().unwrap();
+    assert_eq!(h_r.get_payload_len(16).unwrap(), 0);
+    assert!(h_r.get_payload_len(15).is_err());
+}
+
+#[test]
+fn test_token_by_token_handshake() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&get_inc_key(1))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    // -> e, with a marker byte interleaved after the ephemeral key
+    let mut len = h_i.write_message_token(&mut buffer_msg).unwrap().unwrap();
+    assert_eq!(len, 32);
+    buffer_msg[len] = 0xff;
+    len += 1;
+    assert_eq!(h_i.write_message_token(&mut buffer_msg[len..]).unwrap(), None);
+    len += h_i.write_message(b"abc", &mut buffer_msg[len..]).unwrap();
+
+    match h_i.write_message_token(&mut buffer_msg) {
+        Err(Error::State(StateProblem::NotTurnToWrite)) => {},
+        res => panic!("stepped a write out of turn: {:?}", res),
+    }
+
+    let mut pos = h_r.read_message_token(&buffer_msg[..len]).unwrap().unwrap();
+    assert_eq!(buffer_msg[pos], 0xff);
+    pos += 1;
+    assert_eq!(h_r.read_message_token(&buffer_msg[pos..len]).unwrap(), None);
+    let payload_len = h_r.read_message(&buffer_msg[pos..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..payload_len], b"abc");
+
+    // <- e, ee, s, es, stepping part way and letting write_message do the rest
+    assert_eq!(h_r.write_message_token(&mut buffer_msg[..31]).map_err(|_| ()), Err(()));
+    let mut len = h_r.write_message_token(&mut buffer_msg).unwrap().unwrap();
+    len += h_r.write_message(b"defg", &mut buffer_msg[len..]).unwrap();
+    assert_eq!(len, 32 + 48 + 4 + 16);
+    let payload_len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..payload_len], b"defg");
+
+    // -> s, se, read one token at a time
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    let mut pos = 0;
+    while let Some(consumed) = h_r.read_message_token(&buffer_msg[pos..len]).unwrap() {
+        pos += consumed;
+    }
+    assert_eq!(pos, 48);
+    h_r.read_message(&buffer_msg[pos..len], &mut buffer_out).unwrap();
+    assert!(h_i.is_handshake_finished() && h_r.is_handshake_finished());
+    assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap());
+
+    assert!(h_r.write_message_token(&mut buffer_msg).is_err());
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+}
+
+#[test]
+fn test_get_remote_ephemeral() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    assert!(h_r.get_remote_ephemeral().is_none());
+
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    let init_ephemeral = buffer_msg[..32].to_vec();
+    assert!(h_r.get_remote_ephemeral().is_none());
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(h_r.get_remote_ephemeral().unwrap(), &init_ephemeral[..]);
+
+    assert!(h_i.get_remote_ephemeral().is_none());
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    let resp_ephemeral = buffer_msg[..32].to_vec();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(h_i.get_remote_ephemeral().unwrap(), &resp_ephemeral[..]);
+
+    let h_i = h_i.into_transport_mode().unwrap();
+    let h_r = h_r.into_stateless_transport_mode().unwrap();
+    assert_eq!(h_i.get_remote_ephemeral().unwrap(), &resp_ephemeral[..]);
+    assert_eq!(h_r.get_remote_ephemeral().unwrap(), &init_ephemeral[..]);
+}
+
+#[test]
+fn test_reject_low_order_keys() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    // A responder that sends the all-zero point as its ephemeral key, forcing an all-zero
+    // shared secret no matter what the initiator's key is.
+    for &reject in &[false, true] {
+        let mut h_i = Builder::new(params.clone())
+            .reject_low_order_keys(reject)
+            .build_initiator().unwrap();
+        let mut h_r = Builder::new(params.clone()).build_responder().unwrap();
+
+        let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+        h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+        for byte in &mut buffer_msg[..32] {
+            *byte = 0;
+        }
+
+        let result = h_i.read_message(&buffer_msg[..len], &mut buffer_out);
+        if reject {
+            assert!(matches!(result, Err(snow::Error::Dh)));
+        } else {
+            // Strict Noise doesn't mind the key, only the (now mismatched) tag gives it away.
+            assert!(matches!(result, Err(snow::Error::Decrypt)));
+        }
+    }
+}
+
+#[test]
+fn test_session_outlives_builder_inputs() {
+    struct Connection {
+        noise: Session,
+    }
+
+    fn connect(params: &str) -> Connection {
+        let psk = vec![1u8; 32];
+        let prologue = b"owned".to_vec();
+        let noise = Builder::new(params.parse().unwrap())
+            .psk(0, &psk)
+            .prologue(&prologue)
+            .build_initiator()
+            .unwrap();
+        Connection { noise }
+    }
+
+    fn assert_static<T: 'static>(_: &T) {}
+
+    let mut conn = connect("Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s");
+    assert_static(&conn);
+    let mut buffer_msg = [0u8; 200];
+    assert!(conn.noise.write_message(b"", &mut buffer_msg).is_ok());
+}
+
+#[test]
+fn test_session_is_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+    assert_send_sync(&h_i);
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // Finish the handshake on another thread and hand the session back.
+    let h_r = std::thread::spawn(move || {
+        let mut buffer_msg = [0u8; 200];
+        let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+        (h_r, buffer_msg[..len].to_vec())
+    });
+    let (h_r, msg) = h_r.join().unwrap();
+    h_i.read_message(&msg, &mut buffer_out).unwrap();
+
+    let h_r = h_r.into_transport_mode().unwrap();
+    assert_send_sync(&h_r);
+    assert!(h_i.is_handshake_finished());
+}
+
+#[test]
+fn test_oversized_messages_are_rejected() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let oversized = vec![0u8; 80_000];
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = vec![0u8; 80_000];
+    assert!(matches!(h_r.read_message(&oversized, &mut buffer_out), Err(snow::Error::Input)));
+
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let h_r = h_r.into_stateless_transport_mode().unwrap();
+    assert!(matches!(h_i.read_message(&oversized, &mut buffer_out), Err(snow::Error::Input)));
+    let mut in_place = oversized.clone();
+    assert!(matches!(h_i.read_message_in_place(&mut in_place), Err(snow::Error::Input)));
+    assert!(matches!(h_r.read_message_with_nonce(0, &oversized, &mut buffer_out), Err(snow::Error::Input)));
+
+    // The session is still usable afterwards.
+    let len = h_r.write_message_with_nonce(0, b"hi", &mut buffer_msg).unwrap();
+    let len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hi");
+}
+
+#[test]
+fn test_transport_associated_data() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    assert!(matches!(h_i.write_message_with_ad(b"header", b"", &mut buffer_msg),
+                     Err(snow::Error::State(StateProblem::HandshakeNotFinished))));
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // Works both before and after switching to transport mode.
+    let mut h_r = h_r.into_transport_mode().unwrap();
+    let len = h_i.write_message_with_ad(b"seq=1", b"hello", &mut buffer_msg).unwrap();
+    let len = h_r.read_message_with_ad(b"seq=1", &buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hello");
+
+    // A mismatched header fails to authenticate.
+    let len = h_i.write_message_with_ad(b"seq=2", b"hello", &mut buffer_msg).unwrap();
+    assert!(matches!(h_r.read_message_with_ad(b"seq=3", &buffer_msg[..len], &mut buffer_out),
+                     Err(snow::Error::Decrypt)));
+    let len = h_r.read_message_with_ad(b"seq=2", &buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hello");
+
+    // Empty associated data is the same as plain write_message()/read_message().
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let len = h_i.write_message_with_ad(b"", b"plain", &mut buffer_msg).unwrap();
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"plain");
+}
+
+#[test]
+fn test_handshake_overheads() {
+    let overheads = |name: &str| name.parse::<NoiseParams>().unwrap().handshake_overheads().unwrap();
+    assert_eq!(overheads("Noise_NN_25519_ChaChaPoly_BLAKE2s"), vec![32, 48]);
+    assert_eq!(overheads("Noise_XX_25519_ChaChaPoly_BLAKE2s"), vec![32, 96, 64]);
+    assert_eq!(overheads("Noise_XX_448_AESGCM_SHA512"), vec![56, 144, 88]);
+    assert_eq!(overheads("Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s"), vec![48, 48]);
+    assert_eq!(overheads("Noise_IK_25519_ChaChaPoly_BLAKE2s"), vec![96, 48]);
+    assert!("Noise_NNpsk3_25519_ChaChaPoly_BLAKE2s".parse::<NoiseParams>().unwrap().handshake_overheads().is_err());
+
+    // A fallback handshake picks up where the initiator's ephemeral left off.
+    assert_eq!(overheads("Noise_XXfallback_25519_ChaChaPoly_BLAKE2s"), vec![96, 64]);
+}
+
+#[test]
+fn test_psk_list_handshake() {
+    let params: NoiseParams = "Noise_XXpsk0+psk3_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let (psk_a, psk_b) = ([1u8; 32], [2u8; 32]);
+    let static_i = Builder::new(params.clone()).generate_keypair().unwrap();
+    let static_r = Builder::new(params.clone()).generate_keypair().unwrap();
+
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&static_i.private)
+        .psks(&[&psk_a, &psk_b])
+        .build_initiator().unwrap();
+    // Listing the keys is equivalent to placing them one by one.
+    let mut h_r = Builder::new(params)
+        .local_private_key(&static_r.private)
+        .psk(0, &psk_a)
+        .psk(3, &psk_b)
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    while !(h_i.is_handshake_finished() && h_r.is_handshake_finished()) {
+        let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+        let len = sender.write_message(b"", &mut buffer_msg).unwrap();
+        receiver.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    }
+    assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap());
+}
+
+#[test]
+fn test_abort_handshake() {
+    let params: NoiseParams = "Noise_NNpsk2_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let psk = [9u8; 32];
+    let mut h_i = Builder::new(params.clone()).psk(2, &psk).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).psk(2, &psk).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // Both sides give up halfway, e.g. because a payload didn't validate.
+    h_r.abort();
+    h_i.abort();
+}
+
+/// Wraps a DH key object to record the private key left in it when it's dropped.
+struct WatchedDh(Box<dyn Dh>, std::sync::Arc<std::sync::Mutex<Option<Vec<u8>>>>);
+
+impl Dh for WatchedDh {
+    fn name(&self) -> &'static str { self.0.name() }
+    fn pub_len(&self) -> usize { self.0.pub_len() }
+    fn priv_len(&self) -> usize { self.0.priv_len() }
+    fn set(&mut self, privkey: &[u8]) { self.0.set(privkey) }
+    fn generate(&mut self, rng: &mut dyn Random) { self.0.generate(rng) }
+    fn pubkey(&self) -> &[u8] { self.0.pubkey() }
+    fn privkey(&self) -> &[u8] { self.0.privkey() }
+    fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> Result<(), ()> { self.0.dh(pubkey, out) }
+}
+
+impl Drop for WatchedDh {
+    fn drop(&mut self) {
+        *self.1.lock().unwrap() = Some(self.0.privkey().to_vec());
+    }
+}
+
+#[test]
+fn test_dropping_handshake_wipes_fixed_ephemeral() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    // Dropped halfway, aborted halfway, and turned into a transport session.
+    for &ending in &[0, 1, 2] {
+        let left_behind = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let mut ephemeral = DefaultResolver.resolve_dh(&params.dh).unwrap();
+        ephemeral.set(&[7u8; 32]);
+        let mut h_i = Builder::new(params.clone())
+            .local_ephemeral_dh(Box::new(WatchedDh(ephemeral, left_behind.clone())))
+            .build_initiator().unwrap();
+        let mut h_r = Builder::new(params.clone()).build_responder().unwrap();
+        let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+        h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+        match ending {
+            0 => drop(h_i),
+            1 => h_i.abort(),
+            _ => {
+                let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+                h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+                let h_i = h_i.into_transport_mode().unwrap();
+                assert!(left_behind.lock().unwrap().is_some());
+                drop(h_i);
+            },
+        }
+        assert_eq!(left_behind.lock().unwrap().as_deref(), Some(&[0u8; 32][..]));
+    }
+}
+
+#[test]
+fn test_pregenerated_ephemeral() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut rng = DefaultResolver.resolve_rng().unwrap();
+    let mut pool: Vec<Box<dyn Dh>> = (0..2).map(|_| {
+        let mut dh = DefaultResolver.resolve_dh(&params.dh).unwrap();
+        dh.generate(&mut *rng);
+        dh
+    }).collect();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    while let Some(ephemeral) = pool.pop() {
+        let expected = ephemeral.pubkey().to_vec();
+        let mut h_i = Builder::new(params.clone())
+            .local_ephemeral_dh(ephemeral)
+            .build_initiator().unwrap();
+        let mut h_r = Builder::new(params.clone()).build_responder().unwrap();
+
+        let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+        assert_eq!(&buffer_msg[..32], &expected[..]);
+        h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+        h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert!(h_i.is_handshake_finished() && h_r.is_handshake_finished());
+    }
+
+    #[cfg(feature = "448")]
+    {
+        let mismatched = DefaultResolver.resolve_dh(&DHChoice::Ed448).unwrap();
+        match Builder::new(params).local_ephemeral_dh(mismatched).build_initiator() {
+            Err(snow::Error::Init(InitStage::ValidateKeyLengths)) => {},
+            res => panic!("a 448 ephemeral for a 25519 handshake wasn't rejected: {:?}", res.map(|_| ())),
+        }
+    }
+}
+
+#[test]
+fn test_truncated_handshake_messages() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(&[], &mut buffer_msg).unwrap();
+    assert_eq!(len, 32);
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // The second message is an ephemeral key and just a tag for the empty payload.
+    let len = h_r.write_message(&[], &mut buffer_msg).unwrap();
+    assert_eq!(len, 32 + 16);
+    for short_len in 0..len {
+        assert!(h_i.read_message(&buffer_msg[..short_len], &mut buffer_out).is_err(), "{} bytes", short_len);
+    }
+    assert_eq!(h_i.read_message(&buffer_msg[..len], &mut []).unwrap(), 0);
+
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+    let len = h_i.write_message(&[], &mut buffer_msg).unwrap();
+    assert_eq!(len, 16);
+    assert!(h_r.read_message(&buffer_msg[..15], &mut buffer_out).is_err());
+    assert_eq!(h_r.read_message(&buffer_msg[..len], &mut []).unwrap(), 0);
+}
+
+#[test]
+fn test_handshake_schedule() {
+    let handshake: HandshakeChoice = "XXpsk3".parse().unwrap();
+    assert_eq!(handshake.premessages().unwrap(), (&[][..], &[][..]));
+    assert_eq!(handshake.messages().unwrap(), vec![
+        (true,  vec![Token::E]),
+        // The spec's `es` in the responder's message, from the responder's point of view.
+        (false, vec![Token::E, Token::Dhee, Token::S, Token::Dhse]),
+        (true,  vec![Token::S, Token::Dhse, Token::Psk(3)]),
+    ]);
+
+    let handshake: HandshakeChoice = "IK".parse().unwrap();
+    assert_eq!(handshake.premessages().unwrap(), (&[][..], &[Token::S][..]));
+
+    // In a fallback handshake, the initiator's first message has become a pre-message, so
+    // the responder goes first.
+    let handshake: HandshakeChoice = "XXfallback".parse().unwrap();
+    assert_eq!(handshake.premessages().unwrap(), (&[Token::E][..], &[][..]));
+    let senders: Vec<bool> = handshake.messages().unwrap().into_iter().map(|(initiator, _)| initiator).collect();
+    assert_eq!(senders, vec![false, true]);
+
+    // The schedule lines up with the per-message overheads.
+    let params: NoiseParams = "Noise_XXfallback_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    assert_eq!(params.handshake.messages().unwrap().len(), params.handshake_overheads().unwrap().len());
+}
+
+#[test]
+fn test_padded_transport_messages() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = vec![0u8; 65535];
+    let mut buffer_out = vec![0u8; 65535];
+    assert!(matches!(h_i.write_message_padded(b"", Padding::PowerOfTwo, &mut buffer_msg),
+                     Err(snow::Error::State(StateProblem::HandshakeNotFinished))));
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+
+    // Payloads of different lengths all come out the same size.
+    for payload in &[&b""[..], b"a", b"hello world", &[7u8; 62]] {
+        let len = h_i.write_message_padded(payload, Padding::Fixed(64), &mut buffer_msg).unwrap();
+        assert_eq!(len, 64 + 16);
+        let len = h_r.read_message_padded(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert_eq!(&buffer_out[..len], *payload);
+    }
+    assert!(matches!(h_i.write_message_padded(&[7u8; 63], Padding::Fixed(64), &mut buffer_msg),
+                     Err(snow::Error::Input)));
+
+    for &(payload_len, padded_len) in &[(0, 2), (2, 4), (100, 128), (65000, 65535 - 16)] {
+        let payload = vec![1u8; payload_len];
+        let len = h_r.write_message_padded(&payload, Padding::PowerOfTwo, &mut buffer_msg).unwrap();
+        assert_eq!(len, padded_len + 16, "{}-byte payload", payload_len);
+        let len = h_i.read_message_padded(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert_eq!(&buffer_out[..len], &payload[..]);
+    }
+
+    // An unpadded message of the wrong shape is refused rather than misread.
+    let len = h_i.write_message(b"\xff\xffab", &mut buffer_msg).unwrap();
+    assert!(matches!(h_r.read_message_padded(&buffer_msg[..len], &mut buffer_out), Err(snow::Error::Input)));
+}
+
+#[test]
+#[cfg(feature = "risky-key-export")]
+fn test_export_and_import_transport() {
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let static_i = Builder::new(params.clone()).generate_keypair().unwrap();
+    let static_r = Builder::new(params.clone()).generate_keypair().unwrap();
+    let mut h_i = Builder::new(params.clone()).local_private_key(&static_i.private).build_initiator().unwrap();
+    let mut h_r = Builder::new(params.clone()).local_private_key(&static_r.private).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let mut exported = [0u8; 200];
+    assert!(h_i.export_transport(&mut exported).is_err());
+    while !(h_i.is_handshake_finished() && h_r.is_handshake_finished()) {
+        let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+        let len = sender.write_message(b"", &mut buffer_msg).unwrap();
+        receiver.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    }
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+    let len = h_i.write_message(b"before", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    // Move the responder somewhere else.
+    let exported_len = h_r.export_transport(&mut exported).unwrap();
+    assert!(h_r.export_transport(&mut exported[..exported_len - 1]).is_err());
+    let mut moved = Builder::new(params.clone()).import_transport(&exported[..exported_len]).unwrap();
+    assert_eq!(moved.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap());
+    assert_eq!(moved.get_remote_static().unwrap(), &static_i.public[..]);
+    assert!(!moved.is_initiator());
+    assert_eq!(moved.receiving_nonce().unwrap(), 1);
+    let mut secret_i = [0u8; 32];
+    let mut secret_moved = [0u8; 32];
+    h_i.export_secret(b"side channel", &mut secret_i).unwrap();
+    moved.export_secret(b"side channel", &mut secret_moved).unwrap();
+    assert_eq!(secret_i, secret_moved);
+    drop(h_r);
+
+    let len = h_i.write_message(b"after", &mut buffer_msg).unwrap();
+    let len = moved.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"after");
+    let len = moved.write_message(b"reply", &mut buffer_msg).unwrap();
+    let len = h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"reply");
+
+    // A bad export is refused, and so is exporting after an automatic rekey.
+    assert!(Builder::new(params).import_transport(&exported[..exported_len - 1]).is_err());
+    moved.rekey_outgoing().unwrap();
+    assert!(moved.export_transport(&mut exported).is_err());
+}
+
+#[test]
+#[cfg(feature = "risky-key-export")]
+fn test_raw_split() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    assert!(h_i.dangerously_get_raw_split().is_err());
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let h_r = h_r.into_transport_mode().unwrap();
+
+    // Both sides agree on the keys, which are in the same order for either role.
+    let (initiator_key, responder_key) = h_i.dangerously_get_raw_split().unwrap();
+    assert_eq!(h_r.dangerously_get_raw_split().unwrap(), (initiator_key, responder_key));
+    assert_ne!(initiator_key, responder_key);
+
+    // The initiator's key decrypts its messages with a plain cipher.
+    let len = h_i.write_message(b"record", &mut buffer_msg).unwrap();
+    let mut cipher = DefaultResolver.resolve_cipher(&CipherChoice::ChaChaPoly).unwrap();
+    cipher.set(&initiator_key);
+    let len = cipher.decrypt(0, &[], &buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"record");
+
+    h_i.rekey_outgoing().unwrap();
+    assert!(h_i.dangerously_get_raw_split().is_err());
+}
+
+/// An "RNG" that always produces the same bytes.
+#[allow(unused)]
+struct StuckRng;
+
+impl RngCore for StuckRng {
+    fn next_u32(&mut self) -> u32 {
+        4
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        4
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for StuckRng {}
+impl Random for StuckRng {}
+
+#[allow(unused)]
+struct StuckRngResolver;
+
+impl CryptoResolver for StuckRngResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        Some(Box::new(StuckRng))
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        DefaultResolver.resolve_dh(choice)
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        DefaultResolver.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        DefaultResolver.resolve_cipher(choice)
+    }
+}
+
+/// Run the first two messages of an IK handshake, then (wrongly) switch the responder, which
+/// has already sent its ephemeral key, over to `XXfallback` where it has to send one again.
+fn resend_ephemeral_in_fallback(resp_builder: Builder) -> Result<usize, snow::Error> {
+    let params: NoiseParams = "Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let resp_static = get_inc_key(32);
+    let resp_public = x25519::x25519(resp_static, x25519::X25519_BASEPOINT_BYTES);
+    let mut h_i = Builder::new(params)
+        .local_private_key(&get_inc_key(0))
+        .remote_public_key(&resp_public)
+        .build_initiator().unwrap();
+    let mut h_r = resp_builder.local_private_key(&resp_static).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    h_r.write_message(b"", &mut buffer_msg).unwrap();
+
+    let mut h_r = h_r.into_fallback("Noise_XXfallback_25519_ChaChaPoly_BLAKE2s".parse().unwrap(), &[]).unwrap();
+    h_r.write_message(b"", &mut buffer_msg)
+}
+
+#[test]
+fn test_provided_ephemeral_is_sent_once() {
+    let params: NoiseParams = "Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut ephemeral = DefaultResolver.resolve_dh(&params.dh).unwrap();
+    ephemeral.generate(&mut *DefaultResolver.resolve_rng().unwrap());
+
+    match resend_ephemeral_in_fallback(Builder::new(params).local_ephemeral_dh(ephemeral)) {
+        Err(snow::Error::State(StateProblem::EphemeralReused)) => {},
+        res => panic!("resending a provided ephemeral wasn't refused: {:?}", res),
+    }
+}
+
+#[test]
+fn test_generated_ephemeral_is_replaced() {
+    let params: NoiseParams = "Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    assert!(resend_ephemeral_in_fallback(Builder::new(params)).is_ok());
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "the RNG produced the same ephemeral key twice")]
+fn test_repeated_ephemeral_trips_debug_guard() {
+    let params: NoiseParams = "Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let _ = resend_ephemeral_in_fallback(Builder::with_resolver(params, Box::new(StuckRngResolver)));
+}
+
+#[test]
+fn test_builder_template() {
+    let params: NoiseParams = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let static_r = Builder::new(params.clone()).generate_keypair().unwrap();
+    let psk = [3u8; 32];
+    let template = Builder::new(params.clone())
+        .local_private_key(&static_r.private)
+        .psk(3, &psk)
+        .prologue(b"shared")
+        .into_template()
+        .unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let mut ephemerals = vec![];
+    for template in &[template.clone(), template] {
+        let static_i = Builder::new(params.clone()).generate_keypair().unwrap();
+        let mut h_i = Builder::new(params.clone())
+            .local_private_key(&static_i.private)
+            .psk(3, &psk)
+            .prologue(b"shared")
+            .build_initiator().unwrap();
+        let mut h_r = template.build_responder().unwrap();
+
+        while !(h_i.is_handshake_finished() && h_r.is_handshake_finished()) {
+            let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+            let len = sender.write_message(b"", &mut buffer_msg).unwrap();
+            receiver.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        }
+        assert_eq!(h_i.get_remote_static().unwrap(), &static_r.public[..]);
+        assert_eq!(h_r.get_remote_static().unwrap(), &static_i.public[..]);
+        ephemerals.push(h_i.get_remote_ephemeral().unwrap().to_vec());
+    }
+    assert_ne!(ephemerals[0], ephemerals[1]);
+
+    // Per-session key material can't go into a template.
+    let ephemeral = DefaultResolver.resolve_dh(&params.dh).unwrap();
+    assert!(matches!(Builder::new(params).local_ephemeral_dh(ephemeral).into_template(),
+                     Err(snow::Error::Input)));
+}
+
+/// Counts the ciphers handed out by the default resolver.
+struct CipherCountingResolver(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+impl CryptoResolver for CipherCountingResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        DefaultResolver.resolve_rng()
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        DefaultResolver.resolve_dh(choice)
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        DefaultResolver.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        DefaultResolver.resolve_cipher(choice)
+    }
+}
+
+#[test]
+fn test_builder_template_rebuild() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let resolved = std::sync::Arc::new(AtomicUsize::new(0));
+    let template = Builder::with_resolver(params.clone(), Box::new(CipherCountingResolver(resolved.clone())))
+        .into_template()
+        .unwrap();
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+
+    // A handshake cipher and two transport ciphers for a new session, only the handshake
+    // cipher for one that recycles a finished session's, or an abandoned handshake's.
+    let mut h_r = template.build_responder().unwrap();
+    assert_eq!(resolved.load(Ordering::SeqCst), 3);
+    for round in 0..3 {
+        let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+        let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+        h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+        h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        let mut h_i = h_i.into_transport_mode().unwrap();
+        let mut t_r = h_r.into_transport_mode().unwrap();
+
+        // The recycled ciphers start over with the new session's keys and nonces.
+        for _ in 0..2 {
+            let len = h_i.write_message(&[round], &mut buffer_msg).unwrap();
+            let len = t_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+            assert_eq!(&buffer_out[..len], &[round]);
+        }
+        assert_eq!(t_r.receiving_nonce().unwrap(), 2);
+
+        h_r = template.rebuild_responder(t_r).unwrap();
+        assert_eq!(resolved.load(Ordering::SeqCst), 4 + round as usize);
+    }
+    let h_r = template.rebuild_responder(h_r).unwrap();
+    assert_eq!(resolved.load(Ordering::SeqCst), 7);
+
+    // A stateless transport session's ciphers aren't recycled.
+    let mut h_i = Builder::new(params).build_initiator().unwrap();
+    let mut h_r = h_r;
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let t_r = h_r.into_stateless_transport_mode().unwrap();
+    let _ = template.rebuild_responder(t_r).unwrap();
+    assert_eq!(resolved.load(Ordering::SeqCst), 10);
+}
+
+#[test]
+fn test_builder_template_is_send_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<snow::BuilderTemplate>();
+
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let template = std::sync::Arc::new(Builder::new(params).into_template().unwrap());
+    let threads: Vec<_> = (0..2).map(|_| {
+        let template = template.clone();
+        std::thread::spawn(move || template.build_responder().is_ok())
+    }).collect();
+    for thread in threads {
+        assert!(thread.join().unwrap());
+    }
+}
+
+/// A tiny xorshift generator, so the garbage fed to the read path is the same on every run.
+struct Garbage(u64);
+
+impl Garbage {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+
+    /// Mangle a real message: truncate it, flip a bit, extend it, or throw it away entirely.
+    fn mangle(&mut self, message: &[u8]) -> Vec<u8> {
+        let mut mangled = message.to_vec();
+        match self.below(4) {
+            0 => mangled.truncate(self.below(message.len() + 1)),
+            1 if !mangled.is_empty() => {
+                let i = self.below(mangled.len());
+                mangled[i] ^= 1 << self.below(8);
+            },
+            2 => {
+                for _ in 0..=self.below(64) {
+                    mangled.push(self.next() as u8);
+                }
+            },
+            _ => {
+                mangled = (0..self.below(300)).map(|_| self.next() as u8).collect();
+            },
+        }
+        mangled
+    }
+
+    fn payload_buffer(&mut self) -> Vec<u8> {
+        vec![0u8; [0, 1, 15, 48, 65535][self.below(5)]]
+    }
+}
+
+const FUZZ_PSKS: [&[u8]; 2] = [&[1u8; 32], &[2u8; 32]];
+
+fn fuzz_builders<'a>(params: &NoiseParams, init_static: &'a [u8], resp_static: &'a [u8],
+                     init_public: &'a [u8], resp_public: &'a [u8]) -> (Builder<'a>, Builder<'a>) {
+    let pattern = params.handshake.pattern;
+    let mut init_builder = Builder::new(params.clone()).psks(&FUZZ_PSKS[..params.handshake.modifiers.list.len()]);
+    let mut resp_builder = Builder::new(params.clone()).psks(&FUZZ_PSKS[..params.handshake.modifiers.list.len()]);
+    if pattern.needs_local_static_key(true) {
+        init_builder = init_builder.local_private_key(init_static);
+    }
+    if pattern.need_known_remote_pubkey(true) {
+        init_builder = init_builder.remote_public_key(resp_public);
+    }
+    if pattern.needs_local_static_key(false) {
+        resp_builder = resp_builder.local_private_key(resp_static);
+    }
+    if pattern.need_known_remote_pubkey(false) {
+        resp_builder = resp_builder.remote_public_key(init_public);
+    }
+    (init_builder, resp_builder)
+}
+
+#[test]
+fn test_garbage_input_never_panics() {
+    let init_static = get_inc_key(0);
+    let resp_static = get_inc_key(32);
+    let init_public = x25519::x25519(init_static, x25519::X25519_BASEPOINT_BYTES);
+    let resp_public = x25519::x25519(resp_static, x25519::X25519_BASEPOINT_BYTES);
+    let mut garbage = Garbage(0x5eed);
+    let mut buffer_msg = vec![0u8; 65535];
+
+    for name in &["Noise_NN_25519_ChaChaPoly_BLAKE2s", "Noise_XX_25519_AESGCM_SHA256",
+                  "Noise_IK_25519_ChaChaPoly_SHA512", "Noise_KK_25519_AESGCM_BLAKE2b",
+                  "Noise_X1X1_25519_ChaChaPoly_BLAKE2s", "Noise_NNpsk0_25519_ChaChaPoly_SHA256",
+                  "Noise_XXpsk0+psk3_25519_AESGCM_BLAKE2s", "Noise_N_25519_ChaChaPoly_BLAKE2s"] {
+        let params: NoiseParams = name.parse().unwrap();
+        let messages = params.handshake.messages().unwrap().len();
+
+        // Run the handshake up to message `step`, then throw garbage at whoever reads it.
+        for step in 0..=messages {
+            for _ in 0..32 {
+                let (init_builder, resp_builder) = fuzz_builders(&params, &init_static, &resp_static,
+                                                                 &init_public, &resp_public);
+                let mut h_i = init_builder.build_initiator().unwrap();
+                let mut h_r = resp_builder.build_responder().unwrap();
+                for _ in 0..step {
+                    let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+                    let len = sender.write_message(b"payload", &mut buffer_msg).unwrap();
+                    receiver.read_message(&buffer_msg[..len], &mut [0u8; 64]).unwrap();
+                }
+                if step == messages {
+                    h_i = h_i.into_transport_mode().unwrap();
+                    h_r = h_r.into_transport_mode().unwrap();
+                }
+
+                let (sender, receiver) = if step == messages || h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+                let len = sender.write_message(b"payload", &mut buffer_msg).unwrap();
+                let mut mangled = garbage.mangle(&buffer_msg[..len]);
+                let mut payload = garbage.payload_buffer();
+                let _ = match garbage.below(if step == messages { 6 } else { 4 }) {
+                    0 => receiver.read_message(&mangled, &mut payload),
+                    1 => receiver.read_message_in_place(&mut mangled),
+                    2 => receiver.read_message_with_ad(&mangled[..garbage.below(mangled.len() + 1)], &mangled, &mut payload),
+                    3 => receiver.read_message_to_vec(&mangled, &mut payload).map(|_| 0),
+                    4 => receiver.read_message_padded(&mangled, &mut payload),
+                    _ => receiver.read_framed(&mangled, &mut payload).map(|(_, len)| len),
+                };
+                let _ = receiver.read_message(&mangled, &mut payload);
+            }
+        }
+    }
+}
+
+fn finished_nn_sessions() -> (Session, Session) {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone()).build_initiator().unwrap();
+    let mut h_r = Builder::new(params).build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    (h_i, h_r)
+}
+
+#[test]
+fn test_streaming_transport() {
+    use std::io::{Cursor, Read, Write};
+
+    let (h_i, h_r) = finished_nn_sessions();
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let mut writer = StreamingTransport::new(h_i, Vec::new()).unwrap().rekey_every(2);
+    for piece in data.chunks(7777) {
+        writer.write_all(piece).unwrap();
+    }
+
+    // Only full chunks have gone out so far, the rest waits for the flush.
+    let full_frames = data.len() / (65535 - 16);
+    assert_eq!(writer.get_ref().len(), full_frames * (2 + 65535));
+    writer.flush().unwrap();
+    writer.flush().unwrap();
+    assert_eq!(writer.session().sending_nonce().unwrap(), full_frames as u64 + 1);
+    let (_, stream) = writer.into_inner();
+    assert_eq!(stream.len(), data.len() + (full_frames + 1) * (2 + 16));
+    assert_eq!(&stream[..2], &[0xff, 0xff]);
+
+    let mut reader = StreamingTransport::new(h_r, Cursor::new(&stream[..])).unwrap().rekey_every(2);
+    let mut received = Vec::new();
+    reader.read_to_end(&mut received).unwrap();
+    assert!(received == data);
+    assert_eq!(reader.session().receiving_nonce().unwrap(), full_frames as u64 + 1);
+}
+
+#[test]
+fn test_streaming_transport_errors() {
+    use std::io::{Cursor, ErrorKind, Read, Write};
+
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let unfinished = Builder::new(params).build_initiator().unwrap();
+    match StreamingTransport::new(unfinished, Vec::<u8>::new()) {
+        Err(Error::State(StateProblem::HandshakeNotFinished)) => {},
+        res => panic!("unfinished handshake wasn't refused: {:?}", res.map(|_| ())),
+    }
+    let (h_i, _) = finished_nn_sessions();
+    match StreamingTransport::new(h_i.into_stateless_transport_mode().unwrap(), Vec::<u8>::new()) {
+        Err(Error::State(StateProblem::StatelessTransportMode)) => {},
+        res => panic!("stateless session wasn't refused: {:?}", res.map(|_| ())),
+    }
+
+    let (h_i, h_r) = finished_nn_sessions();
+    let mut writer = StreamingTransport::new(h_i, Vec::new()).unwrap();
+    writer.write_all(b"hello").unwrap();
+    writer.flush().unwrap();
+    writer.write_all(b"world").unwrap();
+    writer.flush().unwrap();
+    let (_, stream) = writer.into_inner();
+    let frame_len = stream.len() / 2;
+
+    // A stream that ends mid-frame is an error, one that ends between frames isn't.
+    let mut reader = StreamingTransport::new(h_r, Cursor::new(&stream[..frame_len + 1])).unwrap();
+    let mut received = [0u8; 3];
+    reader.read_exact(&mut received).unwrap();
+    assert_eq!(&received, b"hel");
+    let mut rest = Vec::new();
+    assert_eq!(reader.read_to_end(&mut rest).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(&rest, b"lo");
+
+    // A tampered frame doesn't decrypt, and neither does one sent on a different rekey schedule.
+    let (_, h_r) = finished_nn_sessions();
+    let mut tampered = stream.clone();
+    tampered[4] ^= 1;
+    let mut reader = StreamingTransport::new(h_r, Cursor::new(tampered)).unwrap();
+    assert_eq!(reader.read(&mut received).unwrap_err().kind(), ErrorKind::InvalidData);
+
+    let (h_i, h_r) = finished_nn_sessions();
+    let mut writer = StreamingTransport::new(h_i, Vec::new()).unwrap().rekey_every(1);
+    writer.write_all(b"hello").unwrap();
+    writer.flush().unwrap();
+    writer.write_all(b"world").unwrap();
+    writer.flush().unwrap();
+    let (_, stream) = writer.into_inner();
+    let mut reader = StreamingTransport::new(h_r, Cursor::new(stream)).unwrap();
+    let mut received = Vec::new();
+    assert_eq!(reader.read_to_end(&mut received).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert_eq!(&received, b"hello");
+}
+
+/// A stream that moves at most 3 bytes at a time, and would block on every other call.
+struct Trickle<S> {
+    inner: S,
+    blocked: bool,
+}
+
+impl<S> Trickle<S> {
+    fn tick(&mut self) -> std::io::Result<()> {
+        self.blocked = !self.blocked;
+        if !self.blocked {
+            Err(std::io::ErrorKind::WouldBlock.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<S: std::io::Read> std::io::Read for Trickle<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.tick()?;
+        let len = buf.len().min(3);
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+impl<S: std::io::Write> std::io::Write for Trickle<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.tick()?;
+        let len = buf.len().min(3);
+        self.inner.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn test_streaming_transport_partial_io() {
+    use std::io::{Cursor, ErrorKind, Read, Write};
+
+    let (h_i, h_r) = finished_nn_sessions();
+    let data: Vec<u8> = (0..150_000u32).map(|i| (i % 241) as u8).collect();
+    let mut writer = StreamingTransport::new(h_i, Trickle { inner: Vec::new(), blocked: true }).unwrap();
+    let mut written = 0;
+    while written < data.len() {
+        match writer.write(&data[written..written + 1000.min(data.len() - written)]) {
+            Ok(len) => written += len,
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {},
+            Err(err) => panic!("write failed: {}", err),
+        }
+    }
+    loop {
+        match writer.flush() {
+            Ok(()) => break,
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {},
+            Err(err) => panic!("flush failed: {}", err),
+        }
+    }
+    let (_, stream) = writer.into_inner();
+
+    let mut reader = StreamingTransport::new(h_r, Trickle { inner: Cursor::new(stream.inner), blocked: true }).unwrap();
+    let mut received = Vec::new();
+    let mut buf = [0u8; 5000];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => received.extend_from_slice(&buf[..len]),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {},
+            Err(err) => panic!("read failed: {}", err),
+        }
+    }
+    assert!(received == data);
+}
+
+/// A stream that takes `limit` bytes, then fails once with `BrokenPipe` and takes the rest.
+struct Hiccup {
+    inner: Vec<u8>,
+    limit: usize,
+}
+
+impl std::io::Write for Hiccup {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.inner.len() == self.limit {
+            self.limit = usize::MAX;
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        let len = buf.len().min(self.limit - self.inner.len());
+        self.inner.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_streaming_transport_write_error_after_sealing() {
+    use std::io::{Cursor, ErrorKind, Read, Write};
+
+    let (h_i, h_r) = finished_nn_sessions();
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 241) as u8).collect();
+    let mut writer = StreamingTransport::new(h_i, Hiccup { inner: Vec::new(), limit: 100 }).unwrap();
+
+    // The failure to send the first frame comes after its bytes were taken, so it's held back
+    // for the next call, which takes nothing. Retrying that call doesn't duplicate anything.
+    let len = writer.write(&data).unwrap();
+    assert!(len < data.len());
+    match writer.write(&data[len..]) {
+        Err(ref err) if err.kind() == ErrorKind::BrokenPipe => {},
+        res => panic!("unexpected result: {:?}", res),
+    }
+    writer.write_all(&data[len..]).unwrap();
+    writer.flush().unwrap();
+    let (_, stream) = writer.into_inner();
+
+    let mut reader = StreamingTransport::new(h_r, Cursor::new(stream.inner)).unwrap();
+    let mut received = Vec::new();
+    reader.read_to_end(&mut received).unwrap();
+    assert!(received == data);
+}
+
+#[test]
+fn test_noise_stream() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let static_i = Builder::new(params.clone()).generate_keypair().unwrap();
+    let static_r = Builder::new(params.clone()).generate_keypair().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let responder = {
+        let params = params.clone();
+        let private = static_r.private.clone();
+        std::thread::spawn(move || {
+            let session = Builder::new(params).local_private_key(&private).build_responder().unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = NoiseStream::handshake(session, stream).unwrap();
+
+            // Echo everything back until the initiator is done.
+            let mut request = Vec::new();
+            socket.read_to_end(&mut request).unwrap();
+            socket.write_all(&request).unwrap();
+            socket.flush().unwrap();
+            socket.session().get_remote_static().unwrap().to_vec()
+        })
+    };
+
+    let session = Builder::new(params).local_private_key(&static_i.private).build_initiator().unwrap();
+    let mut socket = NoiseStream::handshake(session, TcpStream::connect(addr).unwrap()).unwrap();
+    assert_eq!(socket.session().get_remote_static().unwrap(), &static_r.public[..]);
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 239) as u8).collect();
+    socket.write_all(&data).unwrap();
+    socket.flush().unwrap();
+    socket.get_mut().shutdown(std::net::Shutdown::Write).unwrap();
+
+    let mut echoed = Vec::new();
+    socket.read_to_end(&mut echoed).unwrap();
+    assert!(echoed == data);
+    assert_eq!(responder.join().unwrap(), static_i.public);
+}
+
+/// One end of an in-memory, non-blocking duplex pipe.
+struct PipeEnd {
+    incoming: std::rc::Rc<std::cell::RefCell<std::collections::VecDeque<u8>>>,
+    outgoing: std::rc::Rc<std::cell::RefCell<std::collections::VecDeque<u8>>>,
+}
+
+fn pipe() -> (PipeEnd, PipeEnd) {
+    let a = std::rc::Rc::new(std::cell::RefCell::new(std::collections::VecDeque::new()));
+    let b = std::rc::Rc::new(std::cell::RefCell::new(std::collections::VecDeque::new()));
+    (PipeEnd { incoming: a.clone(), outgoing: b.clone() }, PipeEnd { incoming: b, outgoing: a })
+}
+
+impl std::io::Read for PipeEnd {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(incoming.len());
+        for (byte, queued) in buf.iter_mut().zip(incoming.drain(..len)) {
+            *byte = queued;
+        }
+        Ok(len)
+    }
+}
+
+impl std::io::Write for PipeEnd {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.outgoing.borrow_mut().extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_mid_handshake() {
+    use std::io::{ErrorKind, Read, Write};
+
+    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let static_i = Builder::new(params.clone()).generate_keypair().unwrap();
+    let static_r = Builder::new(params.clone()).generate_keypair().unwrap();
+    let h_i = Builder::new(params.clone()).local_private_key(&static_i.private).build_initiator().unwrap();
+    let h_r = Builder::new(params).local_private_key(&static_r.private).build_responder().unwrap();
+    let (end_i, end_r) = pipe();
+    let mut mid_i = MidHandshake::new(h_i, Trickle { inner: end_i, blocked: true });
+    let mut mid_r = MidHandshake::new(h_r, Trickle { inner: end_r, blocked: true });
+
+    // Drive both sides in turn, as an event loop would whenever a socket is ready.
+    let mut rounds = 0;
+    while !(mid_i.is_finished() && mid_r.is_finished()) {
+        for mid in [&mut mid_i, &mut mid_r] {
+            match mid.advance() {
+                Ok(()) => {},
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {},
+                Err(err) => panic!("handshake failed: {}", err),
+            }
+        }
+        rounds += 1;
+        assert!(rounds < 1000);
+    }
+    assert!(rounds > 3);
+    assert_eq!(mid_r.session().get_remote_static().unwrap(), &static_i.public[..]);
+    let mut socket_i = mid_i.into_stream().unwrap();
+    let mut socket_r = mid_r.into_stream().unwrap();
+
+    let data: Vec<u8> = (0..70_000u32).map(|i| (i % 233) as u8).collect();
+    let mut written = 0;
+    let mut received = Vec::new();
+    let mut flushed = false;
+    let mut buf = [0u8; 4096];
+    while received.len() < data.len() {
+        if written < data.len() {
+            match socket_i.write(&data[written..]) {
+                Ok(len) => written += len,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {},
+                Err(err) => panic!("write failed: {}", err),
+            }
+        } else if !flushed {
+            match socket_i.flush() {
+                Ok(()) => flushed = true,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {},
+                Err(err) => panic!("flush failed: {}", err),
+            }
+        }
+        match socket_r.read(&mut buf) {
+            Ok(len) => received.extend_from_slice(&buf[..len]),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {},
+            Err(err) => panic!("read failed: {}", err),
+        }
+    }
+    assert!(received == data);
+
+    // An empty frame can't be a transport message, and isn't waited on forever either.
+    socket_r.get_mut().inner.incoming.borrow_mut().extend(&[0, 0]);
+    loop {
+        match socket_r.read(&mut buf) {
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {},
+            Err(err) => {
+                assert_eq!(err.kind(), ErrorKind::InvalidData);
+                break;
+            },
+            Ok(len) => panic!("empty frame read as {} bytes", len),
+        }
+    }
+
+    let (end, _) = pipe();
+    let unfinished = Builder::new("Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap()).build_initiator().unwrap();
+    assert!(MidHandshake::new(unfinished, end).into_stream().is_err());
+}
+
+#[cfg(feature = "risky-trace")]
+type Trace = std::cell::RefCell<Vec<(String, Vec<u8>, Vec<u8>, Vec<u8>)>>;
+
+#[cfg(feature = "risky-trace")]
+thread_local! {
+    static TRACE_I: Trace = Trace::default();
+    static TRACE_R: Trace = Trace::default();
+}
+
+#[test]
+#[cfg(feature = "risky-trace")]
+fn test_trace_symmetric_state() {
+    fn trace_i(label: &str, data: &[u8], h: &[u8], ck: &[u8]) {
+        TRACE_I.with(|t| t.borrow_mut().push((label.to_string(), data.to_vec(), h.to_vec(), ck.to_vec())));
+    }
+    fn trace_r(label: &str, data: &[u8], h: &[u8], ck: &[u8]) {
+        TRACE_R.with(|t| t.borrow_mut().push((label.to_string(), data.to_vec(), h.to_vec(), ck.to_vec())));
+    }
+
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .prologue(b"prologue")
+        .trace_symmetric_state(trace_i)
+        .build_initiator().unwrap();
+    // Templates carry the hook too.
+    let mut h_r = Builder::new(params)
+        .prologue(b"prologue")
+        .trace_symmetric_state(trace_r)
+        .into_template().unwrap()
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"def", &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+
+    let trace_i = TRACE_I.with(|t| t.borrow().clone());
+    let trace_r = TRACE_R.with(|t| t.borrow().clone());
+    let labels: Vec<&str> = trace_i.iter().map(|step| &step.0[..]).collect();
+    assert_eq!(labels, ["initialize", "mix_hash", "mix_hash", "mix_hash", "mix_hash", "mix_key", "mix_hash"]);
+    assert_eq!(&trace_i[0].1[..], b"Noise_NN_25519_ChaChaPoly_BLAKE2s");
+    assert_eq!(&trace_i[1].1[..], b"prologue");
+    assert_eq!(trace_i, trace_r);
+    assert_eq!(&trace_i.last().unwrap().2[..], h_i.get_handshake_hash().unwrap());
+}
+
+#[test]
+#[cfg(feature = "risky-trace")]
+fn test_trace_failed_static_key_read() {
+    fn trace_r(label: &str, data: &[u8], h: &[u8], ck: &[u8]) {
+        TRACE_R.with(|t| t.borrow_mut().push((label.to_string(), data.to_vec(), h.to_vec(), ck.to_vec())));
+    }
+
+    let params: NoiseParams = "Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let resp_static = get_inc_key(32);
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .remote_public_key(&x25519::x25519(resp_static, x25519::X25519_BASEPOINT_BYTES))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&resp_static)
+        .trace_symmetric_state(trace_r)
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(b"abc", &mut buffer_msg).unwrap();
+
+    // "-> e, es, s, ss": a static key that doesn't decrypt still goes through `ss` and the
+    // payload, the same steps as a read where only the payload is wrong.
+    let mut labels = vec![];
+    for &i in &[40, len - 1] {
+        let mut tampered = buffer_msg;
+        tampered[i] ^= 0x01;
+        TRACE_R.with(|t| t.borrow_mut().clear());
+        assert!(h_r.read_message(&tampered[..len], &mut buffer_out).is_err());
+        labels.push(TRACE_R.with(|t| t.borrow().iter().map(|step| step.0.clone()).collect::<Vec<_>>()));
+    }
+    assert_eq!(labels[0], ["mix_hash", "mix_key", "mix_hash", "mix_key", "mix_hash", "restore"]);
+    assert_eq!(labels[0], labels[1]);
+}
+
+/// A stand-in for Kyber1024, which the `DefaultResolver` only has with the opt-in
+/// `kyber1024` feature, and which couldn't be made to lie anyway: an X25519 key pair, encapsulated to with a fresh X25519 key that goes out as the ciphertext. With
+/// `honest` unset, decapsulation comes up with the wrong secret.
+struct X25519Kem {
+    private: [u8; 32],
+    public: [u8; 32],
+    honest: bool,
+}
+
+impl Kem for X25519Kem {
+    fn name(&self) -> &'static str {
+        "Kyber1024"
+    }
+
+    fn pub_len(&self) -> usize {
+        32
+    }
+
+    fn ciphertext_len(&self) -> usize {
+        32
+    }
+
+    fn shared_secret_len(&self) -> usize {
+        32
+    }
+
+    fn generate(&mut self, rng: &mut dyn Random) {
+        rng.fill_bytes(&mut self.private);
+        self.public = x25519::x25519(self.private, x25519::X25519_BASEPOINT_BYTES);
+    }
+
+    fn pubkey(&self) -> &[u8] {
+        &self.public
+    }
+
+    fn encapsulate(&self, pubkey: &[u8], rng: &mut dyn Random, shared_secret_out: &mut [u8], ciphertext_out: &mut [u8]) -> Result<(), ()> {
+        let mut ephemeral = [0u8; 32];
+        let mut remote = [0u8; 32];
+        rng.fill_bytes(&mut ephemeral);
+        remote.copy_from_slice(pubkey);
+        ciphertext_out.copy_from_slice(&x25519::x25519(ephemeral, x25519::X25519_BASEPOINT_BYTES));
+        shared_secret_out.copy_from_slice(&x25519::x25519(ephemeral, remote));
+        Ok(())
+    }
+
+    fn decapsulate(&self, ciphertext: &[u8], shared_secret_out: &mut [u8]) -> Result<(), ()> {
+        let mut ephemeral = [0u8; 32];
+        ephemeral.copy_from_slice(ciphertext);
+        let private = if self.honest { self.private } else { get_inc_key(0) };
+        shared_secret_out.copy_from_slice(&x25519::x25519(private, ephemeral));
+        Ok(())
+    }
+}
+
+struct KemResolver {
+    honest: bool,
+}
+
+impl CryptoResolver for KemResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        None
+    }
+
+    fn resolve_dh(&self, _choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        None
+    }
+
+    fn resolve_hash(&self, _choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        None
+    }
+
+    fn resolve_cipher(&self, _choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        None
+    }
+
+    fn resolve_kem(&self, choice: &KemChoice) -> Option<Box<dyn Kem>> {
+        match *choice {
+            KemChoice::Kyber1024 => Some(Box::new(X25519Kem { private: [0u8; 32], public: [0u8; 32], honest: self.honest })),
+        }
+    }
+}
+
+fn kem_builder(params: NoiseParams, honest: bool) -> Builder<'static> {
+    Builder::with_resolver(params, Box::new(FallbackResolver::new(Box::new(KemResolver { honest }), Box::new(DefaultResolver))))
+}
+
+#[test]
+fn test_hfs_handshake() {
+    let resp_static = get_inc_key(32);
+    let resp_public = x25519::x25519(resp_static, x25519::X25519_BASEPOINT_BYTES);
+    for name in &["Noise_XXhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s",
+                  "Noise_IKhfs_25519+Kyber1024_AESGCM_SHA256",
+                  "Noise_NNpsk0+hfs_25519+Kyber1024_ChaChaPoly_SHA512"] {
+        let params: NoiseParams = name.parse().unwrap();
+        let psks: &[&[u8]] = if params.handshake.is_psk() { &[&[7u8; 32]] } else { &[] };
+        let mut h_i = kem_builder(params.clone(), true)
+            .local_private_key(&get_inc_key(0))
+            .remote_public_key(&resp_public)
+            .psks(psks)
+            .build_initiator().unwrap();
+        let mut h_r = kem_builder(params, true)
+            .local_private_key(&resp_static)
+            .psks(psks)
+            .build_responder().unwrap();
+
+        let mut buffer_msg = [0u8; 400];
+        let mut buffer_out = [0u8; 400];
+        let mut messages = 0;
+        while !h_i.is_handshake_finished() {
+            let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+            let overhead = sender.get_next_message_overhead();
+            let len = sender.write_message(b"payload", &mut buffer_msg).unwrap();
+            assert_eq!(len, overhead + 7, "{} message {}", name, messages);
+
+            // Flipping a bit anywhere, the KEM public key and ciphertext included, fails the
+            // read without getting in the way of the genuine message. Only the first message of
+            // XX goes out before there's a key to authenticate it with.
+            let authenticated = messages > 0 || !name.contains("XX");
+            for i in (0..len).filter(|_| authenticated) {
+                let mut tampered = buffer_msg;
+                tampered[i] ^= 0x01;
+                assert!(receiver.read_message(&tampered[..len], &mut buffer_out).is_err(),
+                        "{} message {}: flipping byte {} went unnoticed", name, messages, i);
+            }
+            let payload_len = receiver.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+            assert_eq!(&buffer_out[..payload_len], b"payload");
+            messages += 1;
+        }
+        assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap());
+
+        let mut h_i = h_i.into_transport_mode().unwrap();
+        let mut h_r = h_r.into_transport_mode().unwrap();
+        let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+        let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert_eq!(&buffer_out[..len], b"hack the planet");
+    }
+}
+
+#[test]
+fn test_hfs_shared_secret_is_mixed_in() {
+    // The DH half of the handshake is fine, so a KEM that comes up with the wrong secret is
+    // the only reason for the initiator to fail at the responder's static key.
+    let params: NoiseParams = "Noise_XXhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = kem_builder(params.clone(), false)
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = kem_builder(params, true)
+        .local_private_key(&get_inc_key(32))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 400];
+    let mut buffer_out = [0u8; 400];
+    let len = h_i.write_message(b"", &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(b"", &mut buffer_msg).unwrap();
+    match h_i.read_message(&buffer_msg[..len], &mut buffer_out) {
+        Err(Error::Decrypt) => {},
+        res => panic!("a wrong KEM secret went unnoticed: {:?}", res),
+    }
+}
+
+#[test]
+fn test_hfs_from_template() {
+    let params: NoiseParams = "Noise_XXhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let template_i = kem_builder(params.clone(), true).local_private_key(&get_inc_key(0)).into_template().unwrap();
+    let template_r = kem_builder(params, true).local_private_key(&get_inc_key(32)).into_template().unwrap();
+
+    for _ in 0..2 {
+        let mut h_i = template_i.build_initiator().unwrap();
+        let mut h_r = template_r.build_responder().unwrap();
+        let mut buffer_msg = [0u8; 4000];
+        let mut buffer_out = [0u8; 4000];
+        while !h_i.is_handshake_finished() {
+            let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+            let len = sender.write_message(&[], &mut buffer_msg).unwrap();
+            receiver.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        }
+        assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap());
+    }
+}
+
+#[test]
+fn test_hfs_fallback_needs_the_same_kem() {
+    // Fallback patterns can't have the hfs modifier, so an hfs handshake has nothing to fall
+    // back to.
+    let params: NoiseParams = "Noise_IKhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = kem_builder(params, true)
+        .local_private_key(&get_inc_key(0))
+        .remote_public_key(&get_inc_key(1))
+        .build_initiator().unwrap();
+    let mut buffer_msg = [0u8; 4000];
+    h_i.write_message(&[], &mut buffer_msg).unwrap();
+    match h_i.into_fallback("Noise_XXfallback_25519_ChaChaPoly_BLAKE2s".parse().unwrap(), &[]) {
+        Err(Error::Init(InitStage::ValidateFallback)) => {},
+        res => panic!("fell back to params without the KEM: {:?}", res.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_hfs_needs_a_kem() {
+    // Without the kyber1024 feature, none of the bundled resolvers has a KEM.
+    #[cfg(not(feature = "kyber1024"))]
+    {
+        let params: NoiseParams = "Noise_NNhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s".parse().unwrap();
+        match Builder::new(params).build_initiator() {
+            Err(Error::Init(InitStage::GetKemImpl)) => {},
+            res => panic!("built an hfs handshake without a KEM: {:?}", res.map(|_| ())),
+        }
+    }
+
+    // A custom pattern with KEM tokens needs one named in the params.
+    let pattern = CustomPattern::new("NNkem", &[], &[], &[&[Token::E, Token::E1], &[Token::E, Token::Dhee, Token::Ekem1]]).unwrap();
+    match kem_builder("Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap(), true).custom_pattern(pattern).build_initiator() {
+        Err(Error::Init(InitStage::GetKemImpl)) => {},
+        res => panic!("built KEM tokens without a KEM: {:?}", res.map(|_| ())),
+    }
+}
+
+#[test]
+#[cfg(feature = "kyber1024")]
+fn test_hfs_handshake_kyber1024() {
+    let params: NoiseParams = "Noise_XXhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&get_inc_key(0))
+        .build_initiator().unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&get_inc_key(32))
+        .build_responder().unwrap();
+
+    let mut buffer_msg = [0u8; 4000];
+    let mut buffer_out = [0u8; 4000];
+    while !h_i.is_handshake_finished() {
+        let (sender, receiver) = if h_i.is_my_turn() { (&mut h_i, &mut h_r) } else { (&mut h_r, &mut h_i) };
+        let overhead = sender.get_next_message_overhead();
+        let len = sender.write_message(b"payload", &mut buffer_msg).unwrap();
+        assert_eq!(len, overhead + 7);
+        let payload_len = receiver.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        assert_eq!(&buffer_out[..payload_len], b"payload");
+    }
+    assert_eq!(h_i.get_handshake_hash().unwrap(), h_r.get_handshake_hash().unwrap());
+
+    let mut h_i = h_i.into_transport_mode().unwrap();
+    let mut h_r = h_r.into_transport_mode().unwrap();
+    let len = h_i.write_message(b"hack the planet", &mut buffer_msg).unwrap();
+    let len = h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(&buffer_out[..len], b"hack the planet");
+}
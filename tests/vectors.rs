@@ -247,14 +247,9 @@ fn test_vectors_from_json(json: &str) {
 
     let mut passes = 0;
     let mut fails = 0;
-    let mut ignored = 0;
 
     for vector in test_vectors.vectors {
         let params: NoiseParams = vector.protocol_name.parse().unwrap();
-        if params.dh == DHChoice::Ed448 {
-            ignored += 1;
-            continue;
-        }
         let (init, resp) = match build_session_pair(&vector) {
             Ok((init, resp)) => (init, resp),
             Err(s) => {
@@ -280,7 +275,6 @@ fn test_vectors_from_json(json: &str) {
     }
 
     println!("\n{}/{} passed", passes, passes+fails);
-    println!("* ignored {} unsupported variants", ignored);
     if fails > 0 {
         panic!("at least one vector failed.");
     }
@@ -460,6 +454,20 @@ fn test_vectors_cacophony() {
     test_vectors_from_json(include_str!("vectors/cacophony.txt"));
 }
 
+// The harness above happily passes on an empty file, so make sure the handshakes most
+// deployments actually use are always among the vectors being checked.
+#[test]
+fn test_vectors_cacophony_cover_core_patterns() {
+    let test_vectors: TestVectors = serde_json::from_str(include_str!("vectors/cacophony.txt")).unwrap();
+    for pattern in &["NN", "XX", "IK"] {
+        for hash in &["SHA256", "BLAKE2s"] {
+            let protocol_name = format!("Noise_{}_25519_ChaChaPoly_{}", pattern, hash);
+            assert!(test_vectors.vectors.iter().any(|v| v.protocol_name == protocol_name),
+                    "no cacophony vector for {}", protocol_name);
+        }
+    }
+}
+
 #[test]
 fn test_vectors_snow() {
     let file = OpenOptions::new().write(true).create_new(true).open("tests/vectors/snow.txt");